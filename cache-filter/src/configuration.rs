@@ -1,14 +1,331 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+use threescale::breaker::CircuitBreakerConfig;
+use threescale::config::{ensure, humanized, nested_changes, record_change, ConfigError};
+use threescale::local_cache::LocalCacheConfig;
+use threescale::utils::LimitTolerance;
 
-#[derive(Deserialize, Debug, Clone)]
-#[serde(default)]
+/// Behaviour applied to a request that could not be authorized as usual.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum FailurePolicy {
+    /// Request proceeds to the next filter and its usage is not reported.
+    Allow,
+    /// Request is denied.
+    Deny,
+    /// Request proceeds and its usage is handed to the singleton service, which reports it to
+    /// 3scale with the next flush.
+    AllowAndReportLater,
+    /// Request is checked against the cached application, however stale. Without a cached
+    /// application, failure_mode_deny applies.
+    ServeStale,
+}
+
+// Categories of failures, each one handled with its own failure policy.
+// CircuitOpen - Authorize call was not sent since the circuit of the upstream is open.
+// BackendRejectedConfig - 3scale rejected the authorize call for the configuration of the gateway.
+// Other - Failures outside of the configurable categories, eg: unparsable responses from 3scale,
+// always denied since nothing is known about the state of the application.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum FailureCategory {
+    AuthorizeTimeout,
+    BackendError,
+    SharedDataFailure,
+    MessageQueueFull,
+    MissingCredentials,
+    CircuitOpen,
+    BackendRejectedConfig,
+    Other,
+}
+
+/// Failure policies by category of failure. Unset categories follow failure_mode_deny, except
+/// message_queue_full which allows the request and missing_credentials which denies it. Failures
+/// outside of these categories are always denied.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(default, deny_unknown_fields)]
+pub struct FailurePolicies {
+    /// Authorize call to 3scale timed out.
+    pub authorize_timeout: Option<FailurePolicy>,
+    /// 3scale backend answered with a 5xx status or could not be called.
+    pub backend_error: Option<FailurePolicy>,
+    /// Reading or writing shared data (cached applications, callout-locks) failed.
+    pub shared_data_failure: Option<FailurePolicy>,
+    /// Usage of a request could not be handed to the singleton service through the message queue.
+    pub message_queue_full: Option<FailurePolicy>,
+    /// Request carries neither an app id nor a user key.
+    pub missing_credentials: Option<FailurePolicy>,
+    /// Authorize call was not sent since the circuit breaker of the upstream is open. Unset, it
+    /// follows stale_while_unavailable.unknown_apps if enabled.
+    pub circuit_open: Option<FailurePolicy>,
+    /// 3scale rejected the authorize call for the credentials of the service or the metrics of
+    /// the request, eg: an invalid service token.
+    pub backend_rejected_config: Option<FailurePolicy>,
+}
+
+impl FailurePolicies {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        // Neither the singleton service nor the cache can be used for these failures.
+        for (field, policy) in [
+            ("message_queue_full", self.message_queue_full),
+            ("missing_credentials", self.missing_credentials),
+        ]
+        .iter()
+        {
+            ensure(
+                matches!(
+                    policy,
+                    None | Some(FailurePolicy::Allow) | Some(FailurePolicy::Deny)
+                ),
+                field,
+                "must be Allow or Deny",
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn changes(&self, new: &Self) -> Vec<String> {
+        let mut changes = Vec::new();
+        record_change(
+            &mut changes,
+            "authorize_timeout",
+            &self.authorize_timeout,
+            &new.authorize_timeout,
+        );
+        record_change(
+            &mut changes,
+            "backend_error",
+            &self.backend_error,
+            &new.backend_error,
+        );
+        record_change(
+            &mut changes,
+            "shared_data_failure",
+            &self.shared_data_failure,
+            &new.shared_data_failure,
+        );
+        record_change(
+            &mut changes,
+            "message_queue_full",
+            &self.message_queue_full,
+            &new.message_queue_full,
+        );
+        record_change(
+            &mut changes,
+            "missing_credentials",
+            &self.missing_credentials,
+            &new.missing_credentials,
+        );
+        record_change(
+            &mut changes,
+            "circuit_open",
+            &self.circuit_open,
+            &new.circuit_open,
+        );
+        record_change(
+            &mut changes,
+            "backend_rejected_config",
+            &self.backend_rejected_config,
+            &new.backend_rejected_config,
+        );
+        changes
+    }
+}
+
+/** Reasoning behind stale-while-unavailable:
+* Cached applications are kept up to date by the singleton, which authorizes them again with every
+* flush. Once they are max_age old, applications are expired and authorized again before being used.
+* While 3scale backend is unhealthy, such an authorization would only fail, so expired applications
+* keep being used up to max_staleness. Requests for applications that are not cached (or too stale)
+* are handled with the unknown_apps policy instead of waiting for a call bound to fail.
+**/
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(default, deny_unknown_fields)]
+pub struct StaleConfig {
+    /// Enables the expiration of cached applications and their use past it while 3scale backend
+    /// is unhealthy.
+    pub enabled: bool,
+    /// Age after which a cached application is authorized again before being used.
+    #[serde(with = "humanized")]
+    #[cfg_attr(feature = "schema", schemars(with = "String"))]
+    pub max_age: Duration,
+    /// Age up to which an expired application is still used while 3scale backend is unhealthy.
+    #[serde(with = "humanized")]
+    #[cfg_attr(feature = "schema", schemars(with = "String"))]
+    pub max_staleness: Duration,
+    /// Policy for requests of applications that can't be authorized while the circuit of 3scale
+    /// backend is open, unless failure_policies.circuit_open is set.
+    pub unknown_apps: FailurePolicy,
+}
+
+impl Default for StaleConfig {
+    fn default() -> Self {
+        StaleConfig {
+            enabled: false,
+            max_age: Duration::from_secs(300),
+            max_staleness: Duration::from_secs(3600),
+            unknown_apps: FailurePolicy::Deny,
+        }
+    }
+}
+
+impl StaleConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        ensure(
+            self.max_age > Duration::default(),
+            "max_age",
+            "must be greater than 0",
+        )?;
+        ensure(
+            self.max_staleness >= self.max_age,
+            "max_staleness",
+            "must not be lower than max_age",
+        )?;
+        ensure(
+            self.unknown_apps != FailurePolicy::ServeStale,
+            "unknown_apps",
+            "must be Allow, Deny or AllowAndReportLater",
+        )
+    }
+
+    pub fn changes(&self, new: &Self) -> Vec<String> {
+        let mut changes = Vec::new();
+        record_change(&mut changes, "enabled", &self.enabled, &new.enabled);
+        record_change(&mut changes, "max_age", &self.max_age, &new.max_age);
+        record_change(
+            &mut changes,
+            "max_staleness",
+            &self.max_staleness,
+            &new.max_staleness,
+        );
+        record_change(
+            &mut changes,
+            "unknown_apps",
+            &self.unknown_apps,
+            &new.unknown_apps,
+        );
+        changes
+    }
+}
+
+/// Behaviour applied to a request using metrics unknown to its service.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum UnknownMetricPolicy {
+    /// Request is checked and reported with every metric, as if unknown ones had no limit.
+    Allow,
+    /// Request is denied.
+    Deny,
+    /// Unknown metrics are removed from the usage of the request before it is checked.
+    Drop,
+}
+
+/** Reasoning behind the validation of metrics:
+* Metrics of a request without a limit in the cached application are considered unlimited, so a
+* misspelled metric went unnoticed until 3scale rejected the report including it. Metrics and
+* methods are known to a service once they show up in the usage reports or in the hierarchy of an
+* authorize response for any of its applications. Usage-only metrics, having neither a limit nor a
+* parent, never show up there and have to be configured in `known` before denying or dropping
+* unknown metrics. A service without any known metric is not validated, since every metric would
+* be unknown to it.
+**/
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(default, deny_unknown_fields)]
+pub struct UnknownMetricsConfig {
+    /// Policy for requests using metrics unknown to their service.
+    pub policy: UnknownMetricPolicy,
+    /// Metric and method names of single services, by service id, known in addition to those
+    /// learned from authorize responses.
+    pub known: HashMap<String, Vec<String>>,
+}
+
+impl Default for UnknownMetricsConfig {
+    fn default() -> Self {
+        UnknownMetricsConfig {
+            policy: UnknownMetricPolicy::Allow,
+            known: HashMap::new(),
+        }
+    }
+}
+
+impl UnknownMetricsConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        for (service_id, metrics) in self.known.iter() {
+            ensure(
+                metrics.iter().all(|metric| !metric.is_empty()),
+                &format!("known.{}", service_id),
+                "must not contain empty names",
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn changes(&self, new: &Self) -> Vec<String> {
+        let mut changes = Vec::new();
+        record_change(&mut changes, "policy", &self.policy, &new.policy);
+        let mut service_ids = self
+            .known
+            .keys()
+            .chain(new.known.keys())
+            .collect::<Vec<_>>();
+        service_ids.sort();
+        service_ids.dedup();
+        for service_id in service_ids {
+            record_change(
+                &mut changes,
+                &format!("known.{}", service_id),
+                &self.known.get(service_id),
+                &new.known.get(service_id),
+            );
+        }
+        changes
+    }
+
+    /// Returns true if the metric is configured as known to the service.
+    pub fn is_configured(&self, service_id: &str, metric: &str) -> bool {
+        matches!(
+            self.known.get(service_id),
+            Some(metrics) if metrics.iter().any(|known| known == metric)
+        )
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(default, deny_unknown_fields)]
 pub struct FilterConfig {
     /// Behaviour in case of a cache miss and authorize call gets failed.
     pub failure_mode_deny: bool,
+    /// Behaviour for each category of failure, overriding failure_mode_deny.
+    pub failure_policies: FailurePolicies,
     /// Number of retries for setting data to cache
     pub max_tries: u32,
     /// Max memory in bytes that shared data is allowed to use.
     pub max_shared_memory_bytes: u64,
+    /// Duration after which a callout-lock can be taken over by another thread.
+    /// Should be longer than the timeout of the authorize callout.
+    #[serde(with = "humanized")]
+    #[cfg_attr(feature = "schema", schemars(with = "String"))]
+    pub callout_lease_duration: Duration,
+    /// Safety margin applied when checking limits against the local cache.
+    pub limit_tolerance: LimitTolerance,
+    /// Use of expired cached applications while 3scale backend is unhealthy.
+    pub stale_while_unavailable: StaleConfig,
+    /// Circuit breaker applied to authorize calls, which also tells whether 3scale backend is
+    /// unhealthy.
+    pub circuit_breaker: CircuitBreakerConfig,
+    /// Applications kept decoded by every worker, with their hits written in batches.
+    pub local_cache: LocalCacheConfig,
+    /// Validation of the metrics of requests against those known to their service.
+    pub unknown_metrics: UnknownMetricsConfig,
+    /// Time an app key still unknown after authorizing its application again is denied for,
+    /// without authorizing the application once more.
+    #[serde(with = "humanized")]
+    #[cfg_attr(feature = "schema", schemars(with = "String"))]
+    pub rejected_app_key_ttl: Duration,
 }
 
 const DEFAULT_MAX_SHARED_MEMORY: u64 = 4294967296;
@@ -17,8 +334,274 @@ impl Default for FilterConfig {
     fn default() -> Self {
         FilterConfig {
             failure_mode_deny: true,
+            failure_policies: FailurePolicies::default(),
             max_tries: 5,
             max_shared_memory_bytes: DEFAULT_MAX_SHARED_MEMORY, // equivalent to 4GB
+            callout_lease_duration: Duration::from_secs(5),
+            limit_tolerance: LimitTolerance::default(),
+            stale_while_unavailable: StaleConfig::default(),
+            circuit_breaker: CircuitBreakerConfig::default(),
+            local_cache: LocalCacheConfig::default(),
+            unknown_metrics: UnknownMetricsConfig::default(),
+            rejected_app_key_ttl: Duration::from_secs(10),
         }
     }
 }
+
+impl FilterConfig {
+    /// Parses the configuration passed by envoy.yaml, rejecting unknown fields and values out of
+    /// their valid range.
+    pub fn parse(configuration: &[u8]) -> Result<Self, ConfigError> {
+        let config = serde_json::from_slice::<FilterConfig>(configuration)
+            .map_err(|e| ConfigError::ParseFail(e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        ensure(self.max_tries >= 1, "max_tries", "must be at least 1")?;
+        ensure(
+            self.max_shared_memory_bytes > 0,
+            "max_shared_memory_bytes",
+            "must be greater than 0",
+        )?;
+        ensure(
+            self.callout_lease_duration > Duration::default(),
+            "callout_lease_duration",
+            "must be greater than 0",
+        )?;
+        self.failure_policies
+            .validate()
+            .map_err(|e| e.nested("failure_policies"))?;
+        self.limit_tolerance
+            .validate()
+            .map_err(|e| e.nested("limit_tolerance"))?;
+        self.stale_while_unavailable
+            .validate()
+            .map_err(|e| e.nested("stale_while_unavailable"))?;
+        self.circuit_breaker
+            .validate()
+            .map_err(|e| e.nested("circuit_breaker"))?;
+        self.local_cache
+            .validate()
+            .map_err(|e| e.nested("local_cache"))?;
+        self.unknown_metrics
+            .validate()
+            .map_err(|e| e.nested("unknown_metrics"))?;
+        ensure(
+            self.rejected_app_key_ttl > Duration::default(),
+            "rejected_app_key_ttl",
+            "must be greater than 0",
+        )
+    }
+
+    /// Policy applied to a request after a failure of the given category.
+    pub fn failure_policy(&self, category: FailureCategory) -> FailurePolicy {
+        let policies = &self.failure_policies;
+        let policy = match category {
+            FailureCategory::AuthorizeTimeout => policies.authorize_timeout,
+            FailureCategory::BackendError => policies.backend_error,
+            FailureCategory::SharedDataFailure => policies.shared_data_failure,
+            FailureCategory::MessageQueueFull => policies.message_queue_full,
+            FailureCategory::MissingCredentials => policies.missing_credentials,
+            FailureCategory::CircuitOpen => policies.circuit_open.or_else(|| {
+                let stale = &self.stale_while_unavailable;
+                Some(stale.unknown_apps).filter(|_| stale.enabled)
+            }),
+            FailureCategory::BackendRejectedConfig => policies.backend_rejected_config,
+            FailureCategory::Other => Some(FailurePolicy::Deny),
+        };
+        policy.unwrap_or(match category {
+            FailureCategory::MessageQueueFull => FailurePolicy::Allow,
+            FailureCategory::MissingCredentials => FailurePolicy::Deny,
+            _ => self.default_failure_policy(),
+        })
+    }
+
+    /// Policy given by failure_mode_deny.
+    pub fn default_failure_policy(&self) -> FailurePolicy {
+        if self.failure_mode_deny {
+            FailurePolicy::Deny
+        } else {
+            FailurePolicy::Allow
+        }
+    }
+
+    /// Describes every field whose value differs in the new configuration. All of them are
+    /// applied to requests arriving after the change, cached applications are kept.
+    pub fn changes(&self, new: &Self) -> Vec<String> {
+        let mut changes = Vec::new();
+        record_change(
+            &mut changes,
+            "failure_mode_deny",
+            &self.failure_mode_deny,
+            &new.failure_mode_deny,
+        );
+        nested_changes(
+            &mut changes,
+            "failure_policies",
+            self.failure_policies.changes(&new.failure_policies),
+        );
+        record_change(&mut changes, "max_tries", &self.max_tries, &new.max_tries);
+        record_change(
+            &mut changes,
+            "max_shared_memory_bytes",
+            &self.max_shared_memory_bytes,
+            &new.max_shared_memory_bytes,
+        );
+        record_change(
+            &mut changes,
+            "callout_lease_duration",
+            &self.callout_lease_duration,
+            &new.callout_lease_duration,
+        );
+        nested_changes(
+            &mut changes,
+            "limit_tolerance",
+            self.limit_tolerance.changes(&new.limit_tolerance),
+        );
+        nested_changes(
+            &mut changes,
+            "stale_while_unavailable",
+            self.stale_while_unavailable
+                .changes(&new.stale_while_unavailable),
+        );
+        nested_changes(
+            &mut changes,
+            "circuit_breaker",
+            self.circuit_breaker.changes(&new.circuit_breaker),
+        );
+        nested_changes(
+            &mut changes,
+            "local_cache",
+            self.local_cache.changes(&new.local_cache),
+        );
+        nested_changes(
+            &mut changes,
+            "unknown_metrics",
+            self.unknown_metrics.changes(&new.unknown_metrics),
+        );
+        record_change(
+            &mut changes,
+            "rejected_app_key_ttl",
+            &self.rejected_app_key_ttl,
+            &new.rejected_app_key_ttl,
+        );
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unset_categories_follow_failure_mode_deny() {
+        for failure_mode_deny in [true, false].iter() {
+            let config = FilterConfig {
+                failure_mode_deny: *failure_mode_deny,
+                failure_policies: FailurePolicies {
+                    backend_error: Some(FailurePolicy::ServeStale),
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let default_policy = if *failure_mode_deny {
+                FailurePolicy::Deny
+            } else {
+                FailurePolicy::Allow
+            };
+            for category in [
+                FailureCategory::AuthorizeTimeout,
+                FailureCategory::SharedDataFailure,
+                FailureCategory::CircuitOpen,
+                FailureCategory::BackendRejectedConfig,
+            ]
+            .iter()
+            {
+                assert_eq!(config.failure_policy(*category), default_policy);
+            }
+            assert_eq!(
+                config.failure_policy(FailureCategory::BackendError),
+                FailurePolicy::ServeStale
+            );
+            assert_eq!(
+                config.failure_policy(FailureCategory::MessageQueueFull),
+                FailurePolicy::Allow
+            );
+            assert_eq!(
+                config.failure_policy(FailureCategory::MissingCredentials),
+                FailurePolicy::Deny
+            );
+        }
+    }
+
+    #[test]
+    fn open_circuit_follows_unknown_apps_while_serving_stale() {
+        let mut config = FilterConfig {
+            failure_mode_deny: false,
+            ..Default::default()
+        };
+        config.stale_while_unavailable.enabled = true;
+        assert_eq!(
+            config.failure_policy(FailureCategory::CircuitOpen),
+            FailurePolicy::Deny
+        );
+        config.failure_policies.circuit_open = Some(FailurePolicy::AllowAndReportLater);
+        assert_eq!(
+            config.failure_policy(FailureCategory::CircuitOpen),
+            FailurePolicy::AllowAndReportLater
+        );
+    }
+
+    #[test]
+    fn other_failures_are_always_denied() {
+        let config = FilterConfig {
+            failure_mode_deny: false,
+            failure_policies: FailurePolicies {
+                authorize_timeout: Some(FailurePolicy::Allow),
+                backend_error: Some(FailurePolicy::Allow),
+                shared_data_failure: Some(FailurePolicy::Allow),
+                message_queue_full: Some(FailurePolicy::Allow),
+                missing_credentials: Some(FailurePolicy::Allow),
+                circuit_open: Some(FailurePolicy::Allow),
+                backend_rejected_config: Some(FailurePolicy::Allow),
+            },
+            ..Default::default()
+        };
+        assert_eq!(
+            config.failure_policy(FailureCategory::Other),
+            FailurePolicy::Deny
+        );
+    }
+
+    #[test]
+    fn policies_unusable_for_their_category_are_rejected() {
+        let invalid = [
+            (
+                r#"{"failure_policies": {"message_queue_full": "AllowAndReportLater"}}"#,
+                "failure_policies.message_queue_full",
+            ),
+            (
+                r#"{"failure_policies": {"missing_credentials": "ServeStale"}}"#,
+                "failure_policies.missing_credentials",
+            ),
+            (
+                r#"{"stale_while_unavailable": {"unknown_apps": "ServeStale"}}"#,
+                "stale_while_unavailable.unknown_apps",
+            ),
+        ];
+        for (configuration, field) in invalid.iter() {
+            match FilterConfig::parse(configuration.as_bytes()) {
+                Err(ConfigError::InvalidValue { field: invalid, .. }) => {
+                    assert_eq!(invalid, *field)
+                }
+                result => panic!("{} was not rejected: {:?}", configuration, result),
+            }
+        }
+        assert!(FilterConfig::parse(br#"{"failure_policies": {"other": "Allow"}}"#).is_err());
+
+        let valid = br#"{"failure_policies": {"message_queue_full": "Deny", "missing_credentials": "Allow", "backend_error": "ServeStale"}}"#;
+        assert!(FilterConfig::parse(valid).is_ok());
+    }
+}
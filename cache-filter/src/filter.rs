@@ -1,2 +1,4 @@
 pub mod http;
 mod root;
+
+pub use root::_start;
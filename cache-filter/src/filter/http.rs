@@ -3,9 +3,12 @@ use crate::unique_callout;
 #[cfg(not(feature = "unique_callout"))]
 use crate::unique_callout_dummy as unique_callout;
 use crate::{
-    configuration::FilterConfig,
+    configuration::{FailureCategory, FailurePolicy, FilterConfig, UnknownMetricPolicy},
     debug, info,
-    utils::{do_auth_call, in_request_failure, request_process_failure},
+    utils::{
+        do_auth_call, in_request_failure, record_auth_call_outcome, request_process_failure,
+        send_rejection,
+    },
     warn,
 };
 use proxy_wasm::{
@@ -19,10 +22,18 @@ use std::str::FromStr;
 use std::time::{Duration, UNIX_EPOCH};
 use std::vec;
 use threescale::{
+    auth_error::{record_auth_error, AuthorizeError},
+    breaker::{get_circuit, CallOutcome},
+    encoding::{get_service_metrics, is_in_hierarchy, merge_service_hierarchy},
+    host::{Host, ProxyHost},
+    lease::Lease,
+    local_cache::LocalCache,
     proxy::{
         get_app_id_from_cache, get_application_from_cache, set_app_id_to_cache, CacheError,
         CacheKey,
     },
+    refresh::apply_local_consumption,
+    rejected_keys::{get_rejected_keys, remember_rejected_key},
     stats::*,
     structs::*,
     upstream::*,
@@ -36,6 +47,11 @@ use unique_callout::{
 const QUEUE_NAME: &str = "message_queue";
 const TIMEOUT_STATUS: &str = "504";
 
+thread_local! {
+    // Applications decoded by this worker, configured by the root context.
+    pub static LOCAL_CACHE: RefCell<LocalCache> = RefCell::new(LocalCache::default());
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum CacheHitError {
     #[error("duration since time later than self")]
@@ -44,12 +60,26 @@ pub enum CacheHitError {
     MetricsCheckFail(#[from] UpdateMetricsError),
     #[error("unable to resolve message queue with specified vm_id and q_name")]
     MQNotFound,
-    #[error("failed to fetch the app during app_set retries")]
-    AppFetchFail(#[from] CacheError),
+}
+
+impl CacheHitError {
+    pub fn category(&self) -> FailureCategory {
+        match self {
+            CacheHitError::MetricsCheckFail(_) => FailureCategory::SharedDataFailure,
+            CacheHitError::MQNotFound => FailureCategory::MessageQueueFull,
+            CacheHitError::TimeConversionErr(_) => FailureCategory::Other,
+        }
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
 enum AuthResponseError {
+    #[error("3scale rejected the authorize call: {0}")]
+    Rejected(#[from] AuthorizeError),
+    #[error("response from 3scale is empty")]
+    EmptyResponse,
+    #[error("parsing response from 3scale failed: {0}")]
+    ParseFail(String),
     #[error("failure to follow cache hit flow")]
     CacheHitErr(#[from] CacheHitError),
     #[error("conversion from i64 time to u64 duration failed")]
@@ -64,6 +94,19 @@ enum AuthResponseError {
     AppIdNotMapped(#[from] CacheError),
 }
 
+impl AuthResponseError {
+    fn category(&self) -> FailureCategory {
+        match self {
+            AuthResponseError::Rejected(e) if e.is_configuration_error() => {
+                FailureCategory::BackendRejectedConfig
+            }
+            AuthResponseError::CacheHitErr(e) => e.category(),
+            AuthResponseError::AppIdNotMapped(_) => FailureCategory::SharedDataFailure,
+            _ => FailureCategory::Other,
+        }
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum RequestDataError {
     #[error("service_token not found inside request metadata")]
@@ -87,7 +130,7 @@ pub enum RequestDataError {
 }
 
 /// State data collected/updated during a request lifetime.
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct RequestState {
     /// Set to true if all tries fail to update cache from the filter.
     pub update_cache_from_singleton: bool,
@@ -97,10 +140,24 @@ pub struct RequestState {
     pub cache_key: CacheKey,
     /// RateLimit header values. Calculated in limit_check_and_update_app.
     pub rate_limit_info: RateLimitInfo,
+    /// Lease of the callout-lock if this context is the one performing the callout.
+    pub callout_lease: Option<Lease>,
+    /// Set if the request was authenticated with a user_key, which comes without app key.
+    pub user_key_auth: bool,
+    /// Set once the application was authorized for this request, after which an unknown app key
+    /// is denied instead of being authorized again.
+    pub app_key_reauth: bool,
+    /// Set once the request was checked against a stale cached application after a failure.
+    pub serving_stale: bool,
+    /// Set if the cached application expired and is being authorized again.
+    pub expired_app: bool,
+    /// Generation of the circuit of the upstream the authorize call was let through in.
+    pub circuit_generation: u32,
 }
 
 #[derive(Clone)]
-pub struct CacheFilter {
+pub struct CacheFilter<H: Host = ProxyHost> {
+    pub host: H,
     pub context_id: u32,
     pub root_id: u32,
     pub config: FilterConfig,
@@ -108,7 +165,7 @@ pub struct CacheFilter {
     pub state: RequestState,
 }
 
-impl HttpContext for CacheFilter {
+impl<H: Host> HttpContext for CacheFilter<H> {
     fn on_http_request_headers(&mut self, _: usize) -> Action {
         info!(
             self.context_id,
@@ -118,18 +175,22 @@ impl HttpContext for CacheFilter {
             Ok(data) => data,
             Err(e) => {
                 debug!(self.context_id, "fetching request data failed: {}", e);
-                increment_stat(&self.stats.auth_metadata_errors);
+                increment_stat(&self.host, &self.stats.auth_metadata_errors);
+                if let RequestDataError::AuthKeyMissing = e {
+                    return in_request_failure(self, FailureCategory::MissingCredentials);
+                }
                 // Send back local response for not providing relevant request data
-                self.send_http_response(401, vec![], None);
+                self.respond(401, None);
                 return Action::Pause;
             }
         };
 
         self.state.cache_key = CacheKey::from(&request_data.service_id, &request_data.app_id);
         self.state.req_data = request_data.clone();
+        self.state.user_key_auth = matches!(request_data.app_id, AppIdentifier::UserKey(_));
 
         if let AppIdentifier::UserKey(ref user_key) = request_data.app_id {
-            match get_app_id_from_cache(user_key) {
+            match get_app_id_from_cache(&self.host, user_key) {
                 Ok(app_id) => {
                     request_data.app_id = AppIdentifier::from(app_id);
                     self.state.req_data.app_id = request_data.app_id.clone();
@@ -140,14 +201,14 @@ impl HttpContext for CacheFilter {
                         self.context_id,
                         "user_key->app_id mapping not found! considering cache miss: {:?}", e
                     );
-                    increment_stat(&self.stats.cache_misses);
+                    increment_stat(&self.host, &self.stats.cache_misses);
                     match set_callout_lock(self) {
                         Ok(SetCalloutLockStatus::LockAcquired) => {
                             return do_auth_call(self);
                         }
                         Ok(SetCalloutLockStatus::AddedToWaitlist) => return Action::Pause,
                         Ok(SetCalloutLockStatus::ResponseCameFirst) => {
-                            match get_app_id_from_cache(user_key) {
+                            match get_app_id_from_cache(&self.host, user_key) {
                                 Ok(app_id) => {
                                     request_data.app_id = AppIdentifier::from(app_id);
                                     self.state.req_data.app_id = request_data.app_id.clone();
@@ -155,7 +216,10 @@ impl HttpContext for CacheFilter {
                                 }
                                 Err(e) => {
                                     debug!(self.context_id, "user_key->app_id mapping not found after callout response: {:?}", e);
-                                    return in_request_failure(self);
+                                    return in_request_failure(
+                                        self,
+                                        FailureCategory::SharedDataFailure,
+                                    );
                                 }
                             }
                         }
@@ -166,42 +230,52 @@ impl HttpContext for CacheFilter {
                                 self.state.cache_key.as_string(),
                                 e
                             );
-                            return in_request_failure(self);
+                            return in_request_failure(self, FailureCategory::SharedDataFailure);
                         }
                     };
                 }
             }
         }
 
-        match get_application_from_cache(&self.state.cache_key) {
-            Ok((mut app, cas)) => match self.handle_cache_hit(&mut app, cas) {
-                Ok(()) => Action::Continue,
-                Err(e) => {
-                    warn!(self.context_id, "cache hit flow failed: {}", e);
-                    in_request_failure(self)
+        match self.fetch_application() {
+            Ok((mut app, cas)) if !self.is_expired(&app) => {
+                match self.handle_cache_hit(&mut app, cas) {
+                    Ok(action) => action,
+                    Err(e) => {
+                        warn!(self.context_id, "cache hit flow failed: {}", e);
+                        in_request_failure(self, e.category())
+                    }
+                }
+            }
+            cached => {
+                match cached {
+                    Ok(_) => {
+                        info!(self.context_id, "cached application expired");
+                        self.state.expired_app = true;
+                    }
+                    Err(e) => {
+                        info!(self.context_id, "cache miss: {}", e);
+                        increment_stat(&self.host, &self.stats.cache_misses);
+                    }
                 }
-            },
-            Err(e) => {
-                info!(self.context_id, "cache miss: {}", e);
-                increment_stat(&self.stats.cache_misses);
                 match set_callout_lock(self) {
                     Ok(SetCalloutLockStatus::LockAcquired) => do_auth_call(self),
                     Ok(SetCalloutLockStatus::AddedToWaitlist) => Action::Pause,
                     Ok(SetCalloutLockStatus::ResponseCameFirst) => {
-                        match get_application_from_cache(&self.state.cache_key) {
+                        match self.fetch_application() {
                             Ok((mut app, cas)) => match self.handle_cache_hit(&mut app, cas) {
-                                Ok(()) => Action::Continue,
+                                Ok(action) => action,
                                 Err(e) => {
                                     debug!(
                                         self.context_id,
                                         "cache hit flow failed after callout response: {}", e
                                     );
-                                    in_request_failure(self)
+                                    in_request_failure(self, e.category())
                                 }
                             },
                             Err(e) => {
                                 debug!(self.context_id, "failed to fetch app from shared data after callout response: {}", e);
-                                in_request_failure(self)
+                                in_request_failure(self, FailureCategory::SharedDataFailure)
                             }
                         }
                     }
@@ -212,7 +286,7 @@ impl HttpContext for CacheFilter {
                             self.state.cache_key.as_string(),
                             e
                         );
-                        in_request_failure(self)
+                        in_request_failure(self, FailureCategory::SharedDataFailure)
                     }
                 }
             }
@@ -223,23 +297,24 @@ impl HttpContext for CacheFilter {
         #[cfg(feature = "visible_logs")]
         {
             let (key, val) = crate::log::visible_logs::get_logs_header_pair(self.context_id);
-            self.add_http_response_header(key.as_ref(), val.as_ref());
+            self.host
+                .add_http_response_header(key.as_ref(), val.as_ref());
         }
         // Adding RateLimit headers.
         if self.state.rate_limit_info.limit.is_some() {
-            self.add_http_response_header(
+            self.host.add_http_response_header(
                 "RateLimit-Limit",
                 &self.state.rate_limit_info.limit.unwrap().to_string(),
             );
         }
         if self.state.rate_limit_info.remaining.is_some() {
-            self.add_http_response_header(
+            self.host.add_http_response_header(
                 "RateLimit-Remaining",
                 &self.state.rate_limit_info.remaining.unwrap().to_string(),
             );
         }
         if self.state.rate_limit_info.reset.is_some() {
-            self.add_http_response_header(
+            self.host.add_http_response_header(
                 "RateLimit-Reset",
                 &self.state.rate_limit_info.reset.unwrap().to_string(),
             );
@@ -248,14 +323,16 @@ impl HttpContext for CacheFilter {
     }
 }
 
-impl CacheFilter {
+impl<H: Host> CacheFilter<H> {
     fn report_to_singleton(&self, qid: u32, req_time: &Duration) -> bool {
         let message: Message = Message::new(
             self.state.update_cache_from_singleton,
             &self.state.req_data,
             req_time,
         );
-        if let Err(e) = self.enqueue_shared_queue(qid, Some(&bincode::serialize(&message).unwrap()))
+        if let Err(e) = self
+            .host
+            .enqueue_shared_queue(qid, Some(&bincode::serialize(&message).unwrap()))
         {
             warn!(
                 self.context_id,
@@ -270,83 +347,423 @@ impl CacheFilter {
     pub fn handle_cache_hit(
         &mut self,
         app: &mut Application,
-        mut app_cas: u32,
-    ) -> Result<(), CacheHitError> {
+        app_cas: u32,
+    ) -> Result<Action, CacheHitError> {
         info!(self.context_id, "cache hit");
-        increment_stat(&self.stats.cache_hits);
+        increment_stat(&self.host, &self.stats.cache_hits);
+        if !self.state.user_key_auth && !is_app_key_valid(app, &self.state.req_data.app_id) {
+            return Ok(self.handle_unknown_app_key());
+        }
+        if let Some(action) = self.handle_unknown_metrics(app) {
+            return Ok(action);
+        }
         let queue_id = self
+            .host
             .resolve_shared_queue(crate::VM_ID, QUEUE_NAME)
+            .ok()
+            .flatten()
             .ok_or(CacheHitError::MQNotFound)?;
 
-        let current_time = self.get_current_time().duration_since(UNIX_EPOCH)?;
-        let mut updated_cache = false;
-        let mut rate_limited = false;
-        let max_tries = self.config.max_tries;
+        let current_time = self.host.get_current_time().duration_since(UNIX_EPOCH)?;
 
         add_hierarchy_to_metrics(&app.metric_hierarchy, &mut self.state.req_data.metrics);
 
-        // In case of CAS mismatch, new application needs to be fetched and modified again.
-        for num_try in 0..max_tries {
-            match limit_check_and_update_application(
+        // In case of CAS mismatch, new application is fetched and modified again.
+        let status = if self.config.local_cache.enabled {
+            LOCAL_CACHE.with(|cache| {
+                cache.borrow_mut().update_application(
+                    &self.host,
+                    &self.state.req_data,
+                    &self.state.cache_key,
+                    app,
+                    app_cas,
+                    &current_time,
+                    self.config.max_tries,
+                    &self.config.limit_tolerance,
+                )
+            })
+        } else {
+            update_application_with_retries(
+                &self.host,
                 &self.state.req_data,
+                &self.state.cache_key,
                 app,
                 app_cas,
                 &current_time,
-            ) {
-                Ok(RateLimitStatus::Authorized(rate_limit_info)) => {
-                    // App is not rate-limited and updated in cache.
-                    info!(self.context_id, "request is allowed to pass the filter");
-                    self.state.rate_limit_info = rate_limit_info;
-                    if app_cas == 0 {
-                        increment_stat(&self.stats.cached_apps)
-                    }
-                    if !self.report_to_singleton(queue_id, &current_time) {
-                        // TODO: Handle MQ failure here
-                        // Update local cache
-                        // Report to 3scale and get new state using authrep endpoint
-                    }
-                    updated_cache = true;
-                    self.resume_http_request();
-                    break;
+                self.config.max_tries,
+                &self.config.limit_tolerance,
+            )
+        };
+        match status? {
+            Some(RateLimitStatus::Authorized(rate_limit_info)) => {
+                // App is not rate-limited and updated in cache.
+                info!(self.context_id, "request is allowed to pass the filter");
+                self.state.rate_limit_info = rate_limit_info;
+                if app_cas == 0 && !self.state.expired_app {
+                    increment_stat(&self.host, &self.stats.cached_apps)
                 }
-                Ok(RateLimitStatus::RateLimited(rate_limit_info)) => {
-                    info!(self.context_id, "request is rate-limited");
-                    self.state.rate_limit_info = rate_limit_info;
-                    self.send_http_response(429, vec![], Some(b"Request rate-limited.\n"));
-                    // no need to retry if already rate-limted
-                    rate_limited = true;
-                    break;
+                if !self.report_to_singleton(queue_id, &current_time)
+                    && self
+                        .config
+                        .failure_policy(FailureCategory::MessageQueueFull)
+                        == FailurePolicy::Deny
+                {
+                    // Usage of the request would not be reported to 3scale.
+                    self.state.rate_limit_info = RateLimitInfo::default();
+                    self.respond(403, Some(b"Access forbidden.\n"));
+                    return Ok(Action::Pause);
                 }
-                Err(UpdateMetricsError::CacheUpdateFail(reason)) => {
-                    info!(
+                self.resume();
+            }
+            Some(RateLimitStatus::RateLimited(rate_limit_info)) => {
+                info!(self.context_id, "request is rate-limited");
+                self.state.rate_limit_info = rate_limit_info;
+                self.respond(429, Some(b"Request rate-limited.\n"));
+            }
+            None => {
+                // App is not rate-limited and changes are not reflected in the cache yet.
+                // Singleton will try to overwrite and in the mean time till singleton receives
+                // the message, hopefully, contention will reduce.
+                info!(
+                    self.context_id,
+                    "failed to set application to cache after {} tries", self.config.max_tries
+                );
+                self.state.update_cache_from_singleton = true;
+                self.resume();
+            }
+        }
+        Ok(Action::Continue)
+    }
+
+    /// Responds to the request locally instead of forwarding it upstream.
+    pub fn respond(&self, status_code: u32, body: Option<&[u8]>) {
+        if let Err(e) = self.host.send_http_response(status_code, vec![], body) {
+            warn!(
+                self.context_id,
+                "failed to send local response with status {}: {:?}", status_code, e
+            );
+        }
+    }
+
+    /// Resumes the request paused while it was checked.
+    pub fn resume(&self) {
+        if let Err(e) = self.host.resume_http_request() {
+            warn!(self.context_id, "failed to resume request: {:?}", e);
+        }
+    }
+
+    fn current_time(&self) -> Duration {
+        self.host
+            .get_current_time()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+
+    /// Returns the application of the request along with its CAS, from the local cache of the
+    /// worker if enabled. Applications handed to handle_cache_hit must come from here, so that
+    /// hits consumed locally and not yet written to shared data are not lost.
+    pub fn fetch_application(&self) -> Result<(Application, u32), CacheError> {
+        if !self.config.local_cache.enabled {
+            return get_application_from_cache(&self.host, &self.state.cache_key);
+        }
+        let now = self.current_time();
+        LOCAL_CACHE.with(|cache| {
+            cache
+                .borrow_mut()
+                .get_application(&self.host, &self.state.cache_key, &now)
+        })
+    }
+
+    // 3scale backend is unhealthy while the circuit of its upstream is not closed.
+    fn is_backend_unhealthy(&self) -> bool {
+        let upstream = self.state.req_data.upstream.name();
+        match get_circuit(&self.host, upstream) {
+            Ok((circuit, _)) => circuit.is_unhealthy(),
+            Err(e) => {
+                warn!(
+                    self.context_id,
+                    "failed to read circuit of {}: {}", upstream, e
+                );
+                false
+            }
+        }
+    }
+
+    // Cached applications older than max_age are authorized again before being used, unless
+    // 3scale backend is unhealthy and they are not older than max_staleness.
+    fn is_expired(&self, app: &Application) -> bool {
+        let stale = &self.config.stale_while_unavailable;
+        let age = self
+            .current_time()
+            .checked_sub(app.synced_at)
+            .unwrap_or_default();
+        if !stale.enabled || age <= stale.max_age {
+            return false;
+        }
+        if age <= stale.max_staleness && self.is_backend_unhealthy() {
+            info!(
+                self.context_id,
+                "3scale backend is unhealthy, using application synced {:?} ago", age
+            );
+            increment_stat(&self.host, &self.stats.stale_hits);
+            return false;
+        }
+        true
+    }
+
+    /// Frees the callout-lock held by this context, resuming the waiters with the action.
+    pub fn free_callout_lock(&mut self, cache_key: &CacheKey, waiter_action: WaiterAction) {
+        if let Err(e) = free_callout_lock_and_notify_waiters(
+            &self.host,
+            self.root_id,
+            self.context_id,
+            cache_key,
+            self.state.callout_lease.take().as_ref(),
+            waiter_action,
+        ) {
+            warn!(
+                self.context_id,
+                "failed to free callout-lock after auth call: {}", e
+            );
+        }
+    }
+
+    /// Hands the usage of a request allowed after a failure to the singleton service, which
+    /// reports it to 3scale with the next flush.
+    pub fn report_later(&self) {
+        let req_time = self.current_time();
+        match self
+            .host
+            .resolve_shared_queue(crate::VM_ID, QUEUE_NAME)
+            .ok()
+            .flatten()
+        {
+            Some(queue_id) => {
+                self.report_to_singleton(queue_id, &req_time);
+            }
+            None => warn!(
+                self.context_id,
+                "usage of the request can't be reported: message queue not found"
+            ),
+        }
+    }
+
+    /// Checks the request against the cached application after a failure, however stale the
+    /// application is. Returns None if there is no cached application or checking it failed.
+    pub fn serve_stale(&mut self) -> Option<Action> {
+        // Failures while serving from the cache are not served from the cache again.
+        if self.state.serving_stale {
+            return None;
+        }
+        self.state.serving_stale = true;
+        let (mut app, cas) = self.fetch_application().ok()?;
+        info!(
+            self.context_id,
+            "serving request from the cached application"
+        );
+        self.handle_cache_hit(&mut app, cas).ok()
+    }
+
+    // App keys can be added to an application after it was cached, so the application is
+    // authorized once more before denying a request with an unknown app key. Doing so takes the
+    // callout-lock, and app keys still unknown afterwards are denied straight away for
+    // rejected_app_key_ttl, so that bad app keys don't turn every request into a callout.
+    fn handle_unknown_app_key(&mut self) -> Action {
+        if !self.state.app_key_reauth && !self.is_app_key_rejected() {
+            info!(
+                self.context_id,
+                "unknown app key, authorizing the application again"
+            );
+            self.state.app_key_reauth = true;
+            return self.authorize_app_key();
+        }
+        if self.state.app_key_reauth {
+            self.remember_rejected_app_key();
+        }
+        info!(self.context_id, "request denied for unknown app key");
+        increment_stat(&self.host, &self.stats.unknown_app_keys);
+        self.state.rate_limit_info = RateLimitInfo::default();
+        self.respond(403, Some(b"Application key is invalid.\n"));
+        Action::Pause
+    }
+
+    fn authorize_app_key(&mut self) -> Action {
+        match set_callout_lock(self) {
+            Ok(SetCalloutLockStatus::LockAcquired) => do_auth_call(self),
+            Ok(SetCalloutLockStatus::AddedToWaitlist) => Action::Pause,
+            Ok(SetCalloutLockStatus::ResponseCameFirst) => match self.fetch_application() {
+                Ok((mut app, cas)) => match self.handle_cache_hit(&mut app, cas) {
+                    Ok(action) => action,
+                    Err(e) => {
+                        debug!(
+                            self.context_id,
+                            "cache hit flow failed after callout response: {}", e
+                        );
+                        in_request_failure(self, e.category())
+                    }
+                },
+                Err(e) => {
+                    debug!(
                         self.context_id,
-                        "try ({} out of {}): failed to set application to cache: {}",
-                        (num_try as u64) + 1,
-                        max_tries,
-                        reason
+                        "failed to fetch app from shared data after callout response: {}", e
                     );
-                    if num_try < max_tries {
-                        match get_application_from_cache(&self.state.cache_key) {
-                            Ok((new_app, cas)) => {
-                                *app = new_app;
-                                app_cas = cas;
-                            }
-                            Err(e) => return Err(CacheHitError::AppFetchFail(e)),
-                        }
-                        continue;
-                    }
+                    in_request_failure(self, FailureCategory::SharedDataFailure)
                 }
-                Err(e) => return Err(CacheHitError::MetricsCheckFail(e)),
+            },
+            Err(e) => {
+                warn!(
+                    self.context_id,
+                    "failed to set callout-lock for request(key: {}): {:?}",
+                    self.state.cache_key.as_string(),
+                    e
+                );
+                in_request_failure(self, FailureCategory::SharedDataFailure)
             }
         }
-        // App is not rate-limited and changes are not reflected in the cache yet.
-        // Singleton will try to overwrite and in the mean time till singleton receives
-        // the message, hopefully, contention will reduce.
-        if !updated_cache && !rate_limited {
-            self.state.update_cache_from_singleton = true;
-            self.resume_http_request();
+    }
+
+    fn request_app_key(&self) -> Option<&AppKey> {
+        match &self.state.req_data.app_id {
+            AppIdentifier::AppId(_, Some(app_key)) => Some(app_key),
+            _ => None,
+        }
+    }
+
+    // App keys rejected lately are not authorized again, nor any other app key of an application
+    // with too many of them.
+    fn is_app_key_rejected(&self) -> bool {
+        let app_key = match self.request_app_key() {
+            Some(app_key) => app_key,
+            None => return false,
+        };
+        match get_rejected_keys(&self.host, &self.state.cache_key) {
+            Ok((rejected, _)) => {
+                let now = self.current_time();
+                rejected.is_rejected(app_key, &now) || rejected.is_full(&now)
+            }
+            Err(e) => {
+                warn!(self.context_id, "failed to read rejected app keys: {}", e);
+                false
+            }
+        }
+    }
+
+    fn remember_rejected_app_key(&self) {
+        if let Some(app_key) = self.request_app_key() {
+            if let Err(e) = remember_rejected_key(
+                &self.host,
+                &self.state.cache_key,
+                app_key,
+                self.config.rejected_app_key_ttl,
+                &self.current_time(),
+            ) {
+                warn!(
+                    self.context_id,
+                    "failed to remember rejected app key: {}", e
+                );
+            }
+        }
+    }
+
+    // Applies the unknown_metrics policy to the metrics of the request unknown to the service of
+    // the application, returning the action to take if the request is denied. Metrics are not
+    // validated under Allow, since every metric is handled alike.
+    fn handle_unknown_metrics(&mut self, app: &Application) -> Option<Action> {
+        let config = &self.config.unknown_metrics;
+        if config.policy == UnknownMetricPolicy::Allow {
+            return None;
+        }
+        let service_id = app.service_id.as_ref();
+        let mut unknown = self
+            .state
+            .req_data
+            .metrics
+            .borrow()
+            .keys()
+            .filter(|metric| {
+                !app.local_state.contains_key(*metric)
+                    && !is_in_hierarchy(&app.metric_hierarchy, metric)
+                    && !config.is_configured(service_id, metric)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        if unknown.is_empty() {
+            return None;
+        }
+        // Metrics limited only for other applications of the service are known as well.
+        match get_service_metrics(&self.host, &app.service_id) {
+            Ok((metrics, _)) => {
+                if metrics.is_empty()
+                    && app.local_state.is_empty()
+                    && app.metric_hierarchy.is_empty()
+                    && !config.known.contains_key(service_id)
+                {
+                    return None;
+                }
+                unknown.retain(|metric| !metrics.is_known(metric));
+            }
+            Err(e) => {
+                warn!(
+                    self.context_id,
+                    "metrics of service {} could not be read: {}", service_id, e
+                );
+                return None;
+            }
+        }
+        if unknown.is_empty() {
+            return None;
+        }
+        increment_stat(&self.host, &self.stats.unknown_metrics);
+        match config.policy {
+            UnknownMetricPolicy::Allow => None,
+            UnknownMetricPolicy::Deny => {
+                info!(
+                    self.context_id,
+                    "request denied for unknown metrics: {:?}", unknown
+                );
+                self.state.rate_limit_info = RateLimitInfo::default();
+                self.respond(403, Some(b"Metric is unknown.\n"));
+                Some(Action::Pause)
+            }
+            UnknownMetricPolicy::Drop => {
+                info!(
+                    self.context_id,
+                    "unknown metrics dropped from request: {:?}", unknown
+                );
+                let mut metrics = self.state.req_data.metrics.borrow_mut();
+                for metric in unknown.iter() {
+                    metrics.remove(metric);
+                }
+                None
+            }
+        }
+    }
+
+    // Parses the response to the authorize call and caches the application it carries. Responses
+    // without the state of the application are rejections of the call.
+    fn process_auth_response(&mut self, body_size: usize) -> Result<(), AuthResponseError> {
+        let bytes = self
+            .host
+            .get_http_call_response_body(0, body_size)
+            .ok_or(AuthResponseError::EmptyResponse)?;
+        let body =
+            std::str::from_utf8(&bytes).map_err(|e| AuthResponseError::ParseFail(e.to_string()))?;
+        match Authorization::from_str(body) {
+            Ok(Authorization::Status(response)) => {
+                if response.is_authorized() || response.usage_reports().is_some() {
+                    self.handle_auth_response(&response)
+                } else {
+                    let reason = response.reason().unwrap_or_default();
+                    Err(AuthResponseError::Rejected(AuthorizeError::from_reason(
+                        reason,
+                    )))
+                }
+            }
+            Ok(Authorization::Error(auth_error)) => Err(AuthResponseError::Rejected(
+                AuthorizeError::from_code(auth_error.code()),
+            )),
+            Err(e) => Err(AuthResponseError::ParseFail(format!("{:?}", e))),
         }
-        Ok(())
     }
 
     fn handle_auth_response(
@@ -373,7 +790,7 @@ impl CacheFilter {
         // change user_key to app_id for further processing
         if let AppIdentifier::UserKey(user_key) = self.state.cache_key.app_id() {
             self.state.req_data.app_id = app_identifier.clone();
-            set_app_id_to_cache(user_key, &app_id)?;
+            set_app_id_to_cache(&self.host, user_key, &app_id)?;
         }
 
         self.state.cache_key = CacheKey::from(&service_id, &app_identifier);
@@ -400,8 +817,9 @@ impl CacheFilter {
                             ),
                             window: Period::from(&usage.period),
                         },
-                        left_hits: usage.max_value - usage.current_value,
+                        left_hits: usage.max_value.saturating_sub(usage.current_value),
                         max_value: usage.max_value,
+                        synced_left_hits: usage.max_value.saturating_sub(usage.current_value),
                     },
                 );
             }
@@ -413,6 +831,20 @@ impl CacheFilter {
                 hierarchy.insert(parent.clone(), children.clone());
             }
         }
+        // The hierarchy of the service also covers metrics this application has no limits on.
+        // It is only refreshed by the singleton, so that a single setting drops removed metrics.
+        let hierarchy = match merge_service_hierarchy(&self.host, &service_id, &hierarchy) {
+            Ok(service_hierarchy) => service_hierarchy,
+            Err(e) => {
+                warn!(
+                    self.context_id,
+                    "updating hierarchy of service {} failed: {}",
+                    service_id.as_ref(),
+                    e
+                );
+                hierarchy
+            }
+        };
         let keys = app_keys
             .keys()
             .iter()
@@ -424,12 +856,35 @@ impl CacheFilter {
             local_state: state,
             metric_hierarchy: hierarchy,
             app_keys: Some(keys),
+            synced_at: self.current_time(),
+            plan: Some(response.plan().to_string()),
         };
 
-        // note: we have made an assumption that there is not contention with other threads
-        // since it's a fresh application and should not be present in the cache.
-        match self.handle_cache_hit(&mut app, 0) {
-            Ok(()) => Ok(()),
+        if self.state.app_key_reauth {
+            // Application was authorized again for an unknown app key, only its app keys are
+            // refreshed so that local consumption not yet reported is kept.
+            if let Ok((mut cached_app, cas)) = self.fetch_application() {
+                cached_app.app_keys = app.app_keys;
+                return match self.handle_cache_hit(&mut cached_app, cas) {
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(AuthResponseError::CacheHitErr(e)),
+                };
+            }
+        }
+        // State was just fetched from 3scale, an unknown app key is not authorized again.
+        self.state.app_key_reauth = true;
+
+        // Application might have been cached meanwhile (e.g. it expired or the singleton refreshed
+        // it), in which case the fresh state is merged into it and only written if it didn't change.
+        let cas = match self.fetch_application() {
+            Ok((cached_app, cas)) => {
+                apply_local_consumption(&mut app, &cached_app);
+                cas
+            }
+            Err(_) => 0,
+        };
+        match self.handle_cache_hit(&mut app, cas) {
+            Ok(_) => Ok(()),
             Err(e) => Err(AuthResponseError::CacheHitErr(e)),
         }
     }
@@ -438,27 +893,32 @@ impl CacheFilter {
     fn get_request_data(&self) -> Result<ThreescaleData, RequestDataError> {
         // Note: Make changes here when data is fetched from metadata instead of request headers
         let service_token = self
+            .host
             .get_http_request_header("x-3scale-service-token")
             .ok_or(RequestDataError::ServiceTokenNotFound)?;
 
         let service_id = self
+            .host
             .get_http_request_header("x-3scale-service-id")
             .ok_or(RequestDataError::ServiceIdNotFound)?;
 
         let usage_str = self
+            .host
             .get_http_request_header("x-3scale-usages")
             .ok_or(RequestDataError::UsageNotFound)?;
 
         let cluster_name = self
+            .host
             .get_http_request_header("x-3scale-cluster-name")
             .ok_or(RequestDataError::ClusterNameNotFound)?;
 
         let upstream_url = self
+            .host
             .get_http_request_header("x-3scale-upstream-url")
             .ok_or(RequestDataError::UpstreamUrlNotFound)?;
         let parsed_url = url::Url::parse(&upstream_url)?;
 
-        let timeout = match self.get_http_request_header("x-3scale-timeout") {
+        let timeout = match self.host.get_http_request_header("x-3scale-timeout") {
             Some(time_str) => time_str.parse::<u64>().ok(),
             None => None,
         };
@@ -468,11 +928,12 @@ impl CacheFilter {
         let usages = serde_json::from_str::<std::collections::HashMap<String, u64>>(&usage_str)?;
 
         let app_id;
-        if let Some(user_key) = self.get_http_request_header("x-3scale-user-key") {
+        if let Some(user_key) = self.host.get_http_request_header("x-3scale-user-key") {
             app_id = AppIdentifier::UserKey(UserKey::from(user_key.as_ref()));
         } else {
             app_id = AppIdentifier::appid_from_str(
                 &self
+                    .host
                     .get_http_request_header("x-3scale-app-id")
                     .ok_or(RequestDataError::AuthKeyMissing)?,
             );
@@ -488,7 +949,7 @@ impl CacheFilter {
     }
 }
 
-impl Context for CacheFilter {
+impl<H: Host> Context for CacheFilter<H> {
     fn on_http_call_response(&mut self, token_id: u32, _: usize, body_size: usize, _: usize) {
         info!(
             self.context_id,
@@ -498,67 +959,61 @@ impl Context for CacheFilter {
         // Freeing of callout-lock requires the cache_key used to set the lock but cache_key
         // attached to 'self' can change inside handle_auth_response (user_key to app_id).
         let prev_cache_key = self.state.cache_key.clone();
+        // Set for callouts authorizing an unknown app key of a cached application.
+        let app_key_reauth = self.state.app_key_reauth;
 
         // Depending on how response is handled here, waiters should resume accordingly.
         // Note: Inner value of this enum is changed to context_id to resume in send_action_to_waiters().
-        let mut waiter_action = WaiterAction::HandleFailure(0);
+        let waiter_action;
 
-        let headers = self.get_http_call_response_headers();
+        let headers = self.host.get_http_call_response_headers();
         let status = headers
             .iter()
             .find(|(key, _)| key.as_str() == ":status")
             .map(|(_, value)| value)
             .unwrap();
-        if status != TIMEOUT_STATUS {
-            match self.get_http_call_response_body(0, body_size) {
-                Some(bytes) => {
-                    match Authorization::from_str(std::str::from_utf8(&bytes).unwrap()) {
-                        Ok(Authorization::Status(response)) => {
-                            if response.is_authorized() || response.usage_reports().is_some() {
-                                if let Err(e) = self.handle_auth_response(&response) {
-                                    warn!(
-                                        self.context_id,
-                                        "handling auth response failed: {:?}", e
-                                    );
-                                    request_process_failure(self)
-                                } else {
-                                    waiter_action = WaiterAction::HandleCacheHit(0);
-                                }
-                            } else {
-                                increment_stat(&self.stats.unauthorized);
-                                self.send_http_response(
-                                    403,
-                                    vec![],
-                                    Some(response.reason().unwrap().as_bytes()),
-                                )
-                            }
-                        }
-                        Ok(Authorization::Error(auth_error)) => {
-                            info!(
-                                self.context_id,
-                                "authorization error with code: {}",
-                                auth_error.code()
-                            );
-                            request_process_failure(self);
-                            return;
-                        }
-                        Err(e) => {
-                            info!(
-                                self.context_id,
-                                "parsing response from 3scale failed: {:#?} with token: {}",
-                                e,
-                                token_id
-                            );
-                            request_process_failure(self);
-                        }
+        // Timeouts and server errors are failed calls, any other response means 3scale is up.
+        record_auth_call_outcome(self, CallOutcome::from_status(status));
+        if status.starts_with('5') && status != TIMEOUT_STATUS {
+            info!(
+                self.context_id,
+                "3scale responded with status {} for token: {}", status, token_id
+            );
+            request_process_failure(self, FailureCategory::BackendError);
+            waiter_action = WaiterAction::HandleFailure(0, FailureCategory::BackendError);
+        } else if status != TIMEOUT_STATUS {
+            match self.process_auth_response(body_size) {
+                Ok(()) => waiter_action = WaiterAction::HandleCacheHit(0),
+                Err(AuthResponseError::Rejected(error)) if !error.is_configuration_error() => {
+                    info!(
+                        self.context_id,
+                        "3scale rejected authorize call with token {}: {}", token_id, error
+                    );
+                    record_auth_error(&self.host, &self.stats.auth_errors, &error);
+                    increment_stat(&self.host, &self.stats.unauthorized);
+                    if app_key_reauth {
+                        increment_stat(&self.host, &self.stats.unknown_app_keys);
+                        self.remember_rejected_app_key();
                     }
+                    send_rejection(self, &error);
+                    // The callout-lock is shared by every app key of the application, so waiters
+                    // only share rejections that don't depend on the credentials of the request.
+                    waiter_action = if error.applies_to_application() {
+                        WaiterAction::HandleRejection(0, error)
+                    } else {
+                        WaiterAction::HandleCacheHit(0)
+                    };
                 }
-                None => {
-                    info!(
+                Err(e) => {
+                    if let AuthResponseError::Rejected(error) = &e {
+                        record_auth_error(&self.host, &self.stats.auth_errors, error);
+                    }
+                    warn!(
                         self.context_id,
-                        "Found nothing in the response with token: {}", token_id
+                        "handling auth response with token {} failed: {}", token_id, e
                     );
-                    request_process_failure(self);
+                    request_process_failure(self, e.category());
+                    waiter_action = WaiterAction::HandleFailure(0, e.category());
                 }
             }
         } else {
@@ -566,19 +1021,410 @@ impl Context for CacheFilter {
                 self.context_id,
                 "HTTP request timeout for request with token_id: {}", token_id
             );
-            increment_stat(&self.stats.authorize_timeouts);
-            request_process_failure(self);
+            increment_stat(&self.host, &self.stats.authorize_timeouts);
+            request_process_failure(self, FailureCategory::AuthorizeTimeout);
+            waiter_action = WaiterAction::HandleFailure(0, FailureCategory::AuthorizeTimeout);
         }
-        if let Err(e) = free_callout_lock_and_notify_waiters(
-            self.root_id,
-            self.context_id,
-            &prev_cache_key,
-            waiter_action,
-        ) {
-            warn!(
-                self.context_id,
-                "failed to free callout-lock after auth response: {}", e
+        self.free_callout_lock(&prev_cache_key, waiter_action);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::{StaleConfig, UnknownMetricsConfig};
+    use proxy_wasm::types::Status;
+    use threescale::host::{mock::MockHost, SharedQueue};
+    use threescale::proxy::set_application_to_cache;
+
+    fn filter(host: &MockHost, config: FilterConfig) -> CacheFilter<&MockHost> {
+        filter_with(host, config, "app:secret", "{\"hits\": 1}")
+    }
+
+    fn filter_with(
+        host: &MockHost,
+        config: FilterConfig,
+        app_id: &str,
+        usages: &str,
+    ) -> CacheFilter<&MockHost> {
+        host.register_shared_queue(QUEUE_NAME).unwrap();
+        // Thread-specific queue of the root context, where callout waiters are notified.
+        host.register_shared_queue("1").unwrap();
+        host.set_request_headers(vec![
+            ("x-3scale-service-token", "token"),
+            ("x-3scale-service-id", "service"),
+            ("x-3scale-usages", usages),
+            ("x-3scale-cluster-name", "outbound|443||su1.3scale.net"),
+            ("x-3scale-upstream-url", "https://su1.3scale.net"),
+            ("x-3scale-app-id", app_id),
+        ]);
+        CacheFilter {
+            host,
+            context_id: 2,
+            root_id: 1,
+            config,
+            stats: initialize_stats(host),
+            state: RequestState::default(),
+        }
+    }
+
+    fn cache_app(host: &MockHost, left_hits: u64) {
+        cache_app_with_limit(host, "app", "hits", left_hits);
+    }
+
+    fn cache_app_with_limit(host: &MockHost, app_id: &str, metric: &str, left_hits: u64) {
+        let now = host.now();
+        let mut local_state = HashMap::new();
+        local_state.insert(
+            metric.to_string(),
+            UsageReport {
+                period_window: PeriodWindow {
+                    start: now - Duration::from_secs(60),
+                    end: now + Duration::from_secs(3540),
+                    window: Period::Hour,
+                },
+                left_hits,
+                max_value: 100,
+                synced_left_hits: left_hits,
+            },
+        );
+        let app = Application {
+            app_id: AppIdentifier::from(AppId::from(app_id)),
+            service_id: ServiceId::from("service"),
+            local_state,
+            metric_hierarchy: HashMap::new(),
+            app_keys: Some(vec![AppKey::from("secret")]),
+            synced_at: now,
+            plan: None,
+        };
+        let key = CacheKey::from(&app.service_id, &app.app_id);
+        set_application_to_cache(host, &key.as_string(), &app, 0).unwrap();
+    }
+
+    fn cached_left_hits(host: &MockHost) -> u64 {
+        let key = CacheKey::from(
+            &ServiceId::from("service"),
+            &AppIdentifier::from(AppId::from("app")),
+        );
+        let (app, _) = get_application_from_cache(host, &key).unwrap();
+        app.local_state.get("hits").unwrap().left_hits
+    }
+
+    // Authorize response of the application with a limit of 100 hits per hour.
+    fn auth_response(current_value: u64) -> Vec<u8> {
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><status>\
+             <authorized>true</authorized><plan>Basic</plan><usage_reports>\
+             <usage_report metric=\"hits\" period=\"hour\">\
+             <period_start>2020-09-13 12:00:00 +0000</period_start>\
+             <period_end>2020-09-13 13:00:00 +0000</period_end>\
+             <max_value>100</max_value><current_value>{}</current_value>\
+             </usage_report></usage_reports>\
+             <app_keys app=\"app\" svc=\"service\"><key id=\"secret\"/></app_keys></status>",
+            current_value
+        )
+        .into_bytes()
+    }
+
+    fn answer_auth_call(filter: &mut CacheFilter<&MockHost>, status: &str, body: &[u8]) {
+        let calls = filter.host.drain_calls();
+        assert_eq!(calls.len(), 1);
+        assert!(calls[0]
+            .header(":path")
+            .unwrap()
+            .starts_with("/transactions/authorize.xml"));
+        filter
+            .host
+            .set_call_response(vec![(":status", status)], Some(body));
+        filter.on_http_call_response(calls[0].token, 1, body.len(), 0);
+    }
+
+    #[test]
+    fn cache_hit_is_checked_and_reported_to_the_singleton() {
+        let host = MockHost::new();
+        cache_app(&host, 10);
+        let mut filter = filter(&host, FilterConfig::default());
+
+        assert_eq!(filter.on_http_request_headers(0), Action::Continue);
+        assert!(host.drain_calls().is_empty());
+        assert!(host.drain_local_responses().is_empty());
+        assert_eq!(host.resumed_requests(), 1);
+        assert_eq!(cached_left_hits(&host), 9);
+        let queue_id = host.resolve_shared_queue(crate::VM_ID, QUEUE_NAME).unwrap();
+        assert_eq!(host.queue_len(queue_id.unwrap()), 1);
+
+        filter.on_http_response_headers(0);
+        let headers = host.response_headers();
+        assert!(headers.contains(&("RateLimit-Remaining".to_string(), "9".to_string())));
+    }
+
+    #[test]
+    fn cache_hit_without_hits_left_is_rate_limited() {
+        let host = MockHost::new();
+        cache_app(&host, 0);
+        let mut filter = filter(&host, FilterConfig::default());
+
+        filter.on_http_request_headers(0);
+        let responses = host.drain_local_responses();
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].status_code, 429);
+        assert_eq!(host.resumed_requests(), 0);
+        assert_eq!(cached_left_hits(&host), 0);
+    }
+
+    #[test]
+    fn cache_miss_is_authorized_and_cached() {
+        let host = MockHost::new();
+        let mut filter = filter(&host, FilterConfig::default());
+
+        assert_eq!(filter.on_http_request_headers(0), Action::Pause);
+        assert_eq!(host.metric_value("envoy.3scale.cache.misses"), Some(1));
+        answer_auth_call(&mut filter, "200", &auth_response(50));
+
+        assert!(host.drain_local_responses().is_empty());
+        assert_eq!(host.resumed_requests(), 1);
+        assert_eq!(cached_left_hits(&host), 49);
+    }
+
+    #[test]
+    fn reauthorized_application_keeps_hits_consumed_since_its_sync() {
+        let host = MockHost::new();
+        let config = FilterConfig {
+            stale_while_unavailable: StaleConfig {
+                enabled: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut first = filter(&host, config.clone());
+        assert_eq!(first.on_http_request_headers(0), Action::Pause);
+        answer_auth_call(&mut first, "200", &auth_response(10));
+        for _ in 0..4 {
+            let mut hit = filter(&host, config.clone());
+            assert_eq!(hit.on_http_request_headers(0), Action::Continue);
+        }
+        assert_eq!(cached_left_hits(&host), 85);
+
+        // 3scale doesn't know about the hits consumed since the application was cached.
+        host.advance_time(config.stale_while_unavailable.max_age + Duration::from_secs(1));
+        let mut expired = filter(&host, config);
+        assert_eq!(expired.on_http_request_headers(0), Action::Pause);
+        answer_auth_call(&mut expired, "200", &auth_response(10));
+        assert_eq!(cached_left_hits(&host), 84);
+    }
+
+    #[test]
+    fn failed_authorize_call_follows_failure_policy() {
+        for (failure_mode_deny, status) in [(true, "504"), (true, "503"), (false, "504")].iter() {
+            let host = MockHost::new();
+            let config = FilterConfig {
+                failure_mode_deny: *failure_mode_deny,
+                ..Default::default()
+            };
+            let mut filter = filter(&host, config);
+
+            assert_eq!(filter.on_http_request_headers(0), Action::Pause);
+            answer_auth_call(&mut filter, status, b"");
+
+            let responses = host.drain_local_responses();
+            if *failure_mode_deny {
+                assert_eq!(responses.len(), 1);
+                assert_eq!(responses[0].status_code, 403);
+            } else {
+                assert!(responses.is_empty());
+            }
+            assert_eq!(host.resumed_requests(), 1);
+            assert!(get_application_from_cache(
+                &host,
+                &CacheKey::from(
+                    &ServiceId::from("service"),
+                    &AppIdentifier::from(AppId::from("app"))
+                )
+            )
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn unparsable_authorize_response_is_denied_regardless_of_failure_mode() {
+        let host = MockHost::new();
+        let config = FilterConfig {
+            failure_mode_deny: false,
+            ..Default::default()
+        };
+        let mut filter = filter(&host, config);
+
+        assert_eq!(filter.on_http_request_headers(0), Action::Pause);
+        answer_auth_call(&mut filter, "200", b"<status>");
+
+        let responses = host.drain_local_responses();
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].status_code, 403);
+    }
+
+    fn auth_error_response(code: &str) -> Vec<u8> {
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+             <error code=\"{}\">authorize call was rejected</error>",
+            code
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn rejected_request_is_answered_with_the_status_of_its_error() {
+        for (code, call_status, status) in &[
+            ("application_not_found", "404", 404),
+            ("limits_exceeded", "409", 429),
+            ("user_key_invalid", "403", 403),
+        ] {
+            let host = MockHost::new();
+            let mut filter = filter(&host, FilterConfig::default());
+
+            assert_eq!(filter.on_http_request_headers(0), Action::Pause);
+            answer_auth_call(&mut filter, call_status, &auth_error_response(code));
+
+            let responses = host.drain_local_responses();
+            assert_eq!(responses.len(), 1, "code {}", code);
+            assert_eq!(responses[0].status_code, *status, "code {}", code);
+            assert_eq!(host.resumed_requests(), 0);
+            assert_eq!(
+                host.metric_value(&format!("envoy.3scale.auth_errors.{}", code)),
+                Some(1)
             );
         }
     }
+
+    #[test]
+    fn failed_local_response_does_not_panic() {
+        let host = MockHost::new();
+        let mut filter = filter(&host, FilterConfig::default());
+
+        assert_eq!(filter.on_http_request_headers(0), Action::Pause);
+        host.fail_http_stream(Some(Status::InternalFailure));
+        answer_auth_call(
+            &mut filter,
+            "404",
+            &auth_error_response("application_not_found"),
+        );
+
+        assert!(host.drain_local_responses().is_empty());
+        assert_eq!(host.resumed_requests(), 0);
+    }
+
+    fn unknown_metrics_config(policy: UnknownMetricPolicy) -> FilterConfig {
+        FilterConfig {
+            unknown_metrics: UnknownMetricsConfig {
+                policy,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn request_with_unknown_metric_is_denied() {
+        let host = MockHost::new();
+        cache_app(&host, 10);
+        let config = unknown_metrics_config(UnknownMetricPolicy::Deny);
+        let mut filter = filter_with(&host, config, "app:secret", "{\"hits\": 1, \"hitz\": 1}");
+
+        assert_eq!(filter.on_http_request_headers(0), Action::Pause);
+        let responses = host.drain_local_responses();
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].status_code, 403);
+        assert_eq!(
+            host.metric_value("envoy.3scale.cache.unknown_metrics"),
+            Some(1)
+        );
+        assert_eq!(cached_left_hits(&host), 10);
+        assert!(host.drain_calls().is_empty());
+    }
+
+    #[test]
+    fn unknown_metric_is_dropped_from_request() {
+        let host = MockHost::new();
+        cache_app(&host, 10);
+        let config = unknown_metrics_config(UnknownMetricPolicy::Drop);
+        let mut filter = filter_with(&host, config, "app:secret", "{\"hits\": 1, \"hitz\": 1}");
+
+        assert_eq!(filter.on_http_request_headers(0), Action::Continue);
+        assert!(host.drain_local_responses().is_empty());
+        assert_eq!(
+            host.metric_value("envoy.3scale.cache.unknown_metrics"),
+            Some(1)
+        );
+        assert_eq!(cached_left_hits(&host), 9);
+        let metrics = filter.state.req_data.metrics.borrow();
+        assert_eq!(metrics.keys().collect::<Vec<_>>(), vec!["hits"]);
+    }
+
+    #[test]
+    fn metric_known_to_another_app_of_the_service_is_accepted() {
+        let host = MockHost::new();
+        cache_app(&host, 10);
+        cache_app_with_limit(&host, "other_app", "searches", 10);
+        let config = unknown_metrics_config(UnknownMetricPolicy::Deny);
+        let mut filter = filter_with(
+            &host,
+            config,
+            "app:secret",
+            "{\"hits\": 1, \"searches\": 1}",
+        );
+
+        assert_eq!(filter.on_http_request_headers(0), Action::Continue);
+        assert!(host.drain_local_responses().is_empty());
+        assert_eq!(
+            host.metric_value("envoy.3scale.cache.unknown_metrics"),
+            None
+        );
+        assert_eq!(cached_left_hits(&host), 9);
+    }
+
+    #[test]
+    fn metrics_are_not_validated_under_allow() {
+        let host = MockHost::new();
+        cache_app(&host, 10);
+        let config = unknown_metrics_config(UnknownMetricPolicy::Allow);
+        let mut filter = filter_with(&host, config, "app:secret", "{\"hits\": 1, \"hitz\": 1}");
+
+        assert_eq!(filter.on_http_request_headers(0), Action::Continue);
+        assert!(host.drain_local_responses().is_empty());
+        assert_eq!(
+            host.metric_value("envoy.3scale.cache.unknown_metrics"),
+            None
+        );
+        assert_eq!(filter.state.req_data.metrics.borrow().len(), 2);
+    }
+
+    #[test]
+    fn unknown_app_key_is_authorized_again_once_per_ttl() {
+        let host = MockHost::new();
+        cache_app(&host, 10);
+        let config = FilterConfig::default();
+        let request = || filter_with(&host, config.clone(), "app:wrong", "{\"hits\": 1}");
+
+        let mut filter = request();
+        assert_eq!(filter.on_http_request_headers(0), Action::Pause);
+        answer_auth_call(&mut filter, "200", &auth_response(50));
+        let responses = host.drain_local_responses();
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].status_code, 403);
+        assert_eq!(cached_left_hits(&host), 10);
+
+        // Denied straight from the cache while the key is remembered.
+        for _ in 0..3 {
+            assert_eq!(request().on_http_request_headers(0), Action::Pause);
+            assert!(host.drain_calls().is_empty());
+            assert_eq!(host.drain_local_responses()[0].status_code, 403);
+        }
+        assert_eq!(
+            host.metric_value("envoy.3scale.cache.unknown_app_keys"),
+            Some(4)
+        );
+
+        host.advance_time(config.rejected_app_key_ttl);
+        assert_eq!(request().on_http_request_headers(0), Action::Pause);
+        assert_eq!(host.drain_calls().len(), 1);
+    }
 }
@@ -1,52 +1,54 @@
 use crate::configuration::FilterConfig;
-use crate::filter::http::{CacheFilter, RequestState};
-use crate::rand::thread_rng::{thread_rng_init_fallible, ThreadRng};
+use crate::filter::http::{CacheFilter, RequestState, LOCAL_CACHE};
 use crate::{debug, info, warn};
 use proxy_wasm::{
     traits::{Context, HttpContext, RootContext},
     types::{ContextType, LogLevel},
 };
+use std::time::{Duration, UNIX_EPOCH};
 use threescale::{
-    proxy::CacheKey,
+    host::{Host, ProxyHost},
+    rand::thread_rng::{thread_rng_init_fallible, ThreadRng},
     stats::*,
-    structs::{RateLimitInfo, ThreescaleData},
 };
 
-#[no_mangle]
+// Native builds such as unit tests come with an entry point of their own.
+#[cfg_attr(target_arch = "wasm32", no_mangle)]
 pub fn _start() {
     std::panic::set_hook(Box::new(|panic_info| {
         proxy_wasm::hostcalls::log(LogLevel::Critical, &panic_info.to_string()).unwrap();
     }));
     proxy_wasm::set_root_context(|context_id| -> Box<dyn RootContext> {
         Box::new(CacheFilterRoot {
+            host: ProxyHost,
             context_id,
             config: FilterConfig::default(),
-            stats: initialize_stats(),
+            stats: initialize_stats(&ProxyHost),
             rng: ThreadRng,
             id: 0,
+            configured: false,
         })
     });
 }
 
-struct CacheFilterRoot {
+struct CacheFilterRoot<H: Host = ProxyHost> {
+    host: H,
     context_id: u32,
     config: FilterConfig,
     stats: ThreescaleStats,
     rng: ThreadRng,
     id: u32,
+    // Set once the first configuration was applied. Later calls to on_configure reload the
+    // configuration, keeping the thread id, its message queue and the cache.
+    configured: bool,
 }
 
-impl RootContext for CacheFilterRoot {
-    fn on_vm_start(&mut self, _vm_configuration_size: usize) -> bool {
-        info!(self.context_id, "VM started");
-        true
-    }
-
-    fn on_configure(&mut self, _config_size: usize) -> bool {
+impl<H: Host> CacheFilterRoot<H> {
+    fn initialize_thread(&mut self) {
         // Initialize the PRNG for this thread in the root context
         // This only needs to happen once per thread. Since we are
         // single-threaded, this means it just needs to happen once.
-        self.rng = match thread_rng_init_fallible(self, self.context_id) {
+        self.rng = match thread_rng_init_fallible(&ProxyHost, self.context_id) {
             Ok(r) => r,
             Err(e) => {
                 warn!(
@@ -61,145 +63,108 @@ impl RootContext for CacheFilterRoot {
         info!(self.context_id, "root initialized with id: {}", self.id);
 
         // Initializing a thread-specific message queue
-        let queue_id = self.register_shared_queue(&self.id.to_string());
-        info!(
-            self.context_id,
-            "root({}): registered thread-specific MQ ({})", self.id, queue_id
-        );
+        match self.host.register_shared_queue(&self.id.to_string()) {
+            Ok(queue_id) => info!(
+                self.context_id,
+                "root({}): registered thread-specific MQ ({})", self.id, queue_id
+            ),
+            Err(e) => warn!(
+                self.context_id,
+                "root({}): failed to register thread-specific MQ: {:?}", self.id, e
+            ),
+        }
+    }
+}
+
+impl<H: Host + Clone + 'static> RootContext for CacheFilterRoot<H> {
+    fn on_vm_start(&mut self, _vm_configuration_size: usize) -> bool {
+        info!(self.context_id, "VM started");
+        true
+    }
+
+    fn on_configure(&mut self, _config_size: usize) -> bool {
+        if !self.configured {
+            self.initialize_thread();
+        }
 
         //Check for the configuration passed by envoy.yaml
-        let configuration: Vec<u8> = match self.get_configuration() {
+        let configuration: Vec<u8> = match self.host.get_configuration() {
             Some(c) => c,
             None => {
                 warn!(
                     self.context_id,
                     "Configuration missing. Please check the envoy.yaml file for filter configuration"
                 );
+                self.configured = true;
                 return true;
             }
         };
 
-        // Parse and store the configuration passed by envoy.yaml
-        match serde_json::from_slice::<FilterConfig>(configuration.as_ref()) {
-            Ok(config) => {
-                debug!(self.context_id, "configuring with: {:?}", config);
-                self.config = config;
-                true
-            }
+        // Parse and store the configuration passed by envoy.yaml. Invalid configurations are
+        // rejected and the configuration in use is kept.
+        let config = match FilterConfig::parse(configuration.as_ref()) {
+            Ok(config) => config,
             Err(e) => {
-                warn!(
-                    self.context_id,
-                    "Failed to parse envoy.yaml configuration: {:?}", e
-                );
-                true
+                warn!(self.context_id, "Rejected envoy.yaml configuration: {}", e);
+                return false;
+            }
+        };
+        if self.configured {
+            let changes = self.config.changes(&config);
+            if changes.is_empty() {
+                info!(self.context_id, "configuration reloaded without changes");
+            }
+            for change in changes {
+                info!(self.context_id, "configuration changed: {}", change);
             }
+        } else {
+            debug!(self.context_id, "configuring with: {:?}", config);
+        }
+        self.config = config;
+        self.configured = true;
+        LOCAL_CACHE.with(|cache| {
+            cache
+                .borrow_mut()
+                .set_config(&self.host, self.config.local_cache.clone())
+        });
+        // Ticks write the batches of hits of the local cache that waited for max_batch_delay.
+        let tick_period = if self.config.local_cache.enabled {
+            self.config.local_cache.max_batch_delay
+        } else {
+            Duration::default()
+        };
+        if let Err(e) = self.host.set_tick_period(tick_period) {
+            warn!(self.context_id, "failed to set tick period: {:?}", e);
         }
+        true
+    }
+
+    fn on_tick(&mut self) {
+        let now = self
+            .host
+            .get_current_time()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let max_tries = self.config.max_tries;
+        LOCAL_CACHE.with(|cache| cache.borrow_mut().flush_due(&self.host, &now, max_tries));
     }
 
     #[cfg(feature = "unique_callout")]
     fn on_queue_ready(&mut self, queue_id: u32) {
-        use crate::unique_callout::{WaiterAction, WAITING_CONTEXTS};
-        use crate::utils::request_process_failure;
-        use proxy_wasm::hostcalls::set_effective_context;
-        use threescale::{
-            proxy::{get_app_id_from_cache, get_application_from_cache},
-            structs::AppIdentifier,
-        };
+        use crate::unique_callout::{resume_waiter, WaiterAction};
 
         info!(
             self.context_id,
             "thread({}): on_queue called on the filter side", self.id
         );
-        match self.dequeue_shared_queue(queue_id) {
-            Ok(Some(bytes)) => {
-                let message = match bincode::deserialize::<WaiterAction>(&bytes) {
-                    Ok(res) => res,
-                    Err(e) => {
-                        warn!(
-                            self.context_id,
-                            "thread({}): unrecoverable err: deserializing failure: {}", self.id, e
-                        );
-                        return;
-                    }
-                };
-                let context_to_resume: u32 = match message {
-                    WaiterAction::HandleCacheHit(ctxt_id) => ctxt_id,
-                    WaiterAction::HandleFailure(ctxt_id) => ctxt_id,
-                };
-
-                WAITING_CONTEXTS.with(|refcell| {
-                    let mut waiters = refcell.borrow_mut();
-                    let context = waiters.get_mut(&context_to_resume);
-                    if context.is_none() {
-                        warn!(
-                            self.context_id,
-                            "thread({}): unrecoverable err: http context({}) not found while resuming after callout response",
-                            self.id,
-                            context_to_resume
-                        );
-                        return;
-                    }
-
-                    let context = context.unwrap();
-
-                    if let Err(e) = set_effective_context(context_to_resume) {
-                        // NOTE: Ideally this should *not* happen.
-                        warn!(
-                            context_to_resume,
-                            "thread({}): unrecoverable err: failed to set effective context in the host: {:?}", self.id, e
-                        );
-                        waiters.remove(&context_to_resume);
-                        return;
-                    }
-
-                    if let WaiterAction::HandleFailure(_) = message {
-                        // This can happen either there was no response from 3scale (e.g. timeout) or handling
-                        // response failed (e.g. parsing).
-                        info!(context_to_resume, "thread({}): handling auth callout failure for this waiting context", self.id);
-                        request_process_failure(context);
-                        waiters.remove(&context_to_resume);
-                        return;
-                    }
-
-                    // Waiting contexts can have cache_key with user_key pattern but cache stores
-                    // application only with app_id pattern so change if required before accessing it.
-                    if let AppIdentifier::UserKey(ref user_key) = context.state.cache_key.app_id() {
-                        match get_app_id_from_cache(user_key) {
-                            Ok(app_id) => {
-                                context.state.cache_key.set_app_id(&AppIdentifier::from(app_id))
-                            }
-                            Err(e) => {
-                                // This is unlikely since mapping is defined when auth response is handled properly.
-                                // Or someone made a flow error above, allowing this instruction to execute.
-                                warn!(
-                                    self.context_id,
-                                    "failed to map user_key to app_id cache key pattern: {:?}", e
-                                );
-                                waiters.remove(&context_to_resume);
-                                return;
-                            }
-                        }
-                    }
-
-                    match get_application_from_cache(&context.state.cache_key) {
-                        Ok((mut app, cas)) => {
-                            if let Err(e) = context.handle_cache_hit(&mut app, cas) {
-                                debug!(context_to_resume, "handle_cache_hit fail: {}", e);
-                                // if there is error from handle_cache_hit, request flow is not changed
-                                // and should be done by the code handling the returned error.
-                                request_process_failure(context);
-                            } else {
-                                context.resume_http_request();
-                            }
-                        }
-                        Err(e) => warn!(
-                            context_to_resume,
-                            "failed to fetch application from cache: {:?}", e
-                        ),
-                    }
-                    waiters.remove(&context_to_resume);
-                })
-            }
+        match self.host.dequeue_shared_queue(queue_id) {
+            Ok(Some(bytes)) => match bincode::deserialize::<WaiterAction>(&bytes) {
+                Ok(message) => resume_waiter(self.host.clone(), message),
+                Err(e) => warn!(
+                    self.context_id,
+                    "thread({}): unrecoverable err: deserializing failure: {}", self.id, e
+                ),
+            },
             Ok(None) => warn!(
                 self.context_id,
                 "thread({}): on_queue called but found nothing in the MQ", self.id
@@ -213,15 +178,11 @@ impl RootContext for CacheFilterRoot {
 
     fn create_http_context(&self, context: u32) -> Option<Box<dyn HttpContext>> {
         Some(Box::new(CacheFilter {
+            host: self.host.clone(),
             context_id: context,
             root_id: self.id,
             config: self.config.clone(),
-            state: RequestState {
-                update_cache_from_singleton: false,
-                cache_key: CacheKey::default(),
-                req_data: ThreescaleData::default(),
-                rate_limit_info: RateLimitInfo::default(),
-            },
+            state: RequestState::default(),
             stats: self.stats.clone(),
         }))
     }
@@ -231,4 +192,4 @@ impl RootContext for CacheFilterRoot {
     }
 }
 
-impl Context for CacheFilterRoot {}
+impl<H: Host> Context for CacheFilterRoot<H> {}
@@ -2,12 +2,11 @@
 
 const VM_ID: &str = "my_vm_id";
 
-mod configuration;
-mod filter;
+pub mod configuration;
+pub mod filter;
 mod log;
-mod rand;
 #[cfg(feature = "unique_callout")]
-mod unique_callout;
+pub mod unique_callout;
 #[cfg(not(feature = "unique_callout"))]
 mod unique_callout_dummy;
 mod utils;
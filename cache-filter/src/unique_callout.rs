@@ -1,34 +1,67 @@
-use crate::filter::http::CacheFilter;
-use crate::{info, warn};
-use proxy_wasm::{
-    hostcalls::{enqueue_shared_queue, get_shared_data, resolve_shared_queue, set_shared_data},
-    types::Status,
-};
+use crate::configuration::{FailureCategory, FilterConfig};
+use crate::filter::http::{CacheFilter, RequestState};
+use crate::utils::{request_process_failure, send_rejection};
+use crate::{debug, info, warn};
+use proxy_wasm::types::Action;
 use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
 use std::collections::HashMap;
-use threescale::proxy::CacheKey;
+use std::time::UNIX_EPOCH;
+use threescale::{
+    auth_error::AuthorizeError,
+    host::Host,
+    lease::{acquire_lease, release_lease, AcquireStatus, Lease, LeaseError},
+    proxy::{get_app_id_from_cache, CacheError, CacheKey},
+    stats::ThreescaleStats,
+    structs::AppIdentifier,
+};
 
 thread_local! {
-    pub static WAITING_CONTEXTS: RefCell<HashMap<u32, CacheFilter>> = RefCell::new(HashMap::new());
+    pub static WAITING_CONTEXTS: RefCell<HashMap<u32, WaitingContext>> = RefCell::new(HashMap::new());
+}
+
+// Http context waiting for the callout response. It is stored without its host since the root
+// context resuming it shares its host with every http context of the thread.
+#[derive(Clone)]
+pub struct WaitingContext {
+    pub context_id: u32,
+    pub root_id: u32,
+    pub config: FilterConfig,
+    pub stats: ThreescaleStats,
+    pub state: RequestState,
+}
+
+impl WaitingContext {
+    fn from_filter<H: Host>(filter: &CacheFilter<H>) -> Self {
+        WaitingContext {
+            context_id: filter.context_id,
+            root_id: filter.root_id,
+            config: filter.config.clone(),
+            stats: filter.stats.clone(),
+            state: filter.state.clone(),
+        }
+    }
+
+    fn into_filter<H: Host>(self, host: H) -> CacheFilter<H> {
+        CacheFilter {
+            host,
+            context_id: self.context_id,
+            root_id: self.root_id,
+            config: self.config,
+            stats: self.stats,
+            state: self.state,
+        }
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
-pub enum UniqueCalloutError<'a> {
-    #[error("failed to serialize {what:?} due to {reason:?}")]
-    SerializeFail {
-        what: &'a str,
-        reason: bincode::ErrorKind,
-    },
-    #[error("failed to deserialize {what:?} due to {reason:?}")]
-    DeserializeFail {
-        what: &'a str,
-        reason: bincode::ErrorKind,
-    },
-    #[error("failure due to proxy's internal issue: {0:?}")]
-    ProxyFailure(Status),
+pub enum UniqueCalloutError {
     #[error("failed to resolve thread({0}) specific MQ while adding callout-waiter")]
     MQResolveFail(u32),
+    #[error("callout-lease operation failed: {0}")]
+    LeaseFail(#[from] LeaseError),
+    #[error("duration since time later than self")]
+    TimeConversionErr(#[from] std::time::SystemTimeError),
 }
 
 // This struct is serialized and stored in the shared data for callout-lock winner
@@ -41,22 +74,17 @@ pub struct CalloutWaiter {
     pub http_context_id: u32,
 }
 
-/// This struct is serialized and stored as the value of a callout lock.
-#[derive(Debug, Serialize, Deserialize)]
-struct CalloutLockValue {
-    /// Id of the thread who owns the lock.
-    pub owned_by: u32,
-    /// List of contexts that are waiting for lock to be freed.
-    pub waiters: Vec<CalloutWaiter>,
-}
-
 // This enum is passed to thread-specific MQs to let waiters know how to resume processing.
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub enum WaiterAction {
     /// Follow the cache hit path with context_id used as inner value.
     HandleCacheHit(u32),
-    /// Follow in request failure path with context_id used as inner value.
-    HandleFailure(u32),
+    /// Follow in request failure path with context_id used as inner value, along with the
+    /// category of the failure.
+    HandleFailure(u32, FailureCategory),
+    /// Deny the request with the error 3scale rejected the callout with, with context_id used as
+    /// inner value.
+    HandleRejection(u32, AuthorizeError),
 }
 
 // This enum is used to give out status for an http context trying to get callout-lock.
@@ -69,41 +97,25 @@ pub enum SetCalloutLockStatus {
     ResponseCameFirst,
 }
 
-/** TD;LR on how lock is acquired by exploiting host implementation:
-* cache is essentially a hashmap that maps key to a pair of (value, cas).
-* set_shared_data(key, value, cas)'s psuedo-code is as follows:
-*   lock_using_a_mutex() // This guarantees only 1 thread executes its instructions at a time.
-*   if (key is present in the hashmap)
-*      if (cas && cas != cas_of_last_value_added)
-*          return CasMisMatch
-*      update value with new cas
-*   else insert_new_entry_into_the_hashmap
+/** TD;LR on how the callout-lock works:
+* A callout-lock is a lease (see threescale::lease) stored in the shared data under the key
+* "CL_{CacheKey}" whose payload is the list of contexts waiting for the callout response:
+*              ("CL_{CacheKey}", ({SERIALIZED_LEASE_RECORD<Vec<CalloutWaiter>>}, CAS))
 *
-* A lock is acquired by a thread when it successfully adds an entry in the cache of the format:
-*              ("CL_{CacheKey}", ({SERALIZED_CALLOUT_LOCK_VALUE}, CAS))
-
-
-* If you look at Rust SDK spec, when a cache entry is not present, you get (None, None) as result.
-* Now imagine a scenario, when two threads (T1 & T2) check for the same lock in the cache and find no lock.
-* T1 & T2 tries to set an entry by calling: set_shared_data(key, value, **None**).
-* If you go through above-mentioned algo, no matter the order which thread executes first, second thread
-* will overwrite the first one because None CAS is translated into 0 in SDK and second-if condition is passed-over.
-
-* Now imagine if T1 & T2 use '1'(any but 0) as CAS, first thread will insert a new entry since it's not
-* already present in the hashmap and second one will get CasMismatch in the result due to second-if condition.
-* NOTE: Since CAS is a u32 integer, after u32::MAX, CAS resets to 1. What this means for unique-callout
-* is that N threads can successfully acquire the lock again when lock was freed with CAS=1. But the chances
-* of this happening is worse than winning a lottery and proxy can handle multiple callouts.
-
-* It's good to point that 'None' as CAS was intended for overwriting the exisiting value and that's how
-* we free the lock actually, by sending 'None' as value and CAS.
-
-* Please read host implementation (shared_data.cc/h) and Rust SDK/hostcalls.rs for better understanding.
+* The thread that acquires the lease performs the callout and, once the response is handled,
+* releases the lease and notifies every waiter through their thread-specific MQ. Other threads
+* add themselves to the list of waiters with a CAS guarded write.
+*
+* A lease is only valid for 'callout_lease_duration'. If the owner never releases it (e.g. it
+* crashed or the response got lost), the next request for the same key steals the lease along
+* with its waiters and performs the callout again. Since every acquisition hands out a new
+* fencing token, the previous owner won't be able to free the lease or notify waiters anymore.
 **/
 
-// Callout lock is acquired by placing a key-value pair inside shared data.
-// Return Ok(true) when lock is acquired and Ok(false) when added to waitlist.
-pub fn set_callout_lock(context: &CacheFilter) -> Result<SetCalloutLockStatus, UniqueCalloutError> {
+// Callout lock is acquired by acquiring the lease stored inside shared data.
+pub fn set_callout_lock<H: Host>(
+    context: &mut CacheFilter<H>,
+) -> Result<SetCalloutLockStatus, UniqueCalloutError> {
     let callout_lock_key = format!("CL_{}", context.state.cache_key.as_string());
     let context_id = context.context_id;
     let root_id = context.root_id;
@@ -113,13 +125,10 @@ pub fn set_callout_lock(context: &CacheFilter) -> Result<SetCalloutLockStatus, U
         root_id,
         callout_lock_key
     );
-    let callout_lock_value = CalloutLockValue {
-        owned_by: root_id,
-        waiters: Vec::new(),
-    };
-    // Doing this here just for the sake of performance improvement instead of creating it
-    // during every loop run caused by CasMismatch.
-    let queue_id = resolve_shared_queue(crate::VM_ID, &root_id.to_string()).unwrap();
+    let queue_id = context
+        .host
+        .resolve_shared_queue(crate::VM_ID, &root_id.to_string())
+        .unwrap();
     if queue_id.is_none() {
         return Err(UniqueCalloutError::MQResolveFail(root_id));
     }
@@ -127,142 +136,59 @@ pub fn set_callout_lock(context: &CacheFilter) -> Result<SetCalloutLockStatus, U
         queue_id: queue_id.unwrap(),
         http_context_id: context_id,
     };
-    let lock_value_serialized = match bincode::serialize::<CalloutLockValue>(&callout_lock_value) {
-        Ok(res) => res,
-        Err(e) => {
-            return Err(UniqueCalloutError::SerializeFail {
-                what: "CalloutLockValue",
-                reason: *e,
-            })
-        }
-    };
+    let current_time = context.host.get_current_time().duration_since(UNIX_EPOCH)?;
 
-    let mut seen_lock_owner: Option<u32> = None;
-    loop {
-        // check if lock is already acquired or not
-        match get_shared_data(&callout_lock_key) {
-            Ok((_, None)) => {
-                info!(
-                    context_id,
-                    "thread ({}): trying to acquire lock ({}) the first time",
-                    root_id,
-                    callout_lock_key
-                );
-                // Note: CAS is not 'None' here                                        ∨∨∨∨∨∨
-                match set_shared_data(&callout_lock_key, Some(&lock_value_serialized), Some(1)) {
-                    Ok(()) => {
-                        info!(
-                            context_id,
-                            "thread ({}): callout-lock ({}) acquired", root_id, callout_lock_key
-                        );
-                        return Ok(SetCalloutLockStatus::LockAcquired);
-                    }
-                    Err(Status::CasMismatch) => {
-                        info!(
-                            context_id,
-                            "thread ({}): callout-lock ({}) for already acquired by another thread",
-                            root_id,
-                            callout_lock_key
-                        );
-                        // Now try to add it to waitlist instead.
-                        continue;
-                    }
-                    Err(e) => return Err(UniqueCalloutError::ProxyFailure(e)),
-                }
-            }
-            Ok((Some(bytes), Some(cas))) => {
-                let mut stored_lock_value = match bincode::deserialize::<CalloutLockValue>(&bytes) {
-                    Ok(res) => res,
-                    Err(e) => {
-                        return Err(UniqueCalloutError::DeserializeFail {
-                            what: "CalloutLockValue",
-                            reason: *e,
-                        })
-                    }
-                };
-                info!(
-                    context_id,
-                    "thread ({}): callout-lock ({}) already acquired by thread ({}), trying to add to waitlist",
-                    root_id,
-                    callout_lock_key,
-                    stored_lock_value.owned_by
-                );
-                stored_lock_value.waiters.push(callout_waiter.clone());
-                let serialized_updated_lock_value =
-                    match bincode::serialize::<CalloutLockValue>(&stored_lock_value) {
-                        Ok(res) => res,
-                        Err(e) => {
-                            return Err(UniqueCalloutError::SerializeFail {
-                                what: "(updated) CalloutLockValue",
-                                reason: *e,
-                            })
-                        }
-                    };
-                if let Err(Status::CasMismatch) = set_shared_data(
-                    &callout_lock_key,
-                    Some(&serialized_updated_lock_value),
-                    Some(cas),
-                ) {
-                    seen_lock_owner = Some(stored_lock_value.owned_by);
-                    continue;
-                }
-                info!(
-                    context_id,
-                    "thread({}): added to waitlist for callout-lock({})", root_id, callout_lock_key
-                );
-                WAITING_CONTEXTS.with(|waiters| {
-                    waiters
-                        .borrow_mut()
-                        .insert(context.context_id, context.clone())
-                });
-                return Ok(SetCalloutLockStatus::AddedToWaitlist);
-            }
-            Ok((None, Some(cas))) => {
-                if seen_lock_owner.is_some() {
-                    info!(context_id, "thread({}): callout({}) response came before context was added to waitlist", root_id, callout_lock_key);
-                    return Ok(SetCalloutLockStatus::ResponseCameFirst);
-                }
-                info!(
-                    context_id,
-                    "thread ({}): callout-lock ({}) was already free and trying to acquire again",
-                    root_id,
-                    callout_lock_key
-                );
-                match set_shared_data(&callout_lock_key, Some(&lock_value_serialized), Some(cas)) {
-                    Ok(()) => {
-                        info!(
-                            context_id,
-                            "thread ({}): callout-lock ({}) successfully acquired",
-                            root_id,
-                            callout_lock_key
-                        );
-                        return Ok(SetCalloutLockStatus::LockAcquired);
-                    }
-                    Err(Status::CasMismatch) => {
-                        info!(
-                            context_id,
-                            "thread ({}): callout-lock ({}) already acquired by another thread",
-                            root_id,
-                            callout_lock_key
-                        );
-                        // Now try to add context to waitlist.
-                        continue;
-                    }
-                    Err(e) => return Err(UniqueCalloutError::ProxyFailure(e)),
-                }
-            }
-            Err(e) => return Err(UniqueCalloutError::ProxyFailure(e)),
+    match acquire_lease(
+        &context.host,
+        &callout_lock_key,
+        root_id,
+        &current_time,
+        context.config.callout_lease_duration,
+        |waiters: &mut Vec<CalloutWaiter>| waiters.push(callout_waiter.clone()),
+    )? {
+        AcquireStatus::Acquired(lease) => {
+            info!(
+                context_id,
+                "thread ({}): callout-lock ({}) acquired with token ({})",
+                root_id,
+                callout_lock_key,
+                lease.fencing_token
+            );
+            context.state.callout_lease = Some(lease);
+            Ok(SetCalloutLockStatus::LockAcquired)
+        }
+        AcquireStatus::Held(lease) => {
+            info!(
+                context_id,
+                "thread({}): added to waitlist for callout-lock({}) owned by thread ({})",
+                root_id,
+                callout_lock_key,
+                lease.owner
+            );
+            let waiter = WaitingContext::from_filter(context);
+            WAITING_CONTEXTS.with(|waiters| waiters.borrow_mut().insert(context_id, waiter));
+            Ok(SetCalloutLockStatus::AddedToWaitlist)
+        }
+        AcquireStatus::Released(_) => {
+            info!(
+                context_id,
+                "thread({}): callout({}) response came before context was added to waitlist",
+                root_id,
+                callout_lock_key
+            );
+            Ok(SetCalloutLockStatus::ResponseCameFirst)
         }
     }
 }
 
-// NOTE: Right now, there is no option of deleting the pair instead only the value can be erased,
-// and it requires changes in the ABI so change this because it will lead to better memory usage.
-// Callout-lock is freed by setting null value in the cache for the request key.
-pub fn free_callout_lock_and_notify_waiters(
+// Callout-lock is freed by releasing the lease held by the context that performed the callout.
+// Waiters stored with the lease are notified through their thread-specific MQs.
+pub fn free_callout_lock_and_notify_waiters<H: Host>(
+    host: &H,
     root_id: u32,
     context_id: u32,
     cache_key: &CacheKey,
+    lease: Option<&Lease>,
     mut waiter_action: WaiterAction,
 ) -> Result<(), UniqueCalloutError> {
     let callout_lock_key = format!("CL_{}", cache_key.as_string());
@@ -273,68 +199,227 @@ pub fn free_callout_lock_and_notify_waiters(
         callout_lock_key
     );
 
-    match get_shared_data(&callout_lock_key) {
-        Ok((Some(bytes), _)) => {
-            let callout_lock_value = match bincode::deserialize::<CalloutLockValue>(&bytes) {
-                Ok(res) => res,
-                Err(e) => {
-                    return Err(UniqueCalloutError::DeserializeFail {
-                        what: "CalloutLockValue",
-                        reason: *e,
-                    })
-                }
-            };
-            for callout_waiter in callout_lock_value.waiters {
-                match waiter_action {
-                    WaiterAction::HandleFailure(ref mut ctxt_id) => {
-                        *ctxt_id = callout_waiter.http_context_id
-                    }
-                    WaiterAction::HandleCacheHit(ref mut ctxt_id) => {
-                        *ctxt_id = callout_waiter.http_context_id
-                    }
-                }
-                let message = match bincode::serialize::<WaiterAction>(&waiter_action) {
-                    Ok(res) => res,
-                    Err(e) => {
-                        warn!(
-                            context_id,
-                            "failed to serialize WaiterAction {:?}: {}", waiter_action, e
-                        );
-                        continue; // One serialize fail should not prevent others to try.
-                    }
-                };
-                if let Err(e) = enqueue_shared_queue(callout_waiter.queue_id, Some(&message)) {
-                    // There is nothing we can do to signal other threads now and should just
-                    // allow them to timeout and maybe add another mechanism for memory clearance
-                    warn!(
-                        context_id,
-                        "thread({}): enqueue failure for queue({}): {:?}",
-                        root_id,
-                        callout_waiter.queue_id,
-                        e
-                    );
-                    warn!(
-                        context_id,
-                        "failed to enqueue message to notify waiter({:?})", callout_waiter
-                    );
-                    continue; // One enqueue fail should not prevent others to try.
-                }
+    let lease = match lease {
+        Some(lease) => lease,
+        None => {
+            // This should not happen since only the context that acquired the lease performs the callout.
+            warn!(
+                context_id,
+                "thread ({}): trying to free callout-lock ({}) without holding its lease",
+                root_id,
+                callout_lock_key
+            );
+            return Ok(());
+        }
+    };
+
+    let waiters = match release_lease::<_, Vec<CalloutWaiter>>(host, &callout_lock_key, lease)? {
+        Some(waiters) => waiters,
+        None => {
+            // Lease expired before the response came and was taken over by another thread,
+            // which is now responsible for notifying the waiters.
+            warn!(
+                context_id,
+                "thread ({}): callout-lease ({}) with token ({}) expired before being freed",
+                root_id,
+                callout_lock_key,
+                lease.fencing_token
+            );
+            return Ok(());
+        }
+    };
+
+    for callout_waiter in waiters {
+        match waiter_action {
+            WaiterAction::HandleFailure(ref mut ctxt_id, _) => {
+                *ctxt_id = callout_waiter.http_context_id
+            }
+            WaiterAction::HandleCacheHit(ref mut ctxt_id) => {
+                *ctxt_id = callout_waiter.http_context_id
+            }
+            WaiterAction::HandleRejection(ref mut ctxt_id, _) => {
+                *ctxt_id = callout_waiter.http_context_id
             }
-            // Note: safe for current SDK implementation.
-            set_shared_data(&callout_lock_key, None, None).unwrap();
         }
-        Ok((None, _)) => {
-            // This can happen either someother thread freed waiting contexts or there was only
-            // 1 request for this specific application. If this happens for the former
-            // check implementation of acquiring and freeing lock as it's not supposed to happen.
+        let message = match bincode::serialize::<WaiterAction>(&waiter_action) {
+            Ok(res) => res,
+            Err(e) => {
+                warn!(
+                    context_id,
+                    "failed to serialize WaiterAction {:?}: {}", waiter_action, e
+                );
+                continue; // One serialize fail should not prevent others to try.
+            }
+        };
+        if let Err(e) = host.enqueue_shared_queue(callout_waiter.queue_id, Some(&message)) {
+            // There is nothing we can do to signal other threads now and should just
+            // allow them to timeout and maybe add another mechanism for memory clearance
             warn!(
                 context_id,
-                "thread ({}): trying to free non-existing callout-lock ({})",
+                "thread({}): enqueue failure for queue({}): {:?}",
                 root_id,
-                callout_lock_key
+                callout_waiter.queue_id,
+                e
+            );
+            warn!(
+                context_id,
+                "failed to enqueue message to notify waiter({:?})", callout_waiter
             );
+            continue; // One enqueue fail should not prevent others to try.
         }
-        Err(e) => return Err(UniqueCalloutError::ProxyFailure(e)),
     }
     Ok(())
 }
+
+// Resumes the waiting context the action is meant for, with the host of the root context that
+// received the action through its thread-specific MQ.
+pub fn resume_waiter<H: Host>(host: H, message: WaiterAction) {
+    let context_to_resume = match message {
+        WaiterAction::HandleCacheHit(ctxt_id) => ctxt_id,
+        WaiterAction::HandleFailure(ctxt_id, _) => ctxt_id,
+        WaiterAction::HandleRejection(ctxt_id, _) => ctxt_id,
+    };
+    let waiter = WAITING_CONTEXTS.with(|waiters| waiters.borrow_mut().remove(&context_to_resume));
+    let mut context = match waiter {
+        Some(waiter) => waiter.into_filter(host),
+        None => {
+            warn!(
+                context_to_resume,
+                "unrecoverable err: http context not found while resuming after callout response"
+            );
+            return;
+        }
+    };
+    let root_id = context.root_id;
+
+    if let Err(e) = context.host.set_effective_context(context_to_resume) {
+        // NOTE: Ideally this should *not* happen.
+        warn!(
+            context_to_resume,
+            "thread({}): unrecoverable err: failed to set effective context in the host: {:?}",
+            root_id,
+            e
+        );
+        return;
+    }
+
+    if let WaiterAction::HandleFailure(_, category) = message {
+        // This can happen either there was no response from 3scale (e.g. timeout) or handling
+        // response failed (e.g. parsing).
+        info!(
+            context_to_resume,
+            "thread({}): handling auth callout failure for this waiting context", root_id
+        );
+        request_process_failure(&mut context, category);
+        return;
+    }
+
+    if let WaiterAction::HandleRejection(_, ref error) = message {
+        // Only rejections applying to every request of the application are shared.
+        info!(
+            context_to_resume,
+            "thread({}): rejecting this waiting context: {}", root_id, error
+        );
+        send_rejection(&context, error);
+        return;
+    }
+
+    // Waiting contexts can have cache_key with user_key pattern but cache stores
+    // application only with app_id pattern so change if required before accessing it.
+    if let AppIdentifier::UserKey(ref user_key) = context.state.cache_key.app_id() {
+        match get_app_id_from_cache(&context.host, user_key) {
+            Ok(app_id) => context
+                .state
+                .cache_key
+                .set_app_id(&AppIdentifier::from(app_id)),
+            Err(e) => {
+                // This is unlikely since mapping is defined when auth response is handled properly.
+                // Or someone made a flow error above, allowing this instruction to execute.
+                warn!(
+                    context_to_resume,
+                    "failed to map user_key to app_id cache key pattern: {:?}", e
+                );
+                return;
+            }
+        }
+    }
+
+    // Application was just authorized, so an unknown app key is denied instead of
+    // sending another callout, which this waiter would not be around to receive.
+    context.state.app_key_reauth = true;
+    match context.fetch_application() {
+        Ok((mut app, cas)) => {
+            match context.handle_cache_hit(&mut app, cas) {
+                Ok(Action::Continue) => context.resume(),
+                // Request was denied or is waiting for another callout.
+                Ok(_) => {}
+                Err(e) => {
+                    debug!(context_to_resume, "handle_cache_hit fail: {}", e);
+                    // if there is error from handle_cache_hit, request flow is not changed
+                    // and should be done by the code handling the returned error.
+                    request_process_failure(&mut context, e.category());
+                }
+            }
+        }
+        Err(e) => {
+            // Application is not cached when 3scale rejected the credentials of
+            // the request holding the callout-lock, which were not this one's.
+            warn!(
+                context_to_resume,
+                "failed to fetch application from cache: {:?}", e
+            );
+            let category = match e {
+                CacheError::AppNotFound => FailureCategory::Other,
+                _ => FailureCategory::SharedDataFailure,
+            };
+            request_process_failure(&mut context, category);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use threescale::host::mock::MockHost;
+    use threescale::stats::initialize_stats;
+
+    fn wait(host: &MockHost, context_id: u32) {
+        let waiter = WaitingContext {
+            context_id,
+            root_id: 1,
+            config: FilterConfig::default(),
+            stats: initialize_stats(host),
+            state: RequestState::default(),
+        };
+        WAITING_CONTEXTS.with(|waiters| waiters.borrow_mut().insert(context_id, waiter));
+    }
+
+    #[test]
+    fn waiter_is_resumed_through_host_of_root() {
+        let host = MockHost::new();
+        wait(&host, 2);
+        wait(&host, 3);
+
+        resume_waiter(
+            &host,
+            WaiterAction::HandleRejection(2, AuthorizeError::LimitsExceeded),
+        );
+        assert_eq!(host.effective_context(), Some(2));
+        let responses = host.drain_local_responses();
+        assert_eq!(responses.len(), 1);
+        assert_eq!(
+            responses[0].status_code,
+            AuthorizeError::LimitsExceeded.http_status()
+        );
+        WAITING_CONTEXTS.with(|waiters| {
+            let waiters = waiters.borrow();
+            assert!(!waiters.contains_key(&2));
+            assert!(waiters.contains_key(&3));
+        });
+
+        // Waiters are resumed once.
+        resume_waiter(&host, WaiterAction::HandleCacheHit(2));
+        assert!(host.drain_local_responses().is_empty());
+        assert_eq!(host.resumed_requests(), 0);
+    }
+}
@@ -1,5 +1,6 @@
+use crate::configuration::FailureCategory;
 use crate::filter::http::CacheFilter;
-use threescale::proxy::CacheKey;
+use threescale::{auth_error::AuthorizeError, host::Host, lease::Lease, proxy::CacheKey};
 
 /**  Reasoning behind this module:
 * There are few modules (like unique_callout) that are configurable through the
@@ -25,17 +26,22 @@ pub enum SetCalloutLockStatus {
 
 pub enum WaiterAction {
     HandleCacheHit(u32),
-    HandleFailure(u32),
+    HandleFailure(u32, FailureCategory),
+    HandleRejection(u32, AuthorizeError),
 }
 
-pub fn set_callout_lock(_: &CacheFilter) -> Result<SetCalloutLockStatus, UniqueCalloutError> {
+pub fn set_callout_lock<H: Host>(
+    _: &mut CacheFilter<H>,
+) -> Result<SetCalloutLockStatus, UniqueCalloutError> {
     Ok(SetCalloutLockStatus::LockAcquired)
 }
 
-pub fn free_callout_lock_and_notify_waiters(
+pub fn free_callout_lock_and_notify_waiters<H: Host>(
+    _: &H,
     _: u32,
     _: u32,
     _: &CacheKey,
+    _: Option<&Lease>,
     _: WaiterAction,
 ) -> Result<(), UniqueCalloutError> {
     Ok(())
@@ -1,10 +1,18 @@
+use crate::configuration::{FailureCategory, FailurePolicy};
 use crate::filter::http::CacheFilter;
-use crate::info;
-use proxy_wasm::{
-    hostcalls::{resume_http_request, send_http_response},
-    types::Action,
+#[cfg(feature = "unique_callout")]
+use crate::unique_callout::WaiterAction;
+#[cfg(not(feature = "unique_callout"))]
+use crate::unique_callout_dummy::WaiterAction;
+use crate::{info, warn};
+use proxy_wasm::types::Action;
+use std::time::{Duration, UNIX_EPOCH};
+use threescale::{
+    auth_error::AuthorizeError,
+    breaker::{acquire_call, record_call_outcome, CallOutcome},
+    host::Host,
+    structs::{AppIdentifier, RateLimitInfo},
 };
-use threescale::structs::{AppIdentifier, RateLimitInfo};
 use threescalers::{
     api_call::{ApiCall, Kind},
     application::Application,
@@ -16,26 +24,136 @@ use threescalers::{
     usage::Usage,
 };
 
-// Helper function to handle failure when request headers are recieved
-pub fn in_request_failure(filter: &mut CacheFilter) -> Action {
+// Outcome of the failure policy applied to a request.
+enum FailureOutcome {
+    Allowed,
+    Denied,
+    // Request was checked against the cached application, which resumed or responded to it.
+    ServedStale(Action),
+}
+
+fn apply_failure_policy<H: Host>(
+    filter: &mut CacheFilter<H>,
+    category: FailureCategory,
+) -> FailureOutcome {
     filter.state.rate_limit_info = RateLimitInfo::default();
-    if filter.config.failure_mode_deny {
-        send_http_response(403, vec![], Some(b"Access forbidden.\n")).unwrap();
-        return Action::Pause;
+    let policy = filter.config.failure_policy(category);
+    info!(
+        filter.context_id,
+        "handling {:?} failure with policy: {:?}", category, policy
+    );
+    match policy {
+        FailurePolicy::Allow => FailureOutcome::Allowed,
+        FailurePolicy::Deny => FailureOutcome::Denied,
+        FailurePolicy::AllowAndReportLater => {
+            filter.report_later();
+            FailureOutcome::Allowed
+        }
+        FailurePolicy::ServeStale => match filter.serve_stale() {
+            Some(action) => FailureOutcome::ServedStale(action),
+            None if filter.config.default_failure_policy() == FailurePolicy::Deny => {
+                FailureOutcome::Denied
+            }
+            None => FailureOutcome::Allowed,
+        },
+    }
+}
+
+fn send_denial<H: Host>(filter: &CacheFilter<H>, category: FailureCategory) {
+    match category {
+        FailureCategory::MissingCredentials => filter.respond(401, None),
+        _ => filter.respond(403, Some(b"Access forbidden.\n")),
+    }
+}
+
+// Helper function to handle failure when request headers are recieved
+pub fn in_request_failure<H: Host>(
+    filter: &mut CacheFilter<H>,
+    category: FailureCategory,
+) -> Action {
+    match apply_failure_policy(filter, category) {
+        FailureOutcome::Allowed => Action::Continue,
+        FailureOutcome::Denied => {
+            send_denial(filter, category);
+            Action::Pause
+        }
+        FailureOutcome::ServedStale(action) => action,
     }
-    Action::Continue
+}
+
+// Answers a request rejected by 3scale with the status of the error.
+pub fn send_rejection<H: Host>(filter: &CacheFilter<H>, error: &AuthorizeError) {
+    let body = format!("{}\n", error);
+    filter.respond(error.http_status(), Some(body.as_bytes()));
 }
 
 // Helper function to handle failure during processing
-pub fn request_process_failure(filter: &mut CacheFilter) {
-    filter.state.rate_limit_info = RateLimitInfo::default();
-    if filter.config.failure_mode_deny {
-        send_http_response(403, vec![], Some(b"Access forbidden.\n")).unwrap();
+pub fn request_process_failure<H: Host>(filter: &mut CacheFilter<H>, category: FailureCategory) {
+    match apply_failure_policy(filter, category) {
+        FailureOutcome::Allowed => {}
+        FailureOutcome::Denied => send_denial(filter, category),
+        FailureOutcome::ServedStale(_) => return,
+    }
+    filter.resume();
+}
+
+fn current_time<H: Host>(filter: &CacheFilter<H>) -> Duration {
+    filter
+        .host
+        .get_current_time()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+// Records the result of an authorize call in the circuit of the upstream, shared by all workers.
+pub fn record_auth_call_outcome<H: Host>(filter: &CacheFilter<H>, outcome: CallOutcome) {
+    let now = current_time(filter);
+    let upstream = filter.state.req_data.upstream.name();
+    if let Err(e) = record_call_outcome(
+        &filter.host,
+        upstream,
+        &filter.config.circuit_breaker,
+        outcome,
+        filter.state.circuit_generation,
+        &now,
+    ) {
+        warn!(
+            filter.context_id,
+            "failed to record outcome of call to {}: {}", upstream, e
+        );
+    }
+}
+
+// Authorize calls are not sent while the circuit of the upstream is open, except for its probe.
+// Returns the generation of the circuit the call is let through in.
+fn acquire_auth_call<H: Host>(filter: &CacheFilter<H>) -> Option<u32> {
+    let upstream = filter.state.req_data.upstream.name();
+    match acquire_call(
+        &filter.host,
+        upstream,
+        &filter.config.circuit_breaker,
+        &current_time(filter),
+    ) {
+        Ok(generation) => generation,
+        Err(e) => {
+            warn!(
+                filter.context_id,
+                "failed to check circuit of {}: {}", upstream, e
+            );
+            Some(0)
+        }
     }
-    resume_http_request().unwrap();
 }
 
-pub fn do_auth_call(filter: &mut CacheFilter) -> Action {
+// Handles an authorize call that could not be sent. Contexts waiting for its response are
+// resumed with the same failure.
+fn auth_call_failure<H: Host>(filter: &mut CacheFilter<H>, category: FailureCategory) -> Action {
+    let cache_key = filter.state.cache_key.clone();
+    filter.free_callout_lock(&cache_key, WaiterAction::HandleFailure(0, category));
+    in_request_failure(filter, category)
+}
+
+pub fn do_auth_call<H: Host>(filter: &mut CacheFilter<H>) -> Action {
     let request_data = &filter.state.req_data;
     let cred = Credentials::ServiceToken(ServiceToken::from(request_data.service_token.as_ref()));
     let service = Service::new(request_data.service_id.as_ref(), cred);
@@ -70,7 +188,7 @@ pub fn do_auth_call(filter: &mut CacheFilter) -> Action {
         Ok(result) => result,
         Err(e) => {
             info!(filter.context_id, "couldn't contact 3scale: {}", e);
-            return in_request_failure(filter);
+            return in_request_failure(filter, FailureCategory::Other);
         }
     };
 
@@ -83,8 +201,19 @@ pub fn do_auth_call(filter: &mut CacheFilter) -> Action {
         .collect::<Vec<_>>();
 
     info!(filter.context_id, "App : {:?}", apicall);
+    filter.state.circuit_generation = match acquire_auth_call(filter) {
+        Some(generation) => generation,
+        None => {
+            info!(
+                filter.context_id,
+                "circuit of {} is open, authorize call not sent",
+                request_data.upstream.name()
+            );
+            return auth_call_failure(filter, FailureCategory::CircuitOpen);
+        }
+    };
     match request_data.upstream.call(
-        filter,
+        &filter.host,
         uri.as_ref(),
         request.method.as_str(),
         headers,
@@ -98,7 +227,8 @@ pub fn do_auth_call(filter: &mut CacheFilter) -> Action {
         ),
         Err(e) => {
             info!(filter.context_id, "couldn't contact 3scale: {:?}", e);
-            return in_request_failure(filter);
+            record_auth_call_outcome(filter, CallOutcome::Failure);
+            return auth_call_failure(filter, FailureCategory::BackendError);
         }
     };
     // pause the current request to wait for the response from 3scale
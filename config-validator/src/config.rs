@@ -0,0 +1,99 @@
+use cache_filter::configuration::FilterConfig;
+use schemars::schema::RootSchema;
+use schemars::schema_for;
+use singleton_service::configuration::service::ServiceConfig;
+use threescale::config::ConfigError;
+
+/// Configurations of the wasm modules built in this workspace.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConfigKind {
+    /// Configuration of the cache filter, parsed as FilterConfig.
+    Filter,
+    /// Configuration of the singleton service, parsed as ServiceConfig.
+    Singleton,
+}
+
+impl ConfigKind {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "filter" => Some(ConfigKind::Filter),
+            "singleton" => Some(ConfigKind::Singleton),
+            _ => None,
+        }
+    }
+
+    // Root ids the modules are deployed with in the envoy.yaml files of this repository.
+    pub fn from_root_id(root_id: &str) -> Option<Self> {
+        match root_id {
+            "cache_filter" => Some(ConfigKind::Filter),
+            "singleton_service" => Some(ConfigKind::Singleton),
+            _ => None,
+        }
+    }
+
+    pub fn schema(self) -> RootSchema {
+        match self {
+            ConfigKind::Filter => schema_for!(FilterConfig),
+            ConfigKind::Singleton => schema_for!(ServiceConfig),
+        }
+    }
+
+    /// Parses the configuration exactly like the module does when configured by envoy. The
+    /// schema is generated from the same types, so unknown fields and values of the wrong type
+    /// are rejected as the schema describes, then the semantic rules of every field are checked.
+    pub fn validate(self, configuration: &[u8]) -> Result<(), ConfigError> {
+        match self {
+            ConfigKind::Filter => FilterConfig::parse(configuration).map(|_| ()),
+            ConfigKind::Singleton => ServiceConfig::parse(configuration).map(|_| ()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schema_rejects_unknown_fields_and_documents_defaults() {
+        let schema = serde_json::to_value(ConfigKind::Filter.schema()).unwrap();
+        assert_eq!(schema["additionalProperties"], false);
+        assert_eq!(
+            schema["properties"]["callout_lease_duration"]["default"],
+            "5s"
+        );
+
+        let schema = serde_json::to_value(ConfigKind::Singleton.schema()).unwrap();
+        assert_eq!(schema["additionalProperties"], false);
+        assert_eq!(
+            schema["definitions"]["DeltaStoreConfig"]["additionalProperties"],
+            false
+        );
+    }
+
+    #[test]
+    fn configurations_are_checked_against_types_and_rules() {
+        assert_eq!(ConfigKind::Filter.validate(b"{}"), Ok(()));
+        assert_eq!(
+            ConfigKind::Singleton.validate(br#"{"delta_store_config": {"capacity": 10}}"#),
+            Ok(())
+        );
+        assert!(matches!(
+            ConfigKind::Filter.validate(br#"{"max_trie": 5}"#),
+            Err(ConfigError::ParseFail(_))
+        ));
+        assert_eq!(
+            ConfigKind::Singleton
+                .validate(br#"{"delta_store_config": {"capacity": 0}}"#)
+                .unwrap_err()
+                .to_string(),
+            "invalid value for delta_store_config.capacity: must be greater than 0"
+        );
+        assert_eq!(
+            ConfigKind::Filter
+                .validate(br#"{"failure_policies": {"missing_credentials": "ServeStale"}}"#)
+                .unwrap_err()
+                .to_string(),
+            "invalid value for failure_policies.missing_credentials: must be Allow or Deny"
+        );
+    }
+}
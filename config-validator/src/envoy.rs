@@ -0,0 +1,152 @@
+use anyhow::{anyhow, Context};
+use serde_yaml::{Mapping, Value};
+
+const STRING_VALUE_TYPE: &str = "type.googleapis.com/google.protobuf.StringValue";
+
+/// Configuration of a wasm plugin (http filter or bootstrap service) found in envoy.yaml.
+#[derive(Debug, PartialEq)]
+pub struct PluginConfig {
+    /// Location of the plugin config in envoy.yaml, e.g. "bootstrap_extensions[0].typed_config.config".
+    pub location: String,
+    pub root_id: String,
+    /// Configuration passed to the plugin, None when the plugin is configured without one.
+    pub configuration: Option<String>,
+}
+
+/// Collects the configurations of every wasm plugin in envoy.yaml. Plugins are recognized by
+/// their root_id and vm_config fields, wherever they are nested.
+pub fn plugin_configs(envoy_yaml: &str) -> anyhow::Result<Vec<PluginConfig>> {
+    let envoy: Value = serde_yaml::from_str(envoy_yaml).context("failed to parse envoy.yaml")?;
+    let mut plugins = Vec::new();
+    collect_plugin_configs(&envoy, String::new(), &mut plugins)?;
+    Ok(plugins)
+}
+
+fn collect_plugin_configs(
+    value: &Value,
+    location: String,
+    plugins: &mut Vec<PluginConfig>,
+) -> anyhow::Result<()> {
+    match value {
+        Value::Mapping(mapping) => {
+            if let (Some(Value::String(root_id)), Some(_)) =
+                (get(mapping, "root_id"), get(mapping, "vm_config"))
+            {
+                plugins.push(PluginConfig {
+                    configuration: plugin_configuration(mapping)
+                        .with_context(|| format!("invalid plugin config at {}", location))?,
+                    location,
+                    root_id: root_id.clone(),
+                });
+                return Ok(());
+            }
+            for (key, nested) in mapping.iter() {
+                let key = key.as_str().unwrap_or_default();
+                let nested_location = if location.is_empty() {
+                    key.to_string()
+                } else {
+                    format!("{}.{}", location, key)
+                };
+                collect_plugin_configs(nested, nested_location, plugins)?;
+            }
+        }
+        Value::Sequence(sequence) => {
+            for (index, nested) in sequence.iter().enumerate() {
+                collect_plugin_configs(nested, format!("{}[{}]", location, index), plugins)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+// Plugins are configured through a google.protobuf.StringValue holding the JSON configuration.
+fn plugin_configuration(plugin: &Mapping) -> anyhow::Result<Option<String>> {
+    let configuration = match get(plugin, "configuration") {
+        Some(Value::Mapping(configuration)) => configuration,
+        Some(_) => return Err(anyhow!("configuration must be a {}", STRING_VALUE_TYPE)),
+        None => return Ok(None),
+    };
+    match get(configuration, "@type") {
+        Some(Value::String(type_url)) if type_url == STRING_VALUE_TYPE => {}
+        _ => return Err(anyhow!("configuration must be a {}", STRING_VALUE_TYPE)),
+    }
+    match get(configuration, "value") {
+        Some(Value::String(value)) if value.is_empty() => Ok(None),
+        Some(Value::String(value)) => Ok(Some(value.clone())),
+        None => Ok(None),
+        Some(_) => Err(anyhow!("configuration value must be a string")),
+    }
+}
+
+fn get<'a>(mapping: &'a Mapping, key: &str) -> Option<&'a Value> {
+    mapping.get(&Value::String(key.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENVOY_YAML: &str = r#"
+bootstrap_extensions:
+- name: envoy.bootstrap.wasm
+  typed_config:
+    '@type': type.googleapis.com/envoy.extensions.wasm.v3.WasmService
+    singleton: true
+    config:
+      root_id: "singleton_service"
+      configuration:
+        "@type": type.googleapis.com/google.protobuf.StringValue
+        value: |
+          {"delta_store_config": {"capacity": 100}}
+      vm_config:
+        vm_id: "my_vm_id"
+static_resources:
+  listeners:
+  - filter_chains:
+    - filters:
+      - name: envoy.filters.network.http_connection_manager
+        typed_config:
+          http_filters:
+          - name: envoy.filters.http.wasm
+            typed_config:
+              value:
+                config:
+                  root_id: "cache_filter"
+                  vm_config:
+                    vm_id: "my_vm_id"
+          - name: envoy.filters.http.router
+"#;
+
+    #[test]
+    fn plugin_configs_are_found_wherever_nested() {
+        assert_eq!(
+            plugin_configs(ENVOY_YAML).unwrap(),
+            vec![
+                PluginConfig {
+                    location: "bootstrap_extensions[0].typed_config.config".to_string(),
+                    root_id: "singleton_service".to_string(),
+                    configuration: Some("{\"delta_store_config\": {\"capacity\": 100}}\n".to_string()),
+                },
+                PluginConfig {
+                    location: "static_resources.listeners[0].filter_chains[0].filters[0].typed_config.http_filters[0].typed_config.value.config".to_string(),
+                    root_id: "cache_filter".to_string(),
+                    configuration: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn configuration_must_be_a_string_value() {
+        let envoy_yaml = r#"
+config:
+  root_id: "cache_filter"
+  configuration:
+    "@type": type.googleapis.com/google.protobuf.BytesValue
+    value: "{}"
+  vm_config: {}
+"#;
+        assert!(plugin_configs(envoy_yaml).is_err());
+    }
+}
@@ -0,0 +1,97 @@
+#![deny(clippy::all, clippy::cargo)]
+
+mod config;
+mod envoy;
+
+use crate::config::ConfigKind;
+use anyhow::{anyhow, Context};
+use std::process;
+
+const USAGE: &str = "usage:
+    config-validator schema <filter|singleton>
+    config-validator validate <filter|singleton> <config.json>
+    config-validator envoy <envoy.yaml>";
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let result = match args.as_slice() {
+        ["schema", kind] => print_schema(kind).map(|_| true),
+        ["validate", kind, path] => validate_file(kind, path),
+        ["envoy", path] => validate_envoy(path),
+        _ => Err(anyhow!(USAGE)),
+    };
+    match result {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            eprintln!("{:#}", e);
+            process::exit(2);
+        }
+    }
+}
+
+fn config_kind(name: &str) -> anyhow::Result<ConfigKind> {
+    ConfigKind::from_name(name)
+        .ok_or_else(|| anyhow!("unknown configuration '{}'\n{}", name, USAGE))
+}
+
+fn print_schema(kind: &str) -> anyhow::Result<()> {
+    let schema = config_kind(kind)?.schema();
+    println!("{}", serde_json::to_string_pretty(&schema)?);
+    Ok(())
+}
+
+// Validates a JSON configuration, as envoy.yaml passes it to the module. Returns whether it is
+// valid.
+fn validate_file(kind: &str, path: &str) -> anyhow::Result<bool> {
+    let kind = config_kind(kind)?;
+    let configuration = std::fs::read(path).with_context(|| format!("failed to read {}", path))?;
+    Ok(report(path, kind.validate(&configuration)))
+}
+
+// Validates the configuration of every cache filter and singleton service in envoy.yaml.
+// Plugins with other root ids (e.g. the auth filter) are skipped.
+fn validate_envoy(path: &str) -> anyhow::Result<bool> {
+    let envoy_yaml =
+        std::fs::read_to_string(path).with_context(|| format!("failed to read {}", path))?;
+    let mut validated = 0;
+    let mut valid = true;
+    for plugin in envoy::plugin_configs(&envoy_yaml)? {
+        let kind = match ConfigKind::from_root_id(&plugin.root_id) {
+            Some(kind) => kind,
+            None => {
+                println!("{} ({}): skipped", plugin.location, plugin.root_id);
+                continue;
+            }
+        };
+        // Modules configured without a configuration run with the default one.
+        let result = match plugin.configuration {
+            Some(configuration) => kind.validate(configuration.as_bytes()),
+            None => Ok(()),
+        };
+        let name = format!("{} ({})", plugin.location, plugin.root_id);
+        valid &= report(&name, result);
+        validated += 1;
+    }
+    if validated == 0 {
+        return Err(anyhow!(
+            "no cache filter or singleton service configuration found in {}",
+            path
+        ));
+    }
+    Ok(valid)
+}
+
+fn report(name: &str, result: Result<(), threescale::config::ConfigError>) -> bool {
+    match result {
+        Ok(()) => {
+            println!("{}: ok", name);
+            true
+        }
+        Err(e) => {
+            println!("{}: {}", name, e);
+            false
+        }
+    }
+}
@@ -1,2 +1,5 @@
 pub mod delta;
+pub mod dispatch;
+pub mod refresh;
+pub mod report;
 pub mod service;
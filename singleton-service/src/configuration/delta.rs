@@ -1,29 +1,78 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::time::Duration;
+use threescale::config::{ensure, humanized, record_change, ConfigError};
 
 // Represents the method of cache flush.
 // ContainerLimit - Cache update will be performed when the delta store gets filled.
 // Periodical - Cache update will be performed after every periodical tick.
 // Default - A combination of ContainerLimit and Periodical.
-#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub enum FlushMode {
     ContainerLimit,
     Periodical,
     Default,
 }
 
-#[derive(Deserialize, Debug, Clone)]
-#[serde(default)]
+// Represents the unit the capacity of the delta store is measured in.
+// Memory - Approximation of the memory allocated for the keys and values of the deltas.
+// Services - Number of services with deltas.
+// Apps - Number of applications with deltas, across all services.
+// Metrics - Number of metric deltas, across all applications.
+// ReportBytes - Size in bytes of the bodies of the report calls the deltas would be flushed as.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum CapacityMode {
+    Memory,
+    Services,
+    Apps,
+    Metrics,
+    ReportBytes,
+}
+
+/// Flush policy of a single service. Deltas of the service are reported on their own, ahead of
+/// the flushes of the whole delta store, once they are max_latency old and add up to min_delta.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(default, deny_unknown_fields)]
+pub struct FlushPolicy {
+    /// Maximum time deltas of the service wait to be reported.
+    #[serde(with = "humanized")]
+    #[cfg_attr(feature = "schema", schemars(with = "String"))]
+    pub max_latency: Duration,
+
+    /// Minimum sum of the deltas of the service before they are reported on their own.
+    pub min_delta: u64,
+}
+
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        FlushPolicy {
+            max_latency: Duration::from_secs(60),
+            min_delta: 0,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(default, deny_unknown_fields)]
 pub struct DeltaStoreConfig {
-    /// Capacity of the delta store.
+    /// Capacity of the delta store, measured as given by capacity_mode.
     pub capacity: u64,
 
+    /// CapacityMode denotes the unit capacity is measured in.
+    pub capacity_mode: CapacityMode,
+
     /// Flush duration for periodical cache flush in case of low traffic.
-    #[serde(with = "serde_humanize_rs")]
+    #[serde(with = "humanized")]
+    #[cfg_attr(feature = "schema", schemars(with = "String"))]
     pub periodical_flush: Duration,
 
     /// Retry duration in case threescale backend is offline.
-    #[serde(with = "serde_humanize_rs")]
+    #[serde(with = "humanized")]
+    #[cfg_attr(feature = "schema", schemars(with = "String"))]
     pub retry_duration: Duration,
 
     /// Capacity of the await queue in case threescale backend is offline.
@@ -31,16 +80,91 @@ pub struct DeltaStoreConfig {
 
     /// FlushMode denotes the strategy used for cache update.
     pub flush_mode: FlushMode,
+
+    /// Flush policies of single services, by service id.
+    pub service_policies: HashMap<String, FlushPolicy>,
 }
 
 impl Default for DeltaStoreConfig {
     fn default() -> Self {
         DeltaStoreConfig {
             capacity: 100,
+            capacity_mode: CapacityMode::Memory,
             periodical_flush: Duration::from_secs(60),
             retry_duration: Duration::from_secs(30),
             await_queue_capacity: 200,
             flush_mode: FlushMode::Default,
+            service_policies: HashMap::new(),
+        }
+    }
+}
+
+impl DeltaStoreConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        ensure(self.capacity > 0, "capacity", "must be greater than 0")?;
+        ensure(
+            self.periodical_flush > Duration::default(),
+            "periodical_flush",
+            "must be greater than 0",
+        )?;
+        for (service_id, policy) in self.service_policies.iter() {
+            ensure(
+                policy.max_latency > Duration::default(),
+                &format!("service_policies.{}.max_latency", service_id),
+                "must be greater than 0",
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn changes(&self, new: &Self) -> Vec<String> {
+        let mut changes = Vec::new();
+        record_change(&mut changes, "capacity", &self.capacity, &new.capacity);
+        record_change(
+            &mut changes,
+            "capacity_mode",
+            &self.capacity_mode,
+            &new.capacity_mode,
+        );
+        record_change(
+            &mut changes,
+            "periodical_flush",
+            &self.periodical_flush,
+            &new.periodical_flush,
+        );
+        record_change(
+            &mut changes,
+            "retry_duration",
+            &self.retry_duration,
+            &new.retry_duration,
+        );
+        record_change(
+            &mut changes,
+            "await_queue_capacity",
+            &self.await_queue_capacity,
+            &new.await_queue_capacity,
+        );
+        record_change(
+            &mut changes,
+            "flush_mode",
+            &self.flush_mode,
+            &new.flush_mode,
+        );
+        let mut service_ids = self
+            .service_policies
+            .keys()
+            .chain(new.service_policies.keys())
+            .collect::<Vec<_>>();
+        service_ids.sort();
+        service_ids.dedup();
+        for service_id in service_ids {
+            record_change(
+                &mut changes,
+                &format!("service_policies.{}", service_id),
+                &self.service_policies.get(service_id),
+                &new.service_policies.get(service_id),
+            );
         }
+        changes
     }
 }
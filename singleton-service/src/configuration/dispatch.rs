@@ -0,0 +1,97 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use threescale::backoff::BackoffConfig;
+use threescale::config::{ensure, humanized, nested_changes, record_change, ConfigError};
+
+/// Limits applied to the authorize calls sent to refresh the cached applications.
+/// Note: 3scale backend offers no way to fetch the state of several applications in a single
+/// call, so applications are still authorized one by one.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthDispatchConfig {
+    /// Maximum number of authorize calls waiting for a response.
+    pub max_in_flight: usize,
+
+    /// Maximum number of authorize calls sent per second.
+    pub max_per_second: u32,
+
+    /// Interval between two rounds of authorize calls. Pending calls are spread across rounds.
+    #[serde(with = "humanized")]
+    #[cfg_attr(feature = "schema", schemars(with = "String"))]
+    pub dispatch_interval: Duration,
+
+    /// Backoff applied to the authorize calls of an application after they failed with a
+    /// timeout or a server error.
+    pub retry_backoff: BackoffConfig,
+}
+
+impl Default for AuthDispatchConfig {
+    fn default() -> Self {
+        AuthDispatchConfig {
+            max_in_flight: 100,
+            max_per_second: 100,
+            dispatch_interval: Duration::from_secs(1),
+            retry_backoff: BackoffConfig::default(),
+        }
+    }
+}
+
+impl AuthDispatchConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        ensure(
+            self.max_in_flight >= 1,
+            "max_in_flight",
+            "must be at least 1",
+        )?;
+        ensure(
+            self.max_per_second >= 1,
+            "max_per_second",
+            "must be at least 1",
+        )?;
+        ensure(
+            self.dispatch_interval > Duration::default(),
+            "dispatch_interval",
+            "must be greater than 0",
+        )?;
+        self.retry_backoff
+            .validate()
+            .map_err(|e| e.nested("retry_backoff"))
+    }
+
+    pub fn changes(&self, new: &Self) -> Vec<String> {
+        let mut changes = Vec::new();
+        record_change(
+            &mut changes,
+            "max_in_flight",
+            &self.max_in_flight,
+            &new.max_in_flight,
+        );
+        record_change(
+            &mut changes,
+            "max_per_second",
+            &self.max_per_second,
+            &new.max_per_second,
+        );
+        record_change(
+            &mut changes,
+            "dispatch_interval",
+            &self.dispatch_interval,
+            &new.dispatch_interval,
+        );
+        nested_changes(
+            &mut changes,
+            "retry_backoff",
+            self.retry_backoff.changes(&new.retry_backoff),
+        );
+        changes
+    }
+
+    /// Number of authorize calls that can be sent in a single round.
+    pub fn calls_per_round(&self) -> usize {
+        std::cmp::max(
+            1,
+            (self.max_per_second as f64 * self.dispatch_interval.as_secs_f64()) as usize,
+        )
+    }
+}
@@ -0,0 +1,93 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use threescale::config::{ensure, humanized, record_change, ConfigError};
+use threescale::structs::{Application, Period};
+
+/// Thresholds after which an application is re-authorized ahead of the next delta store flush.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(default, deny_unknown_fields)]
+pub struct EarlyRefreshConfig {
+    /// Re-authorize when left hits of any metric drop to or below this fraction of its limit.
+    /// 0.0 only re-authorizes applications that ran out of hits.
+    pub left_hits_ratio: f64,
+
+    /// Re-authorize when any period window of the application ends within this duration.
+    #[serde(with = "humanized")]
+    #[cfg_attr(feature = "schema", schemars(with = "String"))]
+    pub window_end: Duration,
+
+    /// Minimum time between two early refreshes of the same application, so that applications
+    /// staying below the thresholds are not re-authorized on every request.
+    #[serde(with = "humanized")]
+    #[cfg_attr(feature = "schema", schemars(with = "String"))]
+    pub min_interval: Duration,
+
+    /// Disables early re-authorization altogether.
+    pub disabled: bool,
+}
+
+impl Default for EarlyRefreshConfig {
+    fn default() -> Self {
+        EarlyRefreshConfig {
+            left_hits_ratio: 0.1,
+            window_end: Duration::from_secs(10),
+            min_interval: Duration::from_secs(5),
+            disabled: false,
+        }
+    }
+}
+
+impl EarlyRefreshConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        ensure(
+            (0.0..=1.0).contains(&self.left_hits_ratio),
+            "left_hits_ratio",
+            "must be between 0.0 and 1.0",
+        )?;
+        ensure(
+            self.min_interval > Duration::default(),
+            "min_interval",
+            "must be greater than 0",
+        )
+    }
+
+    pub fn changes(&self, new: &Self) -> Vec<String> {
+        let mut changes = Vec::new();
+        record_change(
+            &mut changes,
+            "left_hits_ratio",
+            &self.left_hits_ratio,
+            &new.left_hits_ratio,
+        );
+        record_change(
+            &mut changes,
+            "window_end",
+            &self.window_end,
+            &new.window_end,
+        );
+        record_change(
+            &mut changes,
+            "min_interval",
+            &self.min_interval,
+            &new.min_interval,
+        );
+        record_change(&mut changes, "disabled", &self.disabled, &new.disabled);
+        changes
+    }
+
+    /// Returns true if cached state of the application should be refreshed at time now.
+    pub fn is_refresh_required(&self, app: &Application, now: &Duration) -> bool {
+        if self.disabled {
+            return false;
+        }
+        app.local_state.values().any(|usage| {
+            // Metrics with a limit of 0 are disabled, fetching their state again changes nothing.
+            let low_quota = usage.max_value > 0
+                && usage.left_hits as f64 <= usage.max_value as f64 * self.left_hits_ratio;
+            let window_ending = usage.period_window.window != Period::Eternity
+                && usage.period_window.end <= *now + self.window_end;
+            low_quota || window_ending
+        })
+    }
+}
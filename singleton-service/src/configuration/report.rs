@@ -0,0 +1,82 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use threescale::backoff::BackoffConfig;
+use threescale::config::{ensure, humanized, nested_changes, record_change, ConfigError};
+
+/// Limits of 3scale backend on report calls, reports exceeding them are split in several calls.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(default, deny_unknown_fields)]
+pub struct ReportConfig {
+    /// Maximum number of transactions (one per application) in a single report call.
+    pub max_transactions: usize,
+
+    /// Maximum size in bytes of the body of a report call.
+    pub max_body_bytes: usize,
+
+    /// Backoff applied to flushes after report calls failed with a timeout or a server error.
+    pub retry_backoff: BackoffConfig,
+
+    /// Time 3scale backend is given to apply a report after acknowledging it. Authorize responses
+    /// to calls sent sooner are considered not to include the report yet.
+    #[serde(with = "humanized")]
+    #[cfg_attr(feature = "schema", schemars(with = "String"))]
+    pub reflection_delay: Duration,
+}
+
+impl Default for ReportConfig {
+    fn default() -> Self {
+        ReportConfig {
+            max_transactions: 1000,
+            max_body_bytes: 1024 * 1024,
+            retry_backoff: BackoffConfig::default(),
+            reflection_delay: Duration::from_secs(2),
+        }
+    }
+}
+
+impl ReportConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        ensure(
+            self.max_transactions >= 1,
+            "max_transactions",
+            "must be at least 1",
+        )?;
+        ensure(
+            self.max_body_bytes >= 1,
+            "max_body_bytes",
+            "must be at least 1",
+        )?;
+        self.retry_backoff
+            .validate()
+            .map_err(|e| e.nested("retry_backoff"))
+    }
+
+    pub fn changes(&self, new: &Self) -> Vec<String> {
+        let mut changes = Vec::new();
+        record_change(
+            &mut changes,
+            "max_transactions",
+            &self.max_transactions,
+            &new.max_transactions,
+        );
+        record_change(
+            &mut changes,
+            "max_body_bytes",
+            &self.max_body_bytes,
+            &new.max_body_bytes,
+        );
+        nested_changes(
+            &mut changes,
+            "retry_backoff",
+            self.retry_backoff.changes(&new.retry_backoff),
+        );
+        record_change(
+            &mut changes,
+            "reflection_delay",
+            &self.reflection_delay,
+            &new.reflection_delay,
+        );
+        changes
+    }
+}
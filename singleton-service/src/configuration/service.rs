@@ -1,17 +1,125 @@
 use crate::configuration::delta::DeltaStoreConfig;
-use serde::Deserialize;
+use crate::configuration::dispatch::AuthDispatchConfig;
+use crate::configuration::refresh::EarlyRefreshConfig;
+use crate::configuration::report::ReportConfig;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use threescale::breaker::CircuitBreakerConfig;
+use threescale::config::{ensure, humanized, nested_changes, record_change, ConfigError};
 
-#[derive(Deserialize, Debug)]
-#[serde(default)]
+#[derive(Deserialize, Serialize, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(default, deny_unknown_fields)]
 pub struct ServiceConfig {
     /// Delta store configuration.
     pub delta_store_config: DeltaStoreConfig,
+    /// Early re-authorization of applications running low on quota.
+    pub early_refresh: EarlyRefreshConfig,
+    /// Applications without traffic for this long are no longer re-authorized and get evicted
+    /// from the cache, unless 3scale backend is unhealthy.
+    #[serde(with = "humanized")]
+    #[cfg_attr(feature = "schema", schemars(with = "String"))]
+    pub app_idle_timeout: Duration,
+    /// Limits applied to authorize calls.
+    pub auth_dispatch: AuthDispatchConfig,
+    /// Limits applied to report calls.
+    pub report: ReportConfig,
+    /// Circuit breaker applied to authorize and report calls, which also tells whether 3scale
+    /// backend is unhealthy.
+    pub circuit_breaker: CircuitBreakerConfig,
+    /// Age after which the metric hierarchy of a service is replaced by the one of the next
+    /// authorize response instead of being merged with it.
+    #[serde(with = "humanized")]
+    #[cfg_attr(feature = "schema", schemars(with = "String"))]
+    pub hierarchy_refresh: Duration,
 }
 
 impl Default for ServiceConfig {
     fn default() -> Self {
         ServiceConfig {
             delta_store_config: DeltaStoreConfig::default(),
+            early_refresh: EarlyRefreshConfig::default(),
+            app_idle_timeout: Duration::from_secs(600),
+            auth_dispatch: AuthDispatchConfig::default(),
+            report: ReportConfig::default(),
+            circuit_breaker: CircuitBreakerConfig::default(),
+            hierarchy_refresh: Duration::from_secs(300),
         }
     }
 }
+
+impl ServiceConfig {
+    /// Parses the configuration passed by envoy.yaml, rejecting unknown fields and values out of
+    /// their valid range.
+    pub fn parse(configuration: &[u8]) -> Result<Self, ConfigError> {
+        let config = serde_json::from_slice::<ServiceConfig>(configuration)
+            .map_err(|e| ConfigError::ParseFail(e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.delta_store_config
+            .validate()
+            .map_err(|e| e.nested("delta_store_config"))?;
+        self.early_refresh
+            .validate()
+            .map_err(|e| e.nested("early_refresh"))?;
+        ensure(
+            self.app_idle_timeout > Duration::default(),
+            "app_idle_timeout",
+            "must be greater than 0",
+        )?;
+        self.auth_dispatch
+            .validate()
+            .map_err(|e| e.nested("auth_dispatch"))?;
+        self.report.validate().map_err(|e| e.nested("report"))?;
+        self.circuit_breaker
+            .validate()
+            .map_err(|e| e.nested("circuit_breaker"))?;
+        ensure(
+            self.hierarchy_refresh > Duration::default(),
+            "hierarchy_refresh",
+            "must be greater than 0",
+        )
+    }
+
+    /// Describes every field whose value differs in the new configuration.
+    pub fn changes(&self, new: &Self) -> Vec<String> {
+        let mut changes = Vec::new();
+        nested_changes(
+            &mut changes,
+            "delta_store_config",
+            self.delta_store_config.changes(&new.delta_store_config),
+        );
+        nested_changes(
+            &mut changes,
+            "early_refresh",
+            self.early_refresh.changes(&new.early_refresh),
+        );
+        record_change(
+            &mut changes,
+            "app_idle_timeout",
+            &self.app_idle_timeout,
+            &new.app_idle_timeout,
+        );
+        nested_changes(
+            &mut changes,
+            "auth_dispatch",
+            self.auth_dispatch.changes(&new.auth_dispatch),
+        );
+        nested_changes(&mut changes, "report", self.report.changes(&new.report));
+        nested_changes(
+            &mut changes,
+            "circuit_breaker",
+            self.circuit_breaker.changes(&new.circuit_breaker),
+        );
+        record_change(
+            &mut changes,
+            "hierarchy_refresh",
+            &self.hierarchy_refresh,
+            &new.hierarchy_refresh,
+        );
+        changes
+    }
+}
@@ -1,3 +1,5 @@
 #![deny(clippy::all, clippy::cargo)]
-mod configuration;
+pub mod configuration;
 mod service;
+
+pub use service::proxy::_start;
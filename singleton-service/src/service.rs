@@ -1,4 +1,5 @@
 pub mod auth;
 pub mod deltas;
+pub mod in_flight;
 pub mod proxy;
 pub mod report;
@@ -1,18 +1,20 @@
-use crate::configuration::delta::{DeltaStoreConfig, FlushMode};
-use chrono::offset::Utc;
-use chrono::DateTime;
+use crate::configuration::delta::{CapacityMode, DeltaStoreConfig, FlushMode};
+use crate::service::report::report_body_size;
 use log::info;
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
-use threescale::structs::{AppIdentifier, ThreescaleData};
+use std::mem::size_of;
+use std::time::Duration;
+use threescale::structs::{AppIdentifier, ServiceId, ServiceToken, ThreescaleData};
 
 /// DeltaStore is an in-memory storage built using nested hashmaps to store deltas for different
 /// metrics related to different applications until they gets flushed. Data stored in delta store
 /// is structured in a way that is favourable cache flush operation.
 /// (minimal computations required before flushing)
 pub struct DeltaStore {
-    // Represents the previous cache flush time in UTC. This will be used to flush cache in case of
-    // a low network traffic scenario.
-    pub last_update: Option<DateTime<Utc>>,
+    // Represents the time (since UNIX_EPOCH) every service received its first delta since it was
+    // last flushed. Used to flush services with a flush policy once their deltas get too old.
+    pub last_update: HashMap<String, Duration>,
 
     // Represents a hierarchical storage of deltas.
     // Hierarchy => - Service
@@ -29,12 +31,51 @@ pub struct DeltaStore {
     // bytes are not considerd here. So not suitable to take decisions for memory management.
     // Only intended to provide a mechanism for the user to configure a value for delta store flush to
     // flush cache based on a value propotional to memory allocation.
+    // Use the other capacity modes for a capacity with predictable behaviour.
     pub memory_allocated: usize,
 
+    // Number of services, applications and metrics stored in the deltas hashmap.
+    pub entries: DeltaEntries,
+
+    // Size in bytes of the bodies of the report calls the deltas would be flushed as. Only measured
+    // when capacity is configured in report bytes, since it requires encoding the deltas.
+    pub report_bytes: usize,
+
+    // Encoded report size of every service, tracked per application so that only the application
+    // being updated is encoded again.
+    report_sizes: HashMap<String, ReportSize>,
+
     // DeltaStoreConfig contains all the configurations related for delta store.
     pub config: DeltaStoreConfig,
 }
 
+/// Number of entries at each level of the delta store hierarchy.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct DeltaEntries {
+    pub services: usize,
+    pub apps: usize,
+    pub metrics: usize,
+}
+
+impl DeltaEntries {
+    // Allocation of the hashmap keys and values holding these entries, see memory_allocated.
+    fn memory(&self) -> usize {
+        self.services
+            * (size_of::<String>() + size_of::<HashMap<AppIdentifier, HashMap<String, u64>>>())
+            + self.apps * (size_of::<AppIdentifier>() + size_of::<HashMap<String, u64>>())
+            + self.metrics * (size_of::<String>() + size_of::<u64>())
+    }
+}
+
+// Encoded size of the report of a single service.
+#[derive(Default)]
+struct ReportSize {
+    // Size of the body of a report without transactions, sent once per service.
+    base: usize,
+    // Size added to the body by the transaction of every application.
+    apps: HashMap<AppIdentifier, usize>,
+}
+
 /// DeltaStoreState represents the state of the delta store. Singleton service uses this
 /// state to initiate cache flush when delta store gets filled.
 #[derive(PartialEq)]
@@ -44,18 +85,127 @@ pub enum DeltaStoreState {
 }
 
 impl DeltaStore {
+    pub fn new(config: DeltaStoreConfig) -> Self {
+        DeltaStore {
+            last_update: HashMap::new(),
+            deltas: HashMap::new(),
+            memory_allocated: 0,
+            entries: DeltaEntries::default(),
+            report_bytes: 0,
+            report_sizes: HashMap::new(),
+            config,
+        }
+    }
+
+    /// Applies a new configuration while keeping the deltas. Measurements the new capacity mode
+    /// relies on are taken again, so that no flush is required.
+    pub fn set_config(&mut self, config: DeltaStoreConfig) {
+        let measure_report = config.capacity_mode == CapacityMode::ReportBytes
+            && self.config.capacity_mode != CapacityMode::ReportBytes;
+        self.config = config;
+        self.memory_allocated = if self.config.flush_mode != FlushMode::Periodical {
+            self.entries.memory()
+        } else {
+            0
+        };
+        if measure_report {
+            let apps = self
+                .deltas
+                .iter()
+                .flat_map(|(key, apps)| {
+                    apps.keys().map(move |app_id| (key.clone(), app_id.clone()))
+                })
+                .collect::<Vec<_>>();
+            for (key, app_id) in apps {
+                self.measure_report_size(&key, &app_id);
+            }
+        } else if self.config.capacity_mode != CapacityMode::ReportBytes {
+            self.report_bytes = 0;
+            self.report_sizes.clear();
+        }
+    }
+
+    /// Empties the delta store, returning the deltas to be flushed.
+    pub fn flush(&mut self) -> HashMap<String, HashMap<AppIdentifier, HashMap<String, u64>>> {
+        self.memory_allocated = 0;
+        self.entries = DeltaEntries::default();
+        self.report_bytes = 0;
+        self.report_sizes.clear();
+        self.last_update.clear();
+        std::mem::take(&mut self.deltas)
+    }
+
+    /// Returns the services whose flush policy requires them to be flushed at the given time.
+    pub fn services_due(&self, now: &Duration) -> Vec<String> {
+        let mut due = self
+            .last_update
+            .iter()
+            .filter(|(key, last_update)| {
+                let service_id = key.split('_').next().unwrap_or_default();
+                match self.config.service_policies.get(service_id) {
+                    Some(policy) => {
+                        now.checked_sub(**last_update).unwrap_or_default() >= policy.max_latency
+                            && self.service_delta(key) >= policy.min_delta
+                    }
+                    None => false,
+                }
+            })
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        due.sort();
+        due
+    }
+
+    /// Removes the deltas of a single service, so that they can be reported on their own.
+    pub fn remove_service(
+        &mut self,
+        key: &str,
+    ) -> Option<HashMap<AppIdentifier, HashMap<String, u64>>> {
+        let apps = self.deltas.remove(key)?;
+        let removed = DeltaEntries {
+            services: 1,
+            apps: apps.len(),
+            metrics: apps.values().map(HashMap::len).sum(),
+        };
+        self.entries.services -= removed.services;
+        self.entries.apps -= removed.apps;
+        self.entries.metrics -= removed.metrics;
+        self.memory_allocated = self.memory_allocated.saturating_sub(removed.memory());
+        self.remove_report_size(key);
+        self.last_update.remove(key);
+        Some(apps)
+    }
+
+    // Sum of all the deltas of a service.
+    fn service_delta(&self, key: &str) -> u64 {
+        self.deltas.get(key).map_or(0, |apps| {
+            apps.values()
+                .flat_map(HashMap::values)
+                .fold(0, |sum, value| sum.saturating_add(*value))
+        })
+    }
+
+    /// Returns how filled the delta store is, measured as given by the capacity mode.
+    pub fn usage(&self) -> usize {
+        match self.config.capacity_mode {
+            CapacityMode::Memory => self.memory_allocated,
+            CapacityMode::Services => self.entries.services,
+            CapacityMode::Apps => self.entries.apps,
+            CapacityMode::Metrics => self.entries.metrics,
+            CapacityMode::ReportBytes => self.report_bytes,
+        }
+    }
+
     /// Method to update delta store. Handles scenarios like updating existing metrics,
     /// adding new services, applications with new metrics. Gets called for each message
-    /// received through the message queue.
+    /// received through the message queue, along with the time of the request.
     pub fn update_delta_store(
         &mut self,
         threescale: &ThreescaleData,
+        req_time: &Duration,
     ) -> Result<DeltaStoreState, anyhow::Error> {
-        let delta_increase: usize;
-        match self.get_mut_service(
-            threescale.service_id.as_ref(),
-            threescale.service_token.as_ref(),
-        ) {
+        let key = DeltaStore::service_key(&threescale.service_id, &threescale.service_token);
+        let added = match self.deltas.get_mut(&key) {
             Some(service) => match DeltaStore::get_mut_app_delta(&threescale.app_id, service) {
                 Some(app) => {
                     info!(
@@ -63,51 +213,44 @@ impl DeltaStore {
                         &threescale.app_id.as_ref(),
                         &threescale.service_id.as_ref()
                     );
-                    delta_increase = DeltaStore::update_app_delta(app, threescale);
+                    DeltaEntries {
+                        metrics: DeltaStore::update_app_delta(app, threescale),
+                        ..Default::default()
+                    }
                 }
                 None => {
                     info!(
                         "No application found for service {}",
                         &threescale.service_id.as_ref()
                     );
-                    delta_increase = DeltaStore::add_app_delta(service, threescale);
+                    DeltaStore::add_app_delta(service, threescale)
                 }
             },
             None => {
                 info!("No service and application found for the given key combination");
                 let mut usages: HashMap<AppIdentifier, HashMap<String, u64>> = HashMap::new();
-                usages.insert(
-                    threescale.app_id.clone(),
-                    threescale.metrics.borrow().clone(),
-                );
-                let delta_key = format!(
-                    "{}_{}",
-                    threescale.service_id.as_ref(),
-                    threescale.service_token.as_ref()
-                );
-                self.deltas.insert(delta_key, usages);
-                // transitive_alloc denotes the allocations of the inner hashmaps due to
-                // new entry.
-                let transitive_alloc = (std::mem::size_of::<HashMap<String, u64>>()
-                    + std::mem::size_of::<AppIdentifier>())
-                    + threescale.metrics.borrow().len()
-                        * (std::mem::size_of::<String>() + std::mem::size_of::<u64>());
-
-                // new_alloc denotes the new allocation of the services hashmap.
-                let direct_alloc = std::mem::size_of::<String>()
-                    + std::mem::size_of::<HashMap<AppIdentifier, HashMap<String, u64>>>();
-                // Total value of the delta store memory allocation increase is equal to direct_alloc of the
-                // services hashmap + transitive allocation of the nested hashmaps.
-                delta_increase = direct_alloc + transitive_alloc;
+                let added = DeltaStore::add_app_delta(&mut usages, threescale);
+                self.deltas.insert(key.clone(), usages);
+                self.last_update.insert(key.clone(), *req_time);
+                DeltaEntries {
+                    services: 1,
+                    ..added
+                }
             }
+        };
+        self.entries.services += added.services;
+        self.entries.apps += added.apps;
+        self.entries.metrics += added.metrics;
+        if self.config.capacity_mode == CapacityMode::ReportBytes {
+            self.measure_report_size(&key, &threescale.app_id);
         }
         if self.config.flush_mode != FlushMode::Periodical {
             info!(
                 "Delta store memory allocation increased by: {}",
-                delta_increase
+                added.memory()
             );
-            self.memory_allocated += delta_increase;
-            if self.memory_allocated >= self.config.capacity as usize {
+            self.memory_allocated += added.memory();
+            if self.usage() >= self.config.capacity as usize {
                 Ok(DeltaStoreState::Flush)
             } else {
                 Ok(DeltaStoreState::Ok)
@@ -117,55 +260,354 @@ impl DeltaStore {
         }
     }
 
-    // TODO: Handle app_id -> app_id + app_key scenario.
+    /// Removes the deltas of a single application, so that they can be reported ahead of the
+    /// rest of the delta store. Services left without applications are removed as well.
+    pub fn remove_app_delta(
+        &mut self,
+        threescale: &ThreescaleData,
+    ) -> Option<HashMap<String, u64>> {
+        let key = DeltaStore::service_key(&threescale.service_id, &threescale.service_token);
+        let service = self.deltas.get_mut(&key)?;
+        let app_delta = service.remove(&threescale.app_id)?;
+        let mut removed = DeltaEntries {
+            services: 0,
+            apps: 1,
+            metrics: app_delta.len(),
+        };
+        if service.is_empty() {
+            self.deltas.remove(&key);
+            removed.services = 1;
+        }
+        self.entries.services -= removed.services;
+        self.entries.apps -= removed.apps;
+        self.entries.metrics -= removed.metrics;
+        self.memory_allocated = self.memory_allocated.saturating_sub(removed.memory());
+        if removed.services == 1 {
+            self.remove_report_size(&key);
+            self.last_update.remove(&key);
+        } else if let Some(report_size) = self.report_sizes.get_mut(&key) {
+            let app_size = report_size.apps.remove(&threescale.app_id).unwrap_or(0);
+            self.report_bytes = self.report_bytes.saturating_sub(app_size);
+        }
+        Some(app_delta)
+    }
+
+    /// Deltas of a single application not flushed yet, by metric.
+    pub fn app_delta(
+        &self,
+        service_id: &ServiceId,
+        service_token: &ServiceToken,
+        app_id: &AppIdentifier,
+    ) -> Option<&HashMap<String, u64>> {
+        self.deltas
+            .get(&DeltaStore::service_key(service_id, service_token))?
+            .get(app_id)
+    }
+
+    fn remove_report_size(&mut self, key: &str) {
+        if let Some(report_size) = self.report_sizes.remove(key) {
+            let service_size = report_size.base + report_size.apps.values().sum::<usize>();
+            self.report_bytes = self.report_bytes.saturating_sub(service_size);
+        }
+    }
+
+    fn service_key(service_id: &ServiceId, service_token: &ServiceToken) -> String {
+        format!("{}_{}", service_id.as_ref(), service_token.as_ref())
+    }
+
+    // Encodes the transaction of the application again, since its size changes along with the
+    // values of its deltas.
+    fn measure_report_size(&mut self, key: &str, app_id: &AppIdentifier) {
+        let mut apps = HashMap::new();
+        match self
+            .deltas
+            .get(key)
+            .and_then(|service| service.get_key_value(app_id))
+        {
+            Some((app_id, app_delta)) => apps.insert(app_id.clone(), app_delta.clone()),
+            None => return,
+        };
+        let report_size = match self.report_sizes.entry(key.to_string()) {
+            Entry::Occupied(report_size) => report_size.into_mut(),
+            Entry::Vacant(report_size) => {
+                let base = report_body_size(key, &HashMap::new());
+                self.report_bytes += base;
+                report_size.insert(ReportSize {
+                    base,
+                    ..Default::default()
+                })
+            }
+        };
+        let app_size = report_body_size(key, &apps).saturating_sub(report_size.base);
+        let previous = report_size.apps.insert(app_id.clone(), app_size);
+        self.report_bytes = self.report_bytes.saturating_sub(previous.unwrap_or(0)) + app_size;
+    }
+
+    // Deltas of an application are keyed by its app_id alone, so usages received with different
+    // or missing app keys are merged. The identifier keeps the first app key received, an entry
+    // stored without app key takes the key of the first usage received with one
+    // (app_id -> app_id + app_key). The key is validated before reporting.
     fn get_mut_app_delta<'a>(
         app: &'a AppIdentifier,
         service: &'a mut HashMap<AppIdentifier, HashMap<String, u64>>,
     ) -> Option<&'a mut HashMap<String, u64>> {
+        let missing_key = match (service.get_key_value(app), app) {
+            (Some((AppIdentifier::AppId(_, None), _)), AppIdentifier::AppId(_, Some(_))) => true,
+            (Some(_), _) => false,
+            (None, _) => return None,
+        };
+        if missing_key {
+            // Inserting an equal key keeps the stored one, so the entry is moved instead.
+            let app_delta = service.remove(app)?;
+            service.insert(app.clone(), app_delta);
+        }
         service.get_mut(app)
     }
 
-    fn get_mut_service(
-        &mut self,
-        service_id: &str,
-        service_token: &str,
-    ) -> Option<&mut HashMap<AppIdentifier, HashMap<String, u64>>> {
-        let key = format!("{}_{}", service_id, service_token);
-        self.deltas.get_mut(&key)
-    }
-
+    // Returns the number of metrics added to the application.
     fn update_app_delta(
         app_delta: &mut HashMap<String, u64>,
         threescale: &ThreescaleData,
     ) -> usize {
-        let mut alloc: usize = 0;
+        let mut added: usize = 0;
         for (metric, value) in threescale.metrics.borrow().iter() {
             if app_delta.contains_key(metric) {
                 *app_delta.get_mut(metric).unwrap() += value;
             } else {
                 app_delta.insert(metric.to_string(), *value);
-                // memory allocation increase if a new metric is added.
-                alloc += std::mem::size_of::<String>() + std::mem::size_of::<u64>();
+                added += 1;
             }
         }
-        alloc
+        added
     }
 
     fn add_app_delta(
         service: &mut HashMap<AppIdentifier, HashMap<String, u64>>,
         threescale: &ThreescaleData,
-    ) -> usize {
+    ) -> DeltaEntries {
         service.insert(
             threescale.app_id.clone(),
             threescale.metrics.borrow().clone(),
         );
+        DeltaEntries {
+            services: 0,
+            apps: 1,
+            metrics: threescale.metrics.borrow().len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::delta::{CapacityMode, FlushMode, FlushPolicy};
+    use std::cell::RefCell;
+    use threescale::structs::{AppId, AppKey, ServiceId, ServiceToken};
+
+    fn delta_store() -> DeltaStore {
+        DeltaStore::new(DeltaStoreConfig::default())
+    }
+
+    fn usage(app_id: &str, app_key: Option<&str>) -> ThreescaleData {
+        let app_id = match app_key {
+            Some(key) => AppIdentifier::from((AppId::from(app_id), AppKey::from(key))),
+            None => AppIdentifier::from(AppId::from(app_id)),
+        };
+        ThreescaleData {
+            app_id,
+            service_id: ServiceId::from("service"),
+            service_token: ServiceToken::from("token"),
+            metrics: RefCell::new(vec![("hits".to_string(), 1)].into_iter().collect()),
+            ..Default::default()
+        }
+    }
+
+    // Returns the app key the deltas are stored with and the accumulated hits.
+    fn stored(store: &DeltaStore, app_id: &str) -> (Option<String>, u64) {
+        let apps = store.deltas.get("service_token").unwrap();
+        let (app, deltas) = apps
+            .get_key_value(&AppIdentifier::from(AppId::from(app_id)))
+            .unwrap();
+        let key = match app {
+            AppIdentifier::AppId(_, key) => key.as_ref().map(|key| key.as_ref().to_string()),
+            AppIdentifier::UserKey(_) => None,
+        };
+        (key, deltas["hits"])
+    }
+
+    #[test]
+    fn app_key_is_added_to_app_stored_without_key() {
+        let mut store = delta_store();
+        store
+            .update_delta_store(&usage("app", None), &Duration::default())
+            .unwrap();
+        let memory_allocated = store.memory_allocated;
+        store
+            .update_delta_store(&usage("app", Some("secret")), &Duration::default())
+            .unwrap();
+        assert_eq!(stored(&store, "app"), (Some("secret".to_string()), 2));
+        assert_eq!(store.memory_allocated, memory_allocated);
+    }
+
+    #[test]
+    fn usages_without_app_key_keep_stored_key() {
+        let mut store = delta_store();
+        store
+            .update_delta_store(&usage("app", Some("secret")), &Duration::default())
+            .unwrap();
+        store
+            .update_delta_store(&usage("app", None), &Duration::default())
+            .unwrap();
+        assert_eq!(stored(&store, "app"), (Some("secret".to_string()), 2));
+    }
+
+    #[test]
+    fn first_app_key_is_kept_for_different_keys() {
+        let mut store = delta_store();
+        store
+            .update_delta_store(&usage("app", Some("first")), &Duration::default())
+            .unwrap();
+        store
+            .update_delta_store(&usage("app", Some("second")), &Duration::default())
+            .unwrap();
+        store
+            .update_delta_store(&usage("other", None), &Duration::default())
+            .unwrap();
+        assert_eq!(stored(&store, "app"), (Some("first".to_string()), 2));
+        assert_eq!(stored(&store, "other"), (None, 1));
+    }
+
+    fn usage_for(service_id: &str, app_id: &str, metrics: &[&str]) -> ThreescaleData {
+        ThreescaleData {
+            service_id: ServiceId::from(service_id),
+            metrics: RefCell::new(metrics.iter().map(|m| (m.to_string(), 1)).collect()),
+            ..usage(app_id, None)
+        }
+    }
+
+    #[test]
+    fn entries_are_counted_per_level() {
+        let mut store = delta_store();
+        store
+            .update_delta_store(&usage_for("a", "app_1", &["hits"]), &Duration::default())
+            .unwrap();
+        store
+            .update_delta_store(
+                &usage_for("a", "app_1", &["hits", "other"]),
+                &Duration::default(),
+            )
+            .unwrap();
+        store
+            .update_delta_store(&usage_for("a", "app_2", &["hits"]), &Duration::default())
+            .unwrap();
+        store
+            .update_delta_store(&usage_for("b", "app_1", &["hits"]), &Duration::default())
+            .unwrap();
+        let expected = DeltaEntries {
+            services: 2,
+            apps: 3,
+            metrics: 4,
+        };
+        assert_eq!(store.entries, expected);
+
+        store.remove_app_delta(&usage_for("b", "app_1", &[]));
+        let expected = DeltaEntries {
+            services: 1,
+            apps: 2,
+            metrics: 3,
+        };
+        assert_eq!(store.entries, expected);
+
+        store.flush();
+        assert_eq!(store.entries, DeltaEntries::default());
+        assert_eq!(store.memory_allocated, 0);
+    }
+
+    #[test]
+    fn capacity_in_apps_flushes_at_given_apps() {
+        let mut store = DeltaStore::new(DeltaStoreConfig {
+            capacity: 2,
+            capacity_mode: CapacityMode::Apps,
+            flush_mode: FlushMode::ContainerLimit,
+            ..Default::default()
+        });
+        let mut update = |app_id| {
+            store
+                .update_delta_store(&usage(app_id, None), &Duration::default())
+                .unwrap()
+        };
+        assert!(update("app_1") == DeltaStoreState::Ok);
+        assert!(update("app_1") == DeltaStoreState::Ok);
+        assert!(update("app_2") == DeltaStoreState::Flush);
+    }
+
+    #[test]
+    fn report_bytes_follow_encoded_report() {
+        let mut store = DeltaStore::new(DeltaStoreConfig {
+            capacity: u64::MAX,
+            capacity_mode: CapacityMode::ReportBytes,
+            flush_mode: FlushMode::ContainerLimit,
+            ..Default::default()
+        });
+        for app in 0..5 {
+            let app_id = format!("app_{}", app);
+            for _ in 0..=app * 4 {
+                store
+                    .update_delta_store(
+                        &usage_for("service", &app_id, &["hits", "other"]),
+                        &Duration::default(),
+                    )
+                    .unwrap();
+            }
+        }
+        // Transactions are measured alone, only their index in the report may differ.
+        let encoded = report_body_size("service_token", &store.deltas["service_token"]);
+        assert!(store.report_bytes > 0);
+        assert!((store.report_bytes as i64 - encoded as i64).abs() <= 5);
+
+        store.remove_app_delta(&usage_for("service", "app_4", &[]));
+        let encoded = report_body_size("service_token", &store.deltas["service_token"]);
+        assert!((store.report_bytes as i64 - encoded as i64).abs() <= 5);
+
+        store.flush();
+        assert_eq!(store.report_bytes, 0);
+    }
+
+    #[test]
+    fn services_are_due_by_latency_and_delta() {
+        let mut store = delta_store();
+        store.config.service_policies.insert(
+            "a".to_string(),
+            FlushPolicy {
+                max_latency: Duration::from_secs(5),
+                min_delta: 3,
+            },
+        );
+        let start = Duration::from_secs(100);
+        for service_id in ["a", "a", "b"].iter() {
+            store
+                .update_delta_store(&usage_for(service_id, "app", &["hits"]), &start)
+                .unwrap();
+        }
+        assert_eq!(store.last_update.get("a_token"), Some(&start));
+        assert!(store
+            .services_due(&(start + Duration::from_secs(5)))
+            .is_empty());
+
+        store
+            .update_delta_store(
+                &usage_for("a", "app", &["hits"]),
+                &(start + Duration::from_secs(1)),
+            )
+            .unwrap();
+        assert!(store
+            .services_due(&(start + Duration::from_secs(4)))
+            .is_empty());
+        let due = store.services_due(&(start + Duration::from_secs(5)));
+        assert_eq!(due, vec!["a_token".to_string()]);
 
-        let direct_alloc =
-            std::mem::size_of::<AppIdentifier>() + std::mem::size_of::<HashMap<String, u64>>();
-        // Transitive allocation of the metrics hashmap.
-        let transitive_alloc = threescale.metrics.borrow().len()
-            * (std::mem::size_of::<String>() + std::mem::size_of::<u64>());
-        // Total memory allocation as a summation of direct_alloc and transitive_alloc
-        direct_alloc + transitive_alloc
+        store.remove_service("a_token").unwrap();
+        assert!(store.last_update.get("a_token").is_none());
+        assert_eq!(store.entries.services, 1);
     }
 }
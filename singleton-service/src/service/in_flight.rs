@@ -0,0 +1,150 @@
+use crate::service::report::Report;
+use std::collections::HashMap;
+use std::time::Duration;
+use threescale::proxy::CacheKey;
+use threescale::structs::ServiceId;
+
+/** Reasoning behind tracking reported deltas:
+* Authorize responses only include the hits 3scale backend applied, and reports are applied some
+* time after they are acknowledged. Deltas of a report stay in flight from the moment it is sent
+* until an authorize call of the application is sent reflection_delay after the report was
+* acknowledged, and are taken from the left hits of the responses to earlier authorize calls.
+* Hits that were applied in time are counted twice until the next refresh, which keeps the cache on
+* the safe side. Reports failing are no longer in flight: either their deltas are back in the delta
+* store, or 3scale rejected them and will never apply them.
+**/
+
+// Deltas of a single report call for one application.
+struct InFlightReport {
+    token_id: u32,
+    deltas: HashMap<String, u64>,
+    // Time 3scale backend acknowledged the report.
+    acknowledged_at: Option<Duration>,
+}
+
+/// Deltas of applications reported to 3scale that its authorize responses might not include yet.
+#[derive(Default)]
+pub struct InFlightDeltas {
+    apps: HashMap<CacheKey, Vec<InFlightReport>>,
+}
+
+impl InFlightDeltas {
+    /// Tracks the deltas of every application of a report sent with the given call token.
+    pub fn add(&mut self, token_id: u32, report: &Report) {
+        let service_id = ServiceId::from(report.service_id());
+        for (app_id, usages) in report.usages() {
+            let deltas = usages
+                .iter()
+                .filter_map(|(metric, value)| Some((metric.clone(), value.parse::<u64>().ok()?)))
+                .collect();
+            self.apps
+                .entry(CacheKey::from(&service_id, app_id))
+                .or_insert_with(Vec::new)
+                .push(InFlightReport {
+                    token_id,
+                    deltas,
+                    acknowledged_at: None,
+                });
+        }
+    }
+
+    /// Records that 3scale acknowledged the report with the given call token at time now.
+    pub fn acknowledge(&mut self, token_id: u32, now: &Duration) {
+        for report in self.apps.values_mut().flatten() {
+            if report.token_id == token_id {
+                report.acknowledged_at = Some(*now);
+            }
+        }
+    }
+
+    /// Stops tracking the report with the given call token, which 3scale will never apply.
+    pub fn remove_report(&mut self, token_id: u32) {
+        for reports in self.apps.values_mut() {
+            reports.retain(|report| report.token_id != token_id);
+        }
+        self.apps.retain(|_, reports| !reports.is_empty());
+    }
+
+    pub fn remove_app(&mut self, cache_key: &CacheKey) {
+        self.apps.remove(cache_key);
+    }
+
+    /// Returns the deltas of the application, by metric, that the response to an authorize call
+    /// sent at auth_sent_at might not include. Reports acknowledged at least reflection_delay
+    /// before the call are included in its response and no longer tracked.
+    pub fn take_unreflected(
+        &mut self,
+        cache_key: &CacheKey,
+        auth_sent_at: &Duration,
+        reflection_delay: &Duration,
+    ) -> HashMap<String, u64> {
+        let mut unreflected = HashMap::new();
+        let reports = match self.apps.get_mut(cache_key) {
+            Some(reports) => reports,
+            None => return unreflected,
+        };
+        reports.retain(|report| match report.acknowledged_at {
+            Some(acknowledged_at) => acknowledged_at + *reflection_delay > *auth_sent_at,
+            None => true,
+        });
+        for report in reports.iter() {
+            for (metric, value) in report.deltas.iter() {
+                *unreflected.entry(metric.clone()).or_insert(0) += value;
+            }
+        }
+        if reports.is_empty() {
+            self.apps.remove(cache_key);
+        }
+        unreflected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::report::report;
+    use threescale::structs::{AppId, AppIdentifier};
+
+    fn sent_report(deltas: &[(&str, u64)]) -> Report {
+        let mut apps = HashMap::new();
+        apps.insert(
+            AppIdentifier::from(AppId::from("app")),
+            deltas
+                .iter()
+                .map(|(metric, value)| (metric.to_string(), *value))
+                .collect::<HashMap<_, _>>(),
+        );
+        report("service_token", &apps).unwrap()
+    }
+
+    #[test]
+    fn deltas_are_in_flight_until_reflected() {
+        let delay = Duration::from_secs(2);
+        let key = CacheKey::from(
+            &ServiceId::from("service"),
+            &AppIdentifier::from(AppId::from("app")),
+        );
+        let mut in_flight = InFlightDeltas::default();
+        in_flight.add(1, &sent_report(&[("hits", 5), ("orders", 2)]));
+        in_flight.add(2, &sent_report(&[("hits", 3)]));
+        in_flight.add(3, &sent_report(&[("hits", 7)]));
+        in_flight.remove_report(3);
+
+        let unreflected = in_flight.take_unreflected(&key, &Duration::from_secs(10), &delay);
+        assert_eq!(unreflected.get("hits"), Some(&8));
+        assert_eq!(unreflected.get("orders"), Some(&2));
+
+        in_flight.acknowledge(1, &Duration::from_secs(10));
+        in_flight.acknowledge(2, &Duration::from_secs(11));
+        // Authorize call sent too soon after the reports were acknowledged.
+        let unreflected = in_flight.take_unreflected(&key, &Duration::from_secs(11), &delay);
+        assert_eq!(unreflected.get("hits"), Some(&8));
+
+        let unreflected = in_flight.take_unreflected(&key, &Duration::from_secs(12), &delay);
+        assert_eq!(unreflected.get("hits"), Some(&3));
+        assert_eq!(unreflected.get("orders"), None);
+        let unreflected = in_flight.take_unreflected(&key, &Duration::from_secs(13), &delay);
+        assert!(unreflected.is_empty());
+        assert!(in_flight.apps.is_empty());
+    }
+}
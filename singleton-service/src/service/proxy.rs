@@ -3,32 +3,41 @@ use crate::configuration::service::ServiceConfig;
 use crate::service::{
     auth::*,
     deltas::{DeltaStore, DeltaStoreState},
+    in_flight::InFlightDeltas,
     report::*,
 };
 use anyhow::*;
-use log::{debug, info};
+use log::{debug, info, warn};
 use proxy_wasm::{
-    hostcalls::{dequeue_shared_queue, register_shared_queue, set_shared_data},
     traits::{Context, RootContext},
     types::LogLevel,
 };
-use std::collections::HashMap;
+use rand::RngCore;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::TryInto;
 use std::str::FromStr;
-use std::time::Duration;
+use std::time::{Duration, UNIX_EPOCH};
 use thiserror::Error;
 use threescale::{
+    auth_error::{record_auth_error, AuthorizeError},
+    backoff::Backoff,
+    breaker::{acquire_call, get_circuit, record_call_outcome, CallOutcome},
+    config::ConfigError,
+    encoding::update_service_hierarchy,
+    host::{Host, ProxyHost},
     proxy::{
         get_application_from_cache, remove_application_from_cache, set_application_to_cache,
         CacheKey, SHARED_MEMORY_COUNTER_KEY, SHARED_MEMORY_INITIAL_SIZE,
     },
+    rand::thread_rng::thread_rng_init,
+    refresh::{apply_unreported, ApplicationChanges},
     stats::*,
     structs::{
         AppId, AppIdentifier, AppKey, Application, Message, Period, PeriodWindow, ServiceId,
         ServiceToken, ThreescaleData, UsageReport,
     },
     upstream::*,
-    utils::{limit_check_and_update_application, UpdateMetricsError},
+    utils::{limit_check_and_update_application, LimitTolerance, UpdateMetricsError},
 };
 use threescalers::{http::Request, response::Authorization};
 // QUEUE_NAME should be the same as the one in cache filter.
@@ -36,6 +45,16 @@ const QUEUE_NAME: &str = "message_queue";
 const RATE_LIMIT_STATUS: &str = "409";
 const TIMEOUT_STATUS: &str = "504";
 
+// Upstream of 3scale backend, receiving both authorize and report calls.
+// TODO: read upstream from config when configuration parsing is re-implemented.
+fn backend_upstream() -> Upstream {
+    Upstream {
+        name: "outbound|443||su1.3scale.net".to_string(),
+        url: "https://su1.3scale.net".parse().unwrap(),
+        timeout: Duration::from_millis(5000),
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum SingletonServiceError {
     #[error("Error retrieving local cache entry for cache key: {0}")]
@@ -47,8 +66,8 @@ pub enum SingletonServiceError {
     #[error("Empty reports for authorize for app_id: {0}")]
     EmptyAuthUsages(String),
 
-    #[error("Authorize response error")]
-    AuthResponse,
+    #[error("Authorize call rejected: {0}")]
+    AuthRejected(AuthorizeError),
 
     #[error("Authorize response processing error")]
     AuthResponseProcess,
@@ -56,6 +75,9 @@ pub enum SingletonServiceError {
     #[error("Authorize response app_keys missing")]
     AuthAppKeysMissing,
 
+    #[error("Circuit of upstream {0} is open")]
+    CircuitOpen(String),
+
     #[error("Conversion from i64 time to u64 duration failed")]
     NegativeTimeErr,
 
@@ -63,43 +85,144 @@ pub enum SingletonServiceError {
     UpdateMetricsFail(String),
 }
 
-#[no_mangle]
+// Native builds such as unit tests come with an entry point of their own.
+#[cfg_attr(target_arch = "wasm32", no_mangle)]
 pub fn _start() {
     proxy_wasm::set_log_level(LogLevel::Info);
     proxy_wasm::set_root_context(|context_id| -> Box<dyn RootContext> {
-        Box::new(SingletonService {
-            context_id,
-            config: ServiceConfig::default(),
-            queue_id: None,
-            delta_store: DeltaStore {
-                last_update: None,
-                memory_allocated: 0,
-                deltas: HashMap::new(),
-                config: DeltaStoreConfig::default(),
-            },
-            cache_keys: HashMap::new(),
-            report_requests: HashMap::new(),
-            auth_requests: HashMap::new(),
-            stats: initialize_stats(),
-        })
+        // Thread RNG is seeded from the clock of the proxy, retries of the singleton are jittered
+        // with it.
+        let rng = thread_rng_init(&ProxyHost, context_id);
+        Box::new(SingletonService::new(context_id, ProxyHost, Box::new(rng)))
     });
 }
 
-struct SingletonService {
+/// Application seen through the message queue.
+struct TrackedApp {
+    service_token: ServiceToken,
+    // Time of the last request received for the application.
+    last_seen: Duration,
+    // Whether requests were received since the last delta store flush.
+    seen_since_flush: bool,
+    // Failures of the latest authorize calls, the application is not authorized again before
+    // its backoff is over.
+    auth_backoff: Backoff,
+    // Time the latest authorize call of the application was sent.
+    auth_sent_at: Option<Duration>,
+    // Time of the latest early refresh of the application.
+    last_early_refresh: Option<Duration>,
+}
+
+struct SingletonService<H: Host = ProxyHost> {
+    host: H,
     context_id: u32,
     config: ServiceConfig,
     queue_id: Option<u32>,
     delta_store: DeltaStore,
-    cache_keys: HashMap<CacheKey, ServiceToken>,
+    cache_keys: HashMap<CacheKey, TrackedApp>,
     report_requests: HashMap<u32, Report>,
+    // Deltas reported to 3scale that authorize responses might not include yet.
+    in_flight: InFlightDeltas,
     auth_requests: HashMap<u32, CacheKey>,
+    // Generation of the circuit of the upstream each call in flight was let through in.
+    call_generations: HashMap<u32, u32>,
+    // Applications waiting for an authorize call to be sent, in order of request.
+    auth_queue: VecDeque<CacheKey>,
+    // Applications either waiting in auth_queue or with an authorize call in flight.
+    pending_auths: HashSet<CacheKey>,
+    // Authorize calls that can still be sent in the current dispatch round.
+    auth_budget: usize,
+    // Time of the last delta store flush.
+    last_flush: Option<Duration>,
+    // Failures of the latest report calls, flushes are postponed until the backoff is over.
+    report_backoff: Backoff,
+    // Source of the jitter of backoffs.
+    rng: Box<dyn RngCore>,
     stats: ThreescaleStats,
 }
 
-impl RootContext for SingletonService {
+impl<H: Host> SingletonService<H> {
+    fn new(context_id: u32, host: H, rng: Box<dyn RngCore>) -> Self {
+        let stats = initialize_stats(&host);
+        let config = ServiceConfig::default();
+        SingletonService {
+            host,
+            context_id,
+            auth_budget: config.auth_dispatch.calls_per_round(),
+            config,
+            queue_id: None,
+            delta_store: DeltaStore::new(DeltaStoreConfig::default()),
+            cache_keys: HashMap::new(),
+            report_requests: HashMap::new(),
+            in_flight: InFlightDeltas::default(),
+            auth_requests: HashMap::new(),
+            call_generations: HashMap::new(),
+            auth_queue: VecDeque::new(),
+            pending_auths: HashSet::new(),
+            last_flush: None,
+            report_backoff: Backoff::default(),
+            rng,
+            stats,
+        }
+    }
+
+    /// Applies a new configuration, returning the changes made to the configuration in use.
+    /// Deltas, tracked applications and the cache are kept, every change is applied live.
+    fn apply_configuration(&mut self, configuration: &[u8]) -> Result<Vec<String>, ConfigError> {
+        let config = ServiceConfig::parse(configuration)?;
+        let changes = self.config.changes(&config);
+        self.config = config;
+        self.delta_store
+            .set_config(self.config.delta_store_config.clone());
+        self.auth_budget = std::cmp::min(
+            self.auth_budget,
+            self.config.auth_dispatch.calls_per_round(),
+        );
+        Ok(changes)
+    }
+
+    // Ticks drive delta store flushes, flushes of single services and rounds of authorize calls.
+    fn tick_period(&self) -> Duration {
+        let delta_store_config = &self.config.delta_store_config;
+        delta_store_config
+            .service_policies
+            .values()
+            .map(|policy| policy.max_latency)
+            .filter(|max_latency| *max_latency > Duration::default())
+            .fold(
+                std::cmp::min(
+                    delta_store_config.periodical_flush,
+                    self.config.auth_dispatch.dispatch_interval,
+                ),
+                std::cmp::min,
+            )
+    }
+
+    fn update_tick_period(&self) {
+        if let Err(e) = self.host.set_tick_period(self.tick_period()) {
+            warn!("setting tick period failed: {:?}", e);
+        }
+    }
+
+    /// Reports the deltas of the services whose flush policies require it, ahead of the flush
+    /// of the whole delta store. Services wait in the delta store while reports are backing off.
+    fn flush_due_services(&mut self, now: &Duration) {
+        if !self.report_backoff.is_due(now) {
+            return;
+        }
+        for key in self.delta_store.services_due(now) {
+            if let Some(apps) = self.delta_store.remove_service(&key) {
+                info!("Flushing service {} as required by its flush policy", key);
+                self.send_report(&key, &apps);
+            }
+        }
+    }
+}
+
+impl<H: Host> RootContext for SingletonService<H> {
     // Message queue will get registered when on_vm_start callback gets called.
     fn on_vm_start(&mut self, _vm_configuration_size: usize) -> bool {
-        match register_shared_queue(QUEUE_NAME) {
+        match self.host.register_shared_queue(QUEUE_NAME) {
             Ok(q_id) => self.queue_id = Some(q_id),
             Err(e) => {
                 debug!("registering MQ failed: {:?}", e);
@@ -113,7 +236,7 @@ impl RootContext for SingletonService {
         );
 
         // Initialize shared memory counter with anything that's not significant.
-        if let Err(e) = set_shared_data(
+        if let Err(e) = self.host.set_shared_data(
             SHARED_MEMORY_COUNTER_KEY,
             Some(&SHARED_MEMORY_INITIAL_SIZE.to_be_bytes()),
             None,
@@ -124,12 +247,13 @@ impl RootContext for SingletonService {
         true
     }
 
-    /// Configuration passed by envoy.yaml will get deserialized to ServiceConfig. If there's an issue with the
-    /// passed configuration, default configuration will be used.
+    /// Configuration passed by envoy.yaml will get deserialized to ServiceConfig. Invalid
+    /// configurations are rejected and the configuration in use is kept. If there's no
+    /// configuration passed, default configuration will be used.
     fn on_configure(&mut self, _config_size: usize) -> bool {
         // Check for the configuration passed by envoy.yaml
-        self.set_tick_period(Duration::from_secs(5));
-        let configuration: Vec<u8> = match self.get_configuration() {
+        self.update_tick_period();
+        let configuration: Vec<u8> = match self.host.get_configuration() {
             Some(c) => c,
             None => {
                 info!("Configuration missing. Please check the envoy.yaml file for filter configuration.
@@ -138,21 +262,19 @@ impl RootContext for SingletonService {
             }
         };
 
-        // Parse and store the configuration passed by envoy.yaml
-        match serde_json::from_slice::<ServiceConfig>(configuration.as_ref()) {
-            Ok(config) => {
-                debug!("configuring {}: {:?}", self.context_id, config);
-                self.config = config;
-                self.set_tick_period(self.config.delta_store_config.periodical_flush);
-                self.delta_store.config = self.config.delta_store_config.clone();
+        // Parse and apply the configuration passed by envoy.yaml
+        match self.apply_configuration(configuration.as_ref()) {
+            Ok(changes) => {
+                debug!("configuring {}: {:?}", self.context_id, self.config);
+                for change in changes {
+                    info!("Configuration changed: {}", change);
+                }
+                self.update_tick_period();
                 true
             }
             Err(e) => {
-                info!(
-                    "Failed to parse envoy.yaml configuration: {:?}. Using default configuration.",
-                    e
-                );
-                true
+                warn!("Rejected envoy.yaml configuration: {}", e);
+                false
             }
         }
     }
@@ -162,7 +284,7 @@ impl RootContext for SingletonService {
     ///     * If local cache update is required, update metrics and perform local cache update.
     ///     * Add the entry to delta store.
     fn on_queue_ready(&mut self, _queue_id: u32) {
-        match dequeue_shared_queue(self.queue_id.unwrap()) {
+        match self.host.dequeue_shared_queue(self.queue_id.unwrap()) {
             Ok(queue_entry) => {
                 match queue_entry {
                     Some(message) => {
@@ -180,13 +302,27 @@ impl RootContext for SingletonService {
                                 .unwrap();
                         }
                         // TODO : Handle delta store update failure.
-                        self.cache_keys
+                        let tracked_app = self
+                            .cache_keys
                             .entry(CacheKey::from(&threescale.service_id, &threescale.app_id))
-                            .or_insert_with(|| threescale.service_token.clone());
-                        let delta_store_state =
-                            self.delta_store.update_delta_store(&threescale).unwrap();
+                            .or_insert_with(|| TrackedApp {
+                                service_token: threescale.service_token.clone(),
+                                last_seen: req_time,
+                                seen_since_flush: true,
+                                auth_backoff: Backoff::default(),
+                                auth_sent_at: None,
+                                last_early_refresh: None,
+                            });
+                        tracked_app.last_seen = std::cmp::max(tracked_app.last_seen, req_time);
+                        tracked_app.seen_since_flush = true;
+                        let delta_store_state = self
+                            .delta_store
+                            .update_delta_store(&threescale, &req_time)
+                            .unwrap();
                         if delta_store_state == DeltaStoreState::Flush {
                             self.flush_local_cache();
+                        } else {
+                            self.refresh_application_if_required(&threescale);
                         }
                     }
                     None => {
@@ -202,25 +338,38 @@ impl RootContext for SingletonService {
     }
 
     /// Delta store flush is required in case of a low traffic where it takes a long time to fill the delta store
-    /// container.
+    /// container. Every tick also starts a new round of authorize calls.
     // TODO: Consider requirements of a dynamic tick and timestamp based flush if it makes a significant
     // improvement.
     fn on_tick(&mut self) {
         info!(
             "onTick triggerd. Current tick duration: {:?}",
-            self.config.delta_store_config.periodical_flush
+            self.tick_period()
         );
+        self.auth_budget = self.config.auth_dispatch.calls_per_round();
+        let now = self
+            .host
+            .get_current_time()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let flush_due = self.last_flush.map_or(true, |last_flush| {
+            now.checked_sub(last_flush).unwrap_or_default()
+                >= self.delta_store.config.periodical_flush
+        });
         // Perform cache update based on the cache flush type defined by the user. For Default,
         // other than the container limit this will trigger the cache update.
         // For periodical only this onTick method will trigger the cache update.
         // For ContainerLimit, no effect here.
-        if self.delta_store.config.flush_mode != FlushMode::ContainerLimit {
+        if self.delta_store.config.flush_mode != FlushMode::ContainerLimit && flush_due {
             self.flush_local_cache()
+        } else {
+            self.flush_due_services(&now);
         }
+        self.dispatch_auths();
     }
 }
 
-impl Context for SingletonService {
+impl<H: Host> Context for SingletonService<H> {
     fn on_http_call_response(
         &mut self,
         token_id: u32,
@@ -229,50 +378,52 @@ impl Context for SingletonService {
         _num_trailers: usize,
     ) {
         info!("3scale SM API response for call token :{}", token_id);
-        let headers = self.get_http_call_response_headers();
+        let headers = self.host.get_http_call_response_headers();
         let status = headers
             .iter()
             .find(|(key, _)| key.as_str() == ":status")
             .map(|(_, value)| value)
             .unwrap();
+        let outcome = CallOutcome::from_status(status);
+        let generation = self.call_generations.remove(&token_id).unwrap_or_default();
+        self.record_call_outcome(outcome, generation);
+        self.record_auth_outcome(token_id, outcome);
         if status != TIMEOUT_STATUS {
-            match self.get_http_call_response_body(0, body_size) {
+            // Failed reports come with a body as well.
+            if self.report_requests.contains_key(&token_id) {
+                info!("Report response");
+                self.handle_report_response(status, &token_id);
+                return;
+            }
+            match self.host.get_http_call_response_body(0, body_size) {
                 Some(bytes) => {
                     info!("Auth response");
-                    // If the response for the authorize request is 404 (app not found),
-                    // delete the application from the cache.
-                    if let Err(SingletonServiceError::AuthResponse) =
-                        self.handle_auth_response(bytes, status)
-                    {
-                        self.handle_auth_failure(token_id);
-                    } else {
-                        self.auth_requests.remove(&token_id);
-                    }
-                }
-                None => {
-                    if self.report_requests.contains_key(&token_id) {
-                        info!("Report response");
-                        self.handle_report_response(status, &token_id);
+                    match self.handle_auth_response(bytes, status) {
+                        Err(SingletonServiceError::AuthRejected(error)) => {
+                            self.handle_auth_rejection(token_id, &error)
+                        }
+                        _ => self.complete_auth(token_id),
                     }
                 }
+                None => self.complete_auth(token_id),
             }
         } else {
             info!(
                 "HTTP request timeout for request with token_id: {}",
                 token_id
             );
-            increment_stat(&self.stats.authorize_timeouts);
+            increment_stat(&self.host, &self.stats.authorize_timeouts);
             // Clear the context_id mapping hashmap.
             if self.report_requests.contains_key(&token_id) {
                 self.handle_report_response(status, &token_id);
             } else if self.auth_requests.contains_key(&token_id) {
-                self.auth_requests.remove(&token_id);
+                self.complete_auth(token_id);
             }
         }
     }
 }
 
-impl SingletonService {
+impl<H: Host> SingletonService<H> {
     /// update_application_cache method updates the local application cache if the cache update
     /// fails from the cache filter for a particular request.
     fn update_application_cache(
@@ -281,13 +432,16 @@ impl SingletonService {
         req_time: &Duration,
     ) -> Result<(), anyhow::Error> {
         let cache_key = CacheKey::from(&threescale.service_id, &threescale.app_id);
-        match get_application_from_cache(&cache_key) {
+        match get_application_from_cache(&self.host, &cache_key) {
             Ok((mut application, cas)) => {
+                // Request was already admitted by the filter, only its usage is accounted here.
                 match limit_check_and_update_application(
+                    &self.host,
                     threescale,
                     &mut application,
                     cas,
                     req_time,
+                    &LimitTolerance::default(),
                 ) {
                     Ok(_) => Ok(()),
                     Err(UpdateMetricsError::CacheUpdateFail(reason)) => {
@@ -319,13 +473,29 @@ impl SingletonService {
 
     /// This is a helper method to send http requests. Both Report and Auth calls will use this method to
     /// send http requests after building relevant threescalers request type.
+    /// Calls are not sent while the circuit of the upstream is open, except for its probe call.
     /// TODO : Handle http callout failure from proxy side.
-    fn perform_http_call(&self, request: &Request) -> Result<u32, anyhow::Error> {
-        // TODO: read upstream from config when configuration parsing is re-implemented.
-        let upstream = Upstream {
-            name: "outbound|443||su1.3scale.net".to_string(),
-            url: "https://su1.3scale.net".parse().unwrap(),
-            timeout: Duration::from_millis(5000),
+    fn perform_http_call(&mut self, request: &Request) -> Result<u32, anyhow::Error> {
+        let upstream = backend_upstream();
+        let now = self
+            .host
+            .get_current_time()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let generation = match acquire_call(
+            &self.host,
+            upstream.name(),
+            &self.config.circuit_breaker,
+            &now,
+        ) {
+            Ok(Some(generation)) => generation,
+            Ok(None) => anyhow::bail!(SingletonServiceError::CircuitOpen(
+                upstream.name().to_string()
+            )),
+            Err(e) => {
+                warn!("failed to check circuit of {}: {}", upstream.name(), e);
+                0
+            }
         };
         let (uri, body) = request.uri_and_body();
         let headers = request
@@ -333,86 +503,390 @@ impl SingletonService {
             .iter()
             .map(|(key, value)| (key.as_str(), value.as_str()))
             .collect::<Vec<_>>();
-        let call_token = upstream.call(
-            self,
+        let call_token = match upstream.call(
+            &self.host,
             uri.as_ref(),
             request.method.as_str(),
             headers,
             body.map(str::as_bytes),
             None,
             None,
-        )?;
+        ) {
+            Ok(call_token) => call_token,
+            Err(e) => {
+                self.record_call_outcome(CallOutcome::Failure, generation);
+                return Err(e);
+            }
+        };
+        self.call_generations.insert(call_token, generation);
         info!("http call performed. call token : {}", call_token);
         Ok(call_token)
     }
 
-    /// This method flush the deltas in the deltastore by making a clone and then
-    /// by emptying the deltastore hashmap.  
+    /// This method flush the deltas in the deltastore by taking them out of the deltastore.
     fn flush_delta_store(
         &mut self,
     ) -> HashMap<String, HashMap<AppIdentifier, HashMap<String, u64>>> {
-        let deltas_cloned = self.delta_store.deltas.clone();
-        self.delta_store.deltas.clear();
-        self.delta_store.memory_allocated = 0;
+        let deltas = self.delta_store.flush();
         assert!(self.delta_store.deltas.is_empty());
-        deltas_cloned
+        deltas
     }
 
     /// This method will flush the local cache to the 3scale SM API by sending a report call per each service.
-    /// This method uses flush_delta_store(), build_report_request() and perform_http_call() helper methods to
+    /// This method uses flush_delta_store(), build_report_requests() and perform_http_call() helper methods to
     /// flush local cache. This will be called when delta store is full or when timer based cache flush is required.
+    /// Deltas are kept in the delta store while reports are backing off after failed calls, the
+    /// flush is tried again with the following ticks.
     fn flush_local_cache(&mut self) {
+        let now = self
+            .host
+            .get_current_time()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        if !self.report_backoff.is_due(&now) {
+            info!(
+                "Postponing flush, reports are backing off after {} failures",
+                self.report_backoff.failures()
+            );
+            return;
+        }
+        self.last_flush = Some(now);
         let deltas = self.flush_delta_store();
-        let mut auth_keys = HashMap::new();
         for (key, apps) in deltas {
-            let report: Report = report(&key, &apps).unwrap();
-            let request = build_report_request(&report).unwrap();
-            let app_keys = apps.keys().cloned().collect::<Vec<_>>();
-            auth_keys.insert(key, app_keys);
-            info!("report : {:?}", report);
+            self.send_report(&key, &apps);
+        }
+        self.update_local_cache();
+        self.dispatch_auths();
+    }
+
+    /// Sends the deltas of the applications of a single service, split in as many report
+    /// requests as required by the limits of 3scale backend. Each chunk is tracked separately.
+    fn send_report(&mut self, key: &str, apps: &HashMap<AppIdentifier, HashMap<String, u64>>) {
+        let service_id = ServiceId::from(key.split('_').next().unwrap_or_default());
+        let apps = apps
+            .iter()
+            .map(|(app_id, deltas)| (self.validate_app_key(&service_id, app_id), deltas.clone()))
+            .collect::<HashMap<_, _>>();
+        let report: Report = report(key, &apps).unwrap();
+        info!("report : {:?}", report);
+        let chunks = match build_report_requests(report, &self.config.report) {
+            Ok(chunks) => chunks,
+            Err(err) => {
+                info!("Error creating report requests: {}", err);
+                return;
+            }
+        };
+        for (chunk, request) in chunks {
             // TODO: Handle http local failure
             match self.perform_http_call(&request) {
                 Ok(token_id) => {
-                    self.report_requests.insert(token_id, report);
+                    self.in_flight.add(token_id, &chunk);
+                    self.report_requests.insert(token_id, chunk);
                 }
                 Err(err) => {
                     info!("Report call local failure: {}", err);
+                    self.restore_report_deltas(&chunk);
                 }
             }
         }
-        self.update_local_cache();
     }
 
-    /// Update the local cache by sending authorize requests to 3scale SM API.
+    /// Returns the identifier to report the application with. The app key is only kept if it is
+    /// one of the keys of the cached application, otherwise the application is reported by its
+    /// app_id alone, so that a report is never rejected for a key that was not validated.
+    fn validate_app_key(&self, service_id: &ServiceId, app_id: &AppIdentifier) -> AppIdentifier {
+        let (id, key) = match app_id {
+            AppIdentifier::AppId(id, Some(key)) => (id, key),
+            _ => return app_id.clone(),
+        };
+        match get_application_from_cache(&self.host, &CacheKey::from(service_id, app_id)) {
+            Ok((app, _)) if app.app_keys.iter().flatten().any(|k| k == key) => app_id.clone(),
+            _ => {
+                debug!("Reporting application {} without app key", id.as_ref());
+                AppIdentifier::from(id.clone())
+            }
+        }
+    }
+
+    /// Update the local cache by sending authorize requests to 3scale SM API. Only applications
+    /// that received requests since the last flush or whose period windows end before the next
+    /// flush are re-authorized, once their authorize calls are not backing off. Applications idle for longer than app_idle_timeout are evicted,
+    /// unless 3scale backend is unhealthy since they could not be fetched again.
     fn update_local_cache(&mut self) {
-        for (cache_key, service_token) in self.cache_keys.iter() {
-            if let Ok(auth_request) = auth(
-                cache_key.service_id().as_ref().to_string(),
-                service_token.as_ref().to_string(),
-                cache_key.app_id().clone(),
-            )
-            .and_then(|auth_app| build_auth_request(&auth_app))
+        let now = self
+            .host
+            .get_current_time()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let backend_unhealthy = self.is_backend_unhealthy();
+        let mut refresh_keys = Vec::new();
+        let mut idle_keys = Vec::new();
+        for (cache_key, tracked_app) in self.cache_keys.iter() {
+            if !backend_unhealthy
+                && now.checked_sub(tracked_app.last_seen).unwrap_or_default()
+                    >= self.config.app_idle_timeout
             {
-                // TODO : Handle local failure.
-                match self.perform_http_call(&auth_request) {
-                    Ok(token_id) => {
-                        self.auth_requests.insert(token_id, cache_key.clone());
-                    }
-                    Err(err) => {
-                        info!("Auth call local failure: {}", err);
+                idle_keys.push(cache_key.clone());
+            } else if !tracked_app.auth_backoff.is_due(&now) {
+                // Requests seen meanwhile are kept for the refresh after the backoff.
+                continue;
+            } else if tracked_app.seen_since_flush || self.is_window_ending(cache_key, &now) {
+                refresh_keys.push(cache_key.clone());
+            }
+        }
+        for tracked_app in self.cache_keys.values_mut() {
+            if tracked_app.auth_backoff.is_due(&now) {
+                tracked_app.seen_since_flush = false;
+            }
+        }
+        for cache_key in refresh_keys {
+            self.request_auth(cache_key);
+        }
+        for cache_key in idle_keys {
+            self.evict_application(&cache_key);
+        }
+    }
+
+    /// Returns true if any period window of the cached application ends before the next flush.
+    fn is_window_ending(&self, cache_key: &CacheKey, now: &Duration) -> bool {
+        match get_application_from_cache(&self.host, cache_key) {
+            Ok((app, _)) => app.local_state.values().any(|usage| {
+                usage.period_window.window != Period::Eternity
+                    && usage.period_window.end <= *now + self.delta_store.config.periodical_flush
+            }),
+            Err(_) => false,
+        }
+    }
+
+    /// Records the result of a call to 3scale backend in the circuit of its upstream. Timeouts and
+    /// server errors count as failed calls while any other response means the backend is reachable.
+    fn record_call_outcome(&self, outcome: CallOutcome, generation: u32) {
+        let now = self
+            .host
+            .get_current_time()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let upstream = backend_upstream();
+        if let Err(e) = record_call_outcome(
+            &self.host,
+            upstream.name(),
+            &self.config.circuit_breaker,
+            outcome,
+            generation,
+            &now,
+        ) {
+            warn!(
+                "failed to record outcome of call to {}: {}",
+                upstream.name(),
+                e
+            );
+        }
+    }
+
+    /// Records the outcome of an authorize call in the backoff of its application. Failed calls
+    /// delay the next authorize call of the application, a successful one lifts the backoff.
+    fn record_auth_outcome(&mut self, token_id: u32, outcome: CallOutcome) {
+        let now = self
+            .host
+            .get_current_time()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let tracked_app = match self
+            .auth_requests
+            .get(&token_id)
+            .and_then(|cache_key| self.cache_keys.get_mut(cache_key))
+        {
+            Some(tracked_app) => tracked_app,
+            None => return,
+        };
+        if outcome == CallOutcome::Success {
+            tracked_app.auth_backoff.record_success();
+            return;
+        }
+        let delay = tracked_app.auth_backoff.record_failure(
+            &self.config.auth_dispatch.retry_backoff,
+            &now,
+            self.rng.as_mut(),
+        );
+        info!(
+            "Authorize call {} failed, application is authorized again in {:?}",
+            token_id, delay
+        );
+    }
+
+    /// 3scale backend is unhealthy while the circuit of its upstream is not closed.
+    fn is_backend_unhealthy(&self) -> bool {
+        let upstream = backend_upstream();
+        match get_circuit(&self.host, upstream.name()) {
+            Ok((circuit, _)) => circuit.is_unhealthy(),
+            Err(e) => {
+                warn!("failed to read circuit of {}: {}", upstream.name(), e);
+                false
+            }
+        }
+    }
+
+    /// Stops tracking an idle application and removes it from the cache, the next request for
+    /// it will fetch its state again.
+    fn evict_application(&mut self, cache_key: &CacheKey) {
+        // Response would store the application again.
+        if self.pending_auths.contains(cache_key) {
+            return;
+        }
+        info!("Evicting idle application with key: {:?}", cache_key);
+        self.cache_keys.remove(cache_key);
+        self.in_flight.remove_app(cache_key);
+        if get_application_from_cache(&self.host, cache_key).is_ok() {
+            remove_application_from_cache(&self.host, &cache_key.as_string());
+            decrement_stat(&self.host, &self.stats.cached_apps);
+        }
+    }
+
+    /// Queues an authorize call for the application unless one is already pending or its
+    /// authorize calls are backing off after failures.
+    fn request_auth(&mut self, cache_key: CacheKey) {
+        let now = self
+            .host
+            .get_current_time()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        if matches!(self.cache_keys.get(&cache_key), Some(app) if !app.auth_backoff.is_due(&now)) {
+            return;
+        }
+        if self.pending_auths.insert(cache_key.clone()) {
+            self.auth_queue.push_back(cache_key);
+        }
+    }
+
+    /// Sends queued authorize calls as long as the budget of the current round and the limit of
+    /// calls in flight allow it, so that a flush with many applications doesn't flood 3scale.
+    fn dispatch_auths(&mut self) {
+        let now = self
+            .host
+            .get_current_time()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        while self.auth_budget > 0
+            && self.auth_requests.len() < self.config.auth_dispatch.max_in_flight
+        {
+            let cache_key = match self.auth_queue.pop_front() {
+                Some(cache_key) => cache_key,
+                None => break,
+            };
+            // Application might have been evicted while waiting.
+            let token_id = match self.cache_keys.get(&cache_key) {
+                Some(tracked_app) => {
+                    let service_token = tracked_app.service_token.clone();
+                    self.send_auth(&cache_key, &service_token)
+                }
+                None => None,
+            };
+            match token_id {
+                Some(token_id) => {
+                    self.auth_budget -= 1;
+                    if let Some(tracked_app) = self.cache_keys.get_mut(&cache_key) {
+                        tracked_app.auth_sent_at = Some(now);
                     }
+                    self.auth_requests.insert(token_id, cache_key);
+                }
+                None => {
+                    self.pending_auths.remove(&cache_key);
                 }
-            } else {
-                // TODO : Handle threescalers auth request creation.
-                info!("Error creating Auth request")
             }
         }
     }
 
+    /// Forgets about an authorize call after its response or timeout, making room for queued ones.
+    fn complete_auth(&mut self, token_id: u32) {
+        if let Some(cache_key) = self.auth_requests.remove(&token_id) {
+            self.pending_auths.remove(&cache_key);
+            self.dispatch_auths();
+        }
+    }
+
+    /// Sends an authorize request for a single application and returns the call token.
+    fn send_auth(&mut self, cache_key: &CacheKey, service_token: &ServiceToken) -> Option<u32> {
+        if let Ok(auth_request) = auth(
+            cache_key.service_id().as_ref().to_string(),
+            service_token.as_ref().to_string(),
+            cache_key.app_id().clone(),
+        )
+        .and_then(|auth_app| build_auth_request(&auth_app))
+        {
+            // TODO : Handle local failure.
+            match self.perform_http_call(&auth_request) {
+                Ok(token_id) => Some(token_id),
+                Err(err) => {
+                    info!("Auth call local failure: {}", err);
+                    None
+                }
+            }
+        } else {
+            // TODO : Handle threescalers auth request creation.
+            info!("Error creating Auth request");
+            None
+        }
+    }
+
+    /// Reports the deltas of the application and re-authorizes it ahead of the next flush if
+    /// its cached quota is running low or one of its period windows is about to end, so that
+    /// hot applications converge with 3scale without waiting for the delta store flush.
+    fn refresh_application_if_required(&mut self, threescale: &ThreescaleData) {
+        let cache_key = CacheKey::from(&threescale.service_id, &threescale.app_id);
+        // Application state is already on its way.
+        if self.pending_auths.contains(&cache_key) {
+            return;
+        }
+        let now = match self.host.get_current_time().duration_since(UNIX_EPOCH) {
+            Ok(now) => now,
+            Err(_) => return,
+        };
+        let min_interval = self.config.early_refresh.min_interval;
+        let refreshed_recently = self
+            .cache_keys
+            .get(&cache_key)
+            .and_then(|tracked_app| tracked_app.last_early_refresh)
+            .map_or(false, |last| {
+                now.checked_sub(last).unwrap_or_default() < min_interval
+            });
+        if refreshed_recently {
+            return;
+        }
+        let app = match get_application_from_cache(&self.host, &cache_key) {
+            Ok((app, _)) => app,
+            Err(_) => return,
+        };
+        if !self.config.early_refresh.is_refresh_required(&app, &now) {
+            return;
+        }
+        info!("early refresh of application with key: {:?}", cache_key);
+        if let Some(tracked_app) = self.cache_keys.get_mut(&cache_key) {
+            tracked_app.last_early_refresh = Some(now);
+        }
+        // Deltas wait for the next flush while reports are backing off.
+        let app_delta = if self.report_backoff.is_due(&now) {
+            self.delta_store.remove_app_delta(threescale)
+        } else {
+            None
+        };
+        if let Some(app_delta) = app_delta {
+            let mut apps = HashMap::new();
+            apps.insert(threescale.app_id.clone(), app_delta);
+            let key = format!(
+                "{}_{}",
+                threescale.service_id.as_ref(),
+                threescale.service_token.as_ref()
+            );
+            self.send_report(&key, &apps);
+        }
+        self.request_auth(cache_key);
+        self.dispatch_auths();
+    }
+
     /// Handle Authorize response received from the 3scale SM API. Depending on the response,
     /// several operations will be performed like cache update.
     fn handle_auth_response(
-        &self,
+        &mut self,
         response: Vec<u8>,
         status: &str,
     ) -> Result<(), SingletonServiceError> {
@@ -452,8 +926,11 @@ impl SingletonService {
                                     ),
                                     window: Period::from(&usage.period),
                                 },
-                                left_hits: usage.max_value - usage.current_value,
+                                left_hits: usage.max_value.saturating_sub(usage.current_value),
                                 max_value: usage.max_value,
+                                synced_left_hits: usage
+                                    .max_value
+                                    .saturating_sub(usage.current_value),
                             },
                         );
                     }
@@ -464,7 +941,29 @@ impl SingletonService {
                         }
                     }
 
-                    let app;
+                    let now = self
+                        .host
+                        .get_current_time()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default();
+                    let hierarchy = match update_service_hierarchy(
+                        &self.host,
+                        &service_id,
+                        &hierarchy,
+                        &now,
+                        &self.config.hierarchy_refresh,
+                    ) {
+                        Ok(service_hierarchy) => service_hierarchy,
+                        Err(e) => {
+                            warn!(
+                                "updating hierarchy of service {} failed: {}",
+                                service_id.as_ref(),
+                                e
+                            );
+                            hierarchy
+                        }
+                    };
+                    let mut app;
                     if let Some(app_keys) = data.app_keys() {
                         let keys = app_keys
                             .keys()
@@ -477,6 +976,8 @@ impl SingletonService {
                             local_state: new_app_state,
                             metric_hierarchy: hierarchy,
                             app_keys: Some(keys),
+                            synced_at: now,
+                            plan: Some(data.plan().to_string()),
                         };
                     } else {
                         app = Application {
@@ -485,11 +986,16 @@ impl SingletonService {
                             local_state: new_app_state,
                             metric_hierarchy: hierarchy,
                             app_keys: None,
+                            synced_at: now,
+                            plan: Some(data.plan().to_string()),
                         };
                     }
 
+                    let cache_key = CacheKey::from(&service_id, &app_id);
+                    self.merge_refreshed_application(&cache_key, &mut app);
                     match set_application_to_cache(
-                        CacheKey::from(&service_id, &app_id).as_string().as_ref(),
+                        &self.host,
+                        cache_key.as_string().as_ref(),
                         &app,
                         0,
                     ) {
@@ -500,12 +1006,17 @@ impl SingletonService {
                         )),
                     }
                 } else {
-                    Err(SingletonServiceError::AuthResponse)
+                    let reason = data.reason().unwrap_or_default();
+                    Err(SingletonServiceError::AuthRejected(
+                        AuthorizeError::from_reason(reason),
+                    ))
                 }
             }
             Ok(Authorization::Error(error)) => {
                 info!("auth error response: {:?}", error);
-                Err(SingletonServiceError::AuthResponse)
+                Err(SingletonServiceError::AuthRejected(
+                    AuthorizeError::from_code(error.code()),
+                ))
             }
             Err(e) => {
                 info!("error processing auth response: {:?}", e);
@@ -514,22 +1025,832 @@ impl SingletonService {
         }
     }
 
+    /// Takes the hits of the application that the state fetched from 3scale doesn't include yet
+    /// from its left hits: those waiting in the delta store and those reported but possibly not
+    /// applied by 3scale when the authorize call was sent. Changes of the plan, limits or metrics
+    /// of the application since it was cached are logged and counted.
+    fn merge_refreshed_application(&mut self, cache_key: &CacheKey, app: &mut Application) {
+        if let Ok((cached, _)) = get_application_from_cache(&self.host, cache_key) {
+            let changes = ApplicationChanges::between(&cached, app);
+            if !changes.is_empty() {
+                info!("application {} changed: {}", cache_key.as_string(), changes);
+                increment_stat(&self.host, &self.stats.app_changes);
+            }
+        }
+        let unreported = self.cache_keys.get(cache_key).and_then(|tracked_app| {
+            self.delta_store
+                .app_delta(&app.service_id, &tracked_app.service_token, &app.app_id)
+        });
+        if let Some(unreported) = unreported {
+            apply_unreported(app, unreported);
+        }
+        let auth_sent_at = self
+            .cache_keys
+            .get(cache_key)
+            .and_then(|tracked_app| tracked_app.auth_sent_at)
+            .unwrap_or_default();
+        let unreflected = self.in_flight.take_unreflected(
+            cache_key,
+            &auth_sent_at,
+            &self.config.report.reflection_delay,
+        );
+        apply_unreported(app, &unreflected);
+    }
+
     /// Handle Report response received from the 3scale SM API. Depending on the response received
     /// several operations will take place.
+    /// Deltas of chunks that failed due to a timeout or a server error are put back into the
+    /// delta store so that they get reported with the next flush. Other failures are not retried
+    /// since sending the same report again would fail the same way.
     fn handle_report_response(&mut self, status: &str, token_id: &u32) {
-        // TODO : Handle report failure.
         info!("Report status : {} {}", status, token_id);
-        self.report_requests.remove(token_id);
+        let report = match self.report_requests.remove(token_id) {
+            Some(report) => report,
+            None => return,
+        };
+        let now = self
+            .host
+            .get_current_time()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        if status != TIMEOUT_STATUS && !status.starts_with('5') {
+            self.report_backoff.record_success();
+            if status.starts_with('2') {
+                self.in_flight.acknowledge(*token_id, &now);
+            } else {
+                self.in_flight.remove_report(*token_id);
+            }
+            return;
+        }
+        self.in_flight.remove_report(*token_id);
+        let delay = self.report_backoff.record_failure(
+            &self.config.report.retry_backoff,
+            &now,
+            self.rng.as_mut(),
+        );
+        info!("Retrying report with token {} in {:?}", token_id, delay);
+        self.restore_report_deltas(&report);
     }
 
-    /// Handle authorize failure in case of 404(app not found) response from the 3scale SM API.
+    /// Puts the deltas of a report that could not be sent back into the delta store, so that
+    /// they get reported with the next flush.
+    fn restore_report_deltas(&mut self, report: &Report) {
+        let now = self
+            .host
+            .get_current_time()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        for (app_id, usages) in report.usages() {
+            let metrics = usages
+                .iter()
+                .filter_map(|(metric, value)| Some((metric.clone(), value.parse::<u64>().ok()?)))
+                .collect::<HashMap<_, _>>();
+            let threescale = ThreescaleData {
+                app_id: app_id.clone(),
+                service_id: ServiceId::from(report.service_id()),
+                service_token: ServiceToken::from(report.service_token()),
+                metrics: std::cell::RefCell::new(metrics),
+                ..Default::default()
+            };
+            // Flush is left to the next tick or message.
+            if let Err(err) = self.delta_store.update_delta_store(&threescale, &now) {
+                info!("Failed to put report back into the delta store: {}", err);
+            }
+        }
+    }
+
+    /// Applications 3scale no longer knows or serves are deleted from the cache, while rejections
+    /// due to the configuration of the gateway (eg: an invalid service token) keep them cached.
+    fn handle_auth_rejection(&mut self, token_id: u32, error: &AuthorizeError) {
+        record_auth_error(&self.host, &self.stats.auth_errors, error);
+        if error.evicts_application() {
+            self.handle_auth_failure(token_id);
+        } else {
+            warn!("authorize call {} rejected: {}", token_id, error);
+            self.complete_auth(token_id);
+        }
+    }
+
+    /// Handle authorize failure for an application 3scale rejected with an error evicting it, eg:
+    /// application_not_found.
     fn handle_auth_failure(&mut self, token_id: u32) {
-        if let Some(cache_key) = self.auth_requests.get(&token_id) {
+        if let Some(cache_key) = self.auth_requests.get(&token_id).cloned() {
             info!("Deleting application with key: {:?}", cache_key);
-            remove_application_from_cache(&cache_key.as_string());
-            self.cache_keys.remove(cache_key);
-            decrement_stat(&self.stats.cached_apps);
-            self.auth_requests.remove(&token_id);
+            remove_application_from_cache(&self.host, &cache_key.as_string());
+            self.cache_keys.remove(&cache_key);
+            self.in_flight.remove_app(&cache_key);
+            decrement_stat(&self.host, &self.stats.cached_apps);
+            self.complete_auth(token_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod simulation;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::delta::FlushPolicy;
+    use crate::configuration::dispatch::AuthDispatchConfig;
+    use rand::SeedableRng;
+    use rand_pcg::Pcg32;
+    use std::cell::RefCell;
+    use threescale::backoff::BackoffConfig;
+    use threescale::breaker::{circuit_state_metric, CircuitState};
+    use threescale::host::{
+        mock::{DispatchedCall, MockHost},
+        SharedQueue,
+    };
+
+    fn singleton(flush_mode: FlushMode, capacity: u64) -> SingletonService<MockHost> {
+        let mut service =
+            SingletonService::new(1, MockHost::new(), Box::new(Pcg32::seed_from_u64(7)));
+        service.delta_store.config = DeltaStoreConfig {
+            capacity,
+            flush_mode,
+            ..Default::default()
+        };
+        assert!(service.on_vm_start(0));
+        service
+    }
+
+    fn send_usage(service: &mut SingletonService<MockHost>, service_id: &str, app_id: &str) {
+        let data = ThreescaleData {
+            app_id: AppIdentifier::from(AppId::from(app_id)),
+            service_id: ServiceId::from(service_id),
+            service_token: ServiceToken::from("token"),
+            metrics: RefCell::new(vec![("hits".to_string(), 1)].into_iter().collect()),
+            ..Default::default()
+        };
+        let message = Message::new(false, &data, &service.host.now());
+        let queue_id = service.queue_id.unwrap();
+        service
+            .host
+            .enqueue_shared_queue(queue_id, Some(&bincode::serialize(&message).unwrap()))
+            .unwrap();
+        service.on_queue_ready(queue_id);
+    }
+
+    fn cache_app(service: &SingletonService<MockHost>, app_id: &str, left_hits: u64) {
+        let now = service.host.now();
+        let mut local_state = HashMap::new();
+        local_state.insert(
+            "hits".to_string(),
+            UsageReport {
+                period_window: PeriodWindow {
+                    start: now - Duration::from_secs(60),
+                    end: now + Duration::from_secs(3540),
+                    window: Period::Hour,
+                },
+                left_hits,
+                max_value: 100,
+                synced_left_hits: left_hits,
+            },
+        );
+        let app = Application {
+            app_id: AppIdentifier::from(AppId::from(app_id)),
+            service_id: ServiceId::from("service"),
+            local_state,
+            metric_hierarchy: HashMap::new(),
+            app_keys: None,
+            synced_at: now,
+            plan: None,
+        };
+        let key = CacheKey::from(&app.service_id, &app.app_id);
+        set_application_to_cache(&service.host, &key.as_string(), &app, 0).unwrap();
+    }
+
+    fn app_key(app_id: &str) -> CacheKey {
+        CacheKey::from(
+            &ServiceId::from("service"),
+            &AppIdentifier::from(AppId::from(app_id)),
+        )
+    }
+
+    // Authorize response of the application on the Pro plan, with a limit of 1000 hits per hour.
+    fn auth_response(app_id: &str, current_value: u64) -> Vec<u8> {
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><status>\
+             <authorized>true</authorized><plan>Pro</plan><usage_reports>\
+             <usage_report metric=\"hits\" period=\"hour\">\
+             <period_start>2021-01-01 00:00:00 +0000</period_start>\
+             <period_end>2021-01-01 01:00:00 +0000</period_end>\
+             <max_value>1000</max_value><current_value>{}</current_value>\
+             </usage_report></usage_reports>\
+             <app_keys app=\"{}\" svc=\"service\"><key id=\"secret\"/></app_keys></status>",
+            current_value, app_id
+        )
+        .into_bytes()
+    }
+
+    fn answer_auths(service: &mut SingletonService<MockHost>) {
+        let tokens = service.auth_requests.keys().copied().collect::<Vec<_>>();
+        for token in tokens {
+            service.complete_auth(token);
+        }
+    }
+
+    fn count_calls(calls: &[DispatchedCall], path: &str) -> usize {
+        calls
+            .iter()
+            .filter(|call| call.header(":path").unwrap().starts_with(path))
+            .count()
+    }
+
+    #[test]
+    fn full_delta_store_is_flushed() {
+        let mut service = singleton(FlushMode::ContainerLimit, 1);
+        send_usage(&mut service, "service", "app");
+
+        let calls = service.host.drain_calls();
+        assert_eq!(count_calls(&calls, "/transactions.xml"), 1);
+        assert_eq!(count_calls(&calls, "/transactions/authorize.xml"), 1);
+        assert!(service.delta_store.deltas.is_empty());
+        assert_eq!(service.report_requests.len(), 1);
+        assert_eq!(service.auth_requests.len(), 1);
+    }
+
+    #[test]
+    fn deltas_are_aggregated_until_tick() {
+        let mut service = singleton(FlushMode::Periodical, 1);
+        send_usage(&mut service, "service", "app");
+        send_usage(&mut service, "service", "app");
+        send_usage(&mut service, "service", "other_app");
+        send_usage(&mut service, "other_service", "app");
+        assert!(service.host.drain_calls().is_empty());
+
+        let apps = service.delta_store.deltas.get("service_token").unwrap();
+        let app_deltas = apps.get(&AppIdentifier::from(AppId::from("app"))).unwrap();
+        assert_eq!(app_deltas.get("hits"), Some(&2));
+
+        service.on_tick();
+        let calls = service.host.drain_calls();
+        // One report per service and one authorize per application.
+        assert_eq!(count_calls(&calls, "/transactions.xml"), 2);
+        assert_eq!(count_calls(&calls, "/transactions/authorize.xml"), 3);
+        assert!(service.delta_store.deltas.is_empty());
+    }
+
+    #[test]
+    fn app_running_low_is_refreshed_early() {
+        let mut service = singleton(FlushMode::Periodical, 1);
+        cache_app(&service, "app", 5);
+        send_usage(&mut service, "service", "other_app");
+        send_usage(&mut service, "service", "app");
+
+        let calls = service.host.drain_calls();
+        assert_eq!(count_calls(&calls, "/transactions.xml"), 1);
+        assert_eq!(count_calls(&calls, "/transactions/authorize.xml"), 1);
+        let report = service.report_requests.values().next().unwrap();
+        assert_eq!(report.usages().len(), 1);
+        // Deltas of other applications wait for the flush.
+        let apps = service.delta_store.deltas.get("service_token").unwrap();
+        assert_eq!(apps.len(), 1);
+
+        // Only a single refresh is in flight per application.
+        send_usage(&mut service, "service", "app");
+        assert!(service.host.drain_calls().is_empty());
+    }
+
+    #[test]
+    fn app_staying_low_is_refreshed_once_per_min_interval() {
+        let mut service = singleton(FlushMode::Periodical, 1);
+        cache_app(&service, "app", 5);
+        send_usage(&mut service, "service", "app");
+        assert_eq!(service.host.drain_calls().len(), 2);
+        answer_auths(&mut service);
+
+        let min_interval = service.config.early_refresh.min_interval;
+        service.host.advance_time(min_interval / 2);
+        for _ in 0..3 {
+            send_usage(&mut service, "service", "app");
+        }
+        assert!(service.host.drain_calls().is_empty());
+        assert!(service.auth_requests.is_empty());
+
+        service.host.advance_time(min_interval / 2);
+        send_usage(&mut service, "service", "app");
+        let calls = service.host.drain_calls();
+        assert_eq!(count_calls(&calls, "/transactions.xml"), 1);
+        assert_eq!(count_calls(&calls, "/transactions/authorize.xml"), 1);
+    }
+
+    #[test]
+    fn refreshed_app_keeps_unreported_hits_and_records_changes() {
+        let mut service = singleton(FlushMode::Periodical, 1);
+        cache_app(&service, "app", 90);
+        send_usage(&mut service, "service", "app");
+        send_usage(&mut service, "service", "app");
+
+        service
+            .handle_auth_response(auth_response("app", 10), "200")
+            .unwrap();
+
+        let (app, _) = get_application_from_cache(&service.host, &app_key("app")).unwrap();
+        assert_eq!(app.plan.as_deref(), Some("Pro"));
+        // Both hits are still waiting in the delta store.
+        assert_eq!(app.local_state["hits"].synced_left_hits, 990);
+        assert_eq!(app.local_state["hits"].left_hits, 988);
+        assert_eq!(
+            service.host.metric_value("envoy.3scale.cache.app_changes"),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn reported_hits_are_kept_until_reflected_by_backend() {
+        let mut service = singleton(FlushMode::Periodical, 1);
+        cache_app(&service, "app", 90);
+        send_usage(&mut service, "service", "app");
+        send_usage(&mut service, "service", "app");
+        service.on_tick();
+        let report_token = *service.report_requests.keys().next().unwrap();
+
+        // Response to an authorize call sent along with the report.
+        service
+            .handle_auth_response(auth_response("app", 10), "200")
+            .unwrap();
+        let (app, _) = get_application_from_cache(&service.host, &app_key("app")).unwrap();
+        assert_eq!(app.local_state["hits"].left_hits, 988);
+        answer_auths(&mut service);
+
+        service.handle_report_response("200", &report_token);
+        service
+            .host
+            .advance_time(service.config.report.reflection_delay);
+        service.request_auth(app_key("app"));
+        service.dispatch_auths();
+        service
+            .handle_auth_response(auth_response("app", 12), "200")
+            .unwrap();
+        let (app, _) = get_application_from_cache(&service.host, &app_key("app")).unwrap();
+        assert_eq!(app.local_state["hits"].left_hits, 988);
+    }
+
+    #[test]
+    fn only_apps_unknown_to_backend_are_evicted() {
+        let mut service = singleton(FlushMode::Periodical, 1);
+        cache_app(&service, "app", 90);
+        let not_found = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+                         <error code=\"application_not_found\">application with id=\"app\" \
+                         was not found</error>";
+        let error = match service.handle_auth_response(not_found.as_bytes().to_vec(), "404") {
+            Err(SingletonServiceError::AuthRejected(error)) => error,
+            other => panic!("unexpected result: {:?}", other),
+        };
+        assert_eq!(error, AuthorizeError::ApplicationNotFound);
+
+        service.request_auth(app_key("app"));
+        service.dispatch_auths();
+        let token = *service.auth_requests.keys().next().unwrap();
+        service.handle_auth_rejection(token, &AuthorizeError::ServiceTokenInvalid);
+        assert!(get_application_from_cache(&service.host, &app_key("app")).is_ok());
+        assert!(service.auth_requests.is_empty());
+
+        service.request_auth(app_key("app"));
+        service.dispatch_auths();
+        let token = *service.auth_requests.keys().next().unwrap();
+        service.handle_auth_rejection(token, &error);
+        assert!(get_application_from_cache(&service.host, &app_key("app")).is_err());
+        assert_eq!(
+            service
+                .host
+                .metric_value("envoy.3scale.auth_errors.service_token_invalid"),
+            Some(1)
+        );
+        assert_eq!(
+            service
+                .host
+                .metric_value("envoy.3scale.auth_errors.application_not_found"),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn app_with_quota_waits_for_flush() {
+        let mut service = singleton(FlushMode::Periodical, 1);
+        cache_app(&service, "app", 90);
+        send_usage(&mut service, "service", "app");
+        assert!(service.host.drain_calls().is_empty());
+
+        service.config.early_refresh.disabled = true;
+        cache_app(&service, "app", 0);
+        send_usage(&mut service, "service", "app");
+        assert!(service.host.drain_calls().is_empty());
+    }
+
+    #[test]
+    fn only_apps_with_traffic_are_reauthorized() {
+        let mut service = singleton(FlushMode::Periodical, 1);
+        service.config.app_idle_timeout = Duration::from_secs(7200);
+        send_usage(&mut service, "service", "app");
+        send_usage(&mut service, "service", "other_app");
+        service.on_tick();
+        let calls = service.host.drain_calls();
+        assert_eq!(count_calls(&calls, "/transactions/authorize.xml"), 2);
+        answer_auths(&mut service);
+
+        send_usage(&mut service, "service", "app");
+        service
+            .host
+            .advance_time(service.delta_store.config.periodical_flush);
+        service.on_tick();
+        let calls = service.host.drain_calls();
+        assert_eq!(count_calls(&calls, "/transactions/authorize.xml"), 1);
+        answer_auths(&mut service);
+
+        // Application without traffic but whose window ends before the next flush.
+        cache_app(&service, "other_app", 90);
+        service
+            .host
+            .advance_time(Duration::from_secs(3540) - service.delta_store.config.periodical_flush);
+        service.on_tick();
+        let calls = service.host.drain_calls();
+        assert_eq!(count_calls(&calls, "/transactions/authorize.xml"), 1);
+        assert!(service
+            .auth_requests
+            .values()
+            .all(|key| key.app_id().as_ref() == "other_app"));
+    }
+
+    #[test]
+    fn idle_apps_are_evicted() {
+        let mut service = singleton(FlushMode::Periodical, 1);
+        cache_app(&service, "app", 90);
+        send_usage(&mut service, "service", "app");
+        send_usage(&mut service, "service", "other_app");
+        service.on_tick();
+        service.host.drain_calls();
+        answer_auths(&mut service);
+
+        service
+            .host
+            .advance_time(service.config.app_idle_timeout - Duration::from_secs(1));
+        send_usage(&mut service, "service", "other_app");
+        service.host.advance_time(Duration::from_secs(1));
+        service.on_tick();
+        let calls = service.host.drain_calls();
+        assert_eq!(count_calls(&calls, "/transactions/authorize.xml"), 1);
+        assert_eq!(service.cache_keys.len(), 1);
+        let app_key = CacheKey::from(
+            &ServiceId::from("service"),
+            &AppIdentifier::from(AppId::from("app")),
+        );
+        assert!(get_application_from_cache(&service.host, &app_key).is_err());
+    }
+
+    #[test]
+    fn idle_apps_are_kept_while_backend_is_unhealthy() {
+        let mut service = singleton(FlushMode::Periodical, 1);
+        cache_app(&service, "app", 90);
+        send_usage(&mut service, "service", "app");
+        service.on_tick();
+        service.host.drain_calls();
+        answer_auths(&mut service);
+        for _ in 0..service.config.circuit_breaker.timeout_threshold {
+            service.record_call_outcome(CallOutcome::Timeout, 0);
+        }
+
+        service.host.advance_time(service.config.app_idle_timeout);
+        service.on_tick();
+        assert_eq!(service.cache_keys.len(), 1);
+
+        // Evicted with the next flush once the backend answers again.
+        service.record_call_outcome(CallOutcome::Success, 0);
+        service
+            .host
+            .advance_time(service.delta_store.config.periodical_flush);
+        service.on_tick();
+        assert!(service.cache_keys.is_empty());
+    }
+
+    #[test]
+    fn service_with_flush_policy_is_flushed_on_its_own() {
+        let mut service = singleton(FlushMode::Periodical, 1);
+        service.delta_store.config.service_policies.insert(
+            "billing".to_string(),
+            FlushPolicy {
+                max_latency: Duration::from_secs(5),
+                min_delta: 0,
+            },
+        );
+        service.on_tick();
+        service.host.drain_calls();
+
+        send_usage(&mut service, "billing", "app");
+        send_usage(&mut service, "other", "app");
+        service.host.advance_time(Duration::from_secs(5));
+        service.on_tick();
+        let calls = service.host.drain_calls();
+        assert_eq!(count_calls(&calls, "/transactions.xml"), 1);
+        assert!(!service.delta_store.deltas.contains_key("billing_token"));
+        assert!(service.delta_store.deltas.contains_key("other_token"));
+    }
+
+    #[test]
+    fn configuration_changes_are_applied_live() {
+        let mut service = singleton(FlushMode::Periodical, 1);
+        cache_app(&service, "app", 100);
+        send_usage(&mut service, "service", "app");
+
+        let changes = service
+            .apply_configuration(
+                br#"{
+                    "delta_store_config": {"capacity": 50, "flush_mode": "Periodical"},
+                    "auth_dispatch": {"max_per_second": 10}
+                }"#,
+            )
+            .unwrap();
+        assert_eq!(
+            changes,
+            vec![
+                "delta_store_config.capacity: 100 -> 50",
+                "delta_store_config.flush_mode: Default -> Periodical",
+                "auth_dispatch.max_per_second: 100 -> 10",
+            ]
+        );
+        assert_eq!(service.delta_store.config.capacity, 50);
+        assert!(service.delta_store.deltas.contains_key("service_token"));
+        let cache_key = CacheKey::from(
+            &ServiceId::from("service"),
+            &AppIdentifier::from(AppId::from("app")),
+        );
+        assert!(get_application_from_cache(&service.host, &cache_key).is_ok());
+    }
+
+    #[test]
+    fn configuration_is_read_from_host() {
+        let mut service = singleton(FlushMode::Periodical, 1);
+        service.host.set_configuration(
+            br#"{"delta_store_config": {"capacity": 50, "flush_mode": "Periodical"}}"#,
+        );
+
+        assert!(service.on_configure(0));
+        assert_eq!(service.delta_store.config.capacity, 50);
+        assert_eq!(service.host.tick_period(), service.tick_period());
+
+        service.host.set_configuration(b"not json");
+        assert!(!service.on_configure(0));
+        assert_eq!(service.delta_store.config.capacity, 50);
+    }
+
+    #[test]
+    fn invalid_configuration_is_rejected() {
+        let mut service = singleton(FlushMode::Periodical, 1);
+        let invalid = [
+            "not json",
+            r#"{"delta_store": {"capacity": 50}}"#,
+            r#"{"early_refresh": {"left_hits_ratio": 1.5}}"#,
+        ];
+        for configuration in invalid.iter() {
+            assert!(service
+                .apply_configuration(configuration.as_bytes())
+                .is_err());
+        }
+        let error = service
+            .apply_configuration(br#"{"report": {"max_transactions": 0}}"#)
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "invalid value for report.max_transactions: must be at least 1"
+        );
+        assert_eq!(service.config.report.max_transactions, 1000);
+    }
+
+    #[test]
+    fn reported_app_key_is_validated() {
+        let service = singleton(FlushMode::Periodical, 1);
+        cache_app(&service, "app", 100);
+        let service_id = ServiceId::from("service");
+        let cache_key = CacheKey::from(&service_id, &AppIdentifier::from(AppId::from("app")));
+        let (mut app, cas) = get_application_from_cache(&service.host, &cache_key).unwrap();
+        app.app_keys = Some(vec![AppKey::from("secret")]);
+        set_application_to_cache(&service.host, &cache_key.as_string(), &app, cas).unwrap();
+
+        let reported_key = |app_id: &str, app_key: &str| {
+            let app_id = AppIdentifier::from((AppId::from(app_id), AppKey::from(app_key)));
+            match service.validate_app_key(&service_id, &app_id) {
+                AppIdentifier::AppId(_, key) => key.map(|key| key.as_ref().to_string()),
+                AppIdentifier::UserKey(_) => None,
+            }
+        };
+        assert_eq!(reported_key("app", "secret"), Some("secret".to_string()));
+        assert_eq!(reported_key("app", "other"), None);
+        assert_eq!(reported_key("unknown", "secret"), None);
+    }
+
+    #[test]
+    fn auth_calls_are_capped_and_spread_across_rounds() {
+        let mut service = singleton(FlushMode::Periodical, 1);
+        service.config.auth_dispatch = AuthDispatchConfig {
+            max_in_flight: 2,
+            max_per_second: 3,
+            dispatch_interval: Duration::from_secs(1),
+            ..Default::default()
+        };
+        for app_id in ["a", "b", "c", "d", "e"].iter() {
+            send_usage(&mut service, "service", app_id);
         }
+        service.on_tick();
+        let calls = service.host.drain_calls();
+        assert_eq!(count_calls(&calls, "/transactions/authorize.xml"), 2);
+
+        // Response makes room for a queued call, within the budget of the round.
+        let token = *service.auth_requests.keys().next().unwrap();
+        service.complete_auth(token);
+        assert_eq!(service.host.drain_calls().len(), 1);
+        let token = *service.auth_requests.keys().next().unwrap();
+        service.complete_auth(token);
+        assert!(service.host.drain_calls().is_empty());
+
+        // Next round, delta store flush is not due yet.
+        service.host.advance_time(Duration::from_secs(1));
+        service.on_tick();
+        let calls = service.host.drain_calls();
+        assert_eq!(count_calls(&calls, "/transactions.xml"), 0);
+        assert_eq!(count_calls(&calls, "/transactions/authorize.xml"), 1);
+        assert_eq!(service.auth_requests.len(), 2);
+        assert_eq!(service.auth_queue.len(), 1);
+    }
+
+    #[test]
+    fn report_is_split_in_tracked_chunks() {
+        let mut service = singleton(FlushMode::Periodical, 1);
+        service.config.report.max_transactions = 2;
+        for app_id in ["a", "b", "c"].iter() {
+            send_usage(&mut service, "service", app_id);
+        }
+        service.on_tick();
+        let calls = service.host.drain_calls();
+        assert_eq!(count_calls(&calls, "/transactions.xml"), 2);
+        assert_eq!(service.report_requests.len(), 2);
+    }
+
+    #[test]
+    fn failed_report_chunk_is_retried() {
+        let mut service = singleton(FlushMode::Periodical, 1);
+        service.config.report.max_transactions = 1;
+        send_usage(&mut service, "service", "a");
+        send_usage(&mut service, "service", "a");
+        send_usage(&mut service, "service", "b");
+        service.on_tick();
+        let mut tokens = service
+            .report_requests
+            .iter()
+            .map(|(token, report)| (report.usages().keys().next().unwrap().clone(), *token))
+            .collect::<Vec<_>>();
+        tokens.sort_by(|(a, _), (b, _)| a.as_ref().cmp(b.as_ref()));
+
+        service.handle_report_response("503", &tokens[0].1);
+        service.handle_report_response("403", &tokens[1].1);
+        assert!(service.report_requests.is_empty());
+        let apps = service.delta_store.deltas.get("service_token").unwrap();
+        assert_eq!(apps.len(), 1);
+        let app_deltas = apps.get(&AppIdentifier::from(AppId::from("a"))).unwrap();
+        assert_eq!(app_deltas.get("hits"), Some(&2));
+    }
+
+    #[test]
+    fn open_circuit_keeps_deltas_until_probe() {
+        let mut service = singleton(FlushMode::Periodical, 1);
+        for _ in 0..service.config.circuit_breaker.timeout_threshold {
+            service.record_call_outcome(CallOutcome::Timeout, 0);
+        }
+        send_usage(&mut service, "service", "app");
+        service.on_tick();
+        assert!(service.host.drain_calls().is_empty());
+        assert!(service.report_requests.is_empty());
+        let apps = service.delta_store.deltas.get("service_token").unwrap();
+        let app_deltas = apps.get(&AppIdentifier::from(AppId::from("app"))).unwrap();
+        assert_eq!(app_deltas.get("hits"), Some(&1));
+
+        // Probe is the report of the next flush.
+        service
+            .host
+            .advance_time(service.delta_store.config.periodical_flush);
+        service.on_tick();
+        let calls = service.host.drain_calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(count_calls(&calls, "/transactions.xml"), 1);
+        assert_eq!(
+            service
+                .host
+                .metric_value(&circuit_state_metric(backend_upstream().name())),
+            Some(CircuitState::HalfOpen.gauge_value() as i64)
+        );
+
+        let token = *service.report_requests.keys().next().unwrap();
+        let probe = service.call_generations[&token];
+        service.record_call_outcome(CallOutcome::from_status("202"), probe);
+        service.handle_report_response("202", &token);
+        assert!(service.delta_store.deltas.is_empty());
+        assert_eq!(
+            service
+                .host
+                .metric_value(&circuit_state_metric(backend_upstream().name())),
+            Some(CircuitState::Closed.gauge_value() as i64)
+        );
+    }
+
+    // Backoff whose delays are between 2 and 4 flush periods.
+    fn slow_backoff(service: &SingletonService<MockHost>) -> BackoffConfig {
+        BackoffConfig {
+            initial: service.delta_store.config.periodical_flush * 4,
+            max: service.delta_store.config.periodical_flush * 4,
+        }
+    }
+
+    #[test]
+    fn failed_report_is_retried_after_backoff() {
+        let mut service = singleton(FlushMode::Periodical, 1);
+        service.config.report.retry_backoff = slow_backoff(&service);
+        let periodical_flush = service.delta_store.config.periodical_flush;
+        send_usage(&mut service, "service", "app");
+        service.on_tick();
+        let token = *service.report_requests.keys().next().unwrap();
+        service.host.drain_calls();
+
+        service.handle_report_response("503", &token);
+        assert_eq!(service.report_backoff.failures(), 1);
+        send_usage(&mut service, "service", "app");
+        service.host.advance_time(periodical_flush);
+        service.on_tick();
+        assert_eq!(
+            count_calls(&service.host.drain_calls(), "/transactions.xml"),
+            0
+        );
+        let apps = service.delta_store.deltas.get("service_token").unwrap();
+        let app_deltas = apps.get(&AppIdentifier::from(AppId::from("app"))).unwrap();
+        assert_eq!(app_deltas.get("hits"), Some(&2));
+
+        service.host.advance_time(periodical_flush * 3);
+        service.on_tick();
+        assert_eq!(
+            count_calls(&service.host.drain_calls(), "/transactions.xml"),
+            1
+        );
+        let token = *service.report_requests.keys().next().unwrap();
+        service.handle_report_response("202", &token);
+        assert_eq!(service.report_backoff, Backoff::default());
+    }
+
+    #[test]
+    fn failed_auth_delays_next_auth_of_application() {
+        let mut service = singleton(FlushMode::Periodical, 1);
+        service.config.auth_dispatch.retry_backoff = slow_backoff(&service);
+        let periodical_flush = service.delta_store.config.periodical_flush;
+        send_usage(&mut service, "service", "app");
+        service.on_tick();
+        let token = *service.auth_requests.keys().next().unwrap();
+        service.host.drain_calls();
+
+        service.record_auth_outcome(token, CallOutcome::Timeout);
+        service.complete_auth(token);
+        send_usage(&mut service, "service", "app");
+        service.host.advance_time(periodical_flush);
+        service.on_tick();
+        let calls = service.host.drain_calls();
+        assert_eq!(count_calls(&calls, "/transactions.xml"), 1);
+        assert_eq!(count_calls(&calls, "/transactions/authorize.xml"), 0);
+
+        // Requests seen during the backoff are refreshed once it is over.
+        service.host.advance_time(periodical_flush * 3);
+        service.on_tick();
+        let calls = service.host.drain_calls();
+        assert_eq!(count_calls(&calls, "/transactions/authorize.xml"), 1);
+    }
+
+    #[test]
+    fn call_responses_are_read_from_host() {
+        let mut service = singleton(FlushMode::ContainerLimit, 1);
+        send_usage(&mut service, "service", "app");
+        let report_token = *service.report_requests.keys().next().unwrap();
+        let auth_token = *service.auth_requests.keys().next().unwrap();
+
+        let body = auth_response("app", 10);
+        service
+            .host
+            .set_call_response(vec![(":status", "200")], Some(&body));
+        service.on_http_call_response(auth_token, 1, body.len(), 0);
+        assert!(service.auth_requests.is_empty());
+        let (app, _) = get_application_from_cache(&service.host, &app_key("app")).unwrap();
+        assert_eq!(app.local_state["hits"].synced_left_hits, 990);
+
+        service
+            .host
+            .set_call_response(vec![(":status", "202")], Some(b""));
+        service.on_http_call_response(report_token, 1, 0, 0);
+        assert!(service.report_requests.is_empty());
+    }
+
+    #[test]
+    fn report_response_clears_pending_report() {
+        let mut service = singleton(FlushMode::ContainerLimit, 1);
+        send_usage(&mut service, "service", "app");
+        let token = *service.report_requests.keys().next().unwrap();
+
+        service.handle_report_response("202", &token);
+        assert!(service.report_requests.is_empty());
     }
 }
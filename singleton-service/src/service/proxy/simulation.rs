@@ -0,0 +1,872 @@
+/** Deterministic simulation of several cache filter workers and the singleton service:
+* Every worker runs the cache filter itself (cache lookup through its local cache, limit check and
+* CAS guarded update, authorize calls on cache misses under the callout-lock) and the singleton
+* service consumes the messages enqueued by them. Everything runs on top of a single MockHost, so
+* that workers and singleton share the same shared data and queues, while the local cache and the
+* callout waiters of each worker are swapped in place of the ones of the thread whenever one of its
+* requests runs. Requests waiting for the callout of another one are resumed by their worker once
+* notified through its thread-specific queue, as its root context does.
+*
+* A seeded scheduler picks which request, singleton event, callout response or waiter goes next,
+* replaying a different interleaving for every seed. Callbacks of the singleton are atomic, while
+* other workers may write the hits batched by their local cache between a read and a write of a
+* worker to shared data (see InterleavingHost). The 3scale backend is emulated by the simulation
+* itself, counting the hits reported for each application and answering authorize calls
+* accordingly.
+*
+* Invariants checked at the end of each run:
+* * No application is admitted more hits than the bound derived from the configuration of the
+*   filter: the quota left by its limit tolerance plus the hits pending in the local cache of
+*   every worker.
+* * Every request is either admitted or rate-limited, including every waiter of a callout.
+* * Every admitted hit is reported to the backend, no delta is lost.
+*
+* A failing seed can be replayed alone by setting the SIMULATION_SEED environment variable.
+**/
+use super::*;
+use cache_filter::{
+    configuration::FilterConfig,
+    filter::http::{CacheFilter, RequestState, LOCAL_CACHE},
+    unique_callout::{resume_waiter, WaiterAction, WaitingContext, WAITING_CONTEXTS},
+};
+use chrono::NaiveDateTime;
+use proxy_wasm::traits::HttpContext;
+use proxy_wasm::types::{Bytes, MetricType, Status};
+use rand::{Rng, SeedableRng};
+use rand_pcg::Pcg32;
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::time::SystemTime;
+use threescale::{
+    host::{
+        mock::MockHost, Clock, HttpCallout, HttpStream, Metrics, Plugin, SharedData, SharedQueue,
+    },
+    local_cache::{LocalCache, LocalCacheConfig},
+};
+
+const SEEDS: u64 = 64;
+const WORKERS: u32 = 4;
+const REQUESTS_PER_WORKER: usize = 40;
+// Requests of a single worker waiting for an authorize call at the same time.
+const MAX_ACTIVE_CONTEXTS: usize = 3;
+const SERVICE_ID: &str = "service";
+const SERVICE_TOKEN: &str = "token";
+const APPS: [&str; 2] = ["app_a", "app_b"];
+const LIMIT: u64 = 50;
+const RESERVED_HITS: u64 = 10;
+const FLUSH_HITS: u64 = 3;
+const MAX_TRIES: u32 = 3;
+const STEP: Duration = Duration::from_millis(50);
+// In steps.
+const MAX_CALLOUT_DELAY: u64 = 30;
+// One in INTERLEAVING_ODDS writes of a worker to shared data comes after a write of another one.
+const INTERLEAVING_ODDS: u32 = 4;
+const TICK_STEPS: u64 = 25;
+// Bound on the number of steps, requests left behind by then are reported as never answered.
+const MAX_STEPS: u64 = 100_000;
+// Start of the day containing the initial time of MockHost.
+const PERIOD_START: u64 = 1_599_955_200;
+
+// Request waiting for the response to its authorize call.
+struct SimContext {
+    id: u32,
+    token: u32,
+    state: RequestState,
+}
+
+struct Worker {
+    id: u32,
+    queue_id: u32,
+    local_cache: LocalCache,
+    waiters: HashMap<u32, WaitingContext>,
+    contexts: Vec<SimContext>,
+    remaining: usize,
+}
+
+enum Callout {
+    // Authorize call performed by a filter on a cache miss.
+    Filter { worker: usize, token: u32 },
+    // Report or authorize call performed by the singleton.
+    Singleton(u32),
+}
+
+struct PendingCallout {
+    deliver_at: u64,
+    callout: Callout,
+}
+
+#[derive(Clone, Copy)]
+enum Action {
+    NewRequest(usize),
+    SingletonQueue,
+    Tick,
+    Deliver(usize),
+    ResumeWaiter(usize),
+}
+
+// How the filter handled a request in a single callback.
+enum Outcome {
+    Admitted,
+    Limited,
+    Dispatched(u32),
+    Waiting,
+}
+
+// Per application accounting, reported hits are the state of the emulated backend.
+#[derive(Debug, Default)]
+struct Ledger {
+    admitted: u64,
+    limited: u64,
+    reported: u64,
+}
+
+struct Simulation {
+    seed: u64,
+    rng: Pcg32,
+    step: u64,
+    next_tick: u64,
+    next_context_id: u32,
+    service: SingletonService<MockHost>,
+    config: FilterConfig,
+    stats: ThreescaleStats,
+    workers: Vec<Worker>,
+    callouts: Vec<PendingCallout>,
+    scheduled_tokens: HashSet<u32>,
+    ledgers: HashMap<String, Ledger>,
+}
+
+fn filter_config() -> FilterConfig {
+    FilterConfig {
+        max_tries: MAX_TRIES,
+        limit_tolerance: LimitTolerance {
+            reserved_hits: RESERVED_HITS,
+            ..Default::default()
+        },
+        local_cache: LocalCacheConfig {
+            enabled: true,
+            flush_hits: FLUSH_HITS,
+            max_batch_delay: STEP * TICK_STEPS as u32,
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+// Highest number of hits the filter can admit for an application.
+fn max_admitted(config: &FilterConfig) -> u64 {
+    let fresh = UsageReport {
+        period_window: PeriodWindow {
+            start: Duration::from_secs(PERIOD_START),
+            end: Duration::from_secs(PERIOD_START + Period::Day.as_secs()),
+            window: Period::Day,
+        },
+        left_hits: LIMIT,
+        max_value: LIMIT,
+        synced_left_hits: LIMIT,
+    };
+    config.limit_tolerance.local_quota(&fresh)
+        + WORKERS as u64 * (config.local_cache.flush_hits - 1)
+}
+
+fn set_request_headers(host: &MockHost, app_id: &str) {
+    let upstream = backend_upstream();
+    let app_id = format!("{}:secret", app_id);
+    host.set_request_headers(vec![
+        ("x-3scale-service-token", SERVICE_TOKEN),
+        ("x-3scale-service-id", SERVICE_ID),
+        ("x-3scale-usages", "{\"hits\": 1}"),
+        ("x-3scale-cluster-name", &upstream.name),
+        ("x-3scale-upstream-url", upstream.url.as_str()),
+        ("x-3scale-app-id", &app_id),
+    ]);
+}
+
+fn format_time(secs: u64) -> String {
+    NaiveDateTime::from_timestamp(secs as i64, 0)
+        .format("%Y-%m-%d %H:%M:%S +0000")
+        .to_string()
+}
+
+// Authorize response (with list_app_keys extension) as returned by apisonator.
+fn authorize_response(cache_key: &CacheKey, reported: u64) -> (String, &'static str) {
+    let (authorized, status) = if reported < LIMIT {
+        ("<authorized>true</authorized>", "200")
+    } else {
+        (
+            "<authorized>false</authorized><reason>usage limits are exceeded</reason>",
+            RATE_LIMIT_STATUS,
+        )
+    };
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?><status>{}<plan>Basic</plan>\
+         <usage_reports><usage_report metric=\"hits\" period=\"day\">\
+         <period_start>{}</period_start><period_end>{}</period_end>\
+         <max_value>{}</max_value><current_value>{}</current_value>\
+         </usage_report></usage_reports>\
+         <app_keys app=\"{}\" svc=\"{}\"><key id=\"secret\"/></app_keys></status>",
+        authorized,
+        format_time(PERIOD_START),
+        format_time(PERIOD_START + Period::Day.as_secs()),
+        LIMIT,
+        reported,
+        cache_key.app_id().as_ref(),
+        cache_key.service_id().as_ref(),
+    );
+    (body, status)
+}
+
+// Host of a worker, making other workers write the hits batched by their local cache right before
+// some of the writes of the worker to shared data, as if they ran between its read and its write.
+// Such writes stop once the worker might have to give up on writing, after max_tries CAS
+// mismatches.
+struct InterleavingHost<'a> {
+    host: &'a MockHost,
+    others: RefCell<Vec<&'a mut LocalCache>>,
+    rng: RefCell<Pcg32>,
+    interleavings_left: Cell<u32>,
+    max_batch_delay: Duration,
+}
+
+impl InterleavingHost<'_> {
+    fn interleave(&self) {
+        let mut rng = self.rng.borrow_mut();
+        let mut others = self.others.borrow_mut();
+        if self.interleavings_left.get() == 0
+            || others.is_empty()
+            || !rng.gen_ratio(1, INTERLEAVING_ODDS)
+        {
+            return;
+        }
+        self.interleavings_left
+            .set(self.interleavings_left.get() - 1);
+        // Every batch of the other worker is due, as it would be on its next tick.
+        let due = self.host.now() + self.max_batch_delay;
+        let o = rng.gen_range(0..others.len());
+        others[o].flush_due(self.host, &due, MAX_TRIES);
+    }
+}
+
+impl SharedData for InterleavingHost<'_> {
+    fn get_shared_data(&self, key: &str) -> Result<(Option<Bytes>, Option<u32>), Status> {
+        self.host.get_shared_data(key)
+    }
+
+    fn set_shared_data(
+        &self,
+        key: &str,
+        value: Option<&[u8]>,
+        cas: Option<u32>,
+    ) -> Result<(), Status> {
+        self.interleave();
+        self.host.set_shared_data(key, value, cas)
+    }
+}
+
+impl SharedQueue for InterleavingHost<'_> {
+    fn register_shared_queue(&self, name: &str) -> Result<u32, Status> {
+        self.host.register_shared_queue(name)
+    }
+
+    fn resolve_shared_queue(&self, vm_id: &str, name: &str) -> Result<Option<u32>, Status> {
+        self.host.resolve_shared_queue(vm_id, name)
+    }
+
+    fn enqueue_shared_queue(&self, queue_id: u32, value: Option<&[u8]>) -> Result<(), Status> {
+        self.host.enqueue_shared_queue(queue_id, value)
+    }
+
+    fn dequeue_shared_queue(&self, queue_id: u32) -> Result<Option<Bytes>, Status> {
+        self.host.dequeue_shared_queue(queue_id)
+    }
+}
+
+impl HttpCallout for InterleavingHost<'_> {
+    fn dispatch_http_call(
+        &self,
+        upstream: &str,
+        headers: Vec<(&str, &str)>,
+        body: Option<&[u8]>,
+        trailers: Vec<(&str, &str)>,
+        timeout: Duration,
+    ) -> Result<u32, Status> {
+        self.host
+            .dispatch_http_call(upstream, headers, body, trailers, timeout)
+    }
+}
+
+impl HttpStream for InterleavingHost<'_> {
+    fn get_http_request_header(&self, name: &str) -> Option<String> {
+        self.host.get_http_request_header(name)
+    }
+
+    fn add_http_response_header(&self, name: &str, value: &str) {
+        self.host.add_http_response_header(name, value)
+    }
+
+    fn send_http_response(
+        &self,
+        status_code: u32,
+        headers: Vec<(&str, &str)>,
+        body: Option<&[u8]>,
+    ) -> Result<(), Status> {
+        self.host.send_http_response(status_code, headers, body)
+    }
+
+    fn resume_http_request(&self) -> Result<(), Status> {
+        self.host.resume_http_request()
+    }
+
+    fn get_http_call_response_headers(&self) -> Vec<(String, String)> {
+        self.host.get_http_call_response_headers()
+    }
+
+    fn get_http_call_response_body(&self, start: usize, max_size: usize) -> Option<Bytes> {
+        self.host.get_http_call_response_body(start, max_size)
+    }
+
+    fn set_effective_context(&self, context_id: u32) -> Result<(), Status> {
+        self.host.set_effective_context(context_id)
+    }
+}
+
+impl Clock for InterleavingHost<'_> {
+    fn get_current_time(&self) -> SystemTime {
+        self.host.get_current_time()
+    }
+}
+
+impl Metrics for InterleavingHost<'_> {
+    fn define_metric(&self, metric_type: MetricType, name: &str) -> Result<u32, Status> {
+        self.host.define_metric(metric_type, name)
+    }
+
+    fn increment_metric(&self, metric_id: u32, offset: i64) -> Result<(), Status> {
+        self.host.increment_metric(metric_id, offset)
+    }
+
+    fn record_metric(&self, metric_id: u32, value: u64) -> Result<(), Status> {
+        self.host.record_metric(metric_id, value)
+    }
+
+    fn get_metric(&self, metric_id: u32) -> Result<u64, Status> {
+        self.host.get_metric(metric_id)
+    }
+}
+
+impl Plugin for InterleavingHost<'_> {
+    fn get_configuration(&self) -> Option<Bytes> {
+        self.host.get_configuration()
+    }
+
+    fn set_tick_period(&self, period: Duration) -> Result<(), Status> {
+        self.host.set_tick_period(period)
+    }
+}
+
+fn new_filter<H: Host>(
+    host: H,
+    worker_id: u32,
+    config: &FilterConfig,
+    stats: &ThreescaleStats,
+    context_id: u32,
+    state: RequestState,
+) -> CacheFilter<H> {
+    CacheFilter {
+        host,
+        context_id,
+        root_id: worker_id,
+        config: config.clone(),
+        stats: stats.clone(),
+        state,
+    }
+}
+
+// Runs a callback of a request on a worker, with its local cache and its callout waiters in place
+// of the ones of the thread, and tells how the request was handled.
+#[allow(clippy::too_many_arguments)]
+fn run_worker<F, R>(
+    seed: u64,
+    host: &MockHost,
+    config: &FilterConfig,
+    workers: &mut [Worker],
+    w: usize,
+    rng_seed: u64,
+    context_id: u32,
+    callback: F,
+) -> (Outcome, R)
+where
+    F: FnOnce(&InterleavingHost) -> R,
+{
+    let mut worker = None;
+    let mut others = Vec::new();
+    for (i, each) in workers.iter_mut().enumerate() {
+        if i == w {
+            worker = Some(each);
+        } else {
+            others.push(&mut each.local_cache);
+        }
+    }
+    let worker = worker.unwrap();
+    let interleaving_host = InterleavingHost {
+        host,
+        others: RefCell::new(others),
+        rng: RefCell::new(Pcg32::seed_from_u64(rng_seed)),
+        interleavings_left: Cell::new(MAX_TRIES - 1),
+        max_batch_delay: config.local_cache.max_batch_delay,
+    };
+    let swap = |worker: &mut Worker| {
+        LOCAL_CACHE.with(|cache| std::mem::swap(&mut *cache.borrow_mut(), &mut worker.local_cache));
+        WAITING_CONTEXTS
+            .with(|waiters| std::mem::swap(&mut *waiters.borrow_mut(), &mut worker.waiters));
+    };
+    let resumed = host.resumed_requests();
+    swap(worker);
+    let result = callback(&interleaving_host);
+    swap(worker);
+
+    let calls = host.drain_calls();
+    let responses = host.drain_local_responses();
+    let outcome = match (calls.as_slice(), responses.as_slice()) {
+        ([call], []) => Outcome::Dispatched(call.token),
+        ([], []) if host.resumed_requests() == resumed + 1 => Outcome::Admitted,
+        ([], []) if worker.waiters.contains_key(&context_id) => Outcome::Waiting,
+        ([], [response]) if response.status_code == 429 => Outcome::Limited,
+        _ => panic!(
+            "seed {}: request {} handled with {} calls and responses {:?}",
+            seed,
+            context_id,
+            calls.len(),
+            responses
+        ),
+    };
+    (outcome, result)
+}
+
+impl Simulation {
+    fn new(seed: u64) -> Self {
+        let mut rng = Pcg32::seed_from_u64(seed);
+        let mut service =
+            SingletonService::new(1, MockHost::new(), Box::new(Pcg32::seed_from_u64(seed)));
+        service.delta_store.config = DeltaStoreConfig {
+            capacity: rng.gen_range(1..200),
+            flush_mode: FlushMode::Default,
+            periodical_flush: STEP * TICK_STEPS as u32,
+            ..Default::default()
+        };
+        assert!(service.on_vm_start(0));
+
+        let config = filter_config();
+        let stats = initialize_stats(&service.host);
+        let workers = (0..WORKERS)
+            .map(|id| {
+                let id = id + 2;
+                let mut local_cache = LocalCache::default();
+                local_cache.set_config(&service.host, config.local_cache.clone());
+                Worker {
+                    id,
+                    queue_id: service.host.register_shared_queue(&id.to_string()).unwrap(),
+                    local_cache,
+                    waiters: HashMap::new(),
+                    contexts: Vec::new(),
+                    remaining: REQUESTS_PER_WORKER,
+                }
+            })
+            .collect();
+
+        Simulation {
+            seed,
+            rng,
+            step: 0,
+            next_tick: TICK_STEPS,
+            next_context_id: 1,
+            service,
+            config,
+            stats,
+            workers,
+            callouts: Vec::new(),
+            scheduled_tokens: HashSet::new(),
+            ledgers: HashMap::new(),
+        }
+    }
+
+    fn workers_done(&self) -> bool {
+        self.workers.iter().all(|worker| {
+            worker.remaining == 0
+                && worker.contexts.is_empty()
+                && worker.waiters.is_empty()
+                && self.service.host.queue_len(worker.queue_id) == 0
+        })
+    }
+
+    fn is_finished(&self) -> bool {
+        self.workers_done()
+            && self.service.host.queue_len(self.service.queue_id.unwrap()) == 0
+            && self.callouts.is_empty()
+    }
+
+    fn actions(&self) -> Vec<Action> {
+        let mut actions = Vec::new();
+        for (w, worker) in self.workers.iter().enumerate() {
+            if worker.remaining > 0
+                && worker.contexts.len() + worker.waiters.len() < MAX_ACTIVE_CONTEXTS
+            {
+                actions.push(Action::NewRequest(w));
+            }
+            if self.service.host.queue_len(worker.queue_id) > 0 {
+                actions.push(Action::ResumeWaiter(w));
+            }
+        }
+        if self.service.host.queue_len(self.service.queue_id.unwrap()) > 0 {
+            actions.push(Action::SingletonQueue);
+        }
+        // Ticks stop with the traffic, otherwise authorize calls would keep coming.
+        if self.step >= self.next_tick && !self.workers_done() {
+            actions.push(Action::Tick);
+        }
+        for (i, pending) in self.callouts.iter().enumerate() {
+            if pending.deliver_at <= self.step {
+                actions.push(Action::Deliver(i));
+            }
+        }
+        actions
+    }
+
+    fn run(mut self) -> Self {
+        while !self.is_finished() && self.step < MAX_STEPS {
+            let actions = self.actions();
+            if !actions.is_empty() {
+                let action = actions[self.rng.gen_range(0..actions.len())];
+                self.perform(action);
+            }
+            self.step += 1;
+            self.service.host.advance_time(STEP);
+        }
+
+        // Flush what is left in the local caches and the delta store and answer every call.
+        self.service
+            .host
+            .advance_time(self.service.delta_store.config.periodical_flush);
+        self.flush_local_caches();
+        self.service.on_tick();
+        self.schedule_singleton_calls();
+        while let Some(pending) = self.callouts.pop() {
+            self.deliver(pending.callout);
+        }
+        self
+    }
+
+    fn perform(&mut self, action: Action) {
+        match action {
+            Action::NewRequest(w) => self.new_request(w),
+            Action::SingletonQueue => {
+                let queue_id = self.service.queue_id.unwrap();
+                self.service.on_queue_ready(queue_id);
+                self.schedule_singleton_calls();
+            }
+            Action::Tick => {
+                self.flush_local_caches();
+                self.service.on_tick();
+                self.schedule_singleton_calls();
+                self.next_tick = self.step + TICK_STEPS;
+            }
+            Action::Deliver(i) => {
+                let pending = self.callouts.swap_remove(i);
+                self.deliver(pending.callout);
+            }
+            Action::ResumeWaiter(w) => self.resume_next_waiter(w),
+        }
+    }
+
+    fn new_request(&mut self, w: usize) {
+        let app_id = APPS[self.rng.gen_range(0..APPS.len())];
+        let context_id = self.next_context_id;
+        self.next_context_id += 1;
+        let host = &self.service.host;
+        set_request_headers(host, app_id);
+        self.workers[w].remaining -= 1;
+        let (worker_id, config, stats) = (self.workers[w].id, &self.config, &self.stats);
+        let (outcome, state) = run_worker(
+            self.seed,
+            host,
+            config,
+            &mut self.workers,
+            w,
+            self.rng.gen(),
+            context_id,
+            |host| {
+                let mut filter = new_filter(
+                    host,
+                    worker_id,
+                    config,
+                    stats,
+                    context_id,
+                    RequestState::default(),
+                );
+                filter.on_http_request_headers(0);
+                filter.state
+            },
+        );
+        let ledger = self.ledgers.entry(app_id.to_string()).or_default();
+        match outcome {
+            Outcome::Admitted => ledger.admitted += 1,
+            Outcome::Limited => ledger.limited += 1,
+            Outcome::Dispatched(token) => {
+                self.workers[w].contexts.push(SimContext {
+                    id: context_id,
+                    token,
+                    state,
+                });
+                self.callouts.push(PendingCallout {
+                    deliver_at: self.step + self.rng.gen_range(1..=MAX_CALLOUT_DELAY),
+                    callout: Callout::Filter { worker: w, token },
+                });
+            }
+            // Counted once resumed.
+            Outcome::Waiting => {}
+        }
+    }
+
+    // Resumes the waiter notified first through the thread-specific queue of the worker, as the
+    // root context of the worker does.
+    fn resume_next_waiter(&mut self, w: usize) {
+        let host = &self.service.host;
+        let worker = &self.workers[w];
+        let bytes = host.dequeue_shared_queue(worker.queue_id).unwrap().unwrap();
+        let message = bincode::deserialize::<WaiterAction>(&bytes).unwrap();
+        let context_id = match message {
+            WaiterAction::HandleCacheHit(ctxt_id) => ctxt_id,
+            WaiterAction::HandleFailure(ctxt_id, _) => ctxt_id,
+            WaiterAction::HandleRejection(ctxt_id, _) => ctxt_id,
+        };
+        let app_id = match worker.waiters.get(&context_id) {
+            Some(waiter) => waiter.state.cache_key.app_id().as_ref().to_string(),
+            None => panic!(
+                "seed {}: waiter {} notified more than once",
+                self.seed, context_id
+            ),
+        };
+        let (outcome, _) = run_worker(
+            self.seed,
+            host,
+            &self.config,
+            &mut self.workers,
+            w,
+            self.rng.gen(),
+            context_id,
+            |host| resume_waiter(host, message),
+        );
+        let ledger = self.ledgers.entry(app_id).or_default();
+        match outcome {
+            Outcome::Admitted => ledger.admitted += 1,
+            Outcome::Limited => ledger.limited += 1,
+            Outcome::Dispatched(_) | Outcome::Waiting => panic!(
+                "seed {}: waiter {} not answered once resumed",
+                self.seed, context_id
+            ),
+        }
+    }
+
+    // Writes the batches of hits that waited for max_batch_delay, as the root context of every
+    // worker does on each tick.
+    fn flush_local_caches(&mut self) {
+        let now = self.service.host.now();
+        for worker in self.workers.iter_mut() {
+            worker
+                .local_cache
+                .flush_due(&self.service.host, &now, MAX_TRIES);
+        }
+    }
+
+    // Schedules responses for the calls dispatched by the singleton. Calls are ordered by their
+    // content rather than by token, which depends on the iteration order of hash maps.
+    fn schedule_singleton_calls(&mut self) {
+        self.service.host.drain_calls();
+        let mut calls = self
+            .service
+            .report_requests
+            .iter()
+            .map(|(token, report)| (format!("report_{}", report.service_id()), *token))
+            .chain(
+                self.service
+                    .auth_requests
+                    .iter()
+                    .map(|(token, key)| (format!("auth_{}", key.as_string()), *token)),
+            )
+            .filter(|(_, token)| !self.scheduled_tokens.contains(token))
+            .collect::<Vec<_>>();
+        calls.sort();
+        for (_, token) in calls {
+            self.scheduled_tokens.insert(token);
+            self.callouts.push(PendingCallout {
+                deliver_at: self.step + self.rng.gen_range(1..=MAX_CALLOUT_DELAY),
+                callout: Callout::Singleton(token),
+            });
+        }
+    }
+
+    fn deliver(&mut self, callout: Callout) {
+        match callout {
+            Callout::Filter { worker, token } => self.deliver_filter_call(worker, token),
+            Callout::Singleton(token) => self.deliver_singleton_call(token),
+        }
+    }
+
+    // Emulates the backend answering the authorize call of a filter.
+    fn deliver_filter_call(&mut self, w: usize, token: u32) {
+        let worker = &mut self.workers[w];
+        let worker_id = worker.id;
+        let c = worker
+            .contexts
+            .iter()
+            .position(|context| context.token == token)
+            .unwrap();
+        let SimContext {
+            id: context_id,
+            state,
+            ..
+        } = worker.contexts.remove(c);
+        let app_id = state.cache_key.app_id().as_ref().to_string();
+        let ledger = self.ledgers.entry(app_id).or_default();
+        let (body, status) = authorize_response(&state.cache_key, ledger.reported);
+
+        let host = &self.service.host;
+        host.set_call_response(vec![(":status", status)], Some(body.as_bytes()));
+        let (config, stats) = (&self.config, &self.stats);
+        let (outcome, _) = run_worker(
+            self.seed,
+            host,
+            config,
+            &mut self.workers,
+            w,
+            self.rng.gen(),
+            context_id,
+            |host| {
+                let mut filter = new_filter(host, worker_id, config, stats, context_id, state);
+                filter.on_http_call_response(token, 1, body.len(), 0);
+            },
+        );
+        match outcome {
+            Outcome::Admitted => ledger.admitted += 1,
+            Outcome::Limited => ledger.limited += 1,
+            Outcome::Dispatched(_) | Outcome::Waiting => panic!(
+                "seed {}: request {} authorized twice",
+                self.seed, context_id
+            ),
+        }
+    }
+
+    // Emulates the backend answering a call of the singleton.
+    fn deliver_singleton_call(&mut self, token: u32) {
+        if let Some(report) = self.service.report_requests.get(&token) {
+            for (app_id, usages) in report.usages() {
+                let ledger = self.ledgers.entry(app_id.as_ref().to_string()).or_default();
+                for (metric, value) in usages {
+                    if metric == "hits" {
+                        ledger.reported += value.parse::<u64>().unwrap();
+                    }
+                }
+            }
+            let host = &self.service.host;
+            host.set_call_response(vec![(":status", "202")], Some(b""));
+            self.service.on_http_call_response(token, 1, 0, 0);
+            self.schedule_singleton_calls();
+        } else if let Some(cache_key) = self.service.auth_requests.get(&token).cloned() {
+            // The proxy handles the events of the singleton in order, so messages enqueued before
+            // the response arrived are consumed first.
+            let queue_id = self.service.queue_id.unwrap();
+            while self.service.host.queue_len(queue_id) > 0 {
+                self.service.on_queue_ready(queue_id);
+            }
+            self.schedule_singleton_calls();
+
+            let ledger = self
+                .ledgers
+                .entry(cache_key.app_id().as_ref().to_string())
+                .or_default();
+            let (body, status) = authorize_response(&cache_key, ledger.reported);
+            let host = &self.service.host;
+            host.set_call_response(vec![(":status", status)], Some(body.as_bytes()));
+            // Might dispatch queued authorize calls.
+            self.service.on_http_call_response(token, 1, body.len(), 0);
+            self.schedule_singleton_calls();
+        }
+    }
+
+    fn check_invariants(&self) {
+        for worker in self.workers.iter() {
+            assert!(
+                worker.contexts.is_empty(),
+                "seed {}: worker {} has requests never answered",
+                self.seed,
+                worker.id
+            );
+            assert!(
+                worker.waiters.is_empty(),
+                "seed {}: worker {} has waiters never resumed: {:?}",
+                self.seed,
+                worker.id,
+                worker.waiters.keys().collect::<Vec<_>>()
+            );
+        }
+        let handled: u64 = self
+            .ledgers
+            .values()
+            .map(|ledger| ledger.admitted + ledger.limited)
+            .sum();
+        assert_eq!(handled, WORKERS as u64 * REQUESTS_PER_WORKER as u64);
+
+        let max_admitted = max_admitted(&self.config);
+        for (app_id, ledger) in self.ledgers.iter() {
+            assert_eq!(
+                ledger.reported, ledger.admitted,
+                "seed {}: deltas of {} lost: {:?}",
+                self.seed, app_id, ledger
+            );
+            assert!(
+                ledger.admitted <= max_admitted,
+                "seed {}: {} admitted over {}: {:?}",
+                self.seed,
+                app_id,
+                max_admitted,
+                ledger
+            );
+        }
+    }
+}
+
+#[test]
+fn concurrent_workers_keep_invariants() {
+    let seeds = match std::env::var("SIMULATION_SEED") {
+        Ok(seed) => vec![seed.parse().expect("SIMULATION_SEED must be a number")],
+        Err(_) => (0..SEEDS).collect::<Vec<u64>>(),
+    };
+    let mut limited = 0;
+    for seed in seeds {
+        let simulation = Simulation::new(seed).run();
+        simulation.check_invariants();
+        limited += simulation
+            .ledgers
+            .values()
+            .map(|ledger| ledger.limited)
+            .sum::<u64>();
+    }
+    // Make sure that limits are actually reached by the simulated traffic.
+    assert!(limited > 0);
+}
+
+#[test]
+fn same_seed_replays_same_run() {
+    let outcome = |simulation: Simulation| {
+        let mut ledgers = simulation
+            .ledgers
+            .iter()
+            .map(|(app_id, ledger)| (app_id.clone(), ledger.admitted, ledger.limited))
+            .collect::<Vec<_>>();
+        ledgers.sort();
+        (simulation.step, ledgers)
+    };
+    assert_eq!(
+        outcome(Simulation::new(7).run()),
+        outcome(Simulation::new(7).run())
+    );
+}
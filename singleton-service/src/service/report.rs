@@ -1,4 +1,5 @@
-use log::debug;
+use crate::configuration::report::ReportConfig;
+use log::{debug, info};
 use std::collections::HashMap;
 use std::vec;
 use threescale::structs::AppIdentifier;
@@ -59,6 +60,73 @@ pub fn report<'a>(
     })
 }
 
+/// Size in bytes of the body of the report call the deltas of a service would be sent as.
+pub fn report_body_size(key: &str, apps: &HashMap<AppIdentifier, HashMap<String, u64>>) -> usize {
+    report(key, apps)
+        .and_then(|report| build_report_request(&report))
+        .map_or(0, |request| request.uri_and_body().1.map_or(0, str::len))
+}
+
+/// Builds the requests for the report, splitting it in chunks that respect the limits of 3scale
+/// backend on transactions per report and body size, since oversized reports fail as a whole.
+/// Each chunk is returned along with the request built for it.
+pub fn build_report_requests(
+    report: Report,
+    config: &ReportConfig,
+) -> Result<Vec<(Report, Request)>, anyhow::Error> {
+    let mut usages = report.usages.into_iter().collect::<Vec<_>>();
+    // Deterministic chunks, regardless of the iteration order of the hashmap.
+    usages.sort_by(|(a, _), (b, _)| a.as_ref().cmp(b.as_ref()));
+    let mut requests = Vec::new();
+    for chunk in usages.chunks(std::cmp::max(config.max_transactions, 1)) {
+        split_by_body_size(
+            &report.service_id,
+            &report.service_token,
+            chunk.to_vec(),
+            config.max_body_bytes,
+            &mut requests,
+        )?;
+    }
+    Ok(requests)
+}
+
+// Halves the chunk until the body of its request fits into max_body_bytes.
+fn split_by_body_size(
+    service_id: &str,
+    service_token: &str,
+    mut usages: Vec<(AppIdentifier, Vec<(String, String)>)>,
+    max_body_bytes: usize,
+    requests: &mut Vec<(Report, Request)>,
+) -> Result<(), anyhow::Error> {
+    let chunk = Report {
+        service_id: service_id.to_string(),
+        service_token: service_token.to_string(),
+        usages: usages.iter().cloned().collect(),
+    };
+    let request = build_report_request(&chunk)?;
+    let body_size = request.uri_and_body().1.map_or(0, str::len);
+    if body_size > max_body_bytes && usages.len() > 1 {
+        let second_half = usages.split_off(usages.len() / 2);
+        split_by_body_size(service_id, service_token, usages, max_body_bytes, requests)?;
+        split_by_body_size(
+            service_id,
+            service_token,
+            second_half,
+            max_body_bytes,
+            requests,
+        )
+    } else {
+        if body_size > max_body_bytes {
+            info!(
+                "report of a single application exceeds {} bytes: {}",
+                max_body_bytes, body_size
+            );
+        }
+        requests.push((chunk, request));
+        Ok(())
+    }
+}
+
 /// This method creates a request which is of type threescalers Report.
 pub fn build_report_request(report: &Report) -> Result<Request, anyhow::Error> {
     let creds = Credentials::ServiceToken(ServiceToken::from(report.service_token()));
@@ -97,3 +165,75 @@ pub fn build_report_request(report: &Report) -> Result<Request, anyhow::Error> {
         .build()?;
     Ok(Request::from(&api_call))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use threescale::structs::AppId;
+
+    fn report_for(apps: usize, metrics: usize) -> Report {
+        let mut deltas = HashMap::new();
+        for app in 0..apps {
+            let usage = (0..metrics)
+                .map(|metric| (format!("metric_{}", metric), 1))
+                .collect::<HashMap<_, _>>();
+            deltas.insert(
+                AppIdentifier::from(AppId::from(format!("app_{}", app).as_str())),
+                usage,
+            );
+        }
+        report("service_token", &deltas).unwrap()
+    }
+
+    fn chunk_sizes(requests: &[(Report, Request)]) -> Vec<usize> {
+        requests
+            .iter()
+            .map(|(chunk, _)| chunk.usages().len())
+            .collect()
+    }
+
+    #[test]
+    fn report_within_limits_is_not_split() {
+        let requests = build_report_requests(report_for(10, 2), &ReportConfig::default()).unwrap();
+        assert_eq!(chunk_sizes(&requests), vec![10]);
+    }
+
+    #[test]
+    fn report_is_split_by_transactions() {
+        let config = ReportConfig {
+            max_transactions: 4,
+            ..Default::default()
+        };
+        let requests = build_report_requests(report_for(10, 1), &config).unwrap();
+        assert_eq!(chunk_sizes(&requests), vec![4, 4, 2]);
+        for (chunk, _) in requests.iter() {
+            assert_eq!(chunk.service_id(), "service");
+            assert_eq!(chunk.service_token(), "token");
+        }
+    }
+
+    #[test]
+    fn report_is_split_by_body_size() {
+        let whole = build_report_requests(report_for(8, 5), &ReportConfig::default()).unwrap();
+        let whole_size = whole[0].1.uri_and_body().1.unwrap().len();
+        let config = ReportConfig {
+            max_body_bytes: whole_size / 3,
+            ..Default::default()
+        };
+        let requests = build_report_requests(report_for(8, 5), &config).unwrap();
+        assert_eq!(chunk_sizes(&requests).iter().sum::<usize>(), 8);
+        for (_, request) in requests.iter() {
+            assert!(request.uri_and_body().1.unwrap().len() <= config.max_body_bytes);
+        }
+    }
+
+    #[test]
+    fn oversized_single_transaction_is_sent_alone() {
+        let config = ReportConfig {
+            max_body_bytes: 1,
+            ..Default::default()
+        };
+        let requests = build_report_requests(report_for(3, 1), &config).unwrap();
+        assert_eq!(chunk_sizes(&requests), vec![1, 1, 1]);
+    }
+}
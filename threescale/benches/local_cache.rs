@@ -0,0 +1,144 @@
+/** Cost of a request on a cache hit, with and without the local cache:
+* Both benchmarks handle requests for a single application the way the cache filter does, reading
+* the application and checking its limits. Without the local cache, every request decodes the
+* application and writes it back encoded. With it, the application is only cloned from the local
+* copy and its hits are written once every flush_hits requests.
+* Shared data of MockHost is a map of the same thread, so the hostcalls and the lock shared by the
+* workers in Envoy are not measured: results compare the work of the filter, not Envoy latencies.
+*
+* Run with: cargo bench -p threescale --features mock_host
+**/
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::Duration;
+use threescale::host::mock::MockHost;
+use threescale::local_cache::{LocalCache, LocalCacheConfig};
+use threescale::proxy::{get_application_from_cache, set_application_to_cache, CacheKey};
+use threescale::structs::{
+    AppId, AppIdentifier, AppKey, Application, Period, PeriodWindow, ServiceId, ThreescaleData,
+    UsageReport,
+};
+use threescale::utils::{update_application_with_retries, LimitTolerance};
+
+const METRICS: [&str; 6] = ["hits", "get", "post", "put", "delete", "search"];
+const MAX_TRIES: u32 = 5;
+
+fn cache_key() -> CacheKey {
+    CacheKey::from(
+        &ServiceId::from("service"),
+        &AppIdentifier::from(AppId::from("app")),
+    )
+}
+
+// Application with a limit on every metric, hits being the parent of the other metrics.
+fn store_application(host: &MockHost) {
+    let now = host.now();
+    let mut local_state = HashMap::new();
+    for metric in METRICS.iter() {
+        for (window, seconds) in [(Period::Minute, 60), (Period::Day, 86400)].iter() {
+            local_state.insert(
+                format!("{}_{}", metric, seconds),
+                UsageReport {
+                    period_window: PeriodWindow {
+                        start: now,
+                        end: now + Duration::from_secs(*seconds),
+                        window: window.clone(),
+                    },
+                    left_hits: u64::MAX / 2,
+                    max_value: u64::MAX / 2,
+                    synced_left_hits: u64::MAX / 2,
+                },
+            );
+        }
+    }
+    let mut metric_hierarchy = HashMap::new();
+    metric_hierarchy.insert(
+        "hits".to_string(),
+        METRICS[1..]
+            .iter()
+            .map(|metric| metric.to_string())
+            .collect(),
+    );
+    let app = Application {
+        app_id: AppIdentifier::from(AppId::from("app")),
+        service_id: ServiceId::from("service"),
+        local_state,
+        metric_hierarchy,
+        app_keys: Some(vec![AppKey::from("key_1"), AppKey::from("key_2")]),
+        synced_at: now,
+        plan: None,
+    };
+    set_application_to_cache(host, &cache_key().as_string(), &app, 0).unwrap();
+}
+
+fn request() -> ThreescaleData {
+    ThreescaleData {
+        app_id: AppIdentifier::from(AppId::from("app")),
+        service_id: ServiceId::from("service"),
+        metrics: RefCell::new(
+            vec![("hits_60".to_string(), 1), ("get_60".to_string(), 1)]
+                .into_iter()
+                .collect(),
+        ),
+        ..Default::default()
+    }
+}
+
+fn shared_data_only(c: &mut Criterion) {
+    let host = MockHost::new();
+    store_application(&host);
+    let data = request();
+    let now = host.now();
+    c.bench_function("cache hit without local cache", |b| {
+        b.iter(|| {
+            let (mut app, cas) = get_application_from_cache(&host, &cache_key()).unwrap();
+            update_application_with_retries(
+                &host,
+                &data,
+                &cache_key(),
+                &mut app,
+                cas,
+                &now,
+                MAX_TRIES,
+                &LimitTolerance::default(),
+            )
+            .unwrap()
+        })
+    });
+}
+
+fn local_cache(c: &mut Criterion) {
+    let host = MockHost::new();
+    store_application(&host);
+    let data = request();
+    let now = host.now();
+    let mut cache = LocalCache::default();
+    cache.set_config(
+        &host,
+        LocalCacheConfig {
+            enabled: true,
+            ..Default::default()
+        },
+    );
+    c.bench_function("cache hit with local cache", |b| {
+        b.iter(|| {
+            let (mut app, cas) = cache.get_application(&host, &cache_key(), &now).unwrap();
+            cache
+                .update_application(
+                    &host,
+                    &data,
+                    &cache_key(),
+                    &mut app,
+                    cas,
+                    &now,
+                    MAX_TRIES,
+                    &LimitTolerance::default(),
+                )
+                .unwrap()
+        })
+    });
+}
+
+criterion_group!(benches, shared_data_only, local_cache);
+criterion_main!(benches);
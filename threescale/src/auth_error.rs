@@ -0,0 +1,293 @@
+use crate::host::Metrics;
+use log::debug;
+use proxy_wasm::types::MetricType;
+use serde::{Deserialize, Serialize};
+
+/** Reasoning behind typed authorize errors:
+* 3scale backend rejects an authorize call either with an error carrying a code (eg: an unknown
+* application) or with an unauthorized status carrying a reason (eg: exceeded limits). Both used to
+* be handled the same way, so a misconfigured service token evicted every cached application and
+* denied requests just like an invalid user key did. Rejections caused by the request are answered
+* with their own status, while those caused by the configuration of the gateway follow a failure
+* policy since no request could ever succeed with it. Every rejection is counted by its code.
+**/
+
+/// Reason of 3scale backend for rejecting an authorize call.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize, thiserror::Error)]
+pub enum AuthorizeError {
+    #[error("user key is invalid")]
+    UserKeyInvalid,
+    #[error("application was not found")]
+    ApplicationNotFound,
+    #[error("application key is invalid or missing")]
+    ApplicationKeyInvalid,
+    #[error("application is not active")]
+    ApplicationNotActive,
+    #[error("referrer is not allowed")]
+    ReferrerNotAllowed,
+    #[error("usage limits are exceeded")]
+    LimitsExceeded,
+    #[error("provider key is invalid")]
+    ProviderKeyInvalid,
+    #[error("service token is invalid")]
+    ServiceTokenInvalid,
+    #[error("service id is invalid or missing")]
+    ServiceIdInvalid,
+    #[error("metric is invalid")]
+    MetricInvalid,
+    #[error("usage value is invalid")]
+    UsageValueInvalid,
+    /// Error code or reason unknown to the gateway.
+    #[error("{0}")]
+    Other(String),
+}
+
+impl AuthorizeError {
+    /// Maps the code of an error response of 3scale backend to its error.
+    pub fn from_code(code: &str) -> Self {
+        match code {
+            "user_key_invalid" => AuthorizeError::UserKeyInvalid,
+            "application_not_found" => AuthorizeError::ApplicationNotFound,
+            "application_key_invalid" => AuthorizeError::ApplicationKeyInvalid,
+            "application_not_active" => AuthorizeError::ApplicationNotActive,
+            "referrer_not_allowed" => AuthorizeError::ReferrerNotAllowed,
+            "limits_exceeded" => AuthorizeError::LimitsExceeded,
+            "provider_key_invalid" => AuthorizeError::ProviderKeyInvalid,
+            "service_token_invalid" => AuthorizeError::ServiceTokenInvalid,
+            "service_id_invalid" | "service_id_missing" => AuthorizeError::ServiceIdInvalid,
+            "metric_invalid" => AuthorizeError::MetricInvalid,
+            "usage_value_invalid" => AuthorizeError::UsageValueInvalid,
+            _ => AuthorizeError::Other(code.to_string()),
+        }
+    }
+
+    /// Maps the reason of an unauthorized status to its error, eg: "usage limits are exceeded" or
+    /// "application key \"k\" is invalid".
+    pub fn from_reason(reason: &str) -> Self {
+        if reason.starts_with("usage limits are exceeded") {
+            AuthorizeError::LimitsExceeded
+        } else if reason.starts_with("application key") {
+            AuthorizeError::ApplicationKeyInvalid
+        } else if reason.starts_with("application is not active") {
+            AuthorizeError::ApplicationNotActive
+        } else if reason.starts_with("referrer") {
+            AuthorizeError::ReferrerNotAllowed
+        } else if reason.starts_with("user key") {
+            AuthorizeError::UserKeyInvalid
+        } else {
+            AuthorizeError::Other(reason.to_string())
+        }
+    }
+
+    /// Error code as used by 3scale backend, "other" for unknown codes and reasons.
+    pub fn code(&self) -> &str {
+        match self {
+            AuthorizeError::UserKeyInvalid => "user_key_invalid",
+            AuthorizeError::ApplicationNotFound => "application_not_found",
+            AuthorizeError::ApplicationKeyInvalid => "application_key_invalid",
+            AuthorizeError::ApplicationNotActive => "application_not_active",
+            AuthorizeError::ReferrerNotAllowed => "referrer_not_allowed",
+            AuthorizeError::LimitsExceeded => "limits_exceeded",
+            AuthorizeError::ProviderKeyInvalid => "provider_key_invalid",
+            AuthorizeError::ServiceTokenInvalid => "service_token_invalid",
+            AuthorizeError::ServiceIdInvalid => "service_id_invalid",
+            AuthorizeError::MetricInvalid => "metric_invalid",
+            AuthorizeError::UsageValueInvalid => "usage_value_invalid",
+            AuthorizeError::Other(_) => "other",
+        }
+    }
+
+    /// Whether the error lies in the configuration of the gateway (credentials of the service,
+    /// metrics of the mapping rules) rather than in the request.
+    pub fn is_configuration_error(&self) -> bool {
+        matches!(
+            self,
+            AuthorizeError::ProviderKeyInvalid
+                | AuthorizeError::ServiceTokenInvalid
+                | AuthorizeError::ServiceIdInvalid
+                | AuthorizeError::MetricInvalid
+                | AuthorizeError::UsageValueInvalid
+        )
+    }
+
+    /// Status of the response to a request rejected with this error.
+    pub fn http_status(&self) -> u32 {
+        match self {
+            AuthorizeError::LimitsExceeded => 429,
+            AuthorizeError::ApplicationNotFound => 404,
+            AuthorizeError::ProviderKeyInvalid
+            | AuthorizeError::ServiceTokenInvalid
+            | AuthorizeError::ServiceIdInvalid
+            | AuthorizeError::MetricInvalid
+            | AuthorizeError::UsageValueInvalid => 500,
+            _ => 403,
+        }
+    }
+
+    /// Whether the application can no longer be served from the cache.
+    pub fn evicts_application(&self) -> bool {
+        matches!(
+            self,
+            AuthorizeError::UserKeyInvalid
+                | AuthorizeError::ApplicationNotFound
+                | AuthorizeError::ApplicationNotActive
+        )
+    }
+
+    /// Whether every request of the application would be rejected with this error, whatever its
+    /// app key or referrer. Other errors only apply to the credentials of the rejected request.
+    pub fn applies_to_application(&self) -> bool {
+        matches!(
+            self,
+            AuthorizeError::UserKeyInvalid
+                | AuthorizeError::ApplicationNotFound
+                | AuthorizeError::ApplicationNotActive
+                | AuthorizeError::LimitsExceeded
+        )
+    }
+}
+
+/// Codes authorize calls rejected by 3scale are counted under, see AuthorizeError::code.
+pub const AUTH_ERROR_CODES: [&str; 12] = [
+    "user_key_invalid",
+    "application_not_found",
+    "application_key_invalid",
+    "application_not_active",
+    "referrer_not_allowed",
+    "limits_exceeded",
+    "provider_key_invalid",
+    "service_token_invalid",
+    "service_id_invalid",
+    "metric_invalid",
+    "usage_value_invalid",
+    "other",
+];
+
+/// Ids of the counters of rejected authorize calls, defined once along with the other stats so
+/// that counting a rejection is a single hostcall.
+#[derive(Clone, Copy, Debug)]
+pub struct AuthErrorStats([u32; AUTH_ERROR_CODES.len()]);
+
+impl AuthErrorStats {
+    /// Defines the counter of every code. With the current implementation of rust-sdk, it's safe
+    /// to directly unwrap define_metric().
+    pub fn define<H: Metrics>(host: &H) -> Self {
+        let mut metric_ids = [0; AUTH_ERROR_CODES.len()];
+        for (metric_id, code) in metric_ids.iter_mut().zip(AUTH_ERROR_CODES.iter()) {
+            *metric_id = host
+                .define_metric(MetricType::Counter, &auth_error_metric(code))
+                .unwrap();
+        }
+        AuthErrorStats(metric_ids)
+    }
+
+    fn metric_id(&self, error: &AuthorizeError) -> u32 {
+        let index = AUTH_ERROR_CODES
+            .iter()
+            .position(|code| *code == error.code())
+            .unwrap_or(AUTH_ERROR_CODES.len() - 1);
+        self.0[index]
+    }
+}
+
+/// Name of the counter of authorize calls rejected with the code.
+pub fn auth_error_metric(code: &str) -> String {
+    format!("envoy.3scale.auth_errors.{}", code)
+}
+
+/// Counts an authorize call rejected with the error.
+pub fn record_auth_error<H: Metrics>(host: &H, stats: &AuthErrorStats, error: &AuthorizeError) {
+    if let Err(e) = host.increment_metric(stats.metric_id(error), 1) {
+        debug!(
+            "Error incrementing {} metric: {:?}",
+            auth_error_metric(error.code()),
+            e
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::mock::MockHost;
+
+    #[test]
+    fn codes_and_reasons_are_mapped_to_errors() {
+        for code in [
+            "user_key_invalid",
+            "application_not_found",
+            "application_key_invalid",
+            "limits_exceeded",
+            "provider_key_invalid",
+            "service_token_invalid",
+            "metric_invalid",
+        ]
+        .iter()
+        {
+            assert_eq!(AuthorizeError::from_code(code).code(), *code);
+        }
+        assert_eq!(
+            AuthorizeError::from_code("service_id_missing"),
+            AuthorizeError::ServiceIdInvalid
+        );
+        assert_eq!(
+            AuthorizeError::from_code("oauth_not_enabled"),
+            AuthorizeError::Other("oauth_not_enabled".to_string())
+        );
+
+        assert_eq!(
+            AuthorizeError::from_reason("usage limits are exceeded"),
+            AuthorizeError::LimitsExceeded
+        );
+        assert_eq!(
+            AuthorizeError::from_reason("application key \"k\" is invalid"),
+            AuthorizeError::ApplicationKeyInvalid
+        );
+        let other = AuthorizeError::from_reason("something else");
+        assert_eq!(other.code(), "other");
+        assert_eq!(other.to_string(), "something else");
+    }
+
+    #[test]
+    fn rejections_are_counted_under_their_code() {
+        let host = MockHost::new();
+        let stats = AuthErrorStats::define(&host);
+        for code in AUTH_ERROR_CODES.iter() {
+            assert_eq!(host.metric_value(&auth_error_metric(code)), Some(0));
+        }
+
+        record_auth_error(&host, &stats, &AuthorizeError::LimitsExceeded);
+        record_auth_error(&host, &stats, &AuthorizeError::LimitsExceeded);
+        record_auth_error(&host, &stats, &AuthorizeError::Other("unknown".to_string()));
+        assert_eq!(
+            host.metric_value("envoy.3scale.auth_errors.limits_exceeded"),
+            Some(2)
+        );
+        assert_eq!(host.metric_value("envoy.3scale.auth_errors.other"), Some(1));
+        assert_eq!(
+            host.metric_value("envoy.3scale.auth_errors.application_not_found"),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn errors_of_the_gateway_are_told_apart_from_rejected_requests() {
+        let limited = AuthorizeError::LimitsExceeded;
+        assert_eq!(limited.http_status(), 429);
+        assert!(!limited.is_configuration_error());
+        assert!(!limited.evicts_application());
+        assert!(limited.applies_to_application());
+
+        let not_found = AuthorizeError::ApplicationNotFound;
+        assert_eq!(not_found.http_status(), 404);
+        assert!(not_found.evicts_application());
+
+        let app_key = AuthorizeError::ApplicationKeyInvalid;
+        assert!(!app_key.applies_to_application());
+        assert!(!AuthorizeError::ReferrerNotAllowed.applies_to_application());
+
+        let token = AuthorizeError::ServiceTokenInvalid;
+        assert!(token.is_configuration_error());
+        assert!(!token.evicts_application());
+    }
+}
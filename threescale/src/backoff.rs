@@ -0,0 +1,154 @@
+use crate::config::{ensure, humanized, record_change, ConfigError};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/** Reasoning behind backoff with jitter:
+* Retrying right after a failure, or at a fixed interval, keeps hitting a struggling backend and
+* keeps the retries of everything that failed together in lockstep. Delays between tries grow
+* exponentially with the consecutive failures and half of every delay is random, so that retries
+* spread out. Since wasm modules can't sleep, delays are waited for by checking that a retry is
+* due (see Backoff). CAS retries are not delayed at all: a wasm worker is single-threaded, so
+* spinning would not let the other workers progress. Writes that keep failing are instead handed
+* over to the singleton, which updates the cache in its place.
+**/
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(default, deny_unknown_fields)]
+pub struct BackoffConfig {
+    /// Delay after the first failure, doubled with every consecutive failure.
+    #[serde(with = "humanized")]
+    #[cfg_attr(feature = "schema", schemars(with = "String"))]
+    pub initial: Duration,
+    /// Limit of the delay between two tries.
+    #[serde(with = "humanized")]
+    #[cfg_attr(feature = "schema", schemars(with = "String"))]
+    pub max: Duration,
+}
+
+impl Default for BackoffConfig {
+    fn default() -> Self {
+        BackoffConfig {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+        }
+    }
+}
+
+impl BackoffConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        ensure(
+            self.initial > Duration::default(),
+            "initial",
+            "must be greater than 0",
+        )?;
+        ensure(
+            self.max >= self.initial,
+            "max",
+            "must not be lower than initial",
+        )
+    }
+
+    pub fn changes(&self, new: &Self) -> Vec<String> {
+        let mut changes = Vec::new();
+        record_change(&mut changes, "initial", &self.initial, &new.initial);
+        record_change(&mut changes, "max", &self.max, &new.max);
+        changes
+    }
+
+    /// Returns the delay before the next try after the given number of consecutive failures,
+    /// between half and the whole of initial * 2^(failures - 1), capped to max.
+    pub fn delay<R: RngCore + ?Sized>(&self, failures: u32, rng: &mut R) -> Duration {
+        let exponent = std::cmp::min(failures.saturating_sub(1), 31);
+        let cap = std::cmp::min(
+            self.initial.checked_mul(1 << exponent).unwrap_or(self.max),
+            self.max,
+        );
+        Duration::from_millis(jittered(cap.as_millis() as u64, rng))
+    }
+}
+
+// Random value between half of the cap and the cap.
+fn jittered<R: RngCore + ?Sized>(cap: u64, rng: &mut R) -> u64 {
+    let half = cap / 2;
+    half + rng.next_u64() % (cap - half + 1)
+}
+
+/// Consecutive failures of an operation retried with backoff and the time its next try is due.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Backoff {
+    failures: u32,
+    retry_at: Duration,
+}
+
+impl Backoff {
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Returns true if the operation can be tried at time now.
+    pub fn is_due(&self, now: &Duration) -> bool {
+        *now >= self.retry_at
+    }
+
+    /// Records a failure at time now and returns the delay before the next try.
+    pub fn record_failure<R: RngCore + ?Sized>(
+        &mut self,
+        config: &BackoffConfig,
+        now: &Duration,
+        rng: &mut R,
+    ) -> Duration {
+        self.failures = self.failures.saturating_add(1);
+        let delay = config.delay(self.failures, rng);
+        self.retry_at = *now + delay;
+        delay
+    }
+
+    pub fn record_success(&mut self) {
+        *self = Backoff::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_pcg::Pcg32;
+
+    #[test]
+    fn delays_grow_exponentially_with_jitter() {
+        let mut rng = Pcg32::seed_from_u64(7);
+        let config = BackoffConfig::default();
+        for (failures, cap) in [(1, 1), (2, 2), (3, 4), (6, 32), (7, 60), (40, 60)].iter() {
+            for _ in 0..20 {
+                let delay = config.delay(*failures, &mut rng);
+                assert!(delay >= Duration::from_secs(*cap) / 2, "{:?}", delay);
+                assert!(delay <= Duration::from_secs(*cap), "{:?}", delay);
+            }
+        }
+        let delays = (0..20)
+            .map(|_| config.delay(3, &mut rng))
+            .collect::<std::collections::HashSet<_>>();
+        assert!(delays.len() > 1);
+    }
+
+    #[test]
+    fn backoff_is_due_after_its_delay() {
+        let mut rng = Pcg32::seed_from_u64(7);
+        let config = BackoffConfig::default();
+        let now = Duration::from_secs(100);
+        let mut backoff = Backoff::default();
+        assert!(backoff.is_due(&now));
+
+        let delay = backoff.record_failure(&config, &now, &mut rng);
+        assert!(!backoff.is_due(&(now + delay - Duration::from_millis(1))));
+        assert!(backoff.is_due(&(now + delay)));
+        backoff.record_failure(&config, &now, &mut rng);
+        assert_eq!(backoff.failures(), 2);
+
+        backoff.record_success();
+        assert!(backoff.is_due(&now));
+        assert_eq!(backoff.failures(), 0);
+    }
+}
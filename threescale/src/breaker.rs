@@ -0,0 +1,480 @@
+use crate::config::{ensure, humanized, record_change, ConfigError};
+use crate::host::{Metrics, SharedData};
+use log::debug;
+use proxy_wasm::types::{MetricType, Status};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/** Reasoning behind the circuit breaker:
+* Every authorize and report call to an upstream that keeps failing holds a request (or a flush)
+* until its timeout, only to fail anyway. Each upstream gets a circuit stored in shared data, so
+* that all workers stop calling it as soon as one of them sees it fail repeatedly. Once open,
+* calls fail fast (callers fall back to their failure policies, e.g. serving from the cache) for
+* open_duration, after which a single worker sends a probe call with the circuit half-open. The
+* probe closes the circuit if it succeeds and opens it again otherwise. A probe without any result
+* for open_duration is considered lost and another one is let through. Every call is tagged with
+* the generation of the circuit it was let through in, which is increased by each probe, so that
+* late failures of calls sent before the probe (or of a lost probe) don't open the circuit again.
+* The circuit is also the only record of the health of 3scale backend: it is unhealthy from the
+* moment its circuit opens until a call succeeds. Outcomes are recorded even with the breaker
+* disabled, so that the use of stale applications doesn't depend on it, and every outcome costs a
+* single write at most.
+**/
+
+const CIRCUIT_KEY_PREFIX: &str = "circuit_";
+const MAX_UPDATE_TRIES: u32 = 5;
+const TIMEOUT_STATUS: &str = "504";
+
+#[derive(Debug, thiserror::Error)]
+pub enum BreakerError {
+    #[error("failed to serialize circuit: {0}")]
+    SerializeFail(bincode::ErrorKind),
+    #[error("failed to deserialize circuit: {0}")]
+    DeserializeFail(bincode::ErrorKind),
+    #[error("failure due to proxy's internal issue: {0:?}")]
+    ProxyFailure(Status),
+    #[error("circuit changed concurrently during {0} tries")]
+    UpdateTriesExhausted(u32),
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(default, deny_unknown_fields)]
+pub struct CircuitBreakerConfig {
+    /// Enables failing fast while the circuit is open. Outcomes are recorded regardless.
+    pub enabled: bool,
+    /// Consecutive failed calls (server errors, timeouts or dispatch failures) opening the circuit.
+    pub failure_threshold: u32,
+    /// Consecutive timed out calls opening the circuit.
+    pub timeout_threshold: u32,
+    /// Time the circuit stays open before a probe call is let through.
+    #[serde(with = "humanized")]
+    #[cfg_attr(feature = "schema", schemars(with = "String"))]
+    pub open_duration: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            enabled: true,
+            failure_threshold: 5,
+            timeout_threshold: 3,
+            open_duration: Duration::from_secs(30),
+        }
+    }
+}
+
+impl CircuitBreakerConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        ensure(
+            self.failure_threshold >= 1,
+            "failure_threshold",
+            "must be at least 1",
+        )?;
+        ensure(
+            self.timeout_threshold >= 1,
+            "timeout_threshold",
+            "must be at least 1",
+        )?;
+        ensure(
+            self.open_duration > Duration::default(),
+            "open_duration",
+            "must be greater than 0",
+        )
+    }
+
+    pub fn changes(&self, new: &Self) -> Vec<String> {
+        let mut changes = Vec::new();
+        record_change(&mut changes, "enabled", &self.enabled, &new.enabled);
+        record_change(
+            &mut changes,
+            "failure_threshold",
+            &self.failure_threshold,
+            &new.failure_threshold,
+        );
+        record_change(
+            &mut changes,
+            "timeout_threshold",
+            &self.timeout_threshold,
+            &new.timeout_threshold,
+        );
+        record_change(
+            &mut changes,
+            "open_duration",
+            &self.open_duration,
+            &new.open_duration,
+        );
+        changes
+    }
+}
+
+/// State of a circuit, exposed as a gauge with the value given by gauge_value().
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum CircuitState {
+    /// Calls are let through.
+    Closed,
+    /// Calls fail fast.
+    Open,
+    /// A single probe call was let through, others fail fast until its result.
+    HalfOpen,
+}
+
+impl CircuitState {
+    pub fn gauge_value(self) -> u64 {
+        match self {
+            CircuitState::Closed => 0,
+            CircuitState::Open => 1,
+            CircuitState::HalfOpen => 2,
+        }
+    }
+}
+
+/// Result of a call to an upstream.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CallOutcome {
+    Success,
+    Failure,
+    Timeout,
+}
+
+impl CallOutcome {
+    /// Outcome of a call answered with the status. Any status other than a server error means
+    /// the upstream is reachable.
+    pub fn from_status(status: &str) -> Self {
+        if status == TIMEOUT_STATUS {
+            CallOutcome::Timeout
+        } else if status.starts_with('5') {
+            CallOutcome::Failure
+        } else {
+            CallOutcome::Success
+        }
+    }
+}
+
+/// Circuit of an upstream, shared by all workers.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Circuit {
+    pub state: CircuitState,
+    /// Failed calls, timeouts included, since the last successful one.
+    pub consecutive_failures: u32,
+    /// Timed out calls since the last call with another outcome.
+    pub consecutive_timeouts: u32,
+    /// Time (since UNIX_EPOCH) the circuit was opened or the probe call was let through.
+    pub since: Duration,
+    /// Number of probe calls let through so far, kept when the circuit closes.
+    pub generation: u32,
+}
+
+impl Circuit {
+    /// Returns true if the upstream is unhealthy, which is the case from the moment its circuit
+    /// opens until a call succeeds.
+    pub fn is_unhealthy(&self) -> bool {
+        self.state != CircuitState::Closed
+    }
+}
+
+impl Default for Circuit {
+    fn default() -> Self {
+        Circuit {
+            state: CircuitState::Closed,
+            consecutive_failures: 0,
+            consecutive_timeouts: 0,
+            since: Duration::default(),
+            generation: 0,
+        }
+    }
+}
+
+/// Name of the gauge exposing the state of the circuit of the upstream.
+pub fn circuit_state_metric(upstream: &str) -> String {
+    format!("envoy.3scale.circuit_breaker.{}.state", upstream)
+}
+
+fn circuit_key(upstream: &str) -> String {
+    format!("{}{}", CIRCUIT_KEY_PREFIX, upstream)
+}
+
+/// Returns the circuit of the upstream stored in shared data along with its CAS. An upstream
+/// without recorded calls has a closed circuit.
+pub fn get_circuit<H: SharedData>(
+    host: &H,
+    upstream: &str,
+) -> Result<(Circuit, Option<u32>), BreakerError> {
+    match host.get_shared_data(&circuit_key(upstream)) {
+        Ok((Some(bytes), cas)) => match bincode::deserialize::<Circuit>(&bytes) {
+            Ok(circuit) => Ok((circuit, cas)),
+            Err(e) => Err(BreakerError::DeserializeFail(*e)),
+        },
+        Ok((None, cas)) => Ok((Circuit::default(), cas)),
+        Err(e) => Err(BreakerError::ProxyFailure(e)),
+    }
+}
+
+// Applies the transition to the stored circuit, retrying when it changed concurrently. The
+// transition returns false when the circuit is left as it is. Returns the resulting circuit and
+// whether the transition changed it.
+fn update_circuit<H, F>(
+    host: &H,
+    upstream: &str,
+    transition: F,
+) -> Result<(Circuit, bool), BreakerError>
+where
+    H: SharedData + Metrics,
+    F: Fn(&mut Circuit) -> bool,
+{
+    let key = circuit_key(upstream);
+    for _ in 0..MAX_UPDATE_TRIES {
+        let (mut circuit, cas) = get_circuit(host, upstream)?;
+        let previous_state = circuit.state;
+        if !transition(&mut circuit) {
+            return Ok((circuit, false));
+        }
+        let bytes = match bincode::serialize(&circuit) {
+            Ok(res) => res,
+            Err(e) => return Err(BreakerError::SerializeFail(*e)),
+        };
+        match host.set_shared_data(&key, Some(&bytes), cas) {
+            Ok(()) => {
+                if circuit.state != previous_state {
+                    record_state(host, upstream, circuit.state);
+                }
+                return Ok((circuit, true));
+            }
+            Err(Status::CasMismatch) => continue,
+            Err(e) => return Err(BreakerError::ProxyFailure(e)),
+        }
+    }
+    Err(BreakerError::UpdateTriesExhausted(MAX_UPDATE_TRIES))
+}
+
+// Metrics are shared by all workers, so only the worker changing the circuit records its state.
+fn record_state<H: Metrics>(host: &H, upstream: &str, state: CircuitState) {
+    let name = circuit_state_metric(upstream);
+    if let Err(error) = host
+        .define_metric(MetricType::Gauge, &name)
+        .and_then(|metric_id| host.record_metric(metric_id, state.gauge_value()))
+    {
+        debug!("Error recording {} metric: {:?}", name, error);
+    }
+}
+
+/// Returns the generation to tag a call to the upstream with if it can be dispatched at time now,
+/// None otherwise. While the circuit is open, the first caller after open_duration gets to send
+/// the probe call.
+pub fn acquire_call<H: SharedData + Metrics>(
+    host: &H,
+    upstream: &str,
+    config: &CircuitBreakerConfig,
+    now: &Duration,
+) -> Result<Option<u32>, BreakerError> {
+    if !config.enabled {
+        return Ok(Some(0));
+    }
+    let (circuit, probe) = update_circuit(host, upstream, |circuit| {
+        if circuit.state == CircuitState::Closed
+            || now.checked_sub(circuit.since).unwrap_or_default() < config.open_duration
+        {
+            return false;
+        }
+        circuit.state = CircuitState::HalfOpen;
+        circuit.since = *now;
+        circuit.generation = circuit.generation.wrapping_add(1);
+        true
+    })?;
+    if probe {
+        debug!("Sending probe call to upstream {}", upstream);
+    }
+    Ok(Some(circuit.generation).filter(|_| circuit.state == CircuitState::Closed || probe))
+}
+
+/// Records the outcome at time now of a call to the upstream tagged with the generation given by
+/// acquire_call, and returns the updated circuit.
+pub fn record_call_outcome<H: SharedData + Metrics>(
+    host: &H,
+    upstream: &str,
+    config: &CircuitBreakerConfig,
+    outcome: CallOutcome,
+    generation: u32,
+    now: &Duration,
+) -> Result<Circuit, BreakerError> {
+    let (circuit, _) = update_circuit(host, upstream, |circuit| {
+        let closed = Circuit {
+            generation: circuit.generation,
+            ..Default::default()
+        };
+        if outcome == CallOutcome::Success {
+            // Closed circuits are not written on every successful call.
+            if *circuit == closed {
+                return false;
+            }
+            *circuit = closed;
+            return true;
+        }
+        circuit.consecutive_failures = circuit.consecutive_failures.saturating_add(1);
+        circuit.consecutive_timeouts = match outcome {
+            CallOutcome::Timeout => circuit.consecutive_timeouts.saturating_add(1),
+            _ => 0,
+        };
+        // Results of calls sent before the circuit opened don't extend its open time, only the
+        // probe opens a half-open circuit again.
+        if (circuit.state == CircuitState::HalfOpen && generation == circuit.generation)
+            || (circuit.state == CircuitState::Closed
+                && (circuit.consecutive_failures >= config.failure_threshold
+                    || circuit.consecutive_timeouts >= config.timeout_threshold))
+        {
+            circuit.state = CircuitState::Open;
+            circuit.since = *now;
+        }
+        true
+    })?;
+    Ok(circuit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::mock::MockHost;
+
+    const UPSTREAM: &str = "outbound|443||su1.3scale.net";
+
+    fn now(secs: u64) -> Duration {
+        Duration::from_secs(100 + secs)
+    }
+
+    fn record(host: &MockHost, outcome: CallOutcome, generation: u32, secs: u64) -> Circuit {
+        record_call_outcome(
+            host,
+            UPSTREAM,
+            &CircuitBreakerConfig::default(),
+            outcome,
+            generation,
+            &now(secs),
+        )
+        .unwrap()
+    }
+
+    fn acquire(host: &MockHost, secs: u64) -> Option<u32> {
+        acquire_call(host, UPSTREAM, &CircuitBreakerConfig::default(), &now(secs)).unwrap()
+    }
+
+    fn gauge(host: &MockHost) -> Option<i64> {
+        host.metric_value(&circuit_state_metric(UPSTREAM))
+    }
+
+    #[test]
+    fn circuit_opens_after_consecutive_failures_or_timeouts() {
+        let host = MockHost::new();
+        for secs in 0..4 {
+            record(&host, CallOutcome::Failure, 0, secs);
+        }
+        record(&host, CallOutcome::Success, 0, 4);
+        record(&host, CallOutcome::Timeout, 0, 5);
+        record(&host, CallOutcome::Timeout, 0, 6);
+        record(&host, CallOutcome::Failure, 0, 7);
+        assert_eq!(
+            record(&host, CallOutcome::Timeout, 0, 8).state,
+            CircuitState::Closed
+        );
+        assert_eq!(acquire(&host, 8), Some(0));
+        assert_eq!(gauge(&host), None);
+
+        assert_eq!(
+            record(&host, CallOutcome::Failure, 0, 9).state,
+            CircuitState::Open
+        );
+        assert_eq!(acquire(&host, 9), None);
+        assert_eq!(gauge(&host), Some(1));
+
+        let host = MockHost::new();
+        for secs in 0..3 {
+            record(&host, CallOutcome::Timeout, 0, secs);
+        }
+        assert_eq!(acquire(&host, 3), None);
+    }
+
+    #[test]
+    fn open_circuit_is_probed_once_per_open_duration() {
+        let host = MockHost::new();
+        for _ in 0..3 {
+            record(&host, CallOutcome::Timeout, 0, 0);
+        }
+        // Late results of calls sent before the circuit opened don't keep it open longer.
+        record(&host, CallOutcome::Failure, 0, 10);
+        assert_eq!(acquire(&host, 29), None);
+        assert_eq!(acquire(&host, 30), Some(1));
+        assert_eq!(gauge(&host), Some(2));
+        assert_eq!(acquire(&host, 31), None);
+
+        // Lost probe is replaced by another one.
+        assert_eq!(acquire(&host, 60), Some(2));
+        assert_eq!(
+            record(&host, CallOutcome::Failure, 2, 61).state,
+            CircuitState::Open
+        );
+        assert_eq!(acquire(&host, 62), None);
+        assert_eq!(gauge(&host), Some(1));
+
+        assert_eq!(acquire(&host, 91), Some(3));
+        let closed = record(&host, CallOutcome::Success, 3, 92);
+        assert!(!closed.is_unhealthy());
+        assert_eq!((closed.consecutive_failures, closed.generation), (0, 3));
+        assert_eq!(acquire(&host, 92), Some(3));
+        assert_eq!(gauge(&host), Some(0));
+    }
+
+    #[test]
+    fn only_the_probe_opens_a_half_open_circuit_again() {
+        let host = MockHost::new();
+        for _ in 0..3 {
+            record(&host, CallOutcome::Timeout, 0, 0);
+        }
+        let probe = acquire(&host, 30).unwrap();
+        // Calls sent before the circuit opened and the lost probes of former generations.
+        assert_eq!(
+            record(&host, CallOutcome::Timeout, 0, 31).state,
+            CircuitState::HalfOpen
+        );
+        assert_eq!(
+            record(&host, CallOutcome::Failure, probe - 1, 32).state,
+            CircuitState::HalfOpen
+        );
+        assert_eq!(acquire(&host, 32), None);
+
+        assert_eq!(
+            record(&host, CallOutcome::Failure, probe, 33).state,
+            CircuitState::Open
+        );
+        assert_eq!(acquire(&host, 33), None);
+    }
+
+    #[test]
+    fn health_follows_the_circuit_even_when_disabled() {
+        let host = MockHost::new();
+        let config = CircuitBreakerConfig {
+            enabled: false,
+            ..Default::default()
+        };
+        for secs in 0..3 {
+            let circuit = record_call_outcome(
+                &host,
+                UPSTREAM,
+                &config,
+                CallOutcome::Timeout,
+                0,
+                &now(secs),
+            )
+            .unwrap();
+            assert_eq!(circuit.is_unhealthy(), secs == 2);
+        }
+        assert!(get_circuit(&host, UPSTREAM).unwrap().0.is_unhealthy());
+        assert_eq!(
+            acquire_call(&host, UPSTREAM, &config, &now(3)).unwrap(),
+            Some(0)
+        );
+
+        let circuit =
+            record_call_outcome(&host, UPSTREAM, &config, CallOutcome::Success, 0, &now(4))
+                .unwrap();
+        assert!(!circuit.is_unhealthy());
+    }
+}
@@ -0,0 +1,126 @@
+use std::fmt::Debug;
+
+/// Reason why a configuration was rejected. Rejected configurations are never applied, the
+/// configuration in use is kept instead.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum ConfigError {
+    #[error("failed to parse configuration: {0}")]
+    ParseFail(String),
+    #[error("invalid value for {field}: {reason}")]
+    InvalidValue { field: String, reason: &'static str },
+}
+
+impl ConfigError {
+    // Prefixes the field of an invalid value with the name of the field holding it.
+    pub fn nested(self, field: &str) -> Self {
+        match self {
+            ConfigError::InvalidValue {
+                field: nested,
+                reason,
+            } => ConfigError::InvalidValue {
+                field: format!("{}.{}", field, nested),
+                reason,
+            },
+            e => e,
+        }
+    }
+}
+
+// Returns an InvalidValue error for the field unless the condition holds.
+pub fn ensure(condition: bool, field: &str, reason: &'static str) -> Result<(), ConfigError> {
+    if condition {
+        Ok(())
+    } else {
+        Err(ConfigError::InvalidValue {
+            field: field.to_string(),
+            reason,
+        })
+    }
+}
+
+// Records the change of a configuration field as "field: old -> new", if its value changed.
+pub fn record_change<T: PartialEq + Debug>(
+    changes: &mut Vec<String>,
+    field: &str,
+    old: &T,
+    new: &T,
+) {
+    if old != new {
+        changes.push(format!("{}: {:?} -> {:?}", field, old, new));
+    }
+}
+
+// Prefixes the changes of a nested configuration with the name of the field holding it.
+pub fn nested_changes(changes: &mut Vec<String>, field: &str, nested: Vec<String>) {
+    changes.extend(
+        nested
+            .into_iter()
+            .map(|change| format!("{}.{}", field, change)),
+    );
+}
+
+/// Durations of configurations, written as the human readable strings serde_humanize_rs parses.
+pub mod humanized {
+    use serde::Serializer;
+    use std::time::Duration;
+
+    pub use serde_humanize_rs::deserialize;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format_duration(duration))
+    }
+
+    // Writes the duration in the biggest unit that keeps it exact.
+    pub fn format_duration(duration: &Duration) -> String {
+        if duration.subsec_nanos() == 0 {
+            format!("{}s", duration.as_secs())
+        } else if duration.subsec_nanos() % 1_000_000 == 0 {
+            format!("{}ms", duration.as_millis())
+        } else {
+            format!("{}ns", duration.as_nanos())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn only_changed_fields_are_recorded() {
+        let mut changes = Vec::new();
+        record_change(&mut changes, "max_tries", &5, &5);
+        record_change(
+            &mut changes,
+            "lease",
+            &Duration::from_secs(5),
+            &Duration::from_secs(10),
+        );
+        nested_changes(&mut changes, "limits", vec!["hits: 1 -> 2".to_string()]);
+        assert_eq!(changes, vec!["lease: 5s -> 10s", "limits.hits: 1 -> 2"]);
+    }
+
+    #[test]
+    fn failed_condition_names_the_field() {
+        assert_eq!(ensure(true, "max_tries", "must be at least 1"), Ok(()));
+        assert_eq!(
+            ensure(false, "max_tries", "must be at least 1")
+                .unwrap_err()
+                .nested("config")
+                .to_string(),
+            "invalid value for config.max_tries: must be at least 1"
+        );
+    }
+
+    #[test]
+    fn durations_are_written_in_exact_units() {
+        use humanized::format_duration;
+        assert_eq!(format_duration(&Duration::from_secs(60)), "60s");
+        assert_eq!(format_duration(&Duration::from_millis(1500)), "1500ms");
+        assert_eq!(
+            format_duration(&Duration::from_nanos(1_000_001)),
+            "1000001ns"
+        );
+    }
+}
@@ -0,0 +1,680 @@
+use crate::host::SharedData;
+use crate::proxy::CacheError;
+use crate::structs::{
+    AppIdentifier, AppKey, Application, Hierarchy, Period, PeriodWindow, ServiceId, UsageReport,
+};
+use bincode::Options;
+use proxy_wasm::types::Status;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/** Reasoning behind the compact encoding of applications:
+* Applications used to be stored as the bincode encoding of Application, which repeats the name of
+* every metric and the whole metric hierarchy in each cached application of a service. Metric
+* names and the hierarchy are now stored once per service, under "SM_{service_id}", and the usages
+* of an application refer to their metric by its index in that list, with times stored as whole
+* seconds. Names are only ever appended to the list of a service, so the indexes of applications
+* encoded before stay valid.
+* Records in the former layout start with the variant index of AppIdentifier, never with
+* COMPACT_TAG, so they are still decoded and get rewritten compactly with their next update.
+* Every thread remembers the metrics of the services it read along with their CAS, so that SM_ is
+* only deserialized when its CAS changed since, i.e. when any thread stored new metrics, and only
+* written when an application brings new metrics.
+**/
+
+/** Reasoning behind the baseline layout:
+* Shared data outlives the module when the proxy is upgraded, so applications cached by the
+* baseline release are still decoded, with the fields added since then defaulted. Compact records
+* are told apart from those by COMPACT_TAG.
+**/
+
+/** Reasoning behind the hierarchy of a service:
+* Authorize responses only describe the hierarchy of the metrics the application has limits on, so
+* the hierarchy of a service is the union of those seen in the responses for any of its
+* applications. A new application gets the parent metrics of its requests counted from its first
+* request, even if its own response lacks part of the hierarchy. Since a union never forgets
+* metrics removed from the service, the first response once the hierarchy is older than the
+* refresh period replaces it instead of being merged into it. Applications of the service keep
+* being authorized periodically, so the hierarchy gets refreshed as long as there's traffic.
+**/
+
+const COMPACT_TAG: u8 = 0xCA;
+const SERVICE_METRICS_PREFIX: &str = "SM_";
+const MAX_UPDATE_TRIES: u32 = 5;
+
+thread_local! {
+    // Metrics of the services last read by this thread, by service id.
+    static KNOWN_METRICS: RefCell<HashMap<String, KnownMetrics>> = RefCell::new(HashMap::new());
+}
+
+struct KnownMetrics {
+    metrics: ServiceMetrics,
+    // CAS of the stored metrics when they were read.
+    cas: u32,
+}
+
+/// Metrics of a service shared by all of its cached applications.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ServiceMetrics {
+    /// Names of the metrics, each one encoded as its index.
+    pub names: Vec<String>,
+    pub hierarchy: Hierarchy,
+    /// Time the hierarchy was last replaced by the one of an authorize response.
+    pub hierarchy_synced_at: Duration,
+}
+
+impl ServiceMetrics {
+    /// Returns true if the metric or method is one of the service, either limited by some
+    /// application or part of the hierarchy.
+    pub fn is_known(&self, metric: &str) -> bool {
+        self.names.iter().any(|name| name == metric) || is_in_hierarchy(&self.hierarchy, metric)
+    }
+
+    /// Returns true if no metric of the service is known.
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty() && self.hierarchy.is_empty()
+    }
+}
+
+/// Returns true if the metric is a parent or a child in the hierarchy.
+pub fn is_in_hierarchy(hierarchy: &Hierarchy, metric: &str) -> bool {
+    hierarchy.contains_key(metric)
+        || hierarchy
+            .values()
+            .any(|children| children.iter().any(|child| child == metric))
+}
+
+#[derive(Serialize, Deserialize)]
+struct CompactUsage {
+    metric: u32,
+    window: Period,
+    start: u64,
+    end: u64,
+    left_hits: u64,
+    max_value: u64,
+    synced_left_hits: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CompactApplication {
+    app_id: AppIdentifier,
+    service_id: ServiceId,
+    app_keys: Option<Vec<AppKey>>,
+    synced_at: u64,
+    usages: Vec<CompactUsage>,
+    plan: Option<String>,
+}
+
+// Bincode options of every layout, with trailing bytes rejected so that a record in practice only
+// fits the layout it was written with.
+fn layout_options() -> impl Options + Copy {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .reject_trailing_bytes()
+}
+
+// Usage as stored before synced_left_hits. Hits consumed locally since the last sync are unknown,
+// so the usage counts as synced.
+#[derive(Deserialize)]
+struct BaselineUsageReport {
+    period_window: PeriodWindow,
+    left_hits: u64,
+    max_value: u64,
+}
+
+impl From<BaselineUsageReport> for UsageReport {
+    fn from(baseline: BaselineUsageReport) -> Self {
+        UsageReport {
+            period_window: baseline.period_window,
+            left_hits: baseline.left_hits,
+            max_value: baseline.max_value,
+            synced_left_hits: baseline.left_hits,
+        }
+    }
+}
+
+// Application as stored before synced_left_hits.
+#[derive(Deserialize)]
+struct BaselineApplication {
+    app_id: AppIdentifier,
+    service_id: ServiceId,
+    local_state: HashMap<String, BaselineUsageReport>,
+    metric_hierarchy: Hierarchy,
+    app_keys: Option<Vec<AppKey>>,
+}
+
+impl From<BaselineApplication> for Application {
+    fn from(baseline: BaselineApplication) -> Self {
+        Application {
+            app_id: baseline.app_id,
+            service_id: baseline.service_id,
+            local_state: baseline
+                .local_state
+                .into_iter()
+                .map(|(metric, usage)| (metric, UsageReport::from(usage)))
+                .collect(),
+            metric_hierarchy: baseline.metric_hierarchy,
+            app_keys: baseline.app_keys,
+            // Never synced as far as this release knows.
+            synced_at: Duration::default(),
+            plan: None,
+        }
+    }
+}
+
+fn service_metrics_key(service_id: &ServiceId) -> String {
+    format!("{}{}", SERVICE_METRICS_PREFIX, service_id.as_ref())
+}
+
+// Applies f to the metrics stored for the service along with their CAS. The metrics remembered by
+// this thread are used as long as the stored ones keep the same CAS.
+fn with_service_metrics<H, T, F>(host: &H, service_id: &ServiceId, f: F) -> Result<T, CacheError>
+where
+    H: SharedData,
+    F: FnOnce(&ServiceMetrics, Option<u32>) -> T,
+{
+    let (bytes, cas) = match host.get_shared_data(&service_metrics_key(service_id)) {
+        Ok((Some(bytes), cas)) => (bytes, cas),
+        Ok((None, cas)) => return Ok(f(&ServiceMetrics::default(), cas)),
+        Err(e) => return Err(CacheError::ProxyStatus(e as u8)),
+    };
+    KNOWN_METRICS.with(|known| {
+        let mut known = known.borrow_mut();
+        if let Some(entry) = known.get(service_id.as_ref()) {
+            if Some(entry.cas) == cas {
+                return Ok(f(&entry.metrics, cas));
+            }
+        }
+        let metrics = match bincode::deserialize::<ServiceMetrics>(&bytes) {
+            Ok(metrics) => metrics,
+            Err(e) => return Err(CacheError::DeserializeFail(*e)),
+        };
+        let res = f(&metrics, cas);
+        if let Some(cas) = cas {
+            known.insert(
+                service_id.as_ref().to_string(),
+                KnownMetrics { metrics, cas },
+            );
+        }
+        Ok(res)
+    })
+}
+
+/// Returns the metrics stored for the service along with their CAS. A service without stored
+/// metrics has none.
+pub fn get_service_metrics<H: SharedData>(
+    host: &H,
+    service_id: &ServiceId,
+) -> Result<(ServiceMetrics, Option<u32>), CacheError> {
+    with_service_metrics(host, service_id, |metrics, cas| (metrics.clone(), cas))
+}
+
+// Applies the change to the metrics of the service until it is stored, returning the metrics
+// stored. The change returns false when there is nothing to store.
+fn update_service_metrics<H, F>(
+    host: &H,
+    service_id: &ServiceId,
+    mut change: F,
+) -> Result<ServiceMetrics, CacheError>
+where
+    H: SharedData,
+    F: FnMut(&mut ServiceMetrics) -> bool,
+{
+    let key = service_metrics_key(service_id);
+    for _ in 0..MAX_UPDATE_TRIES {
+        let (mut metrics, cas) = get_service_metrics(host, service_id)?;
+        if !change(&mut metrics) {
+            return Ok(metrics);
+        }
+        let bytes = match bincode::serialize(&metrics) {
+            Ok(res) => res,
+            Err(_) => return Err(CacheError::SerializeFail),
+        };
+        // Note: Like with leases, a non-zero CAS makes a concurrent first insertion fail in the
+        // usual case. Either way the metrics are read again to confirm they were stored.
+        match host.set_shared_data(&key, Some(&bytes), Some(cas.unwrap_or(1))) {
+            Ok(()) | Err(Status::CasMismatch) => continue,
+            Err(e) => return Err(CacheError::ProxyStatus(e as u8)),
+        }
+    }
+    Err(CacheError::ServiceMetricsUpdateFail(MAX_UPDATE_TRIES))
+}
+
+// Returns the metrics of the service once every metric of the application is part of them. A
+// service without hierarchy takes the one of the application, e.g. of an application cached in the
+// former layout.
+fn register_metrics<H: SharedData>(
+    host: &H,
+    app: &Application,
+) -> Result<ServiceMetrics, CacheError> {
+    update_service_metrics(host, &app.service_id, |metrics| {
+        let mut missing = app
+            .local_state
+            .keys()
+            .filter(|name| !metrics.names.contains(name))
+            .cloned()
+            .collect::<Vec<_>>();
+        let adopt_hierarchy = metrics.hierarchy.is_empty() && !app.metric_hierarchy.is_empty();
+        if missing.is_empty() && !adopt_hierarchy {
+            return false;
+        }
+        missing.sort();
+        metrics.names.append(&mut missing);
+        if adopt_hierarchy {
+            metrics.hierarchy = app.metric_hierarchy.clone();
+        }
+        true
+    })
+}
+
+/// Records the hierarchy of an authorize response for the service and returns the hierarchy of
+/// the service. The hierarchy is merged into the stored one, unless that one was synced more than
+/// refresh ago, in which case it gets replaced.
+pub fn update_service_hierarchy<H: SharedData>(
+    host: &H,
+    service_id: &ServiceId,
+    hierarchy: &Hierarchy,
+    now: &Duration,
+    refresh: &Duration,
+) -> Result<Hierarchy, CacheError> {
+    let metrics = update_service_metrics(host, service_id, |metrics| {
+        if now
+            .checked_sub(metrics.hierarchy_synced_at)
+            .unwrap_or_default()
+            >= *refresh
+        {
+            metrics.hierarchy = hierarchy.clone();
+            metrics.hierarchy_synced_at = *now;
+            return true;
+        }
+        merge_hierarchy(&mut metrics.hierarchy, hierarchy)
+    })?;
+    Ok(metrics.hierarchy)
+}
+
+/// Merges the hierarchy of an authorize response into the one of the service and returns the
+/// hierarchy of the service. It is never replaced here, whatever its age: only the singleton, which
+/// owns the refresh of hierarchies, replaces it through update_service_hierarchy.
+pub fn merge_service_hierarchy<H: SharedData>(
+    host: &H,
+    service_id: &ServiceId,
+    hierarchy: &Hierarchy,
+) -> Result<Hierarchy, CacheError> {
+    let metrics = update_service_metrics(host, service_id, |metrics| {
+        merge_hierarchy(&mut metrics.hierarchy, hierarchy)
+    })?;
+    Ok(metrics.hierarchy)
+}
+
+// Adds the children missing in the hierarchy, returning true if any was.
+fn merge_hierarchy(hierarchy: &mut Hierarchy, other: &Hierarchy) -> bool {
+    let mut changed = false;
+    for (parent, children) in other.iter() {
+        let known = hierarchy.entry(parent.clone()).or_insert_with(|| {
+            changed = true;
+            Vec::new()
+        });
+        for child in children.iter() {
+            if !known.contains(child) {
+                known.push(child.clone());
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+// Index of every metric of the application in the names of its service, None if any is missing.
+fn metric_indexes<'a>(names: &[String], app: &'a Application) -> Option<HashMap<&'a str, u32>> {
+    app.local_state
+        .keys()
+        .map(|name| {
+            let index = names.iter().position(|known| known == name)?;
+            Some((name.as_str(), index as u32))
+        })
+        .collect()
+}
+
+/// Encodes the application compactly, registering its metrics in those of its service.
+pub fn encode_application<H: SharedData>(
+    host: &H,
+    app: &Application,
+) -> Result<Vec<u8>, CacheError> {
+    let known = with_service_metrics(host, &app.service_id, |metrics, _| {
+        // Hierarchy of the application is adopted by a service without one.
+        if metrics.hierarchy.is_empty() && !app.metric_hierarchy.is_empty() {
+            return None;
+        }
+        metric_indexes(&metrics.names, app)
+    })?;
+    let indexes = match known {
+        Some(indexes) => indexes,
+        None => {
+            let metrics = register_metrics(host, app)?;
+            metric_indexes(&metrics.names, app)
+                .ok_or(CacheError::ServiceMetricsUpdateFail(MAX_UPDATE_TRIES))?
+        }
+    };
+    let mut usages = app
+        .local_state
+        .iter()
+        .map(|(name, usage)| CompactUsage {
+            metric: indexes[name.as_str()],
+            window: usage.period_window.window.clone(),
+            start: usage.period_window.start.as_secs(),
+            end: usage.period_window.end.as_secs(),
+            left_hits: usage.left_hits,
+            max_value: usage.max_value,
+            synced_left_hits: usage.synced_left_hits,
+        })
+        .collect::<Vec<_>>();
+    usages.sort_by_key(|usage| usage.metric);
+    let compact = CompactApplication {
+        app_id: app.app_id.clone(),
+        service_id: app.service_id.clone(),
+        app_keys: app.app_keys.clone(),
+        synced_at: app.synced_at.as_secs(),
+        usages,
+        plan: app.plan.clone(),
+    };
+    let mut bytes = vec![COMPACT_TAG];
+    match bincode::serialize_into(&mut bytes, &compact) {
+        Ok(()) => Ok(bytes),
+        Err(_) => Err(CacheError::SerializeFail),
+    }
+}
+
+// Names of the metrics of the usages along with the hierarchy of the service, or the index of the
+// first metric unknown to the service.
+fn resolve_metrics(
+    metrics: &ServiceMetrics,
+    usages: &[CompactUsage],
+) -> Result<(Vec<String>, Hierarchy), u32> {
+    let names = usages
+        .iter()
+        .map(|usage| {
+            metrics
+                .names
+                .get(usage.metric as usize)
+                .cloned()
+                .ok_or(usage.metric)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((names, metrics.hierarchy.clone()))
+}
+
+/// Decodes an application stored by encode_application or in the layout of the baseline release.
+pub fn decode_application<H: SharedData>(
+    host: &H,
+    bytes: &[u8],
+) -> Result<Application, CacheError> {
+    if bytes.first() != Some(&COMPACT_TAG) {
+        return match layout_options().deserialize::<BaselineApplication>(bytes) {
+            Ok(baseline) => Ok(Application::from(baseline)),
+            Err(e) => Err(CacheError::DeserializeFail(*e)),
+        };
+    }
+    let compact = match layout_options().deserialize::<CompactApplication>(&bytes[1..]) {
+        Ok(compact) => compact,
+        Err(e) => return Err(CacheError::DeserializeFail(*e)),
+    };
+    let (names, hierarchy) = with_service_metrics(host, &compact.service_id, |metrics, _| {
+        resolve_metrics(metrics, &compact.usages)
+    })?
+    .map_err(CacheError::UnknownMetric)?;
+    let mut local_state = HashMap::with_capacity(compact.usages.len());
+    for (name, usage) in names.into_iter().zip(compact.usages) {
+        local_state.insert(
+            name,
+            UsageReport {
+                period_window: PeriodWindow {
+                    start: Duration::from_secs(usage.start),
+                    end: Duration::from_secs(usage.end),
+                    window: usage.window,
+                },
+                left_hits: usage.left_hits,
+                max_value: usage.max_value,
+                synced_left_hits: usage.synced_left_hits,
+            },
+        );
+    }
+    Ok(Application {
+        app_id: compact.app_id,
+        service_id: compact.service_id,
+        local_state,
+        metric_hierarchy: hierarchy,
+        app_keys: compact.app_keys,
+        synced_at: Duration::from_secs(compact.synced_at),
+        plan: compact.plan,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::mock::MockHost;
+    use crate::proxy::{get_application_from_cache, set_application_to_cache, CacheKey};
+    use crate::structs::AppId;
+
+    fn application(app_id: &str, now: &Duration) -> Application {
+        let mut local_state = HashMap::new();
+        for (metric, left_hits) in [("hits", 10), ("get_products", 4), ("post_orders", 7)].iter() {
+            local_state.insert(
+                metric.to_string(),
+                UsageReport {
+                    period_window: PeriodWindow {
+                        start: *now - Duration::from_secs(30),
+                        end: *now + Duration::from_secs(30),
+                        window: Period::Minute,
+                    },
+                    left_hits: *left_hits,
+                    max_value: 10,
+                    synced_left_hits: *left_hits,
+                },
+            );
+        }
+        let mut metric_hierarchy = Hierarchy::new();
+        metric_hierarchy.insert(
+            "hits".to_string(),
+            vec!["get_products".to_string(), "post_orders".to_string()],
+        );
+        Application {
+            app_id: AppIdentifier::from(AppId::from(app_id)),
+            service_id: ServiceId::from("service"),
+            local_state,
+            metric_hierarchy,
+            app_keys: None,
+            synced_at: *now,
+            plan: None,
+        }
+    }
+
+    // Usage and application as defined by the baseline release, before any field was added.
+    #[derive(Serialize)]
+    struct BaselineUsage {
+        period_window: PeriodWindow,
+        left_hits: u64,
+        max_value: u64,
+    }
+
+    #[derive(Serialize)]
+    struct BaselineApp {
+        app_id: AppIdentifier,
+        service_id: ServiceId,
+        local_state: HashMap<String, BaselineUsage>,
+        metric_hierarchy: Hierarchy,
+        app_keys: Option<Vec<AppKey>>,
+    }
+
+    // Application as the baseline release stored it.
+    fn baseline_bytes(app: &Application) -> Vec<u8> {
+        bincode::serialize(&BaselineApp {
+            app_id: app.app_id.clone(),
+            service_id: app.service_id.clone(),
+            local_state: app
+                .local_state
+                .iter()
+                .map(|(metric, usage)| {
+                    let usage = BaselineUsage {
+                        period_window: usage.period_window.clone(),
+                        left_hits: usage.left_hits,
+                        max_value: usage.max_value,
+                    };
+                    (metric.clone(), usage)
+                })
+                .collect(),
+            metric_hierarchy: app.metric_hierarchy.clone(),
+            app_keys: app.app_keys.clone(),
+        })
+        .unwrap()
+    }
+
+    fn cache_key(app_id: &str) -> CacheKey {
+        CacheKey::from(
+            &ServiceId::from("service"),
+            &AppIdentifier::from(AppId::from(app_id)),
+        )
+    }
+
+    #[test]
+    fn applications_share_the_metrics_of_their_service() {
+        let host = MockHost::new();
+        let now = host.now();
+        let app = application("a", &now);
+        let bytes = encode_application(&host, &app).unwrap();
+        assert!(bytes.len() < bincode::serialize(&app).unwrap().len());
+        encode_application(&host, &application("b", &now)).unwrap();
+
+        let (metrics, _) = get_service_metrics(&host, &app.service_id).unwrap();
+        assert_eq!(metrics.names.len(), 3);
+        assert_eq!(metrics.hierarchy, app.metric_hierarchy);
+        assert!(metrics.is_known("post_orders"));
+        assert!(!metrics.is_known("post_order"));
+
+        let decoded = decode_application(&host, &bytes).unwrap();
+        assert_eq!(decoded.app_id, app.app_id);
+        assert_eq!(decoded.metric_hierarchy, app.metric_hierarchy);
+        assert_eq!(decoded.synced_at, app.synced_at);
+        for (metric, usage) in app.local_state.iter() {
+            let decoded_usage = &decoded.local_state[metric];
+            assert_eq!(decoded_usage.left_hits, usage.left_hits);
+            assert_eq!(decoded_usage.period_window.end, usage.period_window.end);
+        }
+    }
+
+    #[test]
+    fn service_hierarchy_is_merged_until_refreshed() {
+        let host = MockHost::new();
+        let refresh = Duration::from_secs(300);
+        let service_id = ServiceId::from("service");
+        // Hierarchy made of the given (parent, child) pairs.
+        let hierarchy = |pairs: &[(&str, &str)]| {
+            let mut hierarchy = Hierarchy::new();
+            for (parent, child) in pairs.iter() {
+                hierarchy
+                    .entry(parent.to_string())
+                    .or_insert_with(Vec::new)
+                    .push(child.to_string());
+            }
+            hierarchy
+        };
+
+        let first = hierarchy(&[("hits", "get_products")]);
+        let merged = update_service_hierarchy(&host, &service_id, &first, &host.now(), &refresh);
+        assert_eq!(merged.unwrap(), first);
+
+        // A new application whose response lacks part of the hierarchy gets the whole of it.
+        let second = hierarchy(&[("hits", "post_orders"), ("orders", "post_orders")]);
+        let merged = update_service_hierarchy(&host, &service_id, &second, &host.now(), &refresh);
+        let expected = hierarchy(&[
+            ("hits", "get_products"),
+            ("hits", "post_orders"),
+            ("orders", "post_orders"),
+        ]);
+        assert_eq!(merged.unwrap(), expected);
+        let app = application("a", &host.now());
+        let bytes = encode_application(&host, &app).unwrap();
+        let decoded = decode_application(&host, &bytes).unwrap();
+        assert_eq!(decoded.metric_hierarchy, expected);
+
+        // Merging alone never drops metrics, however old the hierarchy is.
+        host.advance_time(refresh);
+        let merged = merge_service_hierarchy(&host, &service_id, &first);
+        assert_eq!(merged.unwrap(), expected);
+
+        let merged = update_service_hierarchy(&host, &service_id, &first, &host.now(), &refresh);
+        assert_eq!(merged.unwrap(), first);
+    }
+
+    #[test]
+    fn former_layout_is_decoded_and_rewritten_compactly() {
+        let host = MockHost::new();
+        let now = host.now();
+        let key = cache_key("a").as_string();
+        let legacy = baseline_bytes(&application("a", &now));
+        host.set_shared_data(&key, Some(&legacy), None).unwrap();
+
+        let (mut app, cas) = get_application_from_cache(&host, &cache_key("a")).unwrap();
+        assert_eq!(app.local_state["get_products"].left_hits, 4);
+        app.local_state.get_mut("hits").unwrap().left_hits = 9;
+        set_application_to_cache(&host, &key, &app, cas).unwrap();
+
+        let (bytes, _) = host.get_shared_data(&key).unwrap();
+        assert_eq!(bytes.unwrap()[0], COMPACT_TAG);
+        let (app, _) = get_application_from_cache(&host, &cache_key("a")).unwrap();
+        assert_eq!(app.local_state["hits"].left_hits, 9);
+        // Service adopts the hierarchy of the application.
+        assert_eq!(app.metric_hierarchy["hits"].len(), 2);
+    }
+
+    #[test]
+    fn usages_stored_before_synced_left_hits_count_as_synced() {
+        let host = MockHost::new();
+        let mut app = application("a", &host.now());
+        app.local_state.get_mut("hits").unwrap().synced_left_hits = 10;
+        app.local_state.get_mut("hits").unwrap().left_hits = 6;
+
+        let decoded = decode_application(&host, &baseline_bytes(&app)).unwrap();
+        let hits = &decoded.local_state["hits"];
+        assert_eq!((hits.left_hits, hits.synced_left_hits), (6, 6));
+        assert_eq!(decoded.local_state.len(), 3);
+        assert_eq!(decoded.metric_hierarchy, app.metric_hierarchy);
+    }
+
+    #[test]
+    fn metrics_known_to_the_thread_are_used_while_their_cas_holds() {
+        let host = MockHost::new();
+        let app = application("a", &host.now());
+        let bytes = encode_application(&host, &app).unwrap();
+        let key = service_metrics_key(&app.service_id);
+        let (_, cas) = host.get_shared_data(&key).unwrap();
+        assert!(decode_application(&host, &bytes).is_ok());
+        let known_cas = || {
+            KNOWN_METRICS.with(|known| {
+                known
+                    .borrow()
+                    .get(app.service_id.as_ref())
+                    .map(|entry| entry.cas)
+            })
+        };
+        assert_eq!(known_cas(), cas);
+
+        // Metrics stored since, by this thread or any other, are picked up at once.
+        let mut other = application("b", &host.now());
+        let usage = other.local_state["hits"].clone();
+        other.local_state.insert("delete_orders".to_string(), usage);
+        let other_bytes = encode_application(&host, &other).unwrap();
+        let decoded = decode_application(&host, &other_bytes).unwrap();
+        assert_eq!(decoded.local_state.len(), 4);
+        let (metrics, cas) = get_service_metrics(&host, &app.service_id).unwrap();
+        assert_eq!(metrics.names.len(), 4);
+        assert_eq!(known_cas(), cas);
+
+        // Remembered metrics are never used once the stored ones changed.
+        host.set_shared_data(&key, Some(b"garbage"), None).unwrap();
+        assert!(decode_application(&host, &bytes).is_err());
+        assert!(encode_application(&host, &app).is_err());
+    }
+}
@@ -0,0 +1,346 @@
+use proxy_wasm::hostcalls;
+use proxy_wasm::types::{BufferType, Bytes, MapType, MetricType, Status};
+use std::time::{Duration, SystemTime};
+
+#[cfg(any(test, feature = "mock_host"))]
+pub mod mock;
+
+/** Reasoning behind this module:
+* Most of the logic of the filter and the singleton talks to the proxy through hostcalls,
+* which are only available when running inside the proxy. Functionalities offered by the host
+* are abstracted behind the traits below so that the same logic can run against ProxyHost in
+* production and against an in-memory implementation (mock::MockHost) in unit tests.
+**/
+
+/// Shared data operations offered by the proxy host.
+pub trait SharedData {
+    /// Returns the value stored for the key along with its CAS.
+    fn get_shared_data(&self, key: &str) -> Result<(Option<Bytes>, Option<u32>), Status>;
+
+    /// Stores the value for the key. If cas is provided and the key is present, the value is
+    /// only stored when cas matches the CAS of the stored value, otherwise CasMismatch is returned.
+    fn set_shared_data(
+        &self,
+        key: &str,
+        value: Option<&[u8]>,
+        cas: Option<u32>,
+    ) -> Result<(), Status>;
+}
+
+/// Shared queue operations offered by the proxy host.
+pub trait SharedQueue {
+    fn register_shared_queue(&self, name: &str) -> Result<u32, Status>;
+
+    fn resolve_shared_queue(&self, vm_id: &str, name: &str) -> Result<Option<u32>, Status>;
+
+    fn enqueue_shared_queue(&self, queue_id: u32, value: Option<&[u8]>) -> Result<(), Status>;
+
+    fn dequeue_shared_queue(&self, queue_id: u32) -> Result<Option<Bytes>, Status>;
+}
+
+/// HTTP callouts offered by the proxy host.
+pub trait HttpCallout {
+    /// Dispatches an HTTP call to the upstream and returns the token identifying the call.
+    fn dispatch_http_call(
+        &self,
+        upstream: &str,
+        headers: Vec<(&str, &str)>,
+        body: Option<&[u8]>,
+        trailers: Vec<(&str, &str)>,
+        timeout: Duration,
+    ) -> Result<u32, Status>;
+}
+
+/// Operations on the HTTP stream of the context being processed by the proxy host.
+pub trait HttpStream {
+    fn get_http_request_header(&self, name: &str) -> Option<String>;
+
+    fn add_http_response_header(&self, name: &str, value: &str);
+
+    /// Responds to the request locally instead of forwarding it upstream.
+    fn send_http_response(
+        &self,
+        status_code: u32,
+        headers: Vec<(&str, &str)>,
+        body: Option<&[u8]>,
+    ) -> Result<(), Status>;
+
+    fn resume_http_request(&self) -> Result<(), Status>;
+
+    /// Headers of the response to the HTTP call being handled.
+    fn get_http_call_response_headers(&self) -> Vec<(String, String)>;
+
+    /// Body of the response to the HTTP call being handled.
+    fn get_http_call_response_body(&self, start: usize, max_size: usize) -> Option<Bytes>;
+
+    /// Makes the HTTP stream of the context the one the following operations apply to.
+    fn set_effective_context(&self, context_id: u32) -> Result<(), Status>;
+}
+
+/// Clock of the proxy host.
+pub trait Clock {
+    fn get_current_time(&self) -> SystemTime;
+}
+
+/// Metrics offered by the proxy host.
+pub trait Metrics {
+    fn define_metric(&self, metric_type: MetricType, name: &str) -> Result<u32, Status>;
+
+    fn increment_metric(&self, metric_id: u32, offset: i64) -> Result<(), Status>;
+
+    fn record_metric(&self, metric_id: u32, value: u64) -> Result<(), Status>;
+
+    fn get_metric(&self, metric_id: u32) -> Result<u64, Status>;
+}
+
+/// Configuration and timer of the plugin offered by the proxy host.
+pub trait Plugin {
+    /// Configuration of the plugin passed by envoy.yaml.
+    fn get_configuration(&self) -> Option<Bytes>;
+
+    fn set_tick_period(&self, period: Duration) -> Result<(), Status>;
+}
+
+/// Every functionality required from the proxy host.
+pub trait Host:
+    SharedData + SharedQueue + HttpCallout + HttpStream + Clock + Metrics + Plugin
+{
+}
+
+impl<T: SharedData + SharedQueue + HttpCallout + HttpStream + Clock + Metrics + Plugin> Host for T {}
+
+// References to a host are hosts as well, so that a single host can be shared by several
+// contexts, eg: filters of different requests running on top of the same MockHost.
+impl<T: SharedData + ?Sized> SharedData for &T {
+    fn get_shared_data(&self, key: &str) -> Result<(Option<Bytes>, Option<u32>), Status> {
+        (**self).get_shared_data(key)
+    }
+
+    fn set_shared_data(
+        &self,
+        key: &str,
+        value: Option<&[u8]>,
+        cas: Option<u32>,
+    ) -> Result<(), Status> {
+        (**self).set_shared_data(key, value, cas)
+    }
+}
+
+impl<T: SharedQueue + ?Sized> SharedQueue for &T {
+    fn register_shared_queue(&self, name: &str) -> Result<u32, Status> {
+        (**self).register_shared_queue(name)
+    }
+
+    fn resolve_shared_queue(&self, vm_id: &str, name: &str) -> Result<Option<u32>, Status> {
+        (**self).resolve_shared_queue(vm_id, name)
+    }
+
+    fn enqueue_shared_queue(&self, queue_id: u32, value: Option<&[u8]>) -> Result<(), Status> {
+        (**self).enqueue_shared_queue(queue_id, value)
+    }
+
+    fn dequeue_shared_queue(&self, queue_id: u32) -> Result<Option<Bytes>, Status> {
+        (**self).dequeue_shared_queue(queue_id)
+    }
+}
+
+impl<T: HttpCallout + ?Sized> HttpCallout for &T {
+    fn dispatch_http_call(
+        &self,
+        upstream: &str,
+        headers: Vec<(&str, &str)>,
+        body: Option<&[u8]>,
+        trailers: Vec<(&str, &str)>,
+        timeout: Duration,
+    ) -> Result<u32, Status> {
+        (**self).dispatch_http_call(upstream, headers, body, trailers, timeout)
+    }
+}
+
+impl<T: HttpStream + ?Sized> HttpStream for &T {
+    fn get_http_request_header(&self, name: &str) -> Option<String> {
+        (**self).get_http_request_header(name)
+    }
+
+    fn add_http_response_header(&self, name: &str, value: &str) {
+        (**self).add_http_response_header(name, value)
+    }
+
+    fn send_http_response(
+        &self,
+        status_code: u32,
+        headers: Vec<(&str, &str)>,
+        body: Option<&[u8]>,
+    ) -> Result<(), Status> {
+        (**self).send_http_response(status_code, headers, body)
+    }
+
+    fn resume_http_request(&self) -> Result<(), Status> {
+        (**self).resume_http_request()
+    }
+
+    fn get_http_call_response_headers(&self) -> Vec<(String, String)> {
+        (**self).get_http_call_response_headers()
+    }
+
+    fn get_http_call_response_body(&self, start: usize, max_size: usize) -> Option<Bytes> {
+        (**self).get_http_call_response_body(start, max_size)
+    }
+
+    fn set_effective_context(&self, context_id: u32) -> Result<(), Status> {
+        (**self).set_effective_context(context_id)
+    }
+}
+
+impl<T: Clock + ?Sized> Clock for &T {
+    fn get_current_time(&self) -> SystemTime {
+        (**self).get_current_time()
+    }
+}
+
+impl<T: Plugin + ?Sized> Plugin for &T {
+    fn get_configuration(&self) -> Option<Bytes> {
+        (**self).get_configuration()
+    }
+
+    fn set_tick_period(&self, period: Duration) -> Result<(), Status> {
+        (**self).set_tick_period(period)
+    }
+}
+
+impl<T: Metrics + ?Sized> Metrics for &T {
+    fn define_metric(&self, metric_type: MetricType, name: &str) -> Result<u32, Status> {
+        (**self).define_metric(metric_type, name)
+    }
+
+    fn increment_metric(&self, metric_id: u32, offset: i64) -> Result<(), Status> {
+        (**self).increment_metric(metric_id, offset)
+    }
+
+    fn record_metric(&self, metric_id: u32, value: u64) -> Result<(), Status> {
+        (**self).record_metric(metric_id, value)
+    }
+
+    fn get_metric(&self, metric_id: u32) -> Result<u64, Status> {
+        (**self).get_metric(metric_id)
+    }
+}
+
+/// Host implementation that forwards every call to the proxy-wasm hostcalls.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProxyHost;
+
+impl SharedData for ProxyHost {
+    fn get_shared_data(&self, key: &str) -> Result<(Option<Bytes>, Option<u32>), Status> {
+        hostcalls::get_shared_data(key)
+    }
+
+    fn set_shared_data(
+        &self,
+        key: &str,
+        value: Option<&[u8]>,
+        cas: Option<u32>,
+    ) -> Result<(), Status> {
+        hostcalls::set_shared_data(key, value, cas)
+    }
+}
+
+impl SharedQueue for ProxyHost {
+    fn register_shared_queue(&self, name: &str) -> Result<u32, Status> {
+        hostcalls::register_shared_queue(name)
+    }
+
+    fn resolve_shared_queue(&self, vm_id: &str, name: &str) -> Result<Option<u32>, Status> {
+        hostcalls::resolve_shared_queue(vm_id, name)
+    }
+
+    fn enqueue_shared_queue(&self, queue_id: u32, value: Option<&[u8]>) -> Result<(), Status> {
+        hostcalls::enqueue_shared_queue(queue_id, value)
+    }
+
+    fn dequeue_shared_queue(&self, queue_id: u32) -> Result<Option<Bytes>, Status> {
+        hostcalls::dequeue_shared_queue(queue_id)
+    }
+}
+
+impl HttpCallout for ProxyHost {
+    fn dispatch_http_call(
+        &self,
+        upstream: &str,
+        headers: Vec<(&str, &str)>,
+        body: Option<&[u8]>,
+        trailers: Vec<(&str, &str)>,
+        timeout: Duration,
+    ) -> Result<u32, Status> {
+        hostcalls::dispatch_http_call(upstream, headers, body, trailers, timeout)
+    }
+}
+
+impl HttpStream for ProxyHost {
+    fn get_http_request_header(&self, name: &str) -> Option<String> {
+        hostcalls::get_map_value(MapType::HttpRequestHeaders, name).unwrap()
+    }
+
+    fn add_http_response_header(&self, name: &str, value: &str) {
+        hostcalls::add_map_value(MapType::HttpResponseHeaders, name, value).unwrap()
+    }
+
+    fn send_http_response(
+        &self,
+        status_code: u32,
+        headers: Vec<(&str, &str)>,
+        body: Option<&[u8]>,
+    ) -> Result<(), Status> {
+        hostcalls::send_http_response(status_code, headers, body)
+    }
+
+    fn resume_http_request(&self) -> Result<(), Status> {
+        hostcalls::resume_http_request()
+    }
+
+    fn get_http_call_response_headers(&self) -> Vec<(String, String)> {
+        hostcalls::get_map(MapType::HttpCallResponseHeaders).unwrap()
+    }
+
+    fn get_http_call_response_body(&self, start: usize, max_size: usize) -> Option<Bytes> {
+        hostcalls::get_buffer(BufferType::HttpCallResponseBody, start, max_size).unwrap()
+    }
+
+    fn set_effective_context(&self, context_id: u32) -> Result<(), Status> {
+        hostcalls::set_effective_context(context_id)
+    }
+}
+
+impl Clock for ProxyHost {
+    fn get_current_time(&self) -> SystemTime {
+        hostcalls::get_current_time().unwrap()
+    }
+}
+
+impl Metrics for ProxyHost {
+    fn define_metric(&self, metric_type: MetricType, name: &str) -> Result<u32, Status> {
+        hostcalls::define_metric(metric_type, name)
+    }
+
+    fn increment_metric(&self, metric_id: u32, offset: i64) -> Result<(), Status> {
+        hostcalls::increment_metric(metric_id, offset)
+    }
+
+    fn record_metric(&self, metric_id: u32, value: u64) -> Result<(), Status> {
+        hostcalls::record_metric(metric_id, value)
+    }
+
+    fn get_metric(&self, metric_id: u32) -> Result<u64, Status> {
+        hostcalls::get_metric(metric_id)
+    }
+}
+
+impl Plugin for ProxyHost {
+    fn get_configuration(&self) -> Option<Bytes> {
+        hostcalls::get_buffer(BufferType::PluginConfiguration, 0, usize::MAX).unwrap()
+    }
+
+    fn set_tick_period(&self, period: Duration) -> Result<(), Status> {
+        hostcalls::set_tick_period(period)
+    }
+}
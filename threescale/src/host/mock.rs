@@ -0,0 +1,429 @@
+use super::{Clock, HttpCallout, HttpStream, Metrics, Plugin, SharedData, SharedQueue};
+use proxy_wasm::types::{Bytes, MetricType, Status};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// HTTP call dispatched through the mock host.
+#[derive(Debug, Clone)]
+pub struct DispatchedCall {
+    pub token: u32,
+    pub upstream: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<Bytes>,
+    pub timeout: Duration,
+}
+
+impl DispatchedCall {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Response sent locally through the mock host instead of forwarding the request upstream.
+#[derive(Debug, Clone)]
+pub struct LocalResponse {
+    pub status_code: u32,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<Bytes>,
+}
+
+/// Metric defined through the mock host.
+#[derive(Debug, Clone)]
+pub struct MockMetric {
+    pub name: String,
+    pub metric_type: MetricType,
+    pub value: i64,
+}
+
+/// In-memory host mimicking the proxy (shared_data.cc, shared_queue.cc):
+/// * A single CAS counter is shared by every key, incremented on each successful set
+///   and never handed out as 0. Setting with a CAS only fails for keys already present.
+/// * Queues are identified by (vm_id, name) and registering twice returns the same id.
+/// * HTTP calls are only recorded and never answered, responses are fed by the test itself.
+/// * The HTTP stream is the one of a single request, local responses and resumptions of the
+///   request are recorded for the test to inspect, along with the last effective context.
+/// * Time only moves forward when the test advances it.
+/// * The plugin configuration is the one set by the test, and the tick period is only recorded.
+#[derive(Debug)]
+pub struct MockHost {
+    vm_id: String,
+    shared_data: RefCell<HashMap<String, (Bytes, u32)>>,
+    cas: Cell<u32>,
+    queue_ids: RefCell<HashMap<(String, String), u32>>,
+    queues: RefCell<HashMap<u32, VecDeque<Bytes>>>,
+    calls: RefCell<Vec<DispatchedCall>>,
+    next_token: Cell<u32>,
+    time: Cell<Duration>,
+    metrics: RefCell<Vec<MockMetric>>,
+    request_headers: RefCell<Vec<(String, String)>>,
+    response_headers: RefCell<Vec<(String, String)>>,
+    local_responses: RefCell<Vec<LocalResponse>>,
+    resumed_requests: Cell<usize>,
+    effective_context: Cell<Option<u32>>,
+    stream_failure: Cell<Option<Status>>,
+    call_response: RefCell<(Vec<(String, String)>, Option<Bytes>)>,
+    configuration: RefCell<Option<Bytes>>,
+    tick_period: Cell<Duration>,
+}
+
+impl Default for MockHost {
+    fn default() -> Self {
+        MockHost::with_initial_cas(0)
+    }
+}
+
+impl MockHost {
+    pub fn new() -> Self {
+        MockHost::default()
+    }
+
+    /// Creates a host whose CAS counter starts at the provided value. Useful to
+    /// reproduce scenarios around the u32::MAX wrap of the CAS.
+    pub fn with_initial_cas(cas: u32) -> Self {
+        MockHost {
+            vm_id: "my_vm_id".to_string(),
+            shared_data: RefCell::new(HashMap::new()),
+            cas: Cell::new(cas),
+            queue_ids: RefCell::new(HashMap::new()),
+            queues: RefCell::new(HashMap::new()),
+            calls: RefCell::new(Vec::new()),
+            next_token: Cell::new(1),
+            time: Cell::new(Duration::from_secs(1_600_000_000)),
+            metrics: RefCell::new(Vec::new()),
+            request_headers: RefCell::new(Vec::new()),
+            response_headers: RefCell::new(Vec::new()),
+            local_responses: RefCell::new(Vec::new()),
+            resumed_requests: Cell::new(0),
+            effective_context: Cell::new(None),
+            stream_failure: Cell::new(None),
+            call_response: RefCell::new((Vec::new(), None)),
+            configuration: RefCell::new(None),
+            tick_period: Cell::new(Duration::default()),
+        }
+    }
+
+    fn next_cas(&self) -> u32 {
+        let mut cas = self.cas.get().wrapping_add(1);
+        if cas == 0 {
+            cas = 1;
+        }
+        self.cas.set(cas);
+        cas
+    }
+
+    /// Current time as duration since UNIX_EPOCH.
+    pub fn now(&self) -> Duration {
+        self.time.get()
+    }
+
+    pub fn set_time(&self, since_epoch: Duration) {
+        self.time.set(since_epoch);
+    }
+
+    pub fn advance_time(&self, by: Duration) {
+        self.time.set(self.time.get() + by);
+    }
+
+    /// Returns the HTTP calls dispatched since the last drain.
+    pub fn drain_calls(&self) -> Vec<DispatchedCall> {
+        self.calls.borrow_mut().drain(..).collect()
+    }
+
+    /// Number of messages waiting in the queue.
+    pub fn queue_len(&self, queue_id: u32) -> usize {
+        self.queues
+            .borrow()
+            .get(&queue_id)
+            .map_or(0, |queue| queue.len())
+    }
+
+    /// Returns the value of the metric with the provided name.
+    pub fn metric_value(&self, name: &str) -> Option<i64> {
+        self.metrics
+            .borrow()
+            .iter()
+            .find(|metric| metric.name == name)
+            .map(|metric| metric.value)
+    }
+
+    /// Keys of all entries stored in shared data.
+    pub fn shared_data_keys(&self) -> Vec<String> {
+        self.shared_data.borrow().keys().cloned().collect()
+    }
+
+    /// Replaces the headers of the request of the HTTP stream.
+    pub fn set_request_headers(&self, headers: Vec<(&str, &str)>) {
+        *self.request_headers.borrow_mut() = headers
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+    }
+
+    /// Headers added to the response of the HTTP stream.
+    pub fn response_headers(&self) -> Vec<(String, String)> {
+        self.response_headers.borrow().clone()
+    }
+
+    /// Returns the responses sent locally since the last drain.
+    pub fn drain_local_responses(&self) -> Vec<LocalResponse> {
+        self.local_responses.borrow_mut().drain(..).collect()
+    }
+
+    /// Number of times the request was resumed.
+    pub fn resumed_requests(&self) -> usize {
+        self.resumed_requests.get()
+    }
+
+    /// Id of the context last made effective.
+    pub fn effective_context(&self) -> Option<u32> {
+        self.effective_context.get()
+    }
+
+    /// Sets the plugin configuration returned to the root context.
+    pub fn set_configuration(&self, configuration: &[u8]) {
+        *self.configuration.borrow_mut() = Some(configuration.to_vec());
+    }
+
+    /// Tick period last set by the root context.
+    pub fn tick_period(&self) -> Duration {
+        self.tick_period.get()
+    }
+
+    /// Makes local responses and resumes of the HTTP stream fail with the provided status.
+    pub fn fail_http_stream(&self, status: Option<Status>) {
+        self.stream_failure.set(status);
+    }
+
+    /// Sets the response to the HTTP call handled next.
+    pub fn set_call_response(&self, headers: Vec<(&str, &str)>, body: Option<&[u8]>) {
+        *self.call_response.borrow_mut() = (
+            headers
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            body.map(|b| b.to_vec()),
+        );
+    }
+}
+
+impl SharedData for MockHost {
+    fn get_shared_data(&self, key: &str) -> Result<(Option<Bytes>, Option<u32>), Status> {
+        match self.shared_data.borrow().get(key) {
+            Some((bytes, cas)) if bytes.is_empty() => Ok((None, Some(*cas))),
+            Some((bytes, cas)) => Ok((Some(bytes.clone()), Some(*cas))),
+            None => Ok((None, None)),
+        }
+    }
+
+    fn set_shared_data(
+        &self,
+        key: &str,
+        value: Option<&[u8]>,
+        cas: Option<u32>,
+    ) -> Result<(), Status> {
+        let mut shared_data = self.shared_data.borrow_mut();
+        if let (Some(cas), Some((_, stored_cas))) = (cas.filter(|c| *c != 0), shared_data.get(key))
+        {
+            if cas != *stored_cas {
+                return Err(Status::CasMismatch);
+            }
+        }
+        let new_cas = self.next_cas();
+        shared_data.insert(
+            key.to_string(),
+            (value.map(|v| v.to_vec()).unwrap_or_default(), new_cas),
+        );
+        Ok(())
+    }
+}
+
+impl SharedQueue for MockHost {
+    fn register_shared_queue(&self, name: &str) -> Result<u32, Status> {
+        let mut queue_ids = self.queue_ids.borrow_mut();
+        let next_id = queue_ids.len() as u32 + 1;
+        let queue_id = *queue_ids
+            .entry((self.vm_id.clone(), name.to_string()))
+            .or_insert(next_id);
+        self.queues
+            .borrow_mut()
+            .entry(queue_id)
+            .or_insert_with(VecDeque::new);
+        Ok(queue_id)
+    }
+
+    fn resolve_shared_queue(&self, vm_id: &str, name: &str) -> Result<Option<u32>, Status> {
+        Ok(self
+            .queue_ids
+            .borrow()
+            .get(&(vm_id.to_string(), name.to_string()))
+            .copied())
+    }
+
+    fn enqueue_shared_queue(&self, queue_id: u32, value: Option<&[u8]>) -> Result<(), Status> {
+        match self.queues.borrow_mut().get_mut(&queue_id) {
+            Some(queue) => {
+                queue.push_back(value.map(|v| v.to_vec()).unwrap_or_default());
+                Ok(())
+            }
+            None => Err(Status::NotFound),
+        }
+    }
+
+    fn dequeue_shared_queue(&self, queue_id: u32) -> Result<Option<Bytes>, Status> {
+        match self.queues.borrow_mut().get_mut(&queue_id) {
+            Some(queue) => Ok(queue.pop_front()),
+            None => Err(Status::NotFound),
+        }
+    }
+}
+
+impl HttpCallout for MockHost {
+    fn dispatch_http_call(
+        &self,
+        upstream: &str,
+        headers: Vec<(&str, &str)>,
+        body: Option<&[u8]>,
+        _trailers: Vec<(&str, &str)>,
+        timeout: Duration,
+    ) -> Result<u32, Status> {
+        let token = self.next_token.get();
+        self.next_token.set(token + 1);
+        self.calls.borrow_mut().push(DispatchedCall {
+            token,
+            upstream: upstream.to_string(),
+            headers: headers
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            body: body.map(|b| b.to_vec()),
+            timeout,
+        });
+        Ok(token)
+    }
+}
+
+impl HttpStream for MockHost {
+    fn get_http_request_header(&self, name: &str) -> Option<String> {
+        self.request_headers
+            .borrow()
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.clone())
+    }
+
+    fn add_http_response_header(&self, name: &str, value: &str) {
+        self.response_headers
+            .borrow_mut()
+            .push((name.to_string(), value.to_string()));
+    }
+
+    fn send_http_response(
+        &self,
+        status_code: u32,
+        headers: Vec<(&str, &str)>,
+        body: Option<&[u8]>,
+    ) -> Result<(), Status> {
+        if let Some(status) = self.stream_failure.get() {
+            return Err(status);
+        }
+        self.local_responses.borrow_mut().push(LocalResponse {
+            status_code,
+            headers: headers
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            body: body.map(|b| b.to_vec()),
+        });
+        Ok(())
+    }
+
+    fn resume_http_request(&self) -> Result<(), Status> {
+        if let Some(status) = self.stream_failure.get() {
+            return Err(status);
+        }
+        self.resumed_requests.set(self.resumed_requests.get() + 1);
+        Ok(())
+    }
+
+    fn get_http_call_response_headers(&self) -> Vec<(String, String)> {
+        self.call_response.borrow().0.clone()
+    }
+
+    fn get_http_call_response_body(&self, start: usize, max_size: usize) -> Option<Bytes> {
+        self.call_response.borrow().1.as_ref().map(|body| {
+            body.iter()
+                .skip(start)
+                .take(max_size)
+                .copied()
+                .collect::<Bytes>()
+        })
+    }
+
+    fn set_effective_context(&self, context_id: u32) -> Result<(), Status> {
+        self.effective_context.set(Some(context_id));
+        Ok(())
+    }
+}
+
+impl Clock for MockHost {
+    fn get_current_time(&self) -> SystemTime {
+        UNIX_EPOCH + self.time.get()
+    }
+}
+
+impl Plugin for MockHost {
+    fn get_configuration(&self) -> Option<Bytes> {
+        self.configuration.borrow().clone()
+    }
+
+    fn set_tick_period(&self, period: Duration) -> Result<(), Status> {
+        self.tick_period.set(period);
+        Ok(())
+    }
+}
+
+impl Metrics for MockHost {
+    fn define_metric(&self, metric_type: MetricType, name: &str) -> Result<u32, Status> {
+        let mut metrics = self.metrics.borrow_mut();
+        if let Some(id) = metrics.iter().position(|metric| metric.name == name) {
+            return Ok(id as u32);
+        }
+        metrics.push(MockMetric {
+            name: name.to_string(),
+            metric_type,
+            value: 0,
+        });
+        Ok((metrics.len() - 1) as u32)
+    }
+
+    fn increment_metric(&self, metric_id: u32, offset: i64) -> Result<(), Status> {
+        match self.metrics.borrow_mut().get_mut(metric_id as usize) {
+            Some(metric) => {
+                metric.value += offset;
+                Ok(())
+            }
+            None => Err(Status::NotFound),
+        }
+    }
+
+    fn record_metric(&self, metric_id: u32, value: u64) -> Result<(), Status> {
+        match self.metrics.borrow_mut().get_mut(metric_id as usize) {
+            Some(metric) => {
+                metric.value = value as i64;
+                Ok(())
+            }
+            None => Err(Status::NotFound),
+        }
+    }
+
+    fn get_metric(&self, metric_id: u32) -> Result<u64, Status> {
+        match self.metrics.borrow().get(metric_id as usize) {
+            Some(metric) => Ok(metric.value as u64),
+            None => Err(Status::NotFound),
+        }
+    }
+}
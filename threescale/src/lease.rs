@@ -0,0 +1,413 @@
+use crate::host::SharedData;
+use proxy_wasm::types::Status;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::time::Duration;
+
+/** Lease-based lock stored in shared data:
+* Every lock key maps to a serialized LeaseRecord that carries the lease currently held (if any),
+* the last fencing token handed out for that key and a payload owned by the lock holder
+* (e.g. list of waiters for unique callouts).
+*
+* The record is never deleted, releasing a lease only clears the lease field. This way every
+* update other than the very first insertion of a key is a read-modify-write guarded by CAS,
+* which only relies on the documented behaviour of set_shared_data: a write with a CAS that
+* does not match the stored one fails with CasMismatch.
+*
+* The very first insertion can't be guarded by CAS since there is nothing to compare against,
+* and reading the record back doesn't tell whether another insertion is about to overwrite it. So
+* the insertion only stores a free record tagged with the generation of its creator, and leases
+* are only ever granted by a CAS guarded write over a record read before. Insertions are written
+* with a CAS of 1, so a late insertion can only overwrite a record stored with that CAS (e.g.
+* right after the CAS counter wrapped). A granted lease is only confirmed once its record is stored
+* with any other CAS, rewriting it if needed. Leases carry the generation of the record they were
+* granted in, so a lease can't be mistaken for one of a record inserted again.
+*
+* A lease expires after its duration and can then be stolen by any other owner. Each acquisition
+* increments the fencing token, so a holder whose lease was stolen can't release the new lease.
+**/
+
+#[derive(Debug, thiserror::Error)]
+pub enum LeaseError {
+    #[error("failed to serialize lease record: {0}")]
+    SerializeFail(bincode::ErrorKind),
+    #[error("failed to deserialize lease record: {0}")]
+    DeserializeFail(bincode::ErrorKind),
+    #[error("failure due to proxy's internal issue: {0:?}")]
+    ProxyFailure(Status),
+}
+
+/// Lease granted to a single owner over a lock key.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+    /// Id of the owner holding the lease.
+    pub owner: u32,
+    /// Time (since UNIX_EPOCH) at which the lease was acquired.
+    pub acquired_at: Duration,
+    /// Duration after which the lease can be stolen by another owner.
+    pub duration: Duration,
+    /// Token incremented on every acquisition of the lock key.
+    pub fencing_token: u64,
+    /// Generation of the record the lease was granted in.
+    pub generation: u64,
+}
+
+impl Lease {
+    pub fn is_expired(&self, now: &Duration) -> bool {
+        match self.acquired_at.checked_add(self.duration) {
+            Some(expiry) => expiry <= *now,
+            None => false,
+        }
+    }
+}
+
+/// Value stored in shared data for every lock key.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LeaseRecord<T> {
+    /// Set by whoever inserted the record, unique among the owners contending for the key.
+    pub generation: u64,
+    /// Lease currently held, None if the lock is free.
+    pub lease: Option<Lease>,
+    /// Last fencing token handed out, kept across releases so that tokens never repeat.
+    pub last_token: u64,
+    /// Data attached to the lock, handed over to whoever releases the lease.
+    pub payload: T,
+}
+
+/// Outcome of trying to acquire a lease.
+#[derive(Debug, PartialEq)]
+pub enum AcquireStatus {
+    /// Lease was granted to the caller.
+    Acquired(Lease),
+    /// Lease is held by another owner and the payload update was stored in its record.
+    Held(Lease),
+    /// Lease seen held by another owner was released before the payload update could be stored.
+    Released(u64),
+}
+
+// Returns the lease record stored for the key with its CAS.
+pub fn get_lease_record<H, T>(
+    host: &H,
+    key: &str,
+) -> Result<(Option<LeaseRecord<T>>, Option<u32>), LeaseError>
+where
+    H: SharedData,
+    T: DeserializeOwned,
+{
+    match host.get_shared_data(key) {
+        Ok((Some(bytes), cas)) => match bincode::deserialize::<LeaseRecord<T>>(&bytes) {
+            Ok(record) => Ok((Some(record), cas)),
+            Err(e) => Err(LeaseError::DeserializeFail(*e)),
+        },
+        Ok((None, cas)) => Ok((None, cas)),
+        Err(e) => Err(LeaseError::ProxyFailure(e)),
+    }
+}
+
+// Stores the lease record, returns Ok(false) on CAS mismatch.
+fn set_lease_record<H, T>(
+    host: &H,
+    key: &str,
+    record: &LeaseRecord<T>,
+    cas: Option<u32>,
+) -> Result<bool, LeaseError>
+where
+    H: SharedData,
+    T: Serialize,
+{
+    let bytes = match bincode::serialize(record) {
+        Ok(res) => res,
+        Err(e) => return Err(LeaseError::SerializeFail(*e)),
+    };
+    match host.set_shared_data(key, Some(&bytes), cas) {
+        Ok(()) => Ok(true),
+        Err(Status::CasMismatch) => Ok(false),
+        Err(e) => Err(LeaseError::ProxyFailure(e)),
+    }
+}
+
+/// Tries to acquire the lease over the key for the owner. If the lease is already held by
+/// someone else and not expired, update_held is applied to the payload of the record instead.
+/// An expired lease is stolen, keeping the payload of the record untouched.
+pub fn acquire_lease<H, T, F>(
+    host: &H,
+    key: &str,
+    owner: u32,
+    now: &Duration,
+    duration: Duration,
+    mut update_held: F,
+) -> Result<AcquireStatus, LeaseError>
+where
+    H: SharedData,
+    T: Serialize + DeserializeOwned + Default,
+    F: FnMut(&mut T),
+{
+    // Fencing token of the lease seen held by another owner during previous tries.
+    let mut seen_token: Option<u64> = None;
+    loop {
+        let (record, cas) = get_lease_record::<H, T>(host, key)?;
+        let (mut record, cas) = match (record, cas) {
+            (Some(record), Some(cas)) => (record, cas),
+            (_, cas) => {
+                // Nothing stored for the key yet, a free record is inserted and read again.
+                let record = LeaseRecord {
+                    generation: generation(owner, now),
+                    lease: None,
+                    last_token: 0,
+                    payload: T::default(),
+                };
+                // Note: Concurrent insertions may overwrite each other, which is harmless since
+                // they all store a free record.
+                set_lease_record(host, key, &record, Some(cas.unwrap_or(1)))?;
+                continue;
+            }
+        };
+
+        match record.lease.clone() {
+            Some(lease) if !lease.is_expired(now) => {
+                // Note: Lease held by the same owner is treated the same way, since another
+                // context of the owner is already waiting on the lease.
+                update_held(&mut record.payload);
+                if set_lease_record(host, key, &record, Some(cas))? {
+                    return Ok(AcquireStatus::Held(lease));
+                }
+                seen_token = Some(lease.fencing_token);
+            }
+            None if seen_token == Some(record.last_token) => {
+                return Ok(AcquireStatus::Released(record.last_token));
+            }
+            _ => {
+                // Lock is free or its lease expired.
+                let lease = Lease {
+                    owner,
+                    acquired_at: *now,
+                    duration,
+                    fencing_token: record.last_token + 1,
+                    generation: record.generation,
+                };
+                record.lease = Some(lease.clone());
+                record.last_token = lease.fencing_token;
+                if set_lease_record(host, key, &record, Some(cas))?
+                    && confirm_grant::<H, T>(host, key, &lease)?
+                {
+                    return Ok(AcquireStatus::Acquired(lease));
+                }
+            }
+        }
+    }
+}
+
+// Returns true once the record holding the granted lease is stored with a CAS a late insertion
+// can't match, false if the lease was overwritten meanwhile.
+fn confirm_grant<H, T>(host: &H, key: &str, lease: &Lease) -> Result<bool, LeaseError>
+where
+    H: SharedData,
+    T: Serialize + DeserializeOwned,
+{
+    loop {
+        let (record, cas) = get_lease_record::<H, T>(host, key)?;
+        match record {
+            Some(record) if record.lease.as_ref() == Some(lease) => {
+                if cas != Some(1) {
+                    return Ok(true);
+                }
+                // Record is stored again with the next CAS handed out.
+                set_lease_record(host, key, &record, Some(1))?;
+            }
+            _ => return Ok(false),
+        }
+    }
+}
+
+// Generation of a record inserted by the owner. Owners are unique among the contenders for a key,
+// and the time tells apart the records the same owner inserts over time.
+fn generation(owner: u32, now: &Duration) -> u64 {
+    (u64::from(owner) << 32) ^ now.as_nanos() as u64
+}
+
+/// Releases the lease if it is still the one stored for the key and returns the payload of the
+/// record, leaving an empty payload behind. Returns None if the lease expired and was taken over,
+/// in which case the payload is left for the new holder.
+pub fn release_lease<H, T>(host: &H, key: &str, lease: &Lease) -> Result<Option<T>, LeaseError>
+where
+    H: SharedData,
+    T: Serialize + DeserializeOwned + Default,
+{
+    loop {
+        let (record, cas) = get_lease_record::<H, T>(host, key)?;
+        let record = match record {
+            Some(record) if record.lease.as_ref() == Some(lease) => record,
+            _ => return Ok(None),
+        };
+        let released = LeaseRecord {
+            generation: record.generation,
+            lease: None,
+            last_token: record.last_token,
+            payload: T::default(),
+        };
+        if set_lease_record(host, key, &released, cas)? {
+            return Ok(Some(record.payload));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::mock::MockHost;
+    use proxy_wasm::types::Bytes;
+    use std::cell::RefCell;
+
+    const KEY: &str = "CL_service_app";
+    const LEASE_DURATION: Duration = Duration::from_secs(5);
+
+    fn now(secs: u64) -> Duration {
+        Duration::from_secs(100 + secs)
+    }
+
+    fn acquire(host: &MockHost, owner: u32, secs: u64) -> AcquireStatus {
+        acquire_lease::<_, Vec<u32>, _>(host, KEY, owner, &now(secs), LEASE_DURATION, |w| {
+            w.push(owner)
+        })
+        .unwrap()
+    }
+
+    fn acquired(status: AcquireStatus) -> Lease {
+        match status {
+            AcquireStatus::Acquired(lease) => lease,
+            other => panic!("expected lease to be acquired, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn first_owner_acquires_and_others_wait() {
+        let host = MockHost::new();
+        let lease = acquired(acquire(&host, 1, 0));
+        assert_eq!(lease.owner, 1);
+        assert_eq!(lease.fencing_token, 1);
+
+        assert_eq!(acquire(&host, 2, 1), AcquireStatus::Held(lease.clone()));
+        assert_eq!(acquire(&host, 3, 2), AcquireStatus::Held(lease.clone()));
+
+        let waiters = release_lease::<_, Vec<u32>>(&host, KEY, &lease).unwrap();
+        assert_eq!(waiters, Some(vec![2, 3]));
+    }
+
+    #[test]
+    fn tokens_increase_across_releases() {
+        let host = MockHost::new();
+        let first = acquired(acquire(&host, 1, 0));
+        release_lease::<_, Vec<u32>>(&host, KEY, &first).unwrap();
+        let second = acquired(acquire(&host, 2, 1));
+        assert!(second.fencing_token > first.fencing_token);
+    }
+
+    #[test]
+    fn expired_lease_is_stolen_and_keeps_waiters() {
+        let host = MockHost::new();
+        let stale = acquired(acquire(&host, 1, 0));
+        assert!(matches!(acquire(&host, 2, 1), AcquireStatus::Held(_)));
+
+        let fresh = acquired(acquire(&host, 3, 10));
+        assert_eq!(fresh.fencing_token, stale.fencing_token + 1);
+
+        // Stale holder can't release the stolen lease or take its waiters.
+        assert_eq!(
+            release_lease::<_, Vec<u32>>(&host, KEY, &stale).unwrap(),
+            None
+        );
+        assert!(matches!(acquire(&host, 4, 11), AcquireStatus::Held(_)));
+        assert_eq!(
+            release_lease::<_, Vec<u32>>(&host, KEY, &fresh).unwrap(),
+            Some(vec![2, 4])
+        );
+    }
+
+    #[test]
+    fn lease_is_not_acquired_twice_after_cas_wrap() {
+        // CAS of the first record written is 1 right after the wrap, which used to allow
+        // a second owner to acquire the lock again.
+        let host = MockHost::with_initial_cas(u32::MAX);
+        let lease = acquired(acquire(&host, 1, 0));
+        assert_eq!(acquire(&host, 2, 1), AcquireStatus::Held(lease.clone()));
+        release_lease::<_, Vec<u32>>(&host, KEY, &lease).unwrap();
+
+        let mut acquired_by = Vec::new();
+        for owner in 2..6 {
+            if let AcquireStatus::Acquired(lease) = acquire(&host, owner, 2) {
+                acquired_by.push(lease.owner);
+            }
+        }
+        assert_eq!(acquired_by, vec![2]);
+    }
+
+    // Host running the interleaved closure right before its first write, as if another owner ran
+    // between the read and the write of the record.
+    struct InterleavedHost<'a> {
+        host: &'a MockHost,
+        interleaved: RefCell<Option<Box<dyn FnOnce() + 'a>>>,
+    }
+
+    impl SharedData for InterleavedHost<'_> {
+        fn get_shared_data(&self, key: &str) -> Result<(Option<Bytes>, Option<u32>), Status> {
+            self.host.get_shared_data(key)
+        }
+
+        fn set_shared_data(
+            &self,
+            key: &str,
+            value: Option<&[u8]>,
+            cas: Option<u32>,
+        ) -> Result<(), Status> {
+            let interleaved = self.interleaved.borrow_mut().take();
+            if let Some(interleaved) = interleaved {
+                interleaved();
+            }
+            self.host.set_shared_data(key, value, cas)
+        }
+    }
+
+    #[test]
+    fn concurrent_first_acquisitions_grant_a_single_lease() {
+        // Records inserted right before and right after the CAS counter wraps included.
+        for initial_cas in [0, u32::MAX - 1, u32::MAX].iter() {
+            let host = MockHost::with_initial_cas(*initial_cas);
+            let first = RefCell::new(None);
+            // Second owner finds no record, then the first one acquires before it inserts one.
+            let second_host = InterleavedHost {
+                host: &host,
+                interleaved: RefCell::new(Some(Box::new(|| {
+                    *first.borrow_mut() = Some(acquire(&host, 1, 0));
+                }))),
+            };
+            let second = acquire_lease::<_, Vec<u32>, _>(
+                &second_host,
+                KEY,
+                2,
+                &now(0),
+                LEASE_DURATION,
+                |w| w.push(2),
+            )
+            .unwrap();
+            let first = acquired(first.borrow_mut().take().unwrap());
+            assert_eq!(second, AcquireStatus::Held(first.clone()));
+            assert_eq!(
+                release_lease::<_, Vec<u32>>(&host, KEY, &first).unwrap(),
+                Some(vec![2])
+            );
+        }
+    }
+
+    #[test]
+    fn release_seen_between_tries_is_reported() {
+        // Simulates a CAS mismatch while adding to the payload by releasing the lease
+        // from within the update closure.
+        let host = MockHost::new();
+        let lease = acquired(acquire(&host, 1, 0));
+        let status =
+            acquire_lease::<_, Vec<u32>, _>(&host, KEY, 2, &now(1), LEASE_DURATION, |_| {
+                release_lease::<_, Vec<u32>>(&host, KEY, &lease).unwrap();
+            })
+            .unwrap();
+        assert_eq!(status, AcquireStatus::Released(lease.fencing_token));
+    }
+}
@@ -1,5 +1,16 @@
 #![deny(clippy::all, clippy::cargo)]
+pub mod auth_error;
+pub mod backoff;
+pub mod breaker;
+pub mod config;
+pub mod encoding;
+pub mod host;
+pub mod lease;
+pub mod local_cache;
 pub mod proxy;
+pub mod rand;
+pub mod refresh;
+pub mod rejected_keys;
 pub mod stats;
 pub mod structs;
 pub mod upstream;
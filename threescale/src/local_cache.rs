@@ -0,0 +1,478 @@
+use crate::config::{ensure, humanized, record_change, ConfigError};
+use crate::encoding::{decode_application, encode_application};
+use crate::host::SharedData;
+use crate::proxy::{set_encoded_application_to_cache, CacheError, CacheKey};
+use crate::structs::{Application, RateLimitStatus, ThreescaleData};
+use crate::utils::{
+    check_limits, update_application_with_retries, LimitTolerance, UpdateMetricsError,
+};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/** Reasoning behind the local cache:
+* Every request reads the application from shared data, which copies and decodes it, and writes it
+* back encoded once its limits are checked. Workers can keep the applications they decoded along
+* with the CAS they were read with: as long as shared data holds the same CAS, the application did
+* not change and is not decoded again. Hits admitted by a worker are consumed from its own copy and
+* only written to shared data in batches, once flush_hits of them are pending or the oldest of them
+* waited for max_batch_delay. Hits pending in a worker are not seen by the others, so up to
+* flush_hits - 1 hits of every application can be admitted over its limit by every worker, much
+* like limit_tolerance bounds the hits admitted by every proxy instance. Whenever the application
+* changes in shared data, it is decoded again and the pending hits are consumed from it once more.
+**/
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(default, deny_unknown_fields)]
+pub struct LocalCacheConfig {
+    /// Keeps the applications decoded by every worker and writes their hits in batches.
+    pub enabled: bool,
+    /// Maximum number of applications kept by every worker. Least recently used ones are
+    /// dropped first.
+    pub capacity: u64,
+    /// Hits of an application consumed locally after which they are written to shared data.
+    pub flush_hits: u64,
+    /// Maximum time hits consumed locally wait to be written to shared data.
+    #[serde(with = "humanized")]
+    #[cfg_attr(feature = "schema", schemars(with = "String"))]
+    pub max_batch_delay: Duration,
+}
+
+impl Default for LocalCacheConfig {
+    fn default() -> Self {
+        LocalCacheConfig {
+            enabled: false,
+            capacity: 1000,
+            flush_hits: 10,
+            max_batch_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+impl LocalCacheConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        ensure(self.capacity >= 1, "capacity", "must be at least 1")?;
+        ensure(self.flush_hits >= 1, "flush_hits", "must be at least 1")?;
+        ensure(
+            self.max_batch_delay > Duration::default(),
+            "max_batch_delay",
+            "must be greater than 0",
+        )
+    }
+
+    pub fn changes(&self, new: &Self) -> Vec<String> {
+        let mut changes = Vec::new();
+        record_change(&mut changes, "enabled", &self.enabled, &new.enabled);
+        record_change(&mut changes, "capacity", &self.capacity, &new.capacity);
+        record_change(
+            &mut changes,
+            "flush_hits",
+            &self.flush_hits,
+            &new.flush_hits,
+        );
+        record_change(
+            &mut changes,
+            "max_batch_delay",
+            &self.max_batch_delay,
+            &new.max_batch_delay,
+        );
+        changes
+    }
+}
+
+// Application decoded by the worker.
+struct LocalEntry {
+    // Application as stored in shared data with the pending hits consumed from it.
+    app: Application,
+    // CAS of the application in shared data the local copy derives from, 0 if unknown.
+    cas: u32,
+    // Hits consumed locally and not yet written to shared data, by metric.
+    pending: HashMap<String, u64>,
+    pending_hits: u64,
+    // Time the oldest pending hit was consumed.
+    pending_since: Duration,
+    last_used: Duration,
+}
+
+impl LocalEntry {
+    fn is_flush_due(&self, config: &LocalCacheConfig, now: &Duration) -> bool {
+        self.pending_hits >= config.flush_hits
+            || (self.pending_hits > 0
+                && now.checked_sub(self.pending_since).unwrap_or_default()
+                    >= config.max_batch_delay)
+    }
+
+    // Replaces the local copy with the application stored in shared data, consuming the hits
+    // that are still pending from it.
+    fn rebase(&mut self, mut app: Application, cas: u32) {
+        for (metric, hits) in self.pending.iter() {
+            if let Some(usage) = app.local_state.get_mut(metric) {
+                usage.left_hits = usage.left_hits.saturating_sub(*hits);
+            }
+        }
+        self.app = app;
+        self.cas = cas;
+    }
+
+    // Writes the local copy to shared data if it didn't change since it was read. The new CAS is
+    // only known if the application is still the one written when read back.
+    fn write<H: SharedData>(&mut self, host: &H, key: &str) -> Result<(), anyhow::Error> {
+        let written = encode_application(host, &self.app)?;
+        set_encoded_application_to_cache(host, key, &written, self.cas)?;
+        self.pending.clear();
+        self.pending_hits = 0;
+        self.cas = match host.get_shared_data(key) {
+            Ok((Some(bytes), Some(cas))) if bytes == written => cas,
+            _ => 0,
+        };
+        Ok(())
+    }
+
+    // Writes the pending hits to shared data, decoding the application again and consuming the
+    // pending hits from it in case of CAS mismatch, up to max_tries times in total.
+    fn flush<H: SharedData>(
+        &mut self,
+        host: &H,
+        key: &str,
+        max_tries: u32,
+    ) -> Result<(), UpdateMetricsError> {
+        for num_try in 0..max_tries {
+            match self.write(host, key) {
+                Ok(()) => return Ok(()),
+                Err(e) => debug!(
+                    "try ({} out of {}): failed to write pending hits of {}: {}",
+                    num_try + 1,
+                    max_tries,
+                    key,
+                    e
+                ),
+            }
+            if num_try + 1 < max_tries {
+                let (app, cas) = fetch_application(host, key)
+                    .map_err(|e| UpdateMetricsError::AppFetchFail(e.to_string()))?;
+                self.rebase(app, cas);
+            }
+        }
+        Err(UpdateMetricsError::CacheUpdateFail(format!(
+            "pending hits of {} not written after {} tries",
+            key, max_tries
+        )))
+    }
+}
+
+fn fetch_application<H: SharedData>(host: &H, key: &str) -> Result<(Application, u32), CacheError> {
+    match host.get_shared_data(key) {
+        Ok((Some(bytes), Some(cas))) => Ok((decode_application(host, &bytes)?, cas)),
+        Ok(_) => Err(CacheError::AppNotFound),
+        Err(e) => Err(CacheError::ProxyStatus(e as u8)),
+    }
+}
+
+/// Applications decoded by a worker, validated against the CAS of shared data before every use.
+#[derive(Default)]
+pub struct LocalCache {
+    config: LocalCacheConfig,
+    entries: HashMap<String, LocalEntry>,
+}
+
+impl LocalCache {
+    /// Applies a new configuration. Applications are dropped once the local cache is disabled,
+    /// after writing their pending hits.
+    pub fn set_config<H: SharedData>(&mut self, host: &H, config: LocalCacheConfig) {
+        self.config = config;
+        if !self.config.enabled {
+            for (key, mut entry) in self.entries.drain() {
+                if entry.pending_hits > 0 {
+                    if let Err(e) = entry.write(host, &key) {
+                        warn!("pending hits of {} dropped: {}", key, e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the application stored in shared data along with its CAS, with the hits pending in
+    /// this worker consumed from it. The application is only decoded if it changed in shared data
+    /// since this worker last read or wrote it.
+    pub fn get_application<H: SharedData>(
+        &mut self,
+        host: &H,
+        key: &CacheKey,
+        now: &Duration,
+    ) -> Result<(Application, u32), CacheError> {
+        let key = key.as_string();
+        let (bytes, cas) = match host.get_shared_data(&key) {
+            Ok((Some(bytes), Some(cas))) => (bytes, cas),
+            Ok(_) => {
+                // Application was evicted, pending hits are still reported by the singleton.
+                self.entries.remove(&key);
+                return Err(CacheError::AppNotFound);
+            }
+            Err(e) => return Err(CacheError::ProxyStatus(e as u8)),
+        };
+        if let Some(entry) = self.entries.get_mut(&key) {
+            entry.last_used = *now;
+            if entry.cas == cas {
+                return Ok((entry.app.clone(), cas));
+            }
+        }
+        let app = decode_application(host, &bytes)?;
+        match self.entries.get_mut(&key) {
+            Some(entry) => entry.rebase(app, cas),
+            None => {
+                self.evict_if_full(host);
+                self.entries.insert(
+                    key.clone(),
+                    LocalEntry {
+                        app,
+                        cas,
+                        pending: HashMap::new(),
+                        pending_hits: 0,
+                        pending_since: *now,
+                        last_used: *now,
+                    },
+                );
+            }
+        }
+        Ok((self.entries[&key].app.clone(), cas))
+    }
+
+    /// Checks the request against the limits of an application returned by get_application and
+    /// consumes its hits locally, writing them to shared data when a batch is due. Applications
+    /// that are not the latest known by this worker (e.g. fresh from 3scale, with a CAS of 0) are
+    /// written right away like update_application_with_retries does.
+    #[allow(clippy::too_many_arguments)]
+    pub fn update_application<H: SharedData>(
+        &mut self,
+        host: &H,
+        data: &ThreescaleData,
+        key: &CacheKey,
+        app: &mut Application,
+        app_cas: u32,
+        current_time: &Duration,
+        max_tries: u32,
+        tolerance: &LimitTolerance,
+    ) -> Result<Option<RateLimitStatus>, UpdateMetricsError> {
+        let key_string = key.as_string();
+        let entry = match self.entries.get_mut(&key_string) {
+            Some(entry) if app_cas != 0 && entry.cas == app_cas => entry,
+            _ => {
+                return update_application_with_retries(
+                    host,
+                    data,
+                    key,
+                    app,
+                    app_cas,
+                    current_time,
+                    max_tries,
+                    tolerance,
+                )
+            }
+        };
+        let status = check_limits(data, app, current_time, tolerance)?;
+        if let RateLimitStatus::RateLimited(_) = status {
+            return Ok(Some(status));
+        }
+        if entry.pending_hits == 0 {
+            entry.pending_since = *current_time;
+        }
+        for (metric, hits) in data.metrics.borrow().iter() {
+            if app.local_state.contains_key(metric) {
+                *entry.pending.entry(metric.clone()).or_insert(0) += *hits;
+                entry.pending_hits += *hits;
+            }
+        }
+        entry.app = app.clone();
+        if entry.is_flush_due(&self.config, current_time) {
+            if let Err(e) = entry.flush(host, &key_string, max_tries) {
+                // Hits stay pending and are written with the next batch.
+                debug!("failed to write batch of {}: {}", key_string, e);
+            }
+        }
+        Ok(Some(status))
+    }
+
+    /// Writes the pending hits of the applications whose batches waited for max_batch_delay.
+    pub fn flush_due<H: SharedData>(&mut self, host: &H, now: &Duration, max_tries: u32) {
+        for (key, entry) in self.entries.iter_mut() {
+            if entry.is_flush_due(&self.config, now) {
+                if let Err(e) = entry.flush(host, key, max_tries) {
+                    warn!("failed to write batch of {}: {}", key, e);
+                }
+            }
+        }
+    }
+
+    // Drops the least recently used application, after a single try to write its pending hits.
+    fn evict_if_full<H: SharedData>(&mut self, host: &H) {
+        if (self.entries.len() as u64) < self.config.capacity {
+            return;
+        }
+        let key = match self
+            .entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(key, _)| key.clone())
+        {
+            Some(key) => key,
+            None => return,
+        };
+        if let Some(mut entry) = self.entries.remove(&key) {
+            if entry.pending_hits > 0 {
+                if let Err(e) = entry.write(host, &key) {
+                    warn!("pending hits of evicted {} dropped: {}", key, e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::mock::MockHost;
+    use crate::proxy::{get_application_from_cache, set_application_to_cache};
+    use crate::structs::{AppId, AppIdentifier, Period, PeriodWindow, ServiceId, UsageReport};
+    use std::cell::RefCell;
+
+    fn cache_key(app_id: &str) -> CacheKey {
+        CacheKey::from(
+            &ServiceId::from("service"),
+            &AppIdentifier::from(AppId::from(app_id)),
+        )
+    }
+
+    fn request(app_id: &str, hits: u64) -> ThreescaleData {
+        ThreescaleData {
+            app_id: AppIdentifier::from(AppId::from(app_id)),
+            service_id: ServiceId::from("service"),
+            metrics: RefCell::new(vec![("hits".to_string(), hits)].into_iter().collect()),
+            ..Default::default()
+        }
+    }
+
+    fn store_application(host: &MockHost, app_id: &str, left_hits: u64) {
+        let now = host.now();
+        let mut local_state = HashMap::new();
+        local_state.insert(
+            "hits".to_string(),
+            UsageReport {
+                period_window: PeriodWindow {
+                    start: now - Duration::from_secs(30),
+                    end: now + Duration::from_secs(30),
+                    window: Period::Minute,
+                },
+                left_hits,
+                max_value: 100,
+                synced_left_hits: left_hits,
+            },
+        );
+        let app = Application {
+            app_id: AppIdentifier::from(AppId::from(app_id)),
+            service_id: ServiceId::from("service"),
+            local_state,
+            metric_hierarchy: HashMap::new(),
+            app_keys: None,
+            synced_at: now,
+            plan: None,
+        };
+        set_application_to_cache(host, &cache_key(app_id).as_string(), &app, 0).unwrap();
+    }
+
+    fn shared_left_hits(host: &MockHost, app_id: &str) -> u64 {
+        let (app, _) = get_application_from_cache(host, &cache_key(app_id)).unwrap();
+        app.local_state["hits"].left_hits
+    }
+
+    fn local_cache(host: &MockHost, flush_hits: u64, capacity: u64) -> LocalCache {
+        let mut cache = LocalCache::default();
+        cache.set_config(
+            host,
+            LocalCacheConfig {
+                enabled: true,
+                capacity,
+                flush_hits,
+                ..Default::default()
+            },
+        );
+        cache
+    }
+
+    // Handles a request the way the cache filter does and returns the remaining hits.
+    fn handle_request(cache: &mut LocalCache, host: &MockHost, app_id: &str) -> Option<u64> {
+        let now = host.now();
+        let (mut app, cas) = cache
+            .get_application(host, &cache_key(app_id), &now)
+            .unwrap();
+        match cache
+            .update_application(
+                host,
+                &request(app_id, 1),
+                &cache_key(app_id),
+                &mut app,
+                cas,
+                &now,
+                5,
+                &LimitTolerance::default(),
+            )
+            .unwrap()
+        {
+            Some(RateLimitStatus::Authorized(info)) => info.remaining,
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn hits_are_written_in_batches() {
+        let host = MockHost::new();
+        store_application(&host, "app", 10);
+        let mut cache = local_cache(&host, 3, 10);
+
+        assert_eq!(handle_request(&mut cache, &host, "app"), Some(9));
+        assert_eq!(handle_request(&mut cache, &host, "app"), Some(8));
+        assert_eq!(shared_left_hits(&host, "app"), 10);
+        assert_eq!(handle_request(&mut cache, &host, "app"), Some(7));
+        assert_eq!(shared_left_hits(&host, "app"), 7);
+
+        // Written application is not decoded again.
+        let (_, cas) = get_application_from_cache(&host, &cache_key("app")).unwrap();
+        assert_eq!(cache.entries["service_app"].cas, cas);
+
+        handle_request(&mut cache, &host, "app");
+        host.advance_time(Duration::from_secs(1));
+        cache.flush_due(&host, &host.now(), 5);
+        assert_eq!(shared_left_hits(&host, "app"), 6);
+    }
+
+    #[test]
+    fn changed_application_keeps_pending_hits() {
+        let host = MockHost::new();
+        store_application(&host, "app", 10);
+        let mut cache = local_cache(&host, 3, 10);
+        handle_request(&mut cache, &host, "app");
+        handle_request(&mut cache, &host, "app");
+
+        // Singleton or another worker writes the application meanwhile.
+        store_application(&host, "app", 50);
+        assert_eq!(handle_request(&mut cache, &host, "app"), Some(47));
+        assert_eq!(shared_left_hits(&host, "app"), 47);
+    }
+
+    #[test]
+    fn evicted_applications_write_pending_hits() {
+        let host = MockHost::new();
+        store_application(&host, "a", 10);
+        store_application(&host, "b", 10);
+        let mut cache = local_cache(&host, 5, 1);
+        handle_request(&mut cache, &host, "a");
+        handle_request(&mut cache, &host, "b");
+        assert_eq!(cache.entries.len(), 1);
+        assert_eq!(shared_left_hits(&host, "a"), 9);
+
+        cache.set_config(&host, LocalCacheConfig::default());
+        assert!(cache.entries.is_empty());
+        assert_eq!(shared_left_hits(&host, "b"), 9);
+    }
+}
@@ -1,6 +1,7 @@
+use crate::encoding::{decode_application, encode_application};
+use crate::host::SharedData;
 use crate::structs::{AppId, AppIdentifier, Application, ServiceId, UserKey};
 use log::{debug, info, warn};
-use proxy_wasm::hostcalls::{get_shared_data, set_shared_data};
 use std::convert::TryInto;
 use std::hash::{Hash, Hasher};
 
@@ -21,6 +22,10 @@ pub enum CacheError {
     DeserializeFail(#[from] bincode::ErrorKind),
     #[error("serializing into bincode format failed")]
     SerializeFail,
+    #[error("metric {0} is not one of the metrics of the service")]
+    UnknownMetric(u32),
+    #[error("metrics of the service changed concurrently during {0} tries")]
+    ServiceMetricsUpdateFail(u32),
 }
 
 #[derive(Debug, Clone, Eq)]
@@ -67,19 +72,22 @@ impl PartialEq for CacheKey {
 }
 
 // Returns Application from shared data with CAS integer
-pub fn get_application_from_cache(key: &CacheKey) -> Result<(Application, u32), CacheError> {
-    match get_shared_data(&key.as_string()) {
-        Ok((Some(bytes), Some(cas))) => match bincode::deserialize::<Application>(&bytes) {
-            Ok(app) => Ok((app, cas)),
-            Err(e) => Err(CacheError::DeserializeFail(*e)),
-        },
+pub fn get_application_from_cache<H: SharedData>(
+    host: &H,
+    key: &CacheKey,
+) -> Result<(Application, u32), CacheError> {
+    match host.get_shared_data(&key.as_string()) {
+        Ok((Some(bytes), Some(cas))) => Ok((decode_application(host, &bytes)?, cas)),
         Ok((_bytes, _cas)) => Err(CacheError::AppNotFound),
         Err(e) => Err(CacheError::ProxyStatus(e as u8)),
     }
 }
 
-pub fn get_app_id_from_cache(user_key: &UserKey) -> Result<AppId, CacheError> {
-    match get_shared_data(user_key.as_ref()) {
+pub fn get_app_id_from_cache<H: SharedData>(
+    host: &H,
+    user_key: &UserKey,
+) -> Result<AppId, CacheError> {
+    match host.get_shared_data(user_key.as_ref()) {
         Ok((Some(bytes), _cas)) => Ok(AppId::from(std::str::from_utf8(&bytes)?)),
         Ok((None, _cas)) => Err(CacheError::AppIdNotFound),
         Err(e) => Err(CacheError::ProxyStatus(e as u8)),
@@ -87,8 +95,13 @@ pub fn get_app_id_from_cache(user_key: &UserKey) -> Result<AppId, CacheError> {
 }
 
 // overwrites if already present inside the cache
-pub fn set_app_id_to_cache(user_key: &UserKey, app_id: &AppId) -> Result<(), CacheError> {
-    if let Err(e) = set_shared_data(user_key.as_ref(), Some(app_id.as_ref().as_bytes()), None) {
+pub fn set_app_id_to_cache<H: SharedData>(
+    host: &H,
+    user_key: &UserKey,
+    app_id: &AppId,
+) -> Result<(), CacheError> {
+    if let Err(e) = host.set_shared_data(user_key.as_ref(), Some(app_id.as_ref().as_bytes()), None)
+    {
         return Err(CacheError::ProxyStatus(e as u8));
     }
     Ok(())
@@ -96,16 +109,27 @@ pub fn set_app_id_to_cache(user_key: &UserKey, app_id: &AppId) -> Result<(), Cac
 
 // if cas is 0, cache record is overwritten
 // returns false on set failure
-pub fn set_application_to_cache(
+pub fn set_application_to_cache<H: SharedData>(
+    host: &H,
     key: &str,
     app: &Application,
     cas: u32,
+) -> Result<(), anyhow::Error> {
+    let serialized_app = encode_application(host, app)?;
+    set_encoded_application_to_cache(host, key, &serialized_app, cas)
+}
+
+// Same as set_application_to_cache for an application already encoded with encode_application.
+pub fn set_encoded_application_to_cache<H: SharedData>(
+    host: &H,
+    key: &str,
+    serialized_app: &[u8],
+    cas: u32,
 ) -> Result<(), anyhow::Error> {
     info!("setting application with key: {}", key);
-    let prev_memory_usage = (get_cache_pair_size(key)?) as i32;
-    let serialized_app = bincode::serialize::<Application>(app)?;
+    let prev_memory_usage = (get_cache_pair_size(host, key)?) as i32;
     let memory_delta: i32 = (key.len() as i32) + (serialized_app.len() as i32) - prev_memory_usage;
-    if let Err(e) = set_shared_data(key, Some(&serialized_app), Some(cas)) {
+    if let Err(e) = host.set_shared_data(key, Some(serialized_app), Some(cas)) {
         anyhow::bail!(
             "set operation failed for key: {} : {:?}",
             key,
@@ -116,7 +140,7 @@ pub fn set_application_to_cache(
     // No strict compilance for memory counter to be 100% accurate, so if it fails three times
     // and since app is already updated, we can trade accuracy for performace.
     for num_try in 0..3 {
-        match update_shared_memory_size(memory_delta) {
+        match update_shared_memory_size(host, memory_delta) {
             Ok(()) => break,
             Err(e) => debug!("try#{} : failed to update memory counter: {}", num_try, e),
         }
@@ -125,8 +149,8 @@ pub fn set_application_to_cache(
 }
 
 // returns memory used in bytes for both key and value pair stored.
-fn get_cache_pair_size(key: &str) -> Result<usize, CacheError> {
-    match get_shared_data(key) {
+fn get_cache_pair_size<H: SharedData>(host: &H, key: &str) -> Result<usize, CacheError> {
+    match host.get_shared_data(key) {
         Ok((Some(bytes), _)) => Ok(key.len() + bytes.len()),
         Ok((None, _)) => Ok(key.len()),
         Err(e) => Err(CacheError::ProxyStatus(e as u8)),
@@ -134,8 +158,8 @@ fn get_cache_pair_size(key: &str) -> Result<usize, CacheError> {
 }
 
 // Adds delta bytes to the shared memory usage counter
-fn update_shared_memory_size(delta: i32) -> Result<(), anyhow::Error> {
-    let (memory_used, cas) = match get_shared_data(SHARED_MEMORY_COUNTER_KEY) {
+fn update_shared_memory_size<H: SharedData>(host: &H, delta: i32) -> Result<(), anyhow::Error> {
+    let (memory_used, cas) = match host.get_shared_data(SHARED_MEMORY_COUNTER_KEY) {
         Ok((Some(bytes), Some(cas))) => {
             let arr: [u8; 8] = match bytes.try_into() {
                 Ok(res) => res,
@@ -145,7 +169,7 @@ fn update_shared_memory_size(delta: i32) -> Result<(), anyhow::Error> {
         }
         Ok((_, _)) => {
             warn!("shared memory size was not initialized at the start or got deleted somehow!");
-            if let Err(e) = set_shared_data(
+            if let Err(e) = host.set_shared_data(
                 SHARED_MEMORY_COUNTER_KEY,
                 Some(&SHARED_MEMORY_INITIAL_SIZE.to_be_bytes()),
                 None,
@@ -176,7 +200,7 @@ fn update_shared_memory_size(delta: i32) -> Result<(), anyhow::Error> {
         final_size = memory_used.saturating_add(delta.try_into().unwrap());
     }
 
-    if let Err(e) = set_shared_data(
+    if let Err(e) = host.set_shared_data(
         SHARED_MEMORY_COUNTER_KEY,
         Some(&final_size.to_be_bytes()),
         Some(cas),
@@ -194,8 +218,8 @@ fn update_shared_memory_size(delta: i32) -> Result<(), anyhow::Error> {
 // authorize call. Due to unavailability of a deletion API, set_shared_data
 // is used by setting the value as None. However a memory leak occur from the keys.
 // Refer to the upstream isssue here: https://github.com/proxy-wasm/proxy-wasm-rust-sdk/issues/109
-pub fn remove_application_from_cache(key: &str) {
-    match set_shared_data(key, None, None) {
+pub fn remove_application_from_cache<H: SharedData>(host: &H, key: &str) {
+    match host.set_shared_data(key, None, None) {
         Ok(()) => {
             info!("Deleting application with key: {} successful", key)
         }
@@ -1,4 +1,4 @@
-use proxy_wasm::traits::Context;
+use crate::host::Clock;
 
 mod seeding;
 pub use seeding::Error;
@@ -6,4 +6,4 @@ pub use seeding::Error;
 pub mod thread_rng;
 pub use thread_rng::{thread_rng_init, thread_rng_init_fallible};
 
-mod prng;
+pub mod prng;
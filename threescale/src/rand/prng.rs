@@ -1,7 +1,7 @@
 use rand::{RngCore, SeedableRng};
 
 use super::seeding;
-use super::Context;
+use super::Clock;
 
 #[repr(transparent)]
 pub struct Prng<R: SeedableRng> {
@@ -9,9 +9,9 @@ pub struct Prng<R: SeedableRng> {
 }
 
 impl<R: RngCore + SeedableRng> Prng<R> {
-    pub fn new(ctx: &(dyn Context + Send + Sync), context_id: u32) -> Result<Self, seeding::Error> {
+    pub fn new(clock: &(dyn Clock + Send + Sync), context_id: u32) -> Result<Self, seeding::Error> {
         Ok(Self {
-            rng: seeding::seed(ctx, context_id)?,
+            rng: seeding::seed(clock, context_id)?,
         })
     }
 }
@@ -54,8 +54,8 @@ pub type DefaultPRNG = rand_pcg::Lcg64Xsh32;
 pub type DefaultPRNG = rand_xorshift::XorShiftRng;
 
 pub fn with_default(
-    ctx: &(dyn Context + Send + Sync),
+    clock: &(dyn Clock + Send + Sync),
     context_id: u32,
 ) -> Result<Prng<DefaultPRNG>, seeding::Error> {
-    Prng::<DefaultPRNG>::new(ctx, context_id)
+    Prng::<DefaultPRNG>::new(clock, context_id)
 }
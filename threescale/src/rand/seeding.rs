@@ -1,7 +1,7 @@
 use core::time::Duration;
 use std::time::SystemTime;
 
-use proxy_wasm::traits::Context;
+use crate::host::Clock;
 use rand::SeedableRng;
 use rand_jitter::{rand_core::RngCore, JitterRng};
 
@@ -11,8 +11,9 @@ pub enum Error {
     JitterTimer(rand_jitter::TimerError),
 }
 
-fn generate_seed_duration(ctx: &dyn Context) -> Duration {
-    ctx.get_current_time()
+fn generate_seed_duration(clock: &dyn Clock) -> Duration {
+    clock
+        .get_current_time()
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap_or_else(|e| {
             // This can only occur if the current time is earlier than UNIX_EPOCH, iff it's even possible,
@@ -23,7 +24,7 @@ fn generate_seed_duration(ctx: &dyn Context) -> Duration {
 }
 
 fn create_jitter_rng<F>(
-    _ctx: &dyn Context,
+    _clock: &dyn Clock,
     context_id: u32,
     f: F,
 ) -> Result<(rand_jitter::JitterRng<F>, u8), Error>
@@ -55,9 +56,9 @@ where
     Ok((jrng, rounds))
 }
 
-fn generate_seed_once(ctx: &(dyn Context + Send + Sync), context_id: u32) -> Result<u128, Error> {
-    let (mut jrng, rounds) = create_jitter_rng(ctx, context_id, || {
-        let ts = generate_seed_duration(ctx);
+fn generate_seed_once(clock: &(dyn Clock + Send + Sync), context_id: u32) -> Result<u128, Error> {
+    let (mut jrng, rounds) = create_jitter_rng(clock, context_id, || {
+        let ts = generate_seed_duration(clock);
 
         // The correct way to calculate the current time is
         // `ts.as_secs() * 1_000_000_000 + ts.subsec_nanos() as u64`
@@ -77,13 +78,13 @@ fn generate_seed_once(ctx: &(dyn Context + Send + Sync), context_id: u32) -> Res
 }
 
 pub fn seed<R: SeedableRng>(
-    ctx: &(dyn Context + Send + Sync),
+    clock: &(dyn Clock + Send + Sync),
     context_id: u32,
 ) -> Result<R, Error> {
     // hash seed with SipHash
     use rand_seeder::Seeder;
 
-    let seed = generate_seed_once(ctx, context_id)?;
+    let seed = generate_seed_once(clock, context_id)?;
     // seed is further hashed and then fed to the chosen RNG
     Ok(Seeder::from(seed).make_rng())
 }
@@ -1,7 +1,7 @@
 use super::prng::with_default;
 use super::prng::DefaultPRNG;
 use super::prng::Prng;
-use super::Context;
+use super::Clock;
 use super::Error;
 
 use rand::RngCore;
@@ -13,10 +13,10 @@ use rand::RngCore;
 #[inline]
 #[allow(dead_code)]
 pub fn thread_rng_init_fallible(
-    ctx: &(dyn Context + Send + Sync),
+    clock: &(dyn Clock + Send + Sync),
     context_id: u32,
 ) -> Result<ThreadRng, Error> {
-    ThreadRng::new(ctx, context_id)
+    ThreadRng::new(clock, context_id)
 }
 
 // Thread RNG callable from anywhere within the thread.
@@ -27,13 +27,13 @@ pub fn thread_rng_init_fallible(
 // Panic: will panic if the thread local RNG could not be initialized.
 #[inline]
 #[allow(dead_code)]
-pub fn thread_rng_init(ctx: &(dyn Context + Send + Sync), context_id: u32) -> ThreadRng {
-    thread_rng_init_fallible(ctx, context_id)
+pub fn thread_rng_init(clock: &(dyn Clock + Send + Sync), context_id: u32) -> ThreadRng {
+    thread_rng_init_fallible(clock, context_id)
         .expect("could not initialize thread local random number generator")
 }
 
 // `ThreadRng` is a thread local pseudo random number generator seeded with
-// jitter from the clock source of a proxy-wasm context.
+// jitter from the clock of the proxy host (see host::Clock).
 //
 // The methods in this struct require the user to first initialize the thread
 // local RNG except for the constructor, thread_rng(). Failure to do so will
@@ -48,8 +48,8 @@ pub struct ThreadRng;
 impl ThreadRng {
     // Construct a thread
     #[inline]
-    pub fn new(ctx: &(dyn Context + Send + Sync), context_id: u32) -> Result<Self, super::Error> {
-        imp::initialize(ctx, context_id).and(Ok(Self))
+    pub fn new(clock: &(dyn Clock + Send + Sync), context_id: u32) -> Result<Self, super::Error> {
+        imp::initialize(clock, context_id).and(Ok(Self))
     }
 
     // next_u32 without using the RngCore trait which requires a mutable reference
@@ -102,14 +102,14 @@ mod imp {
     }
 
     pub(super) fn initialize(
-        ctx: &(dyn Context + Send + Sync),
+        clock: &(dyn Clock + Send + Sync),
         context_id: u32,
     ) -> Result<(), Error> {
         RNG_INIT.with(|once| {
             let mut res = Ok(());
             once.call_once(|| {
                 res = RNG.with(|rng| {
-                    let res_rng = with_default(ctx, context_id);
+                    let res_rng = with_default(clock, context_id);
                     match res_rng {
                         Ok(r) => {
                             let _ = rng.borrow_mut().replace(r);
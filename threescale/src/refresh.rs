@@ -0,0 +1,246 @@
+use crate::structs::{Application, Period};
+use std::collections::HashMap;
+use std::fmt;
+
+/** Reasoning behind merging refreshed applications:
+* An authorize response carries the state of the application as known to 3scale, which lacks the
+* hits admitted since the last report. Writing it as is would give those hits back to the
+* application, so they are taken from the left hits of the refreshed application, and counted as
+* consumed locally since its last sync. Comparing the refreshed application with the cached one
+* also tells when its plan, its limits or its metrics changed in 3scale, which is otherwise only
+* noticed through requests being limited differently.
+**/
+
+/// Limit of a metric that changed with a refresh of the application.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LimitChange {
+    pub metric: String,
+    pub old: (Period, u64),
+    pub new: (Period, u64),
+}
+
+/// Differences between a cached application and the same application fetched again.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ApplicationChanges {
+    /// Former and new plan, if the plan changed.
+    pub plan: Option<(String, String)>,
+    pub added_metrics: Vec<String>,
+    pub removed_metrics: Vec<String>,
+    pub changed_limits: Vec<LimitChange>,
+}
+
+impl ApplicationChanges {
+    /// Compares the cached application with its refreshed state. Plans are only compared when
+    /// both are known.
+    pub fn between(cached: &Application, refreshed: &Application) -> Self {
+        let plan = match (&cached.plan, &refreshed.plan) {
+            (Some(old), Some(new)) if old != new => Some((old.clone(), new.clone())),
+            _ => None,
+        };
+        let mut added_metrics = Vec::new();
+        let mut changed_limits = Vec::new();
+        for (metric, usage) in refreshed.local_state.iter() {
+            match cached.local_state.get(metric) {
+                None => added_metrics.push(metric.clone()),
+                Some(cached_usage)
+                    if cached_usage.max_value != usage.max_value
+                        || cached_usage.period_window.window != usage.period_window.window =>
+                {
+                    changed_limits.push(LimitChange {
+                        metric: metric.clone(),
+                        old: (
+                            cached_usage.period_window.window.clone(),
+                            cached_usage.max_value,
+                        ),
+                        new: (usage.period_window.window.clone(), usage.max_value),
+                    })
+                }
+                Some(_) => {}
+            }
+        }
+        let mut removed_metrics = cached
+            .local_state
+            .keys()
+            .filter(|metric| !refreshed.local_state.contains_key(*metric))
+            .cloned()
+            .collect::<Vec<_>>();
+        added_metrics.sort();
+        removed_metrics.sort();
+        changed_limits.sort_by(|a, b| a.metric.cmp(&b.metric));
+        ApplicationChanges {
+            plan,
+            added_metrics,
+            removed_metrics,
+            changed_limits,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.plan.is_none()
+            && self.added_metrics.is_empty()
+            && self.removed_metrics.is_empty()
+            && self.changed_limits.is_empty()
+    }
+}
+
+impl fmt::Display for ApplicationChanges {
+    // Formats the changes as space separated key=value pairs, e.g.
+    // plan=Basic->Pro limits=hits:Day:100->Day:1000 added=orders removed=products
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut fields = Vec::new();
+        if let Some((old, new)) = &self.plan {
+            fields.push(format!("plan={}->{}", old, new));
+        }
+        if !self.changed_limits.is_empty() {
+            let limits = self
+                .changed_limits
+                .iter()
+                .map(|change| {
+                    format!(
+                        "{}:{:?}:{}->{:?}:{}",
+                        change.metric, change.old.0, change.old.1, change.new.0, change.new.1
+                    )
+                })
+                .collect::<Vec<_>>();
+            fields.push(format!("limits={}", limits.join(",")));
+        }
+        if !self.added_metrics.is_empty() {
+            fields.push(format!("added={}", self.added_metrics.join(",")));
+        }
+        if !self.removed_metrics.is_empty() {
+            fields.push(format!("removed={}", self.removed_metrics.join(",")));
+        }
+        write!(f, "{}", fields.join(" "))
+    }
+}
+
+/// Takes the hits not yet reported to 3scale from the left hits of the refreshed application,
+/// by metric. Metrics no longer limited are ignored.
+pub fn apply_unreported(app: &mut Application, unreported: &HashMap<String, u64>) {
+    for (metric, hits) in unreported.iter() {
+        if let Some(usage) = app.local_state.get_mut(metric) {
+            usage.left_hits = usage.left_hits.saturating_sub(*hits);
+        }
+    }
+}
+
+/// Takes the hits consumed from the cached application since it was last synced from the left
+/// hits of the refreshed application, for the metrics whose period didn't change.
+pub fn apply_local_consumption(app: &mut Application, cached: &Application) {
+    for (metric, usage) in app.local_state.iter_mut() {
+        if let Some(cached_usage) = cached.local_state.get(metric) {
+            if cached_usage.period_window.start == usage.period_window.start
+                && cached_usage.period_window.end == usage.period_window.end
+            {
+                let consumed = cached_usage
+                    .synced_left_hits
+                    .saturating_sub(cached_usage.left_hits);
+                usage.left_hits = usage.left_hits.saturating_sub(consumed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::{AppId, AppIdentifier, PeriodWindow, ServiceId, UsageReport};
+    use std::time::Duration;
+
+    fn application(plan: &str, limits: &[(&str, Period, u64)]) -> Application {
+        let local_state = limits
+            .iter()
+            .map(|(metric, window, max_value)| {
+                let usage = UsageReport {
+                    period_window: PeriodWindow {
+                        start: Duration::from_secs(0),
+                        end: Duration::from_secs(window.as_secs()),
+                        window: window.clone(),
+                    },
+                    left_hits: *max_value,
+                    max_value: *max_value,
+                    synced_left_hits: *max_value,
+                };
+                (metric.to_string(), usage)
+            })
+            .collect();
+        Application {
+            app_id: AppIdentifier::from(AppId::from("app")),
+            service_id: ServiceId::from("service"),
+            local_state,
+            metric_hierarchy: HashMap::new(),
+            app_keys: None,
+            synced_at: Duration::from_secs(0),
+            plan: Some(plan.to_string()),
+        }
+    }
+
+    #[test]
+    fn plan_limits_and_metrics_changes_are_detected() {
+        let cached = application(
+            "Basic",
+            &[("hits", Period::Day, 100), ("products", Period::Hour, 10)],
+        );
+        let same = application(
+            "Basic",
+            &[("hits", Period::Day, 100), ("products", Period::Hour, 10)],
+        );
+        assert!(ApplicationChanges::between(&cached, &same).is_empty());
+
+        let refreshed = application(
+            "Pro",
+            &[("hits", Period::Day, 1000), ("orders", Period::Hour, 10)],
+        );
+        let changes = ApplicationChanges::between(&cached, &refreshed);
+        assert_eq!(changes.plan, Some(("Basic".to_string(), "Pro".to_string())));
+        assert_eq!(changes.added_metrics, vec!["orders".to_string()]);
+        assert_eq!(changes.removed_metrics, vec!["products".to_string()]);
+        assert_eq!(
+            changes.changed_limits,
+            vec![LimitChange {
+                metric: "hits".to_string(),
+                old: (Period::Day, 100),
+                new: (Period::Day, 1000),
+            }]
+        );
+        assert_eq!(
+            changes.to_string(),
+            "plan=Basic->Pro limits=hits:Day:100->Day:1000 added=orders removed=products"
+        );
+    }
+
+    #[test]
+    fn unreported_hits_are_consumed_from_refreshed_application() {
+        let mut app = application("Basic", &[("hits", Period::Day, 100)]);
+        let mut unreported = HashMap::new();
+        unreported.insert("hits".to_string(), 30);
+        unreported.insert("products".to_string(), 5);
+        apply_unreported(&mut app, &unreported);
+        assert_eq!(app.local_state["hits"].left_hits, 70);
+        assert_eq!(app.local_state["hits"].synced_left_hits, 100);
+    }
+
+    #[test]
+    fn local_consumption_is_kept_by_refreshed_application() {
+        let mut cached = application("Basic", &[("hits", Period::Day, 100)]);
+        cached.local_state.get_mut("hits").unwrap().left_hits = 80;
+        let mut app = application(
+            "Basic",
+            &[("hits", Period::Day, 100), ("orders", Period::Day, 10)],
+        );
+        app.local_state.get_mut("hits").unwrap().synced_left_hits = 90;
+        app.local_state.get_mut("hits").unwrap().left_hits = 90;
+        apply_local_consumption(&mut app, &cached);
+        assert_eq!(app.local_state["hits"].left_hits, 70);
+        assert_eq!(app.local_state["hits"].synced_left_hits, 90);
+        assert_eq!(app.local_state["orders"].left_hits, 10);
+
+        // Hits consumed during the former period are not taken from the new one.
+        let mut next_day = application("Basic", &[("hits", Period::Day, 100)]);
+        let usage = next_day.local_state.get_mut("hits").unwrap();
+        usage.period_window.start = usage.period_window.end;
+        usage.period_window.end += Duration::from_secs(Period::Day.as_secs());
+        apply_local_consumption(&mut next_day, &cached);
+        assert_eq!(next_day.local_state["hits"].left_hits, 100);
+    }
+}
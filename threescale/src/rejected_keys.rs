@@ -0,0 +1,157 @@
+use crate::host::SharedData;
+use crate::proxy::CacheKey;
+use crate::structs::AppKey;
+use proxy_wasm::types::Status;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/** Reasoning behind remembering rejected app keys:
+* An app key unknown to a cached application might have been added after it was cached, so the
+* application is authorized again before denying the request. A client looping over bad app keys
+* would then turn every request into an authorize call to 3scale. App keys still unknown after
+* authorizing the application again are remembered in shared data for a short while, so that the
+* following requests using them are denied straight from the cache by every worker.
+* The record of an application is bounded: once it is full of unexpired keys, any other unknown
+* app key of the application is denied without authorizing it again until a key expires, which
+* bounds the authorize calls spent on unknown keys to MAX_REJECTED_KEYS per application and ttl.
+* The record is best-effort, a key lost to a concurrent write only costs another authorize call.
+**/
+
+const REJECTED_KEYS_PREFIX: &str = "RK_";
+const MAX_UPDATE_TRIES: u32 = 5;
+pub const MAX_REJECTED_KEYS: usize = 16;
+
+#[derive(Debug, thiserror::Error)]
+pub enum RejectedKeysError {
+    #[error("failed to serialize rejected app keys: {0}")]
+    SerializeFail(bincode::ErrorKind),
+    #[error("failed to deserialize rejected app keys: {0}")]
+    DeserializeFail(bincode::ErrorKind),
+    #[error("failure due to proxy's internal issue: {0:?}")]
+    ProxyFailure(Status),
+    #[error("rejected app keys changed concurrently during {0} tries")]
+    UpdateTriesExhausted(u32),
+}
+
+/// App keys of an application rejected by 3scale, along with the time (since UNIX_EPOCH) they
+/// are forgotten at.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct RejectedKeys {
+    keys: Vec<(String, Duration)>,
+}
+
+impl RejectedKeys {
+    /// Returns true if the app key was rejected and is still remembered at time now.
+    pub fn is_rejected(&self, app_key: &AppKey, now: &Duration) -> bool {
+        self.keys
+            .iter()
+            .any(|(key, expiry)| key == app_key.as_ref() && expiry > now)
+    }
+
+    /// Returns true if no other app key can be remembered at time now.
+    pub fn is_full(&self, now: &Duration) -> bool {
+        self.keys.iter().filter(|(_, expiry)| expiry > now).count() >= MAX_REJECTED_KEYS
+    }
+}
+
+fn rejected_keys_key(cache_key: &CacheKey) -> String {
+    format!("{}{}", REJECTED_KEYS_PREFIX, cache_key.as_string())
+}
+
+/// Returns the app keys of the application rejected by 3scale along with their CAS.
+pub fn get_rejected_keys<H: SharedData>(
+    host: &H,
+    cache_key: &CacheKey,
+) -> Result<(RejectedKeys, Option<u32>), RejectedKeysError> {
+    match host.get_shared_data(&rejected_keys_key(cache_key)) {
+        Ok((Some(bytes), cas)) => match bincode::deserialize::<RejectedKeys>(&bytes) {
+            Ok(keys) => Ok((keys, cas)),
+            Err(e) => Err(RejectedKeysError::DeserializeFail(*e)),
+        },
+        Ok((None, cas)) => Ok((RejectedKeys::default(), cas)),
+        Err(e) => Err(RejectedKeysError::ProxyFailure(e)),
+    }
+}
+
+/// Remembers the app key of the application rejected at time now for ttl. Expired keys are
+/// dropped, and nothing is written if the key is already remembered or the record is full.
+pub fn remember_rejected_key<H: SharedData>(
+    host: &H,
+    cache_key: &CacheKey,
+    app_key: &AppKey,
+    ttl: Duration,
+    now: &Duration,
+) -> Result<(), RejectedKeysError> {
+    let key = rejected_keys_key(cache_key);
+    for _ in 0..MAX_UPDATE_TRIES {
+        let (mut rejected, cas) = get_rejected_keys(host, cache_key)?;
+        if rejected.is_rejected(app_key, now) || rejected.is_full(now) {
+            return Ok(());
+        }
+        rejected.keys.retain(|(_, expiry)| expiry > now);
+        rejected
+            .keys
+            .push((app_key.as_ref().to_string(), *now + ttl));
+        let bytes = match bincode::serialize(&rejected) {
+            Ok(res) => res,
+            Err(e) => return Err(RejectedKeysError::SerializeFail(*e)),
+        };
+        match host.set_shared_data(&key, Some(&bytes), cas) {
+            Ok(()) => return Ok(()),
+            Err(Status::CasMismatch) => continue,
+            Err(e) => return Err(RejectedKeysError::ProxyFailure(e)),
+        }
+    }
+    Err(RejectedKeysError::UpdateTriesExhausted(MAX_UPDATE_TRIES))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::mock::MockHost;
+    use crate::structs::{AppId, AppIdentifier, ServiceId};
+
+    fn cache_key() -> CacheKey {
+        CacheKey::from(
+            &ServiceId::from("service"),
+            &AppIdentifier::from(AppId::from("app")),
+        )
+    }
+
+    fn now(secs: u64) -> Duration {
+        Duration::from_secs(100 + secs)
+    }
+
+    #[test]
+    fn rejected_keys_are_remembered_until_their_ttl() {
+        let host = MockHost::new();
+        let ttl = Duration::from_secs(10);
+        let bad_key = AppKey::from("bad");
+        remember_rejected_key(&host, &cache_key(), &bad_key, ttl, &now(0)).unwrap();
+
+        let (rejected, _) = get_rejected_keys(&host, &cache_key()).unwrap();
+        assert!(rejected.is_rejected(&bad_key, &now(9)));
+        assert!(!rejected.is_rejected(&AppKey::from("other"), &now(9)));
+        assert!(!rejected.is_rejected(&bad_key, &now(10)));
+    }
+
+    #[test]
+    fn full_record_keeps_its_keys_until_they_expire() {
+        let host = MockHost::new();
+        let ttl = Duration::from_secs(10);
+        for index in 0..=MAX_REJECTED_KEYS {
+            let app_key = AppKey::from(format!("key{}", index).as_str());
+            remember_rejected_key(&host, &cache_key(), &app_key, ttl, &now(0)).unwrap();
+        }
+        let (rejected, _) = get_rejected_keys(&host, &cache_key()).unwrap();
+        assert!(rejected.is_full(&now(0)));
+        let last_key = AppKey::from(format!("key{}", MAX_REJECTED_KEYS).as_str());
+        assert!(!rejected.is_rejected(&last_key, &now(0)));
+
+        remember_rejected_key(&host, &cache_key(), &last_key, ttl, &now(10)).unwrap();
+        let (rejected, _) = get_rejected_keys(&host, &cache_key()).unwrap();
+        assert!(!rejected.is_full(&now(10)));
+        assert!(rejected.is_rejected(&last_key, &now(10)));
+        assert!(!rejected.is_rejected(&AppKey::from("key0"), &now(10)));
+    }
+}
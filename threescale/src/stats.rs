@@ -1,5 +1,6 @@
+use crate::auth_error::AuthErrorStats;
+use crate::host::Metrics;
 use log::debug;
-use proxy_wasm::hostcalls::{define_metric, increment_metric};
 use proxy_wasm::types::MetricType;
 
 /// ThreescaleStat holds a representation of a single metric.
@@ -19,58 +20,94 @@ pub struct ThreescaleStats {
     pub cache_hits: ThreescaleStat,
     // Total number of unauthorized responses for authorize requests from cache filter.
     pub unauthorized: ThreescaleStat,
+    // Total number of requests denied for an app key unknown to the cached application.
+    pub unknown_app_keys: ThreescaleStat,
+    // Total number of requests served from expired applications while 3scale backend is unhealthy.
+    pub stale_hits: ThreescaleStat,
+    // Total number of requests using metrics unknown to their service.
+    pub unknown_metrics: ThreescaleStat,
+    // Total number of refreshed applications whose plan, limits or metrics changed.
+    pub app_changes: ThreescaleStat,
     // TODO: Add stats for cache filter authorize timeouts.
     // Total number of timeouts received for authorize requests (currently only singleton considered)
     pub authorize_timeouts: ThreescaleStat,
     // Total number of error codes due to auth metadata info missing.
     pub auth_metadata_errors: ThreescaleStat,
+    // Total number of authorize calls rejected by 3scale, by error code.
+    pub auth_errors: AuthErrorStats,
 }
 
 // Helper method to increment a metric by 1.
-pub fn increment_stat(metric: &ThreescaleStat) {
-    if let Err(error) = increment_metric(metric.0, 1) {
+pub fn increment_stat<H: Metrics>(host: &H, metric: &ThreescaleStat) {
+    if let Err(error) = host.increment_metric(metric.0, 1) {
         debug!("Error incrementing {} metric: {:?}", metric.1, error);
     }
 }
 
 // Helper method to decrement a metric by 1.
-pub fn decrement_stat(metric: &ThreescaleStat) {
-    if let Err(error) = increment_metric(metric.0, -1) {
+pub fn decrement_stat<H: Metrics>(host: &H, metric: &ThreescaleStat) {
+    if let Err(error) = host.increment_metric(metric.0, -1) {
         debug!("Error decrementing {} metric: {:?}", metric.1, error);
     }
 }
 
 // Initialize all the stats. With the current implementation of rust-sdk, it's safe to
 // directly unwrap define_metric().
-pub fn initialize_stats() -> ThreescaleStats {
+pub fn initialize_stats<H: Metrics>(host: &H) -> ThreescaleStats {
     ThreescaleStats {
         cached_apps: ThreescaleStat(
-            define_metric(MetricType::Gauge, "envoy.3scale.cache.apps").unwrap(),
+            host.define_metric(MetricType::Gauge, "envoy.3scale.cache.apps")
+                .unwrap(),
             "envoy.3scale.cache.apps".to_string(),
         ),
         cache_misses: ThreescaleStat(
-            define_metric(MetricType::Counter, "envoy.3scale.cache.misses").unwrap(),
+            host.define_metric(MetricType::Counter, "envoy.3scale.cache.misses")
+                .unwrap(),
             "envoy.3scale.cache.misses".to_string(),
         ),
         cache_hits: ThreescaleStat(
-            define_metric(MetricType::Counter, "envoy.3scale.cache.hits").unwrap(),
+            host.define_metric(MetricType::Counter, "envoy.3scale.cache.hits")
+                .unwrap(),
             "envoy.3scale.cache.hits".to_string(),
         ),
         unauthorized: ThreescaleStat(
-            define_metric(MetricType::Counter, "envoy.3scale.cache.unauthorized").unwrap(),
+            host.define_metric(MetricType::Counter, "envoy.3scale.cache.unauthorized")
+                .unwrap(),
             "envoy.3scale.cache.unauthorized".to_string(),
         ),
+        unknown_app_keys: ThreescaleStat(
+            host.define_metric(MetricType::Counter, "envoy.3scale.cache.unknown_app_keys")
+                .unwrap(),
+            "envoy.3scale.cache.unknown_app_keys".to_string(),
+        ),
+        stale_hits: ThreescaleStat(
+            host.define_metric(MetricType::Counter, "envoy.3scale.cache.stale_hits")
+                .unwrap(),
+            "envoy.3scale.cache.stale_hits".to_string(),
+        ),
+        unknown_metrics: ThreescaleStat(
+            host.define_metric(MetricType::Counter, "envoy.3scale.cache.unknown_metrics")
+                .unwrap(),
+            "envoy.3scale.cache.unknown_metrics".to_string(),
+        ),
+        app_changes: ThreescaleStat(
+            host.define_metric(MetricType::Counter, "envoy.3scale.cache.app_changes")
+                .unwrap(),
+            "envoy.3scale.cache.app_changes".to_string(),
+        ),
         authorize_timeouts: ThreescaleStat(
-            define_metric(MetricType::Counter, "envoy.3scale.cache.auth_timeouts").unwrap(),
+            host.define_metric(MetricType::Counter, "envoy.3scale.cache.auth_timeouts")
+                .unwrap(),
             "envoy.3scale.cache.timeouts".to_string(),
         ),
         auth_metadata_errors: ThreescaleStat(
-            define_metric(
+            host.define_metric(
                 MetricType::Counter,
                 "envoy.3scale.cache.auth_metadata_errors",
             )
             .unwrap(),
             "envoy.3scale.cache.auth_metadata_errors".to_string(),
         ),
+        auth_errors: AuthErrorStats::define(host),
     }
 }
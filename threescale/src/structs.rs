@@ -50,7 +50,7 @@ impl From<&ResponsePeriod> for Period {
 }
 
 #[allow(dead_code)]
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct PeriodWindow {
     pub start: Duration,
     pub end: Duration,
@@ -58,12 +58,14 @@ pub struct PeriodWindow {
 }
 
 #[allow(dead_code)]
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct UsageReport {
     pub period_window: PeriodWindow,
     pub left_hits: u64,
     // Required to renew window untill new state is fetched from 3scale.
     pub max_value: u64,
+    // left_hits as last fetched from 3scale, before any local consumption.
+    pub synced_left_hits: u64,
 }
 
 #[repr(transparent)]
@@ -200,13 +202,17 @@ impl PartialEq for AppIdentifier {
 }
 
 // Threescale's Application representation for cache
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Application {
     pub app_id: AppIdentifier,
     pub service_id: ServiceId,
     pub local_state: HashMap<String, UsageReport>,
     pub metric_hierarchy: Hierarchy,
     pub app_keys: Option<Vec<AppKey>>,
+    // Time (since UNIX_EPOCH) the state of the application was last fetched from 3scale.
+    pub synced_at: Duration,
+    // Name of the plan of the application, unknown for applications cached without it.
+    pub plan: Option<String>,
 }
 
 // Request data recieved from previous filters
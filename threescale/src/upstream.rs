@@ -1,3 +1,4 @@
+use crate::host::HttpCallout;
 use anyhow::anyhow;
 use core::convert::TryFrom;
 use core::iter::Extend;
@@ -46,8 +47,8 @@ impl Upstream {
     }
 
     #[allow(clippy::too_many_arguments)]
-    fn do_call<C: proxy_wasm::traits::Context>(
-        ctx: &C,
+    fn do_call<H: HttpCallout>(
+        host: &H,
         name: &str,
         scheme: &str,
         authority: &str,
@@ -79,7 +80,7 @@ impl Upstream {
             hdrs,
             body_str.as_ref()
         );
-        ctx.dispatch_http_call(name, hdrs, body, trailers, timeout)
+        host.dispatch_http_call(name, hdrs, body, trailers, timeout)
             .map_err(|e| {
                 anyhow!(
                     "failed to dispatch HTTP ({}) call to cluster {} with authority {}: {:?}",
@@ -92,9 +93,9 @@ impl Upstream {
     }
 
     #[allow(dead_code, clippy::too_many_arguments)]
-    pub fn call<C: proxy_wasm::traits::Context>(
+    pub fn call<H: HttpCallout>(
         &self,
-        ctx: &C,
+        host: &H,
         path: &str,
         method: &str,
         headers: Vec<(&str, &str)>,
@@ -114,7 +115,7 @@ impl Upstream {
         }
 
         Self::do_call(
-            ctx,
+            host,
             self.name(),
             self.scheme(),
             self.authority(),
@@ -1,7 +1,12 @@
-use crate::proxy::{set_application_to_cache, CacheKey};
+use crate::config::{ensure, record_change, ConfigError};
+use crate::host::SharedData;
+use crate::proxy::{get_application_from_cache, set_application_to_cache, CacheKey};
 use crate::structs::{
-    Application, Hierarchy, Metrics, Period, RateLimitInfo, RateLimitStatus, ThreescaleData,
+    AppIdentifier, Application, Hierarchy, Metrics, Period, RateLimitInfo, RateLimitStatus,
+    ThreescaleData, UsageReport,
 };
+use log::debug;
+use serde::{Deserialize, Serialize};
 use std::time::Duration;
 
 #[derive(Debug, Clone, thiserror::Error)]
@@ -12,15 +17,116 @@ pub enum UpdateMetricsError {
     RateLimited,
     #[error("application set was unsuccessful: {0}")]
     CacheUpdateFail(String),
+    #[error("failed to fetch the app during app_set retries: {0}")]
+    AppFetchFail(String),
+}
+
+/** Reasoning behind limit tolerance:
+* Requests are authorized against a local snapshot of the application and reported to 3scale later,
+* so every proxy instance can admit up to left_hits of the snapshot before learning about the others.
+* Keeping part of every limit in reserve and splitting what is left across proxy instances bounds
+* the over-admission, at the cost of rate-limiting some requests 3scale would have authorized.
+**/
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitTolerance {
+    /// Fraction (between 0.0 and 1.0) of every limit that is never admitted locally.
+    pub reserved_fraction: f64,
+    /// Hits of every limit that are never admitted locally. Biggest of both reserves applies.
+    pub reserved_hits: u64,
+    /// Number of proxy instances sharing the quota of an application.
+    pub proxy_instances: u32,
+}
+
+impl Default for LimitTolerance {
+    fn default() -> Self {
+        LimitTolerance {
+            reserved_fraction: 0.0,
+            reserved_hits: 0,
+            proxy_instances: 1,
+        }
+    }
+}
+
+impl LimitTolerance {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        ensure(
+            (0.0..=1.0).contains(&self.reserved_fraction),
+            "reserved_fraction",
+            "must be between 0.0 and 1.0",
+        )?;
+        ensure(
+            self.proxy_instances >= 1,
+            "proxy_instances",
+            "must be at least 1",
+        )
+    }
+
+    pub fn changes(&self, new: &Self) -> Vec<String> {
+        let mut changes = Vec::new();
+        record_change(
+            &mut changes,
+            "reserved_fraction",
+            &self.reserved_fraction,
+            &new.reserved_fraction,
+        );
+        record_change(
+            &mut changes,
+            "reserved_hits",
+            &self.reserved_hits,
+            &new.reserved_hits,
+        );
+        record_change(
+            &mut changes,
+            "proxy_instances",
+            &self.proxy_instances,
+            &new.proxy_instances,
+        );
+        changes
+    }
+
+    // Hits that can be admitted locally out of the quota left when the state was last synced.
+    pub fn local_quota(&self, usage_report: &UsageReport) -> u64 {
+        let fraction = self.reserved_fraction.clamp(0.0, 1.0);
+        let reserve = std::cmp::max(
+            (usage_report.max_value as f64 * fraction).ceil() as u64,
+            self.reserved_hits,
+        );
+        usage_report.synced_left_hits.saturating_sub(reserve)
+            / std::cmp::max(self.proxy_instances, 1) as u64
+    }
 }
 
 // updates application to reflect consumed quota if not rate-limited
 // returns Ok() if not rate-limited and faced no problem updating the application
-pub fn limit_check_and_update_application(
+pub fn limit_check_and_update_application<H: SharedData>(
+    host: &H,
     data: &ThreescaleData,
     app: &mut Application,
     app_cas: u32,
     current_time: &Duration,
+    tolerance: &LimitTolerance,
+) -> Result<RateLimitStatus, UpdateMetricsError> {
+    let status = check_limits(data, app, current_time, tolerance)?;
+    if let RateLimitStatus::RateLimited(_) = status {
+        return Ok(status);
+    }
+    // request is not rate-limited and will be set to cache
+    let cache_key = CacheKey::from(&app.service_id, &app.app_id);
+    if let Err(e) = set_application_to_cache(host, &cache_key.as_string(), app, app_cas) {
+        return Err(UpdateMetricsError::CacheUpdateFail(e.to_string()));
+    }
+    Ok(status)
+}
+
+// Checks the request against the limits of the application and consumes its hits from the
+// application if not rate-limited, without storing the application anywhere.
+pub fn check_limits(
+    data: &ThreescaleData,
+    app: &mut Application,
+    current_time: &Duration,
+    tolerance: &LimitTolerance,
 ) -> Result<RateLimitStatus, UpdateMetricsError> {
     let mut rate_limit_info = RateLimitInfo::default();
 
@@ -54,9 +160,14 @@ pub fn limit_check_and_update_application(
 
                 // reset left hits back to max value
                 usage_report.left_hits = usage_report.max_value;
+                usage_report.synced_left_hits = usage_report.max_value;
             }
 
-            if usage_report.left_hits < *hits {
+            let local_quota = tolerance.local_quota(usage_report);
+            let consumed = usage_report
+                .synced_left_hits
+                .saturating_sub(usage_report.left_hits);
+            if usage_report.left_hits < *hits || local_quota < consumed + *hits {
                 rate_limit_info.limit = Some(usage_report.max_value);
                 rate_limit_info.remaining = Some(0);
                 // Current time is between period window since it's already adjusted.
@@ -71,30 +182,382 @@ pub fn limit_check_and_update_application(
                 || usage_report.max_value < rate_limit_info.limit.unwrap()
             {
                 rate_limit_info.limit = Some(usage_report.max_value);
-                rate_limit_info.remaining = Some(usage_report.left_hits);
+                rate_limit_info.remaining = Some(local_quota - consumed - *hits);
                 rate_limit_info.reset =
                     Some(period.end.checked_sub(*current_time).unwrap().as_secs());
             }
         }
     }
+    Ok(RateLimitStatus::Authorized(rate_limit_info))
+}
 
-    // request is not rate-limited and will be set to cache
-    let cache_key = CacheKey::from(&app.service_id, &app.app_id);
-    if let Err(e) = set_application_to_cache(&cache_key.as_string(), app, app_cas) {
-        return Err(UpdateMetricsError::CacheUpdateFail(e.to_string()));
+// Runs limit_check_and_update_application and in case of CAS mismatch, fetches the latest
+// application from the cache and tries again, up to max_tries times in total.
+// Returns Ok(None) if the request is not rate-limited but all tries failed to update the cache.
+#[allow(clippy::too_many_arguments)]
+pub fn update_application_with_retries<H: SharedData>(
+    host: &H,
+    data: &ThreescaleData,
+    cache_key: &CacheKey,
+    app: &mut Application,
+    mut app_cas: u32,
+    current_time: &Duration,
+    max_tries: u32,
+    tolerance: &LimitTolerance,
+) -> Result<Option<RateLimitStatus>, UpdateMetricsError> {
+    for num_try in 0..max_tries {
+        match limit_check_and_update_application(host, data, app, app_cas, current_time, tolerance)
+        {
+            Ok(status) => return Ok(Some(status)),
+            Err(UpdateMetricsError::CacheUpdateFail(reason)) => {
+                debug!(
+                    "try ({} out of {}): failed to set application to cache: {}",
+                    (num_try as u64) + 1,
+                    max_tries,
+                    reason
+                );
+                if num_try + 1 < max_tries {
+                    match get_application_from_cache(host, cache_key) {
+                        Ok((new_app, cas)) => {
+                            *app = new_app;
+                            app_cas = cas;
+                        }
+                        Err(e) => return Err(UpdateMetricsError::AppFetchFail(e.to_string())),
+                    }
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(None)
+}
+
+// Checks the app key of the request against the app keys of the cached application. Requests
+// for applications without known app keys and requests identified by user_key are not checked.
+pub fn is_app_key_valid(app: &Application, app_id: &AppIdentifier) -> bool {
+    let keys = match &app.app_keys {
+        Some(keys) if !keys.is_empty() => keys,
+        _ => return true,
+    };
+    match app_id {
+        AppIdentifier::AppId(_, Some(app_key)) => keys.contains(app_key),
+        AppIdentifier::AppId(_, None) => false,
+        AppIdentifier::UserKey(_) => true,
     }
-    Ok(RateLimitStatus::Authorized(rate_limit_info))
 }
 
 // It takes the provided hierarchy structure, and uses it
 // to determine how the metrics, m, are affected, incrementing parent metrics
 // based on the value of the parents child/children metrics.
 pub fn add_hierarchy_to_metrics(hierarchy: &Hierarchy, metrics: &mut Metrics) {
+    // Note: Borrowing mutably while iterating over the RefCell would panic at runtime.
+    let metrics = metrics.get_mut();
     for (parent, children) in hierarchy.iter() {
-        for (metric, hits) in metrics.borrow().iter() {
-            if children.contains(metric) {
-                *metrics.borrow_mut().entry(parent.to_string()).or_insert(0) += *hits;
+        let children_hits = metrics
+            .iter()
+            .filter(|(metric, _)| children.contains(*metric))
+            .map(|(_, hits)| *hits)
+            .collect::<Vec<_>>();
+        if !children_hits.is_empty() {
+            *metrics.entry(parent.to_string()).or_insert(0) += children_hits.iter().sum::<u64>();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::mock::MockHost;
+    use crate::structs::{AppId, AppKey, PeriodWindow, ServiceId, UserKey};
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    fn request(usages: &[(&str, u64)]) -> ThreescaleData {
+        ThreescaleData {
+            app_id: AppIdentifier::from(AppId::from("app")),
+            service_id: ServiceId::from("service"),
+            metrics: RefCell::new(
+                usages
+                    .iter()
+                    .map(|(metric, hits)| (metric.to_string(), *hits))
+                    .collect(),
+            ),
+            ..Default::default()
+        }
+    }
+
+    fn application(limits: &[(&str, u64, u64)], now: &Duration) -> Application {
+        let mut local_state = HashMap::new();
+        for (metric, left_hits, max_value) in limits {
+            local_state.insert(
+                metric.to_string(),
+                UsageReport {
+                    period_window: PeriodWindow {
+                        start: *now - Duration::from_secs(30),
+                        end: *now + Duration::from_secs(30),
+                        window: Period::Minute,
+                    },
+                    left_hits: *left_hits,
+                    max_value: *max_value,
+                    synced_left_hits: *left_hits,
+                },
+            );
+        }
+        Application {
+            app_id: AppIdentifier::from(AppId::from("app")),
+            service_id: ServiceId::from("service"),
+            local_state,
+            metric_hierarchy: HashMap::new(),
+            app_keys: None,
+            synced_at: *now,
+            plan: None,
+        }
+    }
+
+    fn cache_key() -> CacheKey {
+        CacheKey::from(
+            &ServiceId::from("service"),
+            &AppIdentifier::from(AppId::from("app")),
+        )
+    }
+
+    fn cached_left_hits(host: &MockHost, metric: &str) -> u64 {
+        let (app, _) = get_application_from_cache(host, &cache_key()).unwrap();
+        app.local_state.get(metric).unwrap().left_hits
+    }
+
+    #[test]
+    fn authorized_request_is_consumed_from_cache() {
+        let host = MockHost::new();
+        let now = host.now();
+        let mut app = application(&[("hits", 10, 10)], &now);
+
+        let status = limit_check_and_update_application(
+            &host,
+            &request(&[("hits", 3)]),
+            &mut app,
+            0,
+            &now,
+            &LimitTolerance::default(),
+        )
+        .unwrap();
+        match status {
+            RateLimitStatus::Authorized(info) => {
+                assert_eq!(info.limit, Some(10));
+                assert_eq!(info.remaining, Some(7));
+                assert_eq!(info.reset, Some(30));
             }
+            RateLimitStatus::RateLimited(_) => panic!("request should not be rate-limited"),
+        }
+        assert_eq!(cached_left_hits(&host, "hits"), 7);
+    }
+
+    #[test]
+    fn request_over_limit_is_rate_limited_and_not_cached() {
+        let host = MockHost::new();
+        let now = host.now();
+        let mut app = application(&[("hits", 2, 10)], &now);
+
+        let status = limit_check_and_update_application(
+            &host,
+            &request(&[("hits", 3)]),
+            &mut app,
+            0,
+            &now,
+            &LimitTolerance::default(),
+        )
+        .unwrap();
+        assert!(matches!(status, RateLimitStatus::RateLimited(_)));
+        assert!(get_application_from_cache(&host, &cache_key()).is_err());
+    }
+
+    #[test]
+    fn expired_period_window_is_renewed() {
+        let host = MockHost::new();
+        let now = host.now();
+        let mut app = application(&[("hits", 0, 10)], &now);
+        let later = now + Duration::from_secs(95);
+
+        let status = limit_check_and_update_application(
+            &host,
+            &request(&[("hits", 1)]),
+            &mut app,
+            0,
+            &later,
+            &LimitTolerance::default(),
+        )
+        .unwrap();
+        assert!(matches!(status, RateLimitStatus::Authorized(_)));
+        let usage = app.local_state.get("hits").unwrap();
+        assert_eq!(usage.left_hits, 9);
+        assert_eq!(usage.period_window.start, now + Duration::from_secs(90));
+        assert_eq!(usage.period_window.end, now + Duration::from_secs(150));
+    }
+
+    #[test]
+    fn reserved_quota_is_not_admitted() {
+        let host = MockHost::new();
+        let now = host.now();
+        let tolerance = LimitTolerance {
+            reserved_fraction: 0.1,
+            reserved_hits: 3,
+            ..Default::default()
+        };
+        let mut app = application(&[("hits", 10, 20)], &now);
+        let mut admitted = 0;
+        while let RateLimitStatus::Authorized(_) = limit_check_and_update_application(
+            &host,
+            &request(&[("hits", 1)]),
+            &mut app,
+            0,
+            &now,
+            &tolerance,
+        )
+        .unwrap()
+        {
+            admitted += 1;
+        }
+        // Biggest reserve is 3 hits out of the 10 left.
+        assert_eq!(admitted, 7);
+        assert_eq!(app.local_state.get("hits").unwrap().left_hits, 3);
+    }
+
+    #[test]
+    fn quota_is_split_across_proxy_instances() {
+        let host = MockHost::new();
+        let now = host.now();
+        let tolerance = LimitTolerance {
+            proxy_instances: 4,
+            ..Default::default()
+        };
+        let mut app = application(&[("hits", 10, 10)], &now);
+        match limit_check_and_update_application(
+            &host,
+            &request(&[("hits", 2)]),
+            &mut app,
+            0,
+            &now,
+            &tolerance,
+        )
+        .unwrap()
+        {
+            RateLimitStatus::Authorized(info) => assert_eq!(info.remaining, Some(0)),
+            RateLimitStatus::RateLimited(_) => panic!("request should not be rate-limited"),
         }
+        let status = limit_check_and_update_application(
+            &host,
+            &request(&[("hits", 1)]),
+            &mut app,
+            0,
+            &now,
+            &tolerance,
+        )
+        .unwrap();
+        assert!(matches!(status, RateLimitStatus::RateLimited(_)));
+
+        // Whole quota is available again once the window is renewed.
+        let later = now + Duration::from_secs(60);
+        let status = limit_check_and_update_application(
+            &host,
+            &request(&[("hits", 2)]),
+            &mut app,
+            0,
+            &later,
+            &tolerance,
+        )
+        .unwrap();
+        assert!(matches!(status, RateLimitStatus::Authorized(_)));
+    }
+
+    #[test]
+    fn cas_mismatch_is_retried_with_latest_application() {
+        let host = MockHost::new();
+        let now = host.now();
+        let key = cache_key().as_string();
+        set_application_to_cache(&host, &key, &application(&[("hits", 10, 10)], &now), 0).unwrap();
+        let (mut stale_app, stale_cas) = get_application_from_cache(&host, &cache_key()).unwrap();
+
+        // Another worker consumes from the same application in the meantime.
+        let (mut app, cas) = get_application_from_cache(&host, &cache_key()).unwrap();
+        limit_check_and_update_application(
+            &host,
+            &request(&[("hits", 4)]),
+            &mut app,
+            cas,
+            &now,
+            &LimitTolerance::default(),
+        )
+        .unwrap();
+
+        let status = update_application_with_retries(
+            &host,
+            &request(&[("hits", 1)]),
+            &cache_key(),
+            &mut stale_app,
+            stale_cas,
+            &now,
+            5,
+            &LimitTolerance::default(),
+        )
+        .unwrap();
+        assert!(matches!(status, Some(RateLimitStatus::Authorized(_))));
+        assert_eq!(cached_left_hits(&host, "hits"), 5);
+    }
+
+    #[test]
+    fn retries_give_up_after_max_tries() {
+        let host = MockHost::new();
+        let now = host.now();
+        let key = cache_key().as_string();
+        set_application_to_cache(&host, &key, &application(&[("hits", 10, 10)], &now), 0).unwrap();
+        let (mut stale_app, stale_cas) = get_application_from_cache(&host, &cache_key()).unwrap();
+        set_application_to_cache(&host, &key, &application(&[("hits", 8, 10)], &now), 0).unwrap();
+
+        let status = update_application_with_retries(
+            &host,
+            &request(&[("hits", 1)]),
+            &cache_key(),
+            &mut stale_app,
+            stale_cas,
+            &now,
+            1,
+            &LimitTolerance::default(),
+        )
+        .unwrap();
+        assert!(status.is_none());
+        assert_eq!(cached_left_hits(&host, "hits"), 8);
+    }
+
+    #[test]
+    fn hierarchy_adds_child_hits_to_parent() {
+        let mut hierarchy = Hierarchy::new();
+        hierarchy.insert(
+            "hits".to_string(),
+            vec!["get".to_string(), "post".to_string()],
+        );
+        let mut metrics = request(&[("get", 2), ("post", 3)]).metrics;
+        add_hierarchy_to_metrics(&hierarchy, &mut metrics);
+        assert_eq!(metrics.borrow().get("hits"), Some(&5));
+    }
+
+    #[test]
+    fn app_key_is_checked_against_known_keys() {
+        let now = Duration::from_secs(1_600_000_000);
+        let mut app = application(&[("hits", 10, 10)], &now);
+        let with_key = |key: &str| AppIdentifier::from((AppId::from("app"), AppKey::from(key)));
+        // No known keys yet, nothing to validate against.
+        assert!(is_app_key_valid(&app, &with_key("secret")));
+
+        app.app_keys = Some(vec![AppKey::from("secret")]);
+        assert!(is_app_key_valid(&app, &with_key("secret")));
+        assert!(!is_app_key_valid(&app, &with_key("other")));
+        assert!(!is_app_key_valid(
+            &app,
+            &AppIdentifier::from(AppId::from("app"))
+        ));
+        assert!(is_app_key_valid(
+            &app,
+            &AppIdentifier::from(UserKey::from("user_key"))
+        ));
     }
 }